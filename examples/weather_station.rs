@@ -16,9 +16,22 @@ use panic_probe as _;
 
 // LoRaWAN credentials (replace with your own)
 const DEV_EUI: [u8; 8] = [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
-const APP_EUI: [u8; 8] = [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+const JOIN_EUI: [u8; 8] = [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+const NWK_KEY: [u8; 16] = [0x00; 16];
 const APP_KEY: [u8; 16] = [0x00; 16];
 
+/// Uplink and downlink queues of the LoRaWAN task
+#[cfg(feature = "lora")]
+static LORAWAN: aeonnode::lora::LoRaWANChannels = aeonnode::lora::LoRaWANChannels::new();
+
+/// EEPROM partition holding the LoRaWAN session, DevNonce and frame counters
+#[cfg(feature = "lora")]
+static SESSION_STORAGE: static_cell::StaticCell<aeonnode::core::DataEeprom> = static_cell::StaticCell::new();
+
+/// EEPROM partition holding readings waiting to be sent
+#[cfg(feature = "lora")]
+static BACKLOG_STORAGE: static_cell::StaticCell<aeonnode::core::DataEeprom> = static_cell::StaticCell::new();
+
 /// Main entry point
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
@@ -33,12 +46,19 @@ async fn main(spawner: Spawner) {
     // Initialize LoRa radio
     #[cfg(feature = "lora")]
     {
-        use aeonnode::lora::{SX1276, LoRaConfig, LoRaWAN, LoRaWANConfig, DeviceClass};
+        use aeonnode::lora::{
+            lorawan_task, persist, uplink_queue, Activation, SX1276, LoRaConfig, LoRaWAN, LoRaWANConfig,
+            LoRaWANVersion, DeviceClass, Region, RetryPolicy, UplinkQueue,
+        };
+        use embassy_time::Delay;
+        use embedded_hal_bus::spi::ExclusiveDevice;
+        
+        // The radio has the SPI bus to itself; ExclusiveDevice drives NSS
+        let lora_spi = ExclusiveDevice::new(board.lora_spi, board.lora_nss, Delay).unwrap();
         
         let lora_config = LoRaConfig::default();
         let mut sx1276 = SX1276::new(
-            board.lora_spi,
-            board.lora_nss,
+            lora_spi,
             board.lora_reset,
             board.lora_dio0,
             lora_config,
@@ -50,19 +70,45 @@ async fn main(spawner: Spawner) {
         }
         
         let lorawan_config = LoRaWANConfig {
-            dev_eui: DEV_EUI,
-            app_eui: APP_EUI,
-            app_key: APP_KEY,
+            version: LoRaWANVersion::V1_1,
+            activation: Activation::Otaa {
+                dev_eui: DEV_EUI,
+                join_eui: JOIN_EUI,
+                nwk_key: NWK_KEY,
+                app_key: APP_KEY,
+            },
             device_class: DeviceClass::ClassA,
+            ping_slot_periodicity: 7,
+            region: Region::AS923,
+            sub_band: None,
+            adr: true,
+            retry: RetryPolicy::default(),
+            wait_for_duty_cycle: true,
         };
         
+        // Session first, then the uplink backlog; the rest of the EEPROM is
+        // left for firmware update state
+        let (session_eeprom, eeprom) = board.eeprom.split(persist::STORAGE_LEN);
+        let (backlog_eeprom, _fuota_eeprom) = eeprom.split(uplink_queue::SPILL_LEN);
+        
+        // The join server rejects a DevNonce it has seen, so the counter
+        // must survive resets
         let mut lorawan = LoRaWAN::new(sx1276, lorawan_config);
+        if lorawan.attach_storage(SESSION_STORAGE.init(session_eeprom)) {
+            info!("✓ Resumed stored LoRaWAN session");
+        }
         
-        info!("Joining LoRaWAN network...");
-        match lorawan.join().await {
-            Ok(_) => info!("✓ Joined LoRaWAN network"),
-            Err(e) => error!("Failed to join: {:?}", e),
+        // Readings taken while the network is unreachable wait in the backlog
+        let mut backlog = UplinkQueue::new();
+        let restored = backlog.attach_storage(BACKLOG_STORAGE.init(backlog_eeprom));
+        if restored > 0 {
+            info!("✓ Restored {} queued readings", restored);
         }
+        
+        // The task joins the network and owns the stack from here on
+        info!("Starting LoRaWAN task...");
+        spawner.spawn(lorawan_task(lorawan, backlog, &LORAWAN)).unwrap();
+        spawner.spawn(config_handler()).unwrap();
     }
     
     info!("Entering main loop...");
@@ -107,10 +153,15 @@ async fn main(spawner: Spawner) {
             ];
             
             info!("Transmitting data...");
-            // TODO: Uncomment when lorawan is properly integrated
-            // if let Err(e) = lorawan.send(1, &payload, false).await {
-            //     error!("Failed to send data: {:?}", e);
-            // }
+            let options = aeonnode::lora::UplinkOptions {
+                priority: aeonnode::lora::Priority::Periodic,
+                confirmed: false,
+                // Readings may be sent long after they were taken
+                timestamp: true,
+            };
+            if let Err(e) = LORAWAN.send_with(1, &payload, options).await {
+                error!("Failed to queue data: {:?}", defmt::Debug2Format(&e));
+            }
         }
         
         counter += 1;
@@ -121,3 +172,20 @@ async fn main(spawner: Spawner) {
         Timer::after(Duration::from_secs(900)).await;
     }
 }
+
+/// Applies configuration downlinks received on FPort 10
+#[cfg(feature = "lora")]
+#[embassy_executor::task]
+async fn config_handler() {
+    let mut handler = LORAWAN.subscribe(Some(10)).unwrap();
+    loop {
+        let downlink = handler.next().await;
+        info!(
+            "Config downlink: {} bytes (RSSI {} dBm, SNR {} dB)",
+            downlink.payload().len(),
+            downlink.rssi,
+            downlink.snr
+        );
+        // TODO: Apply the measurement interval etc.
+    }
+}
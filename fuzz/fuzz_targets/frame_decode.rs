@@ -0,0 +1,33 @@
+//! Feed arbitrary PHYPayloads to the frame decoders
+//!
+//! Every input must decode or fail with a `FrameError`; a panic is a bug.
+//! Run with `cargo +nightly fuzz run frame_decode` from the repository root.
+
+#![no_main]
+
+use aeonnode::lora::frame::{decode_mhdr, split_mic, DataFrame, JoinAccept, JoinRequest, RejoinRequest};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|phy: &[u8]| {
+    let _ = decode_mhdr(phy);
+    let _ = JoinRequest::decode(phy);
+    let _ = JoinAccept::decode(phy);
+    let _ = RejoinRequest::decode(phy);
+
+    let Ok((msg, _)) = split_mic(phy) else {
+        return;
+    };
+    let Ok(frame) = DataFrame::decode(msg) else {
+        return;
+    };
+    assert!(frame.fopts_range().end <= msg.len());
+    assert!(frame.payload_range().end <= msg.len());
+    frame.mac_commands().for_each(drop);
+
+    // Whatever decodes encodes back to the same bytes, except for the
+    // MHDR RFU bits the decoder ignores
+    let mut out = vec![0u8; msg.len()];
+    let len = frame.encode(&mut out).expect("decoded frame re-encodes");
+    assert_eq!(out[0], frame.mtype.mhdr());
+    assert_eq!(&out[1..len], &msg[1..]);
+});
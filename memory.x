@@ -1,7 +1,9 @@
 MEMORY
 {
-  /* STM32L082CZ: 192K Flash, 20K RAM */
-  FLASH : ORIGIN = 0x08000000, LENGTH = 192K
+  /* STM32L082CZ: 192K Flash in two banks, 20K RAM */
+  /* Bank 1 holds the application, bank 2 receives firmware updates */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 96K
+  IMAGE : ORIGIN = 0x08018000, LENGTH = 96K
   RAM : ORIGIN = 0x20000000, LENGTH = 20K
 }
 
@@ -9,7 +9,8 @@
 use embassy_stm32::{
     adc::Adc,
     bind_interrupts,
-    gpio::{Level, Output, Speed, Input, Pull},
+    exti::ExtiInput,
+    gpio::{Level, Output, Speed, Pull},
     i2c::{self, I2c},
     peripherals,
     spi::{self, Spi},
@@ -17,6 +18,10 @@ use embassy_stm32::{
 };
 use embassy_stm32::time::Hertz;
 
+use super::eeprom::DataEeprom;
+use super::flash::ImageFlash;
+use super::rtc::RtcClock;
+
 /// RAK3112 Board configuration and peripherals
 pub struct Board {
     /// SPI bus for SX1276 LoRa radio
@@ -29,8 +34,8 @@ pub struct Board {
     pub lora_reset: Output<'static>,
     
     /// SX1276 DIO pins for interrupt handling
-    pub lora_dio0: Input<'static>,
-    pub lora_dio1: Input<'static>,
+    pub lora_dio0: ExtiInput<'static>,
+    pub lora_dio1: ExtiInput<'static>,
     
     /// I2C bus for environmental sensors (BME280, etc.)
     pub sensor_i2c: I2c<'static, peripherals::I2C1>,
@@ -43,6 +48,15 @@ pub struct Board {
     
     /// Solar panel voltage sense pin (ADC channel)
     pub solar_sense: peripherals::PA1,
+
+    /// 6 KB data EEPROM (LoRaWAN session, counters)
+    pub eeprom: DataEeprom,
+
+    /// Flash bank 2, where firmware updates are received
+    pub image_flash: ImageFlash,
+
+    /// RTC on the LSE, set from the LoRaWAN network time
+    pub rtc: RtcClock,
 }
 
 impl Board {
@@ -68,9 +82,9 @@ impl Board {
         let lora_nss = Output::new(p.PA4, Level::High, Speed::VeryHigh);
         let lora_reset = Output::new(p.PB0, Level::High, Speed::Low);
         
-        // SX1276 interrupt pins (DIO0 and DIO1)
-        let lora_dio0 = Input::new(p.PB1, Pull::Down);
-        let lora_dio1 = Input::new(p.PB10, Pull::Down);
+        // SX1276 interrupt pins (DIO0 and DIO1), EXTI-backed so they can be awaited
+        let lora_dio0 = ExtiInput::new(p.PB1, p.EXTI1, Pull::Down);
+        let lora_dio1 = ExtiInput::new(p.PB10, p.EXTI10, Pull::Down);
 
         // Configure I2C for environmental sensors (I2C1)
         // I2C1: SCL=PB6, SDA=PB7
@@ -94,6 +108,9 @@ impl Board {
             adc,
             battery_sense: p.PA0,
             solar_sense: p.PA1,
+            eeprom: DataEeprom::new(p.FLASH),
+            image_flash: ImageFlash::new(),
+            rtc: RtcClock::new(p.RTC),
         }
     }
 
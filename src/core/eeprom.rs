@@ -0,0 +1,189 @@
+//! STM32L0 data EEPROM
+//!
+//! The STM32L082CZ has 6 KB of data EEPROM at 0x0808_0000 with an endurance
+//! of about 100k writes per word. Reads are plain memory accesses; writes go
+//! through the FLASH interface after unlocking PECR. Words that already hold
+//! the requested value are not rewritten, so callers can store whole records
+//! without wearing out unchanged bytes.
+
+use core::ptr;
+
+use embassy_stm32::pac::FLASH;
+use embassy_stm32::peripherals;
+
+/// Start of the data EEPROM in the address space
+const EEPROM_BASE: usize = 0x0808_0000;
+/// Size of the data EEPROM on the STM32L082CZ
+pub const EEPROM_SIZE: usize = 6 * 1024;
+
+/// PEKEYR unlock sequence
+const PEKEY1: u32 = 0x89AB_CDEF;
+const PEKEY2: u32 = 0x0203_0405;
+
+/// Data EEPROM errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum EepromError {
+    /// Access outside this partition
+    OutOfRange,
+    /// PECR stayed locked after the unlock sequence
+    Locked,
+    /// The FLASH interface reported a programming error
+    WriteFailed,
+}
+
+/// A window of the data EEPROM
+///
+/// [`DataEeprom::new`] covers the whole EEPROM; [`split`](DataEeprom::split)
+/// hands out disjoint partitions, e.g. one for the LoRaWAN session and one
+/// for firmware update state.
+pub struct DataEeprom {
+    start: usize,
+    len: usize,
+}
+
+impl DataEeprom {
+    /// Take ownership of the data EEPROM
+    pub fn new(_flash: peripherals::FLASH) -> Self {
+        Self {
+            start: 0,
+            len: EEPROM_SIZE,
+        }
+    }
+
+    /// Split into `[0, at)` and `[at, len)`, `at` rounded down to a word
+    pub fn split(self, at: usize) -> (Self, Self) {
+        let at = (at & !3).min(self.len);
+        (
+            Self {
+                start: self.start,
+                len: at,
+            },
+            Self {
+                start: self.start + at,
+                len: self.len - at,
+            },
+        )
+    }
+
+    /// Size of this partition in bytes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether this partition is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Read `buf.len()` bytes at `offset`
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), EepromError> {
+        let addr = self.address(offset, buf.len())?;
+        for (i, byte) in buf.iter_mut().enumerate() {
+            // SAFETY: the range was checked against the EEPROM size
+            *byte = unsafe { ptr::read_volatile((addr + i) as *const u8) };
+        }
+        Ok(())
+    }
+
+    /// Write `data` at `offset`, skipping words that already match
+    pub fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), EepromError> {
+        let addr = self.address(offset, data.len())?;
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        Self::unlock()?;
+        let result = Self::program(addr, data);
+        Self::lock();
+        result
+    }
+
+    /// Absolute address of `offset`, checking that `len` bytes fit
+    fn address(&self, offset: usize, len: usize) -> Result<usize, EepromError> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len => Ok(EEPROM_BASE + self.start + offset),
+            _ => Err(EepromError::OutOfRange),
+        }
+    }
+
+    /// Program word by word, merging partial words with their current contents
+    fn program(addr: usize, data: &[u8]) -> Result<(), EepromError> {
+        let first_word = addr & !3;
+        let end = addr + data.len();
+        let mut word_addr = first_word;
+
+        while word_addr < end {
+            // SAFETY: word-aligned address inside the data EEPROM
+            let current = unsafe { ptr::read_volatile(word_addr as *const u32) };
+            let mut bytes = current.to_le_bytes();
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                let a = word_addr + i;
+                if (addr..end).contains(&a) {
+                    *byte = data[a - addr];
+                }
+            }
+            let value = u32::from_le_bytes(bytes);
+
+            if value != current {
+                // SAFETY: PECR is unlocked, so a word write starts programming
+                unsafe { ptr::write_volatile(word_addr as *mut u32, value) };
+                Self::wait_ready()?;
+            }
+            word_addr += 4;
+        }
+        Ok(())
+    }
+
+    fn unlock() -> Result<(), EepromError> {
+        if FLASH.pecr().read().pelock() {
+            FLASH.pekeyr().write(|w| w.set_pekeyr(PEKEY1));
+            FLASH.pekeyr().write(|w| w.set_pekeyr(PEKEY2));
+        }
+        if FLASH.pecr().read().pelock() {
+            return Err(EepromError::Locked);
+        }
+        Ok(())
+    }
+
+    fn lock() {
+        FLASH.pecr().modify(|w| w.set_pelock(true));
+    }
+
+    /// Busy-wait for the end of a word write (about 3.2 ms)
+    fn wait_ready() -> Result<(), EepromError> {
+        while FLASH.sr().read().bsy() {}
+
+        let sr = FLASH.sr().read();
+        if sr.wrperr() || sr.pgaerr() || sr.sizerr() {
+            // Error flags are cleared by writing 1
+            FLASH.sr().write(|w| {
+                w.set_wrperr(true);
+                w.set_pgaerr(true);
+                w.set_sizerr(true);
+            });
+            return Err(EepromError::WriteFailed);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "lora")]
+impl crate::lora::persist::NvStorage for DataEeprom {
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), crate::lora::persist::NvError> {
+        DataEeprom::read(self, offset, buf).map_err(Into::into)
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), crate::lora::persist::NvError> {
+        DataEeprom::write(self, offset, data).map_err(Into::into)
+    }
+}
+
+#[cfg(feature = "lora")]
+impl From<EepromError> for crate::lora::persist::NvError {
+    fn from(e: EepromError) -> Self {
+        match e {
+            EepromError::OutOfRange => crate::lora::persist::NvError::OutOfRange,
+            EepromError::Locked | EepromError::WriteFailed => crate::lora::persist::NvError::WriteFailed,
+        }
+    }
+}
@@ -0,0 +1,204 @@
+//! Firmware image region in program flash
+//!
+//! The STM32L082CZ has two 96 KB flash banks. The application runs from
+//! bank 1 (see `memory.x`) and firmware updates are received into bank 2,
+//! where a bootloader picks them up. Bank 2 can be written while code runs
+//! from bank 1.
+//!
+//! Program flash is erased by 128-byte page (erased bytes read 0x00) and
+//! written by word, so writes read, erase and reprogram every page they
+//! touch. Pages whose contents do not change are left alone.
+
+use core::ptr;
+
+use embassy_stm32::pac::FLASH;
+
+/// Start of flash bank 2
+const IMAGE_BASE: usize = 0x0801_8000;
+/// Size of flash bank 2
+pub const IMAGE_REGION_SIZE: usize = 96 * 1024;
+/// Erase unit
+const PAGE_SIZE: usize = 128;
+
+/// PEKEYR unlock sequence
+const PEKEY1: u32 = 0x89AB_CDEF;
+const PEKEY2: u32 = 0x0203_0405;
+/// PRGKEYR unlock sequence
+const PRGKEY1: u32 = 0x8C9D_AEBF;
+const PRGKEY2: u32 = 0x1314_1516;
+
+/// Program flash errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum FlashError {
+    /// Access outside the image region
+    OutOfRange,
+    /// PECR stayed locked after the unlock sequence
+    Locked,
+    /// The FLASH interface reported an erase or programming error
+    WriteFailed,
+}
+
+/// Flash bank 2, holding received firmware images
+pub struct ImageFlash {
+    _private: (),
+}
+
+impl ImageFlash {
+    /// The image region
+    ///
+    /// Only [`Board::take`](super::Board::take) creates one; it shares the
+    /// FLASH interface with [`DataEeprom`](super::DataEeprom).
+    pub(crate) fn new() -> Self {
+        Self { _private: () }
+    }
+
+    /// Size of the region in bytes
+    pub fn len(&self) -> usize {
+        IMAGE_REGION_SIZE
+    }
+
+    /// Whether the region is empty
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// Read `buf.len()` bytes at `offset`
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), FlashError> {
+        let addr = Self::address(offset, buf.len())?;
+        for (i, byte) in buf.iter_mut().enumerate() {
+            // SAFETY: the range was checked against the region
+            *byte = unsafe { ptr::read_volatile((addr + i) as *const u8) };
+        }
+        Ok(())
+    }
+
+    /// Write `data` at `offset`, rewriting the pages it touches
+    pub fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), FlashError> {
+        let addr = Self::address(offset, data.len())?;
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        Self::unlock()?;
+        let result = Self::program(addr, data);
+        Self::lock();
+        result
+    }
+
+    /// Absolute address of `offset`, checking that `len` bytes fit
+    fn address(offset: usize, len: usize) -> Result<usize, FlashError> {
+        match offset.checked_add(len) {
+            Some(end) if end <= IMAGE_REGION_SIZE => Ok(IMAGE_BASE + offset),
+            _ => Err(FlashError::OutOfRange),
+        }
+    }
+
+    fn program(addr: usize, data: &[u8]) -> Result<(), FlashError> {
+        let end = addr + data.len();
+        let mut page_addr = addr & !(PAGE_SIZE - 1);
+
+        while page_addr < end {
+            let mut page = [0u8; PAGE_SIZE];
+            for (i, byte) in page.iter_mut().enumerate() {
+                // SAFETY: page-aligned page inside bank 2
+                *byte = unsafe { ptr::read_volatile((page_addr + i) as *const u8) };
+            }
+            let current = page;
+            for (i, byte) in page.iter_mut().enumerate() {
+                let a = page_addr + i;
+                if (addr..end).contains(&a) {
+                    *byte = data[a - addr];
+                }
+            }
+
+            if page != current {
+                Self::erase_page(page_addr)?;
+                for (i, word) in page.chunks_exact(4).enumerate() {
+                    let value = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+                    if value != 0 {
+                        // SAFETY: the page is erased and PRGLOCK is clear
+                        unsafe { ptr::write_volatile((page_addr + i * 4) as *mut u32, value) };
+                        Self::wait_ready()?;
+                    }
+                }
+            }
+            page_addr += PAGE_SIZE;
+        }
+        Ok(())
+    }
+
+    fn erase_page(page_addr: usize) -> Result<(), FlashError> {
+        FLASH.pecr().modify(|w| {
+            w.set_erase(true);
+            w.set_prog(true);
+        });
+        // SAFETY: with ERASE and PROG set, writing any word erases its page
+        unsafe { ptr::write_volatile(page_addr as *mut u32, 0) };
+        let result = Self::wait_ready();
+        FLASH.pecr().modify(|w| {
+            w.set_erase(false);
+            w.set_prog(false);
+        });
+        result
+    }
+
+    fn unlock() -> Result<(), FlashError> {
+        if FLASH.pecr().read().pelock() {
+            FLASH.pekeyr().write(|w| w.set_pekeyr(PEKEY1));
+            FLASH.pekeyr().write(|w| w.set_pekeyr(PEKEY2));
+        }
+        if FLASH.pecr().read().prglock() {
+            FLASH.prgkeyr().write(|w| w.set_prgkeyr(PRGKEY1));
+            FLASH.prgkeyr().write(|w| w.set_prgkeyr(PRGKEY2));
+        }
+        if FLASH.pecr().read().pelock() || FLASH.pecr().read().prglock() {
+            return Err(FlashError::Locked);
+        }
+        Ok(())
+    }
+
+    fn lock() {
+        FLASH.pecr().modify(|w| {
+            w.set_prglock(true);
+            w.set_pelock(true);
+        });
+    }
+
+    /// Busy-wait for the end of an erase or word write
+    fn wait_ready() -> Result<(), FlashError> {
+        while FLASH.sr().read().bsy() {}
+
+        let sr = FLASH.sr().read();
+        if sr.wrperr() || sr.pgaerr() || sr.sizerr() {
+            // Error flags are cleared by writing 1
+            FLASH.sr().write(|w| {
+                w.set_wrperr(true);
+                w.set_pgaerr(true);
+                w.set_sizerr(true);
+            });
+            return Err(FlashError::WriteFailed);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "lora")]
+impl crate::lora::persist::NvStorage for ImageFlash {
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), crate::lora::persist::NvError> {
+        ImageFlash::read(self, offset, buf).map_err(Into::into)
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), crate::lora::persist::NvError> {
+        ImageFlash::write(self, offset, data).map_err(Into::into)
+    }
+}
+
+#[cfg(feature = "lora")]
+impl From<FlashError> for crate::lora::persist::NvError {
+    fn from(e: FlashError) -> Self {
+        match e {
+            FlashError::OutOfRange => crate::lora::persist::NvError::OutOfRange,
+            FlashError::Locked | FlashError::WriteFailed => crate::lora::persist::NvError::WriteFailed,
+        }
+    }
+}
@@ -3,5 +3,11 @@
 //! This module provides the fundamental building blocks for the AeonNode framework.
 
 pub mod board;
+pub mod eeprom;
+pub mod flash;
+pub mod rtc;
 
 pub use board::Board;
+pub use eeprom::DataEeprom;
+pub use flash::ImageFlash;
+pub use rtc::RtcClock;
@@ -0,0 +1,215 @@
+//! STM32L0 real-time clock
+//!
+//! The RTC runs from the 32.768 kHz LSE and keeps calendar time with 1 s
+//! resolution through Stop mode. [`RtcClock`] sets it from the LoRaWAN
+//! network time (DeviceTimeAns) and remembers the moment of the last sync
+//! against the Embassy time base, so reads right after a sync have
+//! millisecond resolution and the application can judge how stale the
+//! clock is.
+
+use core::cell::RefCell;
+
+use embassy_stm32::peripherals;
+use embassy_stm32::rtc::{DateTime, DayOfWeek, Rtc, RtcConfig};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+
+/// Seconds in a day
+const SECS_PER_DAY: u64 = 86_400;
+
+/// Calendar times before this (2024-01-01) mean the RTC was never set
+const MIN_VALID_UNIX_S: u64 = 1_704_067_200;
+
+/// RTC errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ClockError {
+    /// The time is outside the range of the RTC calendar (2000-2099)
+    OutOfRange,
+    /// The RTC did not accept the new time or could not be read
+    Rtc,
+}
+
+/// A UTC calendar time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct UtcTime {
+    pub year: u16,
+    /// 1-12
+    pub month: u8,
+    /// 1-31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub millisecond: u16,
+}
+
+impl UtcTime {
+    /// Calendar time of `unix_ms` milliseconds since the Unix epoch
+    pub fn from_unix_ms(unix_ms: u64) -> Self {
+        let secs = unix_ms / 1000;
+        let (year, month, day) = civil_from_days((secs / SECS_PER_DAY) as i64);
+        let time = secs % SECS_PER_DAY;
+        Self {
+            year: year as u16,
+            month,
+            day,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+            millisecond: (unix_ms % 1000) as u16,
+        }
+    }
+
+    /// Milliseconds since the Unix epoch
+    pub fn to_unix_ms(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month, self.day) as u64;
+        let secs = days * SECS_PER_DAY
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64;
+        secs * 1000 + self.millisecond as u64
+    }
+
+    /// Day of the week
+    pub fn day_of_week(&self) -> DayOfWeek {
+        // 1970-01-01 was a Thursday
+        match (self.to_unix_ms() / 1000 / SECS_PER_DAY + 3) % 7 {
+            0 => DayOfWeek::Monday,
+            1 => DayOfWeek::Tuesday,
+            2 => DayOfWeek::Wednesday,
+            3 => DayOfWeek::Thursday,
+            4 => DayOfWeek::Friday,
+            5 => DayOfWeek::Saturday,
+            _ => DayOfWeek::Sunday,
+        }
+    }
+}
+
+struct ClockState {
+    rtc: Rtc,
+    /// Local instant and Unix time of the last sync
+    synced: Option<(Instant, u64)>,
+}
+
+/// The RTC as a shared wall clock
+///
+/// All methods take `&self`, so one `&'static RtcClock` can be handed to the
+/// LoRaWAN stack (see [`LoRaWAN::attach_clock`](crate::lora::LoRaWAN::attach_clock))
+/// and read from application tasks at the same time.
+pub struct RtcClock {
+    state: Mutex<CriticalSectionRawMutex, RefCell<ClockState>>,
+}
+
+impl RtcClock {
+    /// Take ownership of the RTC
+    pub fn new(rtc: peripherals::RTC) -> Self {
+        Self {
+            state: Mutex::new(RefCell::new(ClockState {
+                rtc: Rtc::new(rtc, RtcConfig::default()),
+                synced: None,
+            })),
+        }
+    }
+
+    /// Set the clock to `unix_ms` milliseconds since the Unix epoch (UTC)
+    pub fn set_unix_ms(&self, unix_ms: u64) -> Result<(), ClockError> {
+        let now = Instant::now();
+        let utc = UtcTime::from_unix_ms(unix_ms);
+        if !(2000..2100).contains(&utc.year) {
+            return Err(ClockError::OutOfRange);
+        }
+        let datetime = DateTime::from(
+            utc.year,
+            utc.month,
+            utc.day,
+            utc.day_of_week(),
+            utc.hour,
+            utc.minute,
+            utc.second,
+        )
+        .map_err(|_| ClockError::OutOfRange)?;
+
+        self.state.lock(|state| {
+            let mut state = state.borrow_mut();
+            state.rtc.set_datetime(datetime).map_err(|_| ClockError::Rtc)?;
+            state.synced = Some((now, unix_ms));
+            Ok(())
+        })
+    }
+
+    /// Current time in milliseconds since the Unix epoch, if the clock is set
+    ///
+    /// After a sync this counts from the sync point with millisecond
+    /// resolution; otherwise it falls back to the RTC calendar, which
+    /// survives a reset as long as the RTC domain stays powered.
+    pub fn now_unix_ms(&self) -> Option<u64> {
+        self.state.lock(|state| {
+            let state = state.borrow();
+            if let Some((at, unix_ms)) = state.synced {
+                return Some(unix_ms + Instant::now().saturating_duration_since(at).as_millis());
+            }
+            let datetime = state.rtc.now().ok()?;
+            let utc = UtcTime {
+                year: datetime.year(),
+                month: datetime.month(),
+                day: datetime.day(),
+                hour: datetime.hour(),
+                minute: datetime.minute(),
+                second: datetime.second(),
+                millisecond: 0,
+            };
+            let unix_ms = utc.to_unix_ms();
+            (unix_ms >= MIN_VALID_UNIX_S * 1000).then_some(unix_ms)
+        })
+    }
+
+    /// Current UTC calendar time, if the clock is set
+    pub fn now(&self) -> Option<UtcTime> {
+        self.now_unix_ms().map(UtcTime::from_unix_ms)
+    }
+
+    /// Time since the clock was last set, `None` if not since boot
+    pub fn since_last_sync(&self) -> Option<Duration> {
+        self.state.lock(|state| {
+            state
+                .borrow()
+                .synced
+                .map(|(at, _)| Instant::now().saturating_duration_since(at))
+        })
+    }
+}
+
+#[cfg(feature = "lora")]
+impl crate::lora::time::WallClock for RtcClock {
+    fn set_unix_ms(&self, unix_ms: u64) {
+        if let Err(e) = RtcClock::set_unix_ms(self, unix_ms) {
+            defmt::warn!("RTC sync failed: {}", e);
+        }
+    }
+}
+
+/// Days since 1970-01-01 to (year, month, day), proleptic Gregorian
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// (year, month, day) to days since 1970-01-01, proleptic Gregorian
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
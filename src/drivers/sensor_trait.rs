@@ -33,6 +33,10 @@ pub enum SensorError {
 }
 
 /// Common sensor interface for all environmental sensors
+///
+/// Sensors are driven from the single-threaded executor, so the futures
+/// need no `Send` bound.
+#[allow(async_fn_in_trait)]
 pub trait Sensor {
     /// Initialize the sensor
     async fn init(&mut self) -> Result<(), SensorError>;
@@ -68,3 +72,9 @@ impl SensorHub {
         Ok(())
     }
 }
+
+impl Default for SensorHub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
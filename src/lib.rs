@@ -1,4 +1,4 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![doc = include_str!("../README.md")]
 
 //! # AeonNode Framework
@@ -29,6 +29,8 @@
 //! }
 //! ```
 
+// Board support needs the STM32 HAL, which only builds for the target
+#[cfg(target_os = "none")]
 pub mod core;
 
 #[cfg(feature = "power")]
@@ -40,6 +42,22 @@ pub mod drivers;
 #[cfg(feature = "lora")]
 pub mod lora;
 
+// Host tests have no RTT; their defmt output is dropped
+#[cfg(test)]
+mod test_logger {
+    #[defmt::global_logger]
+    struct Discard;
+
+    unsafe impl defmt::Logger for Discard {
+        fn acquire() {}
+        unsafe fn flush() {}
+        unsafe fn release() {}
+        unsafe fn write(_bytes: &[u8]) {}
+    }
+
+    defmt::timestamp!("{=u8}", 0);
+}
+
 /// Re-export commonly used types from Embassy
 pub mod prelude {
     pub use embassy_executor::Spawner;
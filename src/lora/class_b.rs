@@ -0,0 +1,421 @@
+//! Class B beacons and ping slots
+//!
+//! Class B gateways broadcast a beacon every 128 s, starting at GPS times
+//! that are multiples of 128 s. A 2.12 s beacon reserved period and a 3 s
+//! guard before the next beacon leave 122.88 s for 4096 ping slots of 30 ms.
+//! A device opens one slot every ping period, at an offset that changes
+//! every beacon period and that the network computes the same way (see
+//! [`ping_offset`]).
+//!
+//! [`BeaconTracker`] keeps track of when beacons are due: first from a
+//! DeviceTimeAns or BeaconTimingAns estimate, then from the received
+//! beacons themselves. Missed beacons widen the receive windows by the
+//! drift of the local clock, for up to [`BEACONLESS_TIMEOUT`]. Nothing here
+//! touches the radio; [`super::lorawan::LoRaWAN`] opens the windows.
+
+use embassy_time::{Duration, Instant};
+
+use super::crypto;
+use super::region::BeaconFormat;
+use super::time::NetworkTime;
+
+/// Time between two beacons
+pub const BEACON_PERIOD: Duration = Duration::from_secs(BEACON_PERIOD_S as u64);
+/// [`BEACON_PERIOD`] in seconds
+pub const BEACON_PERIOD_S: u32 = 128;
+/// Start of a beacon period reserved for the beacon itself
+pub const BEACON_RESERVED: Duration = Duration::from_millis(2120);
+/// Length of a ping slot
+pub const PING_SLOT_LEN: Duration = Duration::from_millis(30);
+/// Ping slots in a beacon period
+pub const PING_SLOTS: u16 = 4096;
+/// How long ping slots continue without beacons before returning to Class A
+pub const BEACONLESS_TIMEOUT: Duration = Duration::from_secs(2 * 3600);
+/// Windows are never widened further than this on either side
+pub const MAX_WIDENING: Duration = Duration::from_secs(1);
+
+/// Worst case drift of the local time base, in ppm
+const CLOCK_DRIFT_PPM: u64 = 40;
+/// Accuracy of a beacon time estimated from DeviceTimeAns or BeaconTimingAns
+const ESTIMATE_UNCERTAINTY: Duration = Duration::from_millis(100);
+
+/// A received beacon
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Beacon {
+    /// GPS time of the beacon start in seconds
+    pub time: u32,
+    /// InfoDesc and Info of the gateway, if their CRC is valid
+    pub gw_specific: Option<[u8; 7]>,
+}
+
+/// Beacon parse errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum BeaconError {
+    /// The payload length does not match the region's beacon
+    Length,
+    /// The CRC of the time field is wrong
+    Crc,
+}
+
+/// Parse a beacon payload in the layout of `format`
+///
+/// Only the common part (RFU and time) must pass its CRC; the
+/// gateway-specific part is dropped if its own CRC fails.
+pub fn parse_beacon(format: &BeaconFormat, payload: &[u8]) -> Result<Beacon, BeaconError> {
+    if payload.len() != format.len() {
+        return Err(BeaconError::Length);
+    }
+    let (common, gw) = payload.split_at(format.rfu1_len + 6);
+    let (data, crc) = common.split_at(format.rfu1_len + 4);
+    if crc16(data) != u16::from_le_bytes([crc[0], crc[1]]) {
+        return Err(BeaconError::Crc);
+    }
+    let t = &data[format.rfu1_len..];
+    let time = u32::from_le_bytes([t[0], t[1], t[2], t[3]]);
+
+    let (gw_data, gw_crc) = gw.split_at(gw.len() - 2);
+    let gw_specific = (crc16(gw_data) == u16::from_le_bytes([gw_crc[0], gw_crc[1]])).then(|| {
+        let mut info = [0u8; 7];
+        info.copy_from_slice(&gw_data[..7]);
+        info
+    });
+    Ok(Beacon { time, gw_specific })
+}
+
+/// CRC-16/CCITT (polynomial 0x1021, initial value 0) of the beacon fields
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Ping slots from one of the device's ping slots to the next
+///
+/// PingSlotInfoReq periodicity 0..=7 gives 2^(7 - periodicity) ping slots
+/// per beacon period, one every 2^periodicity seconds (roughly).
+pub fn ping_period(periodicity: u8) -> u16 {
+    1 << (5 + periodicity.min(7))
+}
+
+/// Slot of the device's first ping slot in the beacon period at `beacon_time`
+///
+/// `dev_addr` is the DevAddr, or the McAddr of a multicast group.
+pub fn ping_offset(beacon_time: u32, dev_addr: u32, periodicity: u8) -> u16 {
+    let rand = crypto::ping_slot_rand(beacon_time, dev_addr);
+    u16::from_le_bytes([rand[0], rand[1]]) % ping_period(periodicity)
+}
+
+/// Start of the first ping slot at or after `after`, in the beacon period
+/// starting at `beacon_start`
+///
+/// Returns `None` once the period has no more slots for the device.
+pub fn next_ping_slot(beacon_start: Instant, offset: u16, periodicity: u8, after: Instant) -> Option<Instant> {
+    (offset..PING_SLOTS)
+        .step_by(ping_period(periodicity) as usize)
+        .map(|slot| beacon_start + BEACON_RESERVED + PING_SLOT_LEN * slot as u32)
+        .find(|&at| at >= after)
+}
+
+/// How well the device knows the beacon timing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum BeaconState {
+    /// No idea when the next beacon is due
+    Unsynced,
+    /// Beacon times are estimated from DeviceTimeAns or BeaconTimingAns
+    Estimated,
+    /// The last beacon was received
+    Locked,
+    /// Beacons are being missed; ping slots follow the local clock
+    Beaconless,
+}
+
+/// A beacon period as seen by the local clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BeaconSlot {
+    /// Local time the beacon starts
+    pub start: Instant,
+    /// GPS time of the beacon in seconds, unknown after a BeaconTimingAns
+    pub time: Option<u32>,
+    /// Beacon channel from BeaconTimingAns, while `time` is unknown
+    pub channel: Option<u8>,
+}
+
+/// Beacon timing of a Class B device
+#[derive(Debug, Clone)]
+pub struct BeaconTracker {
+    /// Local time of a beacon start
+    anchor: Option<Instant>,
+    /// GPS time of the anchor beacon in seconds
+    anchor_time: Option<u32>,
+    /// Channel of the anchor beacon, from BeaconTimingAns
+    anchor_channel: Option<u8>,
+    /// The anchor is a received beacon rather than an estimate
+    anchor_received: bool,
+    /// A beacon was missed since the anchor
+    missed: bool,
+    last: Option<Beacon>,
+}
+
+impl BeaconTracker {
+    pub const fn new() -> Self {
+        Self {
+            anchor: None,
+            anchor_time: None,
+            anchor_channel: None,
+            anchor_received: false,
+            missed: false,
+            last: None,
+        }
+    }
+
+    /// How well the beacon timing is known
+    pub fn state(&self) -> BeaconState {
+        match (self.anchor, self.anchor_received, self.missed) {
+            (None, _, _) => BeaconState::Unsynced,
+            (Some(_), false, _) => BeaconState::Estimated,
+            (Some(_), true, false) => BeaconState::Locked,
+            (Some(_), true, true) => BeaconState::Beaconless,
+        }
+    }
+
+    /// Last beacon received
+    pub fn last_beacon(&self) -> Option<Beacon> {
+        self.last
+    }
+
+    /// Forget the beacon timing
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Estimate the beacon times from the network time
+    ///
+    /// Ignored once beacons have been received, as they are more accurate.
+    pub fn set_network_time(&mut self, time: NetworkTime) {
+        if self.anchor_received {
+            return;
+        }
+        let period_ms = BEACON_PERIOD_S as u64 * 1000;
+        let next_ms = time.gps_ms.div_ceil(period_ms) * period_ms;
+        self.anchor = Some(time.at + Duration::from_millis(next_ms - time.gps_ms));
+        self.anchor_time = Some((next_ms / 1000) as u32);
+        self.anchor_channel = None;
+    }
+
+    /// Estimate the beacon times from a BeaconTimingAns: the next beacon
+    /// starts at `next_beacon` on `channel`
+    pub fn set_beacon_timing(&mut self, next_beacon: Instant, channel: u8) {
+        if self.anchor_received {
+            return;
+        }
+        self.anchor = Some(next_beacon);
+        self.anchor_time = None;
+        self.anchor_channel = Some(channel);
+    }
+
+    /// A beacon starting at `start` was received
+    pub fn beacon_received(&mut self, start: Instant, beacon: &Beacon) {
+        self.anchor = Some(start);
+        self.anchor_time = Some(beacon.time);
+        self.anchor_channel = None;
+        self.anchor_received = true;
+        self.missed = false;
+        self.last = Some(*beacon);
+    }
+
+    /// The beacon starting at `start` was not received
+    ///
+    /// Returns `false` once the last received beacon is more than
+    /// [`BEACONLESS_TIMEOUT`] old and Class B must end.
+    pub fn beacon_missed(&mut self, start: Instant) -> bool {
+        let Some(anchor) = self.anchor.filter(|_| self.anchor_received) else {
+            return true;
+        };
+        self.missed = true;
+        start.saturating_duration_since(anchor) < BEACONLESS_TIMEOUT
+    }
+
+    /// First beacon starting at or after `after`
+    pub fn next_beacon(&self, after: Instant) -> Option<BeaconSlot> {
+        let anchor = self.anchor?;
+        let diff = after.as_ticks() as i64 - anchor.as_ticks() as i64;
+        // Round up to a whole number of beacon periods
+        let periods = -(-diff).div_euclid(BEACON_PERIOD.as_ticks() as i64);
+        Some(self.slot(anchor, periods))
+    }
+
+    /// Beacon period `now` falls in, if its beacon time is known
+    ///
+    /// Ping slots need the beacon time, so this is `None` until a beacon or
+    /// DeviceTimeAns gave it.
+    pub fn current_period(&self, now: Instant) -> Option<BeaconSlot> {
+        let anchor = self.anchor?;
+        let diff = now.as_ticks() as i64 - anchor.as_ticks() as i64;
+        let slot = self.slot(anchor, diff.div_euclid(BEACON_PERIOD.as_ticks() as i64));
+        slot.time.map(|_| slot)
+    }
+
+    /// How much earlier to open (and later to close) a window at `at`
+    ///
+    /// Grows with the clock drift since the anchor, on top of the
+    /// uncertainty of an estimated anchor.
+    pub fn widening(&self, at: Instant) -> Duration {
+        let Some(anchor) = self.anchor else {
+            return MAX_WIDENING;
+        };
+        let elapsed = at.saturating_duration_since(anchor);
+        let drift = Duration::from_micros(elapsed.as_micros() * CLOCK_DRIFT_PPM / 1_000_000);
+        let base = if self.anchor_received { Duration::from_ticks(0) } else { ESTIMATE_UNCERTAINTY };
+        (base + drift).min(MAX_WIDENING)
+    }
+
+    /// The beacon `periods` beacon periods after the anchor
+    fn slot(&self, anchor: Instant, periods: i64) -> BeaconSlot {
+        let ticks = anchor.as_ticks() as i64 + periods * BEACON_PERIOD.as_ticks() as i64;
+        BeaconSlot {
+            start: Instant::from_ticks(ticks.max(0) as u64),
+            time: self
+                .anchor_time
+                .map(|t| (t as i64 + periods * BEACON_PERIOD_S as i64) as u32),
+            channel: self.anchor_channel.map(|c| (c as i64 + periods).rem_euclid(8) as u8),
+        }
+    }
+}
+
+impl Default for BeaconTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lora::region::Region;
+
+    /// GPS time 1399999872 (0x53724D80), InfoDesc 0, latitude/longitude
+    /// 0x002001 / 0x038100
+    const BEACON_TIME: u32 = 1_399_999_872;
+    const BEACON_EU868: [u8; 17] = [
+        0x00, 0x00, 0x80, 0x4D, 0x72, 0x53, 0x69, 0x86, 0x00, 0x01, 0x20, 0x00, 0x00, 0x81, 0x03, 0xDE, 0x55,
+    ];
+    const BEACON_US915: [u8; 23] = [
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x4D, 0x72, 0x53, 0x69, 0x86, 0x00, 0x01, 0x20, 0x00, 0x00, 0x81, 0x03,
+        0x00, 0x00, 0x00, 0x16, 0x83,
+    ];
+    const BEACON_IN865: [u8; 19] = [
+        0x00, 0x80, 0x4D, 0x72, 0x53, 0x69, 0x86, 0x00, 0x01, 0x20, 0x00, 0x00, 0x81, 0x03, 0x00, 0x00, 0x00, 0x16,
+        0x83,
+    ];
+    const GW_SPECIFIC: [u8; 7] = [0x00, 0x01, 0x20, 0x00, 0x00, 0x81, 0x03];
+
+    #[test]
+    fn ping_offset_known_answers() {
+        // Rand = aes128_encrypt(0^16, BeaconTime | DevAddr | pad16)
+        assert_eq!(
+            crypto::ping_slot_rand(0x4A1B_2C00, 0x2601_1BDA),
+            [
+                0x9D, 0x8B, 0x79, 0xA4, 0xCD, 0x91, 0x67, 0x30, 0xD9, 0x3F, 0x5A, 0x6A, 0x05, 0xAF, 0x47, 0xF8
+            ]
+        );
+        // (Rand[0] + 256 * Rand[1]) % pingPeriod for periodicity 0..=7
+        let offsets: [u16; 8] = core::array::from_fn(|p| ping_offset(0x4A1B_2C00, 0x2601_1BDA, p as u8));
+        assert_eq!(offsets, [29, 29, 29, 157, 413, 925, 925, 2973]);
+        let offsets: [u16; 8] = core::array::from_fn(|p| ping_offset(BEACON_TIME, 0x0102_0304, p as u8));
+        assert_eq!(offsets, [17, 49, 49, 177, 433, 945, 1969, 1969]);
+    }
+
+    #[test]
+    fn parse_beacon_each_format() {
+        let beacons: [(Region, &[u8]); 3] =
+            [(Region::EU868, &BEACON_EU868), (Region::US915, &BEACON_US915), (Region::IN865, &BEACON_IN865)];
+        for (region, payload) in beacons {
+            let format = region.beacon_format();
+            let expected = Beacon { time: BEACON_TIME, gw_specific: Some(GW_SPECIFIC) };
+            assert_eq!(parse_beacon(&format, payload), Ok(expected));
+
+            // A bad time CRC rejects the beacon
+            let mut bad = payload.to_vec();
+            bad[format.rfu1_len] ^= 0x01;
+            assert_eq!(parse_beacon(&format, &bad), Err(BeaconError::Crc));
+
+            // A bad gateway-specific CRC only drops that part
+            let mut bad = payload.to_vec();
+            bad[format.rfu1_len + 8] ^= 0x01;
+            assert_eq!(parse_beacon(&format, &bad), Ok(Beacon { gw_specific: None, ..expected }));
+
+            assert_eq!(parse_beacon(&format, &payload[1..]), Err(BeaconError::Length));
+        }
+    }
+
+    #[test]
+    fn next_ping_slot_steps_by_ping_period() {
+        let start = Instant::from_secs(1000);
+        let first = start + Duration::from_millis(2120 + 29 * 30);
+        assert_eq!(next_ping_slot(start, 29, 0, start), Some(first));
+        assert_eq!(next_ping_slot(start, 29, 0, first), Some(first));
+        assert_eq!(
+            next_ping_slot(start, 29, 0, first + Duration::from_ticks(1)),
+            Some(first + Duration::from_millis(32 * 30))
+        );
+        // Periodicity 7: a single slot per beacon period
+        let only = start + Duration::from_millis(2120 + 2973 * 30);
+        assert_eq!(next_ping_slot(start, 2973, 7, first), Some(only));
+        assert_eq!(next_ping_slot(start, 2973, 7, only + Duration::from_ticks(1)), None);
+
+        // Slot 29 + 127 * 32 = 4093 is the last one in the period
+        let last = start + Duration::from_millis(2120 + 4093 * 30);
+        assert_eq!(next_ping_slot(start, 29, 0, last), Some(last));
+        assert_eq!(next_ping_slot(start, 29, 0, last + Duration::from_ticks(1)), None);
+    }
+
+    #[test]
+    fn widening_grows_after_missed_beacons() {
+        let start = Instant::from_secs(1000);
+        let beacon = Beacon { time: BEACON_TIME, gw_specific: None };
+        let mut tracker = BeaconTracker::new();
+        assert_eq!(tracker.widening(start), MAX_WIDENING);
+
+        tracker.beacon_received(start, &beacon);
+        assert_eq!(tracker.state(), BeaconState::Locked);
+        assert_eq!(tracker.widening(start), Duration::from_ticks(0));
+
+        // 40 ppm of one beacon period
+        assert!(tracker.beacon_missed(start + BEACON_PERIOD));
+        assert_eq!(tracker.state(), BeaconState::Beaconless);
+        assert_eq!(tracker.widening(start + BEACON_PERIOD), Duration::from_micros(5120));
+        let slot = tracker.next_beacon(start + Duration::from_secs(1)).unwrap();
+        assert_eq!(slot.start, start + BEACON_PERIOD);
+        assert_eq!(slot.time, Some(BEACON_TIME + BEACON_PERIOD_S));
+
+        // Still ping slots after almost two hours, with wider windows
+        let late = start + BEACONLESS_TIMEOUT - BEACON_PERIOD;
+        assert!(tracker.beacon_missed(late));
+        assert_eq!(tracker.widening(late), Duration::from_micros((7200 - 128) * 40));
+        assert!(!tracker.beacon_missed(start + BEACONLESS_TIMEOUT));
+        assert_eq!(tracker.widening(start + Duration::from_secs(10 * 3600)), MAX_WIDENING);
+
+        // The next beacon locks again
+        tracker.beacon_received(late, &Beacon { time: BEACON_TIME + 7072, gw_specific: None });
+        assert_eq!(tracker.state(), BeaconState::Locked);
+        assert_eq!(tracker.widening(late), Duration::from_ticks(0));
+    }
+
+    #[test]
+    fn estimated_anchor_adds_uncertainty() {
+        let now = Instant::from_secs(1000);
+        let mut tracker = BeaconTracker::new();
+        // 1 s before a beacon
+        tracker.set_network_time(NetworkTime { at: now, gps_ms: BEACON_TIME as u64 * 1000 - 1000 });
+        assert_eq!(tracker.state(), BeaconState::Estimated);
+        let slot = tracker.next_beacon(now).unwrap();
+        assert_eq!(slot.start, now + Duration::from_secs(1));
+        assert_eq!(slot.time, Some(BEACON_TIME));
+        assert_eq!(tracker.widening(slot.start), ESTIMATE_UNCERTAINTY);
+    }
+}
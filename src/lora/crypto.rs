@@ -0,0 +1,496 @@
+//! LoRaWAN cryptographic primitives
+//!
+//! AES-128 and AES-CMAC helpers for the LoRaWAN 1.0.x and 1.1 join
+//! procedures and data frames. Everything here is pure and allocation-free
+//! so it can be checked on the host against the specification's test
+//! vectors.
+
+use aes::cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit};
+use aes::Aes128;
+use cmac::{Cmac, Mac};
+
+/// AES-128 key
+pub type AesKey = [u8; 16];
+
+/// Session keys derived from a successful join
+///
+/// LoRaWAN 1.0.x has a single NwkSKey; it fills all three network keys.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SessionKeys {
+    /// Forwarding network session integrity key (uplink MIC)
+    pub f_nwk_s_int_key: AesKey,
+    /// Serving network session integrity key (downlink MIC, 1.1 uplink MIC)
+    pub s_nwk_s_int_key: AesKey,
+    /// Network session encryption key (FOpts and port 0 payloads)
+    pub nwk_s_enc_key: AesKey,
+    /// Application session key (payload encryption)
+    pub app_skey: AesKey,
+}
+
+impl SessionKeys {
+    /// LoRaWAN 1.0.x keys
+    pub const fn v1_0(nwk_skey: AesKey, app_skey: AesKey) -> Self {
+        Self {
+            f_nwk_s_int_key: nwk_skey,
+            s_nwk_s_int_key: nwk_skey,
+            nwk_s_enc_key: nwk_skey,
+            app_skey,
+        }
+    }
+}
+
+/// LoRaWAN 1.1 keys protecting JoinAccepts, derived from NwkKey and DevEUI
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct JoinKeys {
+    /// JoinAccept MIC and Rejoin type 1 MIC
+    pub js_int_key: AesKey,
+    /// JoinAccept encryption after a Rejoin-request
+    pub js_enc_key: AesKey,
+}
+
+/// Direction of a data frame, as used in the A and B0 blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Uplink = 0,
+    Downlink = 1,
+}
+
+/// Encrypt a single 16-byte block in place
+pub fn aes128_encrypt(key: &AesKey, block: &mut [u8; 16]) {
+    let cipher = Aes128::new(GenericArray::from_slice(key));
+    cipher.encrypt_block(GenericArray::from_mut_slice(block));
+}
+
+/// AES-CMAC over the concatenation of `parts`
+pub fn aes128_cmac(key: &AesKey, parts: &[&[u8]]) -> [u8; 16] {
+    let mut mac = <Cmac<Aes128> as Mac>::new(GenericArray::from_slice(key));
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// MIC of a JoinRequest (`msg` is MHDR | AppEUI | DevEUI | DevNonce)
+pub fn join_request_mic(app_key: &AesKey, msg: &[u8]) -> [u8; 4] {
+    truncate_mic(&aes128_cmac(app_key, &[msg]))
+}
+
+/// MIC of a decrypted JoinAccept (`msg` is MHDR up to and including CFList)
+pub fn join_accept_mic(app_key: &AesKey, msg: &[u8]) -> [u8; 4] {
+    truncate_mic(&aes128_cmac(app_key, &[msg]))
+}
+
+/// MIC of a decrypted LoRaWAN 1.1 JoinAccept (OptNeg set)
+///
+/// `join_req_type` is 0xFF after a JoinRequest and the rejoin type after a
+/// Rejoin-request; `nonce` is the DevNonce or RJcount that was sent. The EUI
+/// is taken as it appears on air.
+pub fn join_accept_mic_v1_1(
+    js_int_key: &AesKey,
+    join_req_type: u8,
+    join_eui: &[u8; 8],
+    nonce: u16,
+    msg: &[u8],
+) -> [u8; 4] {
+    let nonce = nonce.to_le_bytes();
+    truncate_mic(&aes128_cmac(js_int_key, &[&[join_req_type], join_eui, &nonce, msg]))
+}
+
+/// MIC of a Rejoin-request (`msg` is MHDR up to and including RJcount)
+///
+/// Types 0 and 2 are signed with SNwkSIntKey, type 1 with JSIntKey.
+pub fn rejoin_request_mic(key: &AesKey, msg: &[u8]) -> [u8; 4] {
+    truncate_mic(&aes128_cmac(key, &[msg]))
+}
+
+/// Decrypt a JoinAccept in place
+///
+/// `data` is everything after the MHDR, MIC included, and must be a multiple
+/// of 16 bytes. The network encrypts with AES decrypt, so the device decrypts
+/// with AES encrypt. The key is AppKey (1.0), NwkKey (1.1, after a
+/// JoinRequest) or JSEncKey (1.1, after a Rejoin-request).
+pub fn decrypt_join_accept(app_key: &AesKey, data: &mut [u8]) {
+    let cipher = Aes128::new(GenericArray::from_slice(app_key));
+    for block in data.chunks_exact_mut(16) {
+        cipher.encrypt_block(GenericArray::from_mut_slice(block));
+    }
+}
+
+/// Derive NwkSKey and AppSKey from the JoinAccept fields
+///
+/// `app_nonce` and `net_id` are taken as they appear on air (little endian).
+pub fn derive_session_keys(
+    app_key: &AesKey,
+    app_nonce: &[u8; 3],
+    net_id: &[u8; 3],
+    dev_nonce: u16,
+) -> SessionKeys {
+    SessionKeys::v1_0(
+        derive_key(app_key, 0x01, app_nonce, net_id, dev_nonce),
+        derive_key(app_key, 0x02, app_nonce, net_id, dev_nonce),
+    )
+}
+
+/// Derive the LoRaWAN 1.1 session keys from a JoinAccept with OptNeg set
+///
+/// `join_nonce` and `join_eui` are taken as they appear on air; `nonce` is
+/// the DevNonce or RJcount that was sent.
+pub fn derive_session_keys_v1_1(
+    nwk_key: &AesKey,
+    app_key: &AesKey,
+    join_nonce: &[u8; 3],
+    join_eui: &[u8; 8],
+    nonce: u16,
+) -> SessionKeys {
+    SessionKeys {
+        f_nwk_s_int_key: derive_key(nwk_key, 0x01, join_nonce, join_eui, nonce),
+        s_nwk_s_int_key: derive_key(nwk_key, 0x03, join_nonce, join_eui, nonce),
+        nwk_s_enc_key: derive_key(nwk_key, 0x04, join_nonce, join_eui, nonce),
+        app_skey: derive_key(app_key, 0x02, join_nonce, join_eui, nonce),
+    }
+}
+
+/// Derive JSIntKey and JSEncKey (`dev_eui` as it appears on air)
+pub fn derive_join_keys(nwk_key: &AesKey, dev_eui: &[u8; 8]) -> JoinKeys {
+    let key = |prefix: u8| {
+        let mut block = [0u8; 16];
+        block[0] = prefix;
+        block[1..9].copy_from_slice(dev_eui);
+        aes128_encrypt(nwk_key, &mut block);
+        block
+    };
+    JoinKeys {
+        js_int_key: key(0x06),
+        js_enc_key: key(0x05),
+    }
+}
+
+/// Encrypt or decrypt an FRMPayload in place
+///
+/// The keystream is AES(key, Ai) for i = 1..; applying it twice restores
+/// the input. Use AppSKey for FPort > 0 and NwkSKey for FPort 0.
+pub fn crypt_frm_payload(
+    key: &AesKey,
+    dir: Direction,
+    dev_addr: u32,
+    fcnt: u32,
+    data: &mut [u8],
+) {
+    let cipher = Aes128::new(GenericArray::from_slice(key));
+    for (i, chunk) in data.chunks_mut(16).enumerate() {
+        let mut block = frame_block(0x01, [0; 4], dir, dev_addr, fcnt, (i + 1) as u8);
+        cipher.encrypt_block(GenericArray::from_mut_slice(&mut block));
+        for (byte, k) in chunk.iter_mut().zip(block.iter()) {
+            *byte ^= k;
+        }
+    }
+}
+
+/// Encrypt or decrypt a LoRaWAN 1.1 FOpts field in place with NwkSEncKey
+///
+/// FOpts use the keystream of the first FRMPayload block.
+pub fn crypt_fopts(nwk_s_enc_key: &AesKey, dir: Direction, dev_addr: u32, fcnt: u32, fopts: &mut [u8]) {
+    crypt_frm_payload(nwk_s_enc_key, dir, dev_addr, fcnt, fopts);
+}
+
+/// MIC of a data frame (`msg` is MHDR | FHDR | FPort | FRMPayload)
+pub fn data_mic(
+    nwk_skey: &AesKey,
+    dir: Direction,
+    dev_addr: u32,
+    fcnt: u32,
+    msg: &[u8],
+) -> [u8; 4] {
+    let b0 = frame_block(0x49, [0; 4], dir, dev_addr, fcnt, msg.len() as u8);
+    truncate_mic(&aes128_cmac(nwk_skey, &[&b0, msg]))
+}
+
+/// MIC of a LoRaWAN 1.1 data uplink
+///
+/// `conf_fcnt` is the FCnt of the confirmed downlink being acknowledged (0
+/// if the ACK bit is clear); `tx_dr` and `tx_ch` are the data rate and
+/// channel index the frame goes out on, so every retransmission is signed
+/// again.
+pub fn uplink_mic_v1_1(
+    keys: &SessionKeys,
+    conf_fcnt: u16,
+    tx_dr: u8,
+    tx_ch: u8,
+    dev_addr: u32,
+    fcnt: u32,
+    msg: &[u8],
+) -> [u8; 4] {
+    let len = msg.len() as u8;
+    let b0 = frame_block(0x49, [0; 4], Direction::Uplink, dev_addr, fcnt, len);
+    let [c0, c1] = conf_fcnt.to_le_bytes();
+    let b1 = frame_block(0x49, [c0, c1, tx_dr, tx_ch], Direction::Uplink, dev_addr, fcnt, len);
+    let cmac_s = aes128_cmac(&keys.s_nwk_s_int_key, &[&b1, msg]);
+    let cmac_f = aes128_cmac(&keys.f_nwk_s_int_key, &[&b0, msg]);
+    [cmac_s[0], cmac_s[1], cmac_f[0], cmac_f[1]]
+}
+
+/// MIC of a LoRaWAN 1.1 data downlink
+///
+/// `conf_fcnt` is the FCntUp of the confirmed uplink being acknowledged (0
+/// if the ACK bit is clear).
+pub fn downlink_mic_v1_1(
+    s_nwk_s_int_key: &AesKey,
+    conf_fcnt: u16,
+    dev_addr: u32,
+    fcnt: u32,
+    msg: &[u8],
+) -> [u8; 4] {
+    let [c0, c1] = conf_fcnt.to_le_bytes();
+    let b0 = frame_block(0x49, [c0, c1, 0, 0], Direction::Downlink, dev_addr, fcnt, msg.len() as u8);
+    truncate_mic(&aes128_cmac(s_nwk_s_int_key, &[&b0, msg]))
+}
+
+/// McRootKey of a LoRaWAN 1.0.x device, from its GenAppKey (TS005)
+pub fn mc_root_key_v1_0(gen_app_key: &AesKey) -> AesKey {
+    prefixed_block(gen_app_key, 0x00)
+}
+
+/// McRootKey of a LoRaWAN 1.1 device, from its AppKey (TS005)
+pub fn mc_root_key_v1_1(app_key: &AesKey) -> AesKey {
+    prefixed_block(app_key, 0x20)
+}
+
+/// Decrypt the McKey of a McGroupSetupReq with the McKEKey derived from
+/// `mc_root_key`
+///
+/// As with JoinAccepts, the server encrypts with AES decrypt.
+pub fn decrypt_mc_key(mc_root_key: &AesKey, mc_key_encrypted: &AesKey) -> AesKey {
+    let mc_ke_key = prefixed_block(mc_root_key, 0x00);
+    let mut mc_key = *mc_key_encrypted;
+    aes128_encrypt(&mc_ke_key, &mut mc_key);
+    mc_key
+}
+
+/// Derive McAppSKey and McNwkSKey of a multicast group from its McKey
+pub fn derive_mc_session_keys(mc_key: &AesKey, mc_addr: u32) -> (AesKey, AesKey) {
+    let key = |prefix: u8| {
+        let mut block = [0u8; 16];
+        block[0] = prefix;
+        block[1..5].copy_from_slice(&mc_addr.to_le_bytes());
+        aes128_encrypt(mc_key, &mut block);
+        block
+    };
+    (key(0x01), key(0x02))
+}
+
+/// Class B ping slot randomization: aes128_encrypt(0x00..00, BeaconTime | DevAddr | pad16)
+pub fn ping_slot_rand(beacon_time: u32, dev_addr: u32) -> [u8; 16] {
+    let mut block = [0u8; 16];
+    block[0..4].copy_from_slice(&beacon_time.to_le_bytes());
+    block[4..8].copy_from_slice(&dev_addr.to_le_bytes());
+    aes128_encrypt(&[0u8; 16], &mut block);
+    block
+}
+
+/// aes128_encrypt(key, prefix | pad16)
+fn prefixed_block(key: &AesKey, prefix: u8) -> AesKey {
+    let mut block = [0u8; 16];
+    block[0] = prefix;
+    aes128_encrypt(key, &mut block);
+    block
+}
+
+/// Build an A or B block: tag | 4 bytes | Dir | DevAddr | FCnt | 0x00 | last
+///
+/// The four bytes after the tag are zero except in the 1.1 MIC blocks.
+fn frame_block(tag: u8, extra: [u8; 4], dir: Direction, dev_addr: u32, fcnt: u32, last: u8) -> [u8; 16] {
+    let mut block = [0u8; 16];
+    block[0] = tag;
+    block[1..5].copy_from_slice(&extra);
+    block[5] = dir as u8;
+    block[6..10].copy_from_slice(&dev_addr.to_le_bytes());
+    block[10..14].copy_from_slice(&fcnt.to_le_bytes());
+    block[15] = last;
+    block
+}
+
+/// aes128_encrypt(key, prefix | AppNonce | NetID | DevNonce | pad16)
+///
+/// LoRaWAN 1.1 puts the JoinNonce and JoinEUI where 1.0 has the AppNonce
+/// and NetID.
+fn derive_key(key: &AesKey, prefix: u8, app_nonce: &[u8; 3], id: &[u8], dev_nonce: u16) -> AesKey {
+    let mut block = [0u8; 16];
+    let nonce_at = 4 + id.len();
+    block[0] = prefix;
+    block[1..4].copy_from_slice(app_nonce);
+    block[4..nonce_at].copy_from_slice(id);
+    block[nonce_at..nonce_at + 2].copy_from_slice(&dev_nonce.to_le_bytes());
+    aes128_encrypt(key, &mut block);
+    block
+}
+
+fn truncate_mic(cmac: &[u8; 16]) -> [u8; 4] {
+    [cmac[0], cmac[1], cmac[2], cmac[3]]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const APP_KEY: AesKey = [
+        0x2B, 0x7E, 0x15, 0x16, 0x28, 0xAE, 0xD2, 0xA6, 0xAB, 0xF7, 0x15, 0x88, 0x09, 0xCF, 0x4F, 0x3C,
+    ];
+    const NWK_KEY: AesKey = [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F,
+    ];
+    const DEV_NONCE: u16 = 0x102D;
+    /// JoinEUI 01-02-03-04-05-06-07-08 as it appears on air
+    const JOIN_EUI: [u8; 8] = [8, 7, 6, 5, 4, 3, 2, 1];
+    /// DevEUI 08-07-06-05-04-03-02-01 as it appears on air
+    const DEV_EUI: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+    const DEV_ADDR: u32 = 0x2601_1234;
+    const FCNT: u32 = 0x0001_0002;
+    /// Confirmed uplink with ACK, FPort 1 and a 3-byte payload
+    const DATA_MSG: [u8; 12] = [0x80, 0x34, 0x12, 0x01, 0x26, 0x20, 0x02, 0x00, 0x01, 0xA1, 0xB2, 0xC3];
+
+    /// RFC 4493 section 4, examples 1 to 4
+    #[test]
+    fn cmac_rfc4493() {
+        let msg = [
+            0x6B, 0xC1, 0xBE, 0xE2, 0x2E, 0x40, 0x9F, 0x96, 0xE9, 0x3D, 0x7E, 0x11, 0x73, 0x93, 0x17, 0x2A, 0xAE,
+            0x2D, 0x8A, 0x57, 0x1E, 0x03, 0xAC, 0x9C, 0x9E, 0xB7, 0x6F, 0xAC, 0x45, 0xAF, 0x8E, 0x51, 0x30, 0xC8,
+            0x1C, 0x46, 0xA3, 0x5C, 0xE4, 0x11, 0xE5, 0xFB, 0xC1, 0x19, 0x1A, 0x0A, 0x52, 0xEF, 0xF6, 0x9F, 0x24,
+            0x45, 0xDF, 0x4F, 0x9B, 0x17, 0xAD, 0x2B, 0x41, 0x7B, 0xE6, 0x6C, 0x37, 0x10,
+        ];
+        assert_eq!(
+            aes128_cmac(&APP_KEY, &[]),
+            [0xBB, 0x1D, 0x69, 0x29, 0xE9, 0x59, 0x37, 0x28, 0x7F, 0xA3, 0x7D, 0x12, 0x9B, 0x75, 0x67, 0x46]
+        );
+        assert_eq!(
+            aes128_cmac(&APP_KEY, &[&msg[..16]]),
+            [0x07, 0x0A, 0x16, 0xB4, 0x6B, 0x4D, 0x41, 0x44, 0xF7, 0x9B, 0xDD, 0x9D, 0xD0, 0x4A, 0x28, 0x7C]
+        );
+        // Split across parts, as the B0 block and the frame are
+        assert_eq!(
+            aes128_cmac(&APP_KEY, &[&msg[..7], &msg[7..40]]),
+            [0xDF, 0xA6, 0x67, 0x47, 0xDE, 0x9A, 0xE6, 0x30, 0x30, 0xCA, 0x32, 0x61, 0x14, 0x97, 0xC8, 0x27]
+        );
+        assert_eq!(
+            aes128_cmac(&APP_KEY, &[&msg[..16], &msg[16..]]),
+            [0x51, 0xF0, 0xBE, 0xBF, 0x7E, 0x3B, 0x9D, 0x92, 0xFC, 0x49, 0x74, 0x17, 0x79, 0x36, 0x3C, 0xFE]
+        );
+    }
+
+    /// AppKey of the JoinRequest and JoinAccept examples published with the
+    /// lora-packet decoder
+    const DECODER_APP_KEY: AesKey = [
+        0xB6, 0xB5, 0x3F, 0x4A, 0x16, 0x8A, 0x7A, 0x88, 0xBD, 0xF7, 0xEA, 0x13, 0x5C, 0xE9, 0xCF, 0xCA,
+    ];
+
+    #[test]
+    fn join_request_mic_vector() {
+        // ANwAANB+1bNwHm/t9XzurwDIhgMK8sk=: AppEUI 70B3D57ED00000DC,
+        // DevEUI 00AFEE7CF5ED6F1E, DevNonce 0x86C8
+        let frame = [
+            0x00, 0xDC, 0x00, 0x00, 0xD0, 0x7E, 0xD5, 0xB3, 0x70, 0x1E, 0x6F, 0xED, 0xF5, 0x7C, 0xEE, 0xAF, 0x00,
+            0xC8, 0x86, 0x03, 0x0A, 0xF2, 0xC9,
+        ];
+        assert_eq!(join_request_mic(&DECODER_APP_KEY, &frame[..19]), frame[19..]);
+    }
+
+    #[test]
+    fn join_accept_v1_0() {
+        // IIE/R/UI/6JnC24j4B+EueJdnEEV8C7qCz3T4gs+ypLa
+        let mut frame = [
+            0x20, 0x81, 0x3F, 0x47, 0xF5, 0x08, 0xFF, 0xA2, 0x67, 0x0B, 0x6E, 0x23, 0xE0, 0x1F, 0x84, 0xB9, 0xE2,
+            0x5D, 0x9C, 0x41, 0x15, 0xF0, 0x2E, 0xEA, 0x0B, 0x3D, 0xD3, 0xE2, 0x0B, 0x3E, 0xCA, 0x92, 0xDA,
+        ];
+        decrypt_join_accept(&DECODER_APP_KEY, &mut frame[1..]);
+        // AppNonce 0xF15F99, NetID 0x000013, DevAddr 26012E29, RX1DROffset 0,
+        // RX2 DR3, RxDelay 1 s, CFList 867.1-867.9 MHz
+        assert_eq!(
+            frame[1..13],
+            [0x99, 0x5F, 0xF1, 0x13, 0x00, 0x00, 0x29, 0x2E, 0x01, 0x26, 0x03, 0x01]
+        );
+        assert_eq!(
+            frame[13..29],
+            [0x18, 0x4F, 0x84, 0xE8, 0x56, 0x84, 0xB8, 0x5E, 0x84, 0x88, 0x66, 0x84, 0x58, 0x6E, 0x84, 0x00]
+        );
+        assert_eq!(join_accept_mic(&DECODER_APP_KEY, &frame[..29]), [0x73, 0x4C, 0xCD, 0xC4]);
+        assert_eq!(frame[29..], [0x73, 0x4C, 0xCD, 0xC4]);
+
+        // With the DevNonce of the JoinRequest example
+        let keys = derive_session_keys(&DECODER_APP_KEY, &[0x99, 0x5F, 0xF1], &[0x13, 0x00, 0x00], 0x86C8);
+        let nwk_skey = [0xF8, 0xCE, 0x97, 0x4A, 0x07, 0x76, 0xC9, 0x29, 0x05, 0xA7, 0xA3, 0xA6, 0x6F, 0x61, 0xAA, 0x5D];
+        assert!(keys == SessionKeys::v1_0(
+            nwk_skey,
+            [0x54, 0xFE, 0xBD, 0xA3, 0xD0, 0xBE, 0x19, 0xE5, 0x4C, 0x66, 0x3C, 0x5E, 0xE2, 0xCF, 0x09, 0xEF]
+        ));
+    }
+
+    #[test]
+    fn join_accept_v1_1() {
+        let join_keys = derive_join_keys(&NWK_KEY, &DEV_EUI);
+        assert_eq!(
+            join_keys.js_int_key,
+            [0x9C, 0x66, 0xA4, 0xD1, 0x26, 0x0F, 0x3D, 0x78, 0xD4, 0x1C, 0x3D, 0xB7, 0xDE, 0xCC, 0x62, 0xAD]
+        );
+        assert_eq!(
+            join_keys.js_enc_key,
+            [0x38, 0x6A, 0x41, 0x2D, 0x77, 0x77, 0xE3, 0xB1, 0x50, 0x50, 0xBA, 0x7D, 0x6B, 0x73, 0x45, 0xB6]
+        );
+
+        let mut frame = [
+            0x20, 0xAA, 0xF8, 0x13, 0x43, 0xBE, 0x35, 0x63, 0x6B, 0x17, 0xC5, 0xFD, 0x11, 0xBD, 0x63, 0x05, 0xF7,
+        ];
+        decrypt_join_accept(&NWK_KEY, &mut frame[1..]);
+        assert_eq!(
+            frame[..13],
+            [0x20, 0x01, 0x02, 0x03, 0x13, 0x00, 0x00, 0x34, 0x12, 0x01, 0x26, 0x80, 0x01]
+        );
+        let mic = join_accept_mic_v1_1(&join_keys.js_int_key, 0xFF, &JOIN_EUI, DEV_NONCE, &frame[..13]);
+        assert_eq!(mic, [0x91, 0xB3, 0xE0, 0xDE]);
+        assert_eq!(frame[13..], mic);
+
+        let keys = derive_session_keys_v1_1(&NWK_KEY, &APP_KEY, &[0x01, 0x02, 0x03], &JOIN_EUI, DEV_NONCE);
+        assert_eq!(
+            keys.f_nwk_s_int_key,
+            [0x7C, 0x34, 0xA4, 0x68, 0xEA, 0x5F, 0x5B, 0x1D, 0x16, 0x19, 0xE7, 0x3B, 0xCC, 0x02, 0x84, 0xC8]
+        );
+        assert_eq!(
+            keys.s_nwk_s_int_key,
+            [0x10, 0xF0, 0xFB, 0x23, 0xDA, 0xD9, 0x89, 0xCE, 0x7E, 0x08, 0x81, 0x86, 0xE4, 0x29, 0xD8, 0xC0]
+        );
+        assert_eq!(
+            keys.nwk_s_enc_key,
+            [0xFD, 0x21, 0xE4, 0xD1, 0x0E, 0x73, 0xD0, 0x87, 0x52, 0xEC, 0x76, 0xC3, 0x74, 0x0F, 0x2F, 0x61]
+        );
+        assert_eq!(
+            keys.app_skey,
+            [0x6E, 0xA0, 0x38, 0xE8, 0x50, 0x1F, 0xB8, 0x61, 0x09, 0x31, 0x27, 0x58, 0xA4, 0x20, 0x6B, 0x25]
+        );
+    }
+
+    #[test]
+    fn data_mic_v1_0() {
+        let nwk_skey = [
+            0x44, 0x02, 0x42, 0x41, 0xED, 0x4C, 0xE9, 0xA6, 0x8C, 0x6A, 0x8B, 0xC0, 0x55, 0x23, 0x3F, 0xD3,
+        ];
+        let app_skey = [
+            0xEC, 0x92, 0x58, 0x02, 0xAE, 0x43, 0x0C, 0xA7, 0x7F, 0xD3, 0xDD, 0x73, 0xCB, 0x2C, 0xC5, 0x88,
+        ];
+        let mut frame = [
+            0x40, 0xF1, 0x7D, 0xBE, 0x49, 0x00, 0x02, 0x00, 0x01, 0x95, 0x43, 0x78, 0x76, 0x2B, 0x11, 0xFF, 0x0D,
+        ];
+        let mic = data_mic(&nwk_skey, Direction::Uplink, 0x49BE_7DF1, 2, &frame[..13]);
+        assert_eq!(frame[13..], mic);
+        crypt_frm_payload(&app_skey, Direction::Uplink, 0x49BE_7DF1, 2, &mut frame[9..13]);
+        assert_eq!(&frame[9..13], b"test");
+    }
+
+    #[test]
+    fn data_mic_v1_1() {
+        let keys = derive_session_keys_v1_1(&NWK_KEY, &APP_KEY, &[0x01, 0x02, 0x03], &JOIN_EUI, DEV_NONCE);
+        // B1 carries ConfFCnt 5, TxDr 3 and TxCh 2
+        assert_eq!(uplink_mic_v1_1(&keys, 5, 3, 2, DEV_ADDR, FCNT, &DATA_MSG), [0x36, 0x86, 0x74, 0x8C]);
+        assert_eq!(
+            downlink_mic_v1_1(&keys.s_nwk_s_int_key, 7, DEV_ADDR, FCNT, &DATA_MSG),
+            [0x82, 0xA2, 0xCF, 0x22]
+        );
+    }
+}
@@ -0,0 +1,239 @@
+//! Duty-cycle bookkeeping
+//!
+//! Tracks when each regional sub-band, and the aggregated limit set by
+//! DutyCycleReq, allows the next transmission. A transmission of T on a
+//! band with duty cycle 1/d keeps that band silent for T * (d - 1).
+//!
+//! JoinRequests are additionally limited by the LoRaWAN 1.0.4 join back-off:
+//! 36 s of airtime per hour during the first hour of joining, 36 s per 10 h
+//! up to hour 11, then 8.7 s per 24 h.
+
+use embassy_time::{Duration, Instant};
+
+use super::region::{Region, MAX_BANDS};
+
+/// Earliest allowed transmission per sub-band
+#[derive(Clone)]
+pub struct DutyCycle {
+    region: Region,
+    /// Sub-band time-off expiry
+    band_ready: [Instant; MAX_BANDS],
+    /// Aggregated time-off expiry (DutyCycleReq)
+    aggregated_ready: Instant,
+}
+
+impl DutyCycle {
+    /// Start with every band available
+    pub fn new(region: Region) -> Self {
+        Self {
+            region,
+            band_ready: [Instant::from_ticks(0); MAX_BANDS],
+            aggregated_ready: Instant::from_ticks(0),
+        }
+    }
+
+    /// Start over for a new session
+    ///
+    /// Drops the time-off of the previous session, except on the band of
+    /// the JoinRequest or Rejoin-request just sent on `frequency`.
+    pub fn restart(&mut self, frequency: u32) {
+        let kept = self.region.duty_cycle_band(frequency).map(|(band, _)| (band, self.band_ready[band]));
+        *self = Self::new(self.region);
+        if let Some((band, ready)) = kept {
+            self.band_ready[band] = ready;
+        }
+    }
+
+    /// Earliest time a transmission on `frequency` is allowed
+    pub fn ready_at(&self, frequency: u32) -> Instant {
+        let band = match self.region.duty_cycle_band(frequency) {
+            Some((band, _)) => self.band_ready[band],
+            None => Instant::from_ticks(0),
+        };
+        band.max(self.aggregated_ready)
+    }
+
+    /// Record a transmission on `frequency` that lasted `airtime` and ended at `end`
+    ///
+    /// `max_duty_cycle` is the DutyCycleReq value; the aggregated duty cycle
+    /// is limited to 1 / 2^max_duty_cycle, 0 meaning no limit.
+    pub fn record(&mut self, frequency: u32, end: Instant, airtime: Duration, max_duty_cycle: u8) {
+        if let Some((band, divisor)) = self.region.duty_cycle_band(frequency) {
+            self.band_ready[band] = end + airtime * (divisor - 1);
+        }
+        if max_duty_cycle > 0 {
+            let divisor = 1u32 << max_duty_cycle.min(15);
+            self.aggregated_ready = end + airtime * (divisor - 1);
+        }
+    }
+}
+
+/// Join back-off phases: (elapsed time since the first JoinRequest, 1 / duty cycle)
+const JOIN_BACKOFF: [(Duration, u32); 2] = [
+    (Duration::from_secs(3_600), 100),
+    (Duration::from_secs(11 * 3_600), 1_000),
+];
+/// Join duty cycle after the first 11 hours (8.7 s per 24 h)
+const JOIN_BACKOFF_LATE: u32 = 10_000;
+
+/// Join back-off state that must survive a reset
+///
+/// Stored in [`JoinContext`](super::persist::JoinContext) after every
+/// JoinRequest, so a brownout or watchdog reset does not restart the
+/// back-off at its first, most permissive hour.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct JoinBackoffProgress {
+    /// Minutes from the first to the last JoinRequest since the last
+    /// successful join
+    pub elapsed_min: u16,
+    /// Time-off imposed by the last JoinRequest, in seconds, rounded up
+    pub time_off_s: u16,
+}
+
+/// Aggregated time-off for JoinRequest retries
+#[derive(Clone, Default)]
+pub struct JoinBackoff {
+    /// End of the first JoinRequest since the last successful join or reset
+    start: Option<Instant>,
+    /// Joining time before the last reset
+    carried: Duration,
+    /// Earliest next JoinRequest
+    ready: Option<Instant>,
+    /// State to persist
+    progress: JoinBackoffProgress,
+}
+
+impl JoinBackoff {
+    /// No JoinRequest sent yet
+    pub const fn new() -> Self {
+        Self {
+            start: None,
+            carried: Duration::from_ticks(0),
+            ready: None,
+            progress: JoinBackoffProgress {
+                elapsed_min: 0,
+                time_off_s: 0,
+            },
+        }
+    }
+
+    /// Continue a back-off saved before a reset
+    ///
+    /// The downtime is unknown and counts for nothing: the time-off of the
+    /// last JoinRequest starts again at `now`, and the phase continues where
+    /// it stood.
+    pub fn resume(progress: JoinBackoffProgress, now: Instant) -> Self {
+        let time_off = Duration::from_secs(progress.time_off_s as u64);
+        Self {
+            start: None,
+            carried: Duration::from_secs(progress.elapsed_min as u64 * 60),
+            ready: (progress.time_off_s > 0).then_some(now + time_off),
+            progress,
+        }
+    }
+
+    /// Earliest time the next JoinRequest may start
+    pub fn ready_at(&self) -> Instant {
+        self.ready.unwrap_or(Instant::from_ticks(0))
+    }
+
+    /// State to persist after [`record`](Self::record)
+    pub fn progress(&self) -> JoinBackoffProgress {
+        self.progress
+    }
+
+    /// Record a JoinRequest that lasted `airtime` and ended at `end`
+    pub fn record(&mut self, end: Instant, airtime: Duration) {
+        let start = *self.start.get_or_insert(end);
+        let elapsed = self.carried + end.saturating_duration_since(start);
+        let divisor = JOIN_BACKOFF
+            .iter()
+            .find(|(until, _)| elapsed < *until)
+            .map_or(JOIN_BACKOFF_LATE, |(_, divisor)| *divisor);
+        let time_off = airtime * (divisor - 1);
+        self.ready = Some(end + time_off);
+        self.progress = JoinBackoffProgress {
+            elapsed_min: (elapsed.as_secs() / 60).min(u16::MAX as u64) as u16,
+            time_off_s: time_off.as_millis().div_ceil(1000).min(u16::MAX as u64) as u16,
+        };
+    }
+
+    /// Start over after a successful join
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duty_cycle_per_band_and_aggregated() {
+        let airtime = Duration::from_secs(1);
+        let mut duty_cycle = DutyCycle::new(Region::EU868);
+        assert_eq!(duty_cycle.ready_at(868_100_000), Instant::from_ticks(0));
+
+        // 868.0-868.6 MHz is a 1% band shared by the three default channels
+        duty_cycle.record(868_100_000, Instant::from_secs(10), airtime, 0);
+        assert_eq!(duty_cycle.ready_at(868_100_000), Instant::from_secs(10 + 99));
+        assert_eq!(duty_cycle.ready_at(868_500_000), Instant::from_secs(10 + 99));
+        assert_eq!(duty_cycle.ready_at(867_100_000), Instant::from_ticks(0));
+        // 869.4-869.65 MHz allows 10%
+        duty_cycle.record(869_525_000, Instant::from_secs(20), airtime, 0);
+        assert_eq!(duty_cycle.ready_at(869_525_000), Instant::from_secs(20 + 9));
+        assert_eq!(duty_cycle.ready_at(868_100_000), Instant::from_secs(10 + 99));
+
+        // DutyCycleReq MaxDCycle 4 limits every band together to 1/16
+        duty_cycle.record(867_100_000, Instant::from_secs(200), airtime, 4);
+        assert_eq!(duty_cycle.ready_at(867_100_000), Instant::from_secs(200 + 99));
+        assert_eq!(duty_cycle.ready_at(868_100_000), Instant::from_secs(200 + 15));
+        assert_eq!(duty_cycle.ready_at(869_525_000), Instant::from_secs(200 + 15));
+        // A longer band time-off still wins
+        duty_cycle.record(868_100_000, Instant::from_secs(300), airtime, 4);
+        assert_eq!(duty_cycle.ready_at(868_300_000), Instant::from_secs(300 + 99));
+        assert_eq!(duty_cycle.ready_at(868_900_000), Instant::from_secs(300 + 15));
+
+        // Regions without sub-bands only know the aggregated limit
+        let mut duty_cycle = DutyCycle::new(Region::US915);
+        duty_cycle.record(902_300_000, Instant::from_secs(10), airtime, 0);
+        assert_eq!(duty_cycle.ready_at(902_300_000), Instant::from_ticks(0));
+        duty_cycle.record(902_300_000, Instant::from_secs(10), airtime, 2);
+        assert_eq!(duty_cycle.ready_at(903_900_000), Instant::from_secs(10 + 3));
+    }
+
+    #[test]
+    fn restart_keeps_the_join_band() {
+        let airtime = Duration::from_secs(1);
+        let mut duty_cycle = DutyCycle::new(Region::EU868);
+        duty_cycle.record(867_100_000, Instant::from_secs(10), airtime, 4);
+        duty_cycle.record(868_100_000, Instant::from_secs(20), airtime, 4);
+        duty_cycle.restart(868_100_000);
+        assert_eq!(duty_cycle.ready_at(868_300_000), Instant::from_secs(20 + 99));
+        assert_eq!(duty_cycle.ready_at(867_100_000), Instant::from_ticks(0));
+        assert_eq!(duty_cycle.ready_at(869_525_000), Instant::from_ticks(0));
+    }
+
+    #[test]
+    fn join_backoff_survives_reset() {
+        let airtime = Duration::from_secs(1);
+        let mut backoff = JoinBackoff::new();
+        backoff.record(Instant::from_secs(10), airtime);
+        assert_eq!(backoff.ready_at(), Instant::from_secs(10 + 99));
+        backoff.record(Instant::from_secs(10 + 2 * 3_600), airtime);
+        let progress = backoff.progress();
+        assert_eq!(progress, JoinBackoffProgress { elapsed_min: 120, time_off_s: 999 });
+
+        // After the reset the time-off starts over and the phase continues
+        let mut resumed = JoinBackoff::resume(progress, Instant::from_secs(5));
+        assert_eq!(resumed.ready_at(), Instant::from_secs(5 + 999));
+        resumed.record(Instant::from_secs(1_100), airtime);
+        assert_eq!(resumed.ready_at(), Instant::from_secs(1_100 + 999));
+        resumed.record(Instant::from_secs(1_100 + 9 * 3_600), airtime);
+        assert_eq!(resumed.progress().time_off_s, 9_999);
+
+        resumed.reset();
+        assert_eq!(resumed.progress(), JoinBackoffProgress::default());
+        assert_eq!(resumed.ready_at(), Instant::from_ticks(0));
+    }
+}
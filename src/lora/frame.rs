@@ -0,0 +1,620 @@
+//! LoRaWAN frame codec
+//!
+//! Layout of the PHYPayloads the stack sends and receives: the MHDR,
+//! JoinRequest, Rejoin-request, JoinAccept and data frames with their FHDR
+//! and FOpts. Everything works on caller-provided buffers, without the radio
+//! or any session state, so the parsers can be run against the spec test
+//! vectors and fuzzed on a host. Downlinks are untrusted radio input; every
+//! decoding step fails with its own [`FrameError`] instead of panicking.
+//!
+//! Only the layout lives here. MICs and encryption are computed by
+//! [`super::crypto`] over the ranges this module reports, and the MAC
+//! commands carried in FOpts or a port 0 payload are parsed by
+//! [`super::mac`].
+
+use core::ops::Range;
+
+use super::mac::{MacCommands, MAX_FOPTS_LEN};
+
+/// Length of the MIC closing every PHYPayload except proprietary ones
+pub const MIC_LEN: usize = 4;
+/// Length of a JoinRequest
+pub const JOIN_REQUEST_LEN: usize = 23;
+/// Length of a JoinAccept without CFList
+pub const JOIN_ACCEPT_LEN: usize = 17;
+/// Length of a JoinAccept carrying a CFList
+pub const JOIN_ACCEPT_CF_LIST_LEN: usize = JOIN_ACCEPT_LEN + 16;
+/// Length of a type 0 or type 2 Rejoin-request
+pub const REJOIN_REQUEST_LEN: usize = 19;
+/// Length of a type 1 Rejoin-request
+pub const REJOIN_REQUEST_TYPE1_LEN: usize = 24;
+
+/// Offset of FOpts in a data frame: MHDR | DevAddr | FCtrl | FCnt
+const FOPTS_START: usize = 8;
+/// The only Major version defined, LoRaWAN R1
+const MAJOR_R1: u8 = 0;
+const MAJOR_MASK: u8 = 0x03;
+
+// FCtrl bits
+pub const FCTRL_ADR: u8 = 0x80;
+pub const FCTRL_ADR_ACK_REQ: u8 = 0x40;
+pub const FCTRL_ACK: u8 = 0x20;
+pub const FCTRL_FPENDING: u8 = 0x10;
+/// The FPending bit of uplinks: the device is in Class B
+pub const FCTRL_CLASS_B: u8 = 0x10;
+pub const FCTRL_FOPTS_LEN: u8 = 0x0F;
+
+/// DLSettings bit set by LoRaWAN 1.1 join servers
+pub const DL_SETTINGS_OPT_NEG: u8 = 0x80;
+
+/// Frame encoding and decoding errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum FrameError {
+    /// No MHDR
+    Empty,
+    /// The MHDR names a Major version other than LoRaWAN R1
+    UnsupportedMajor(u8),
+    /// The frame is of another message type than expected
+    UnexpectedType(MType),
+    /// The frame is shorter than its MIC or fixed header
+    TooShort,
+    /// A join frame is not one of the lengths its type allows
+    Length(usize),
+    /// Rejoin-request type other than 0, 1 or 2
+    UnknownRejoinType(u8),
+    /// FOptsLen points past the end of the frame
+    FOptsOverrun,
+    /// More than 15 bytes of FOpts to encode
+    FOptsTooLong,
+    /// MAC commands in both FOpts and a port 0 payload
+    FOptsWithPort0,
+    /// FRMPayload without an FPort to encode
+    PayloadWithoutPort,
+    /// The output buffer cannot hold the frame
+    BufferTooSmall,
+}
+
+/// Message type from the MHDR
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum MType {
+    JoinRequest = 0,
+    JoinAccept = 1,
+    UnconfirmedDataUp = 2,
+    UnconfirmedDataDown = 3,
+    ConfirmedDataUp = 4,
+    ConfirmedDataDown = 5,
+    RejoinRequest = 6,
+    Proprietary = 7,
+}
+
+impl MType {
+    /// MHDR of a LoRaWAN R1 frame of this type
+    pub const fn mhdr(self) -> u8 {
+        (self as u8) << 5 | MAJOR_R1
+    }
+
+    /// Whether this is one of the four data frame types
+    pub fn is_data(self) -> bool {
+        matches!(
+            self,
+            Self::UnconfirmedDataUp | Self::UnconfirmedDataDown | Self::ConfirmedDataUp | Self::ConfirmedDataDown
+        )
+    }
+
+    /// Whether this is a confirmed data frame
+    pub fn is_confirmed(self) -> bool {
+        matches!(self, Self::ConfirmedDataUp | Self::ConfirmedDataDown)
+    }
+
+    /// Whether frames of this type are sent by the device
+    pub fn is_uplink(self) -> bool {
+        matches!(
+            self,
+            Self::JoinRequest | Self::UnconfirmedDataUp | Self::ConfirmedDataUp | Self::RejoinRequest
+        )
+    }
+}
+
+/// Message type of a PHYPayload, from its MHDR
+///
+/// The RFU bits are ignored, as the spec asks of receivers.
+pub fn decode_mhdr(frame: &[u8]) -> Result<MType, FrameError> {
+    let &mhdr = frame.first().ok_or(FrameError::Empty)?;
+    let major = mhdr & MAJOR_MASK;
+    if major != MAJOR_R1 {
+        return Err(FrameError::UnsupportedMajor(major));
+    }
+    Ok(match mhdr >> 5 {
+        0 => MType::JoinRequest,
+        1 => MType::JoinAccept,
+        2 => MType::UnconfirmedDataUp,
+        3 => MType::UnconfirmedDataDown,
+        4 => MType::ConfirmedDataUp,
+        5 => MType::ConfirmedDataDown,
+        6 => MType::RejoinRequest,
+        _ => MType::Proprietary,
+    })
+}
+
+/// Split a PHYPayload into the message the MIC covers and the MIC
+pub fn split_mic(frame: &[u8]) -> Result<(&[u8], [u8; MIC_LEN]), FrameError> {
+    let msg_len = frame.len().checked_sub(MIC_LEN).ok_or(FrameError::TooShort)?;
+    let (msg, mic) = frame.split_at(msg_len);
+    let mut out = [0u8; MIC_LEN];
+    out.copy_from_slice(mic);
+    Ok((msg, out))
+}
+
+/// EUIs are configured most significant byte first and sent reversed
+pub fn eui_on_air(eui: &[u8; 8]) -> [u8; 8] {
+    let mut reversed = *eui;
+    reversed.reverse();
+    reversed
+}
+
+/// Check the MHDR and the length of a join frame
+fn check_join_frame(frame: &[u8], mtype: MType, lengths: &[usize]) -> Result<(), FrameError> {
+    let found = decode_mhdr(frame)?;
+    if found != mtype {
+        return Err(FrameError::UnexpectedType(found));
+    }
+    if !lengths.contains(&frame.len()) {
+        return Err(FrameError::Length(frame.len()));
+    }
+    Ok(())
+}
+
+fn output(frame: &mut [u8], len: usize) -> Result<&mut [u8], FrameError> {
+    frame.get_mut(..len).ok_or(FrameError::BufferTooSmall)
+}
+
+fn u16_at(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([data[at], data[at + 1]])
+}
+
+fn u24_at(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], 0])
+}
+
+fn u32_at(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+fn eui_at(data: &[u8], at: usize) -> [u8; 8] {
+    let mut eui = [0u8; 8];
+    eui.copy_from_slice(&data[at..at + 8]);
+    eui.reverse();
+    eui
+}
+
+/// MHDR | JoinEUI | DevEUI | DevNonce | MIC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JoinRequest {
+    /// JoinEUI (AppEUI in LoRaWAN 1.0), most significant byte first
+    pub join_eui: [u8; 8],
+    /// DevEUI, most significant byte first
+    pub dev_eui: [u8; 8],
+    pub dev_nonce: u16,
+}
+
+impl JoinRequest {
+    /// Decode a JoinRequest, MIC included
+    pub fn decode(frame: &[u8]) -> Result<Self, FrameError> {
+        check_join_frame(frame, MType::JoinRequest, &[JOIN_REQUEST_LEN])?;
+        Ok(Self {
+            join_eui: eui_at(frame, 1),
+            dev_eui: eui_at(frame, 9),
+            dev_nonce: u16_at(frame, 17),
+        })
+    }
+
+    /// Encode the JoinRequest up to the MIC, returning the length; the
+    /// caller appends the MIC
+    pub fn encode(&self, frame: &mut [u8]) -> Result<usize, FrameError> {
+        let out = output(frame, JOIN_REQUEST_LEN)?;
+        out[0] = MType::JoinRequest.mhdr();
+        out[1..9].copy_from_slice(&eui_on_air(&self.join_eui));
+        out[9..17].copy_from_slice(&eui_on_air(&self.dev_eui));
+        out[17..19].copy_from_slice(&self.dev_nonce.to_le_bytes());
+        Ok(JOIN_REQUEST_LEN - MIC_LEN)
+    }
+}
+
+/// A LoRaWAN 1.1 Rejoin-request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejoinRequest {
+    /// Type 0 or 2: MHDR | type | NetID | DevEUI | RJcount0 | MIC
+    NetId {
+        rejoin_type: u8,
+        net_id: u32,
+        dev_eui: [u8; 8],
+        rj_count0: u16,
+    },
+    /// Type 1: MHDR | type | JoinEUI | DevEUI | RJcount1 | MIC
+    JoinEui {
+        join_eui: [u8; 8],
+        dev_eui: [u8; 8],
+        rj_count1: u16,
+    },
+}
+
+impl RejoinRequest {
+    /// Decode a Rejoin-request, MIC included
+    pub fn decode(frame: &[u8]) -> Result<Self, FrameError> {
+        check_join_frame(frame, MType::RejoinRequest, &[REJOIN_REQUEST_LEN, REJOIN_REQUEST_TYPE1_LEN])?;
+        match (frame[1], frame.len()) {
+            (rejoin_type @ (0 | 2), REJOIN_REQUEST_LEN) => Ok(Self::NetId {
+                rejoin_type,
+                net_id: u24_at(frame, 2),
+                dev_eui: eui_at(frame, 5),
+                rj_count0: u16_at(frame, 13),
+            }),
+            (1, REJOIN_REQUEST_TYPE1_LEN) => Ok(Self::JoinEui {
+                join_eui: eui_at(frame, 2),
+                dev_eui: eui_at(frame, 10),
+                rj_count1: u16_at(frame, 18),
+            }),
+            (0..=2, len) => Err(FrameError::Length(len)),
+            (rejoin_type, _) => Err(FrameError::UnknownRejoinType(rejoin_type)),
+        }
+    }
+
+    /// Encode the Rejoin-request up to the MIC, returning the length; the
+    /// caller appends the MIC
+    pub fn encode(&self, frame: &mut [u8]) -> Result<usize, FrameError> {
+        match *self {
+            Self::NetId { rejoin_type, net_id, dev_eui, rj_count0 } => {
+                if !matches!(rejoin_type, 0 | 2) {
+                    return Err(FrameError::UnknownRejoinType(rejoin_type));
+                }
+                let out = output(frame, REJOIN_REQUEST_LEN)?;
+                out[0] = MType::RejoinRequest.mhdr();
+                out[1] = rejoin_type;
+                out[2..5].copy_from_slice(&net_id.to_le_bytes()[..3]);
+                out[5..13].copy_from_slice(&eui_on_air(&dev_eui));
+                out[13..15].copy_from_slice(&rj_count0.to_le_bytes());
+                Ok(REJOIN_REQUEST_LEN - MIC_LEN)
+            }
+            Self::JoinEui { join_eui, dev_eui, rj_count1 } => {
+                let out = output(frame, REJOIN_REQUEST_TYPE1_LEN)?;
+                out[0] = MType::RejoinRequest.mhdr();
+                out[1] = 1;
+                out[2..10].copy_from_slice(&eui_on_air(&join_eui));
+                out[10..18].copy_from_slice(&eui_on_air(&dev_eui));
+                out[18..20].copy_from_slice(&rj_count1.to_le_bytes());
+                Ok(REJOIN_REQUEST_TYPE1_LEN - MIC_LEN)
+            }
+        }
+    }
+}
+
+/// MHDR | JoinNonce | NetID | DevAddr | DLSettings | RxDelay | [CFList] | MIC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JoinAccept {
+    /// JoinNonce (AppNonce in LoRaWAN 1.0), 24 bits
+    pub join_nonce: u32,
+    /// NetID, 24 bits
+    pub net_id: u32,
+    pub dev_addr: u32,
+    pub dl_settings: u8,
+    /// RxDelay in seconds, 0 meaning 1
+    pub rx_delay: u8,
+    pub cf_list: Option<[u8; 16]>,
+}
+
+impl JoinAccept {
+    /// Check the MHDR and length of a JoinAccept before decrypting it
+    pub fn check(frame: &[u8]) -> Result<(), FrameError> {
+        check_join_frame(frame, MType::JoinAccept, &[JOIN_ACCEPT_LEN, JOIN_ACCEPT_CF_LIST_LEN])
+    }
+
+    /// Decode a decrypted JoinAccept, MIC included
+    pub fn decode(frame: &[u8]) -> Result<Self, FrameError> {
+        Self::check(frame)?;
+        let cf_list = frame.get(13..29).map(|c| {
+            let mut list = [0u8; 16];
+            list.copy_from_slice(c);
+            list
+        });
+        Ok(Self {
+            join_nonce: u24_at(frame, 1),
+            net_id: u24_at(frame, 4),
+            dev_addr: u32_at(frame, 7),
+            dl_settings: frame[11],
+            rx_delay: frame[12] & 0x0F,
+            cf_list,
+        })
+    }
+
+    /// Encode the JoinAccept up to the MIC, returning the length; the
+    /// caller appends the MIC and encrypts everything after the MHDR
+    pub fn encode(&self, frame: &mut [u8]) -> Result<usize, FrameError> {
+        let len = if self.cf_list.is_some() { JOIN_ACCEPT_CF_LIST_LEN } else { JOIN_ACCEPT_LEN };
+        let out = output(frame, len)?;
+        out[0] = MType::JoinAccept.mhdr();
+        out[1..4].copy_from_slice(&self.join_nonce.to_le_bytes()[..3]);
+        out[4..7].copy_from_slice(&self.net_id.to_le_bytes()[..3]);
+        out[7..11].copy_from_slice(&self.dev_addr.to_le_bytes());
+        out[11] = self.dl_settings;
+        out[12] = self.rx_delay;
+        if let Some(cf_list) = &self.cf_list {
+            out[13..29].copy_from_slice(cf_list);
+        }
+        Ok(len - MIC_LEN)
+    }
+
+    /// Whether a LoRaWAN 1.1 join server answered
+    pub fn opt_neg(&self) -> bool {
+        self.dl_settings & DL_SETTINGS_OPT_NEG != 0
+    }
+
+    pub fn rx1_dr_offset(&self) -> u8 {
+        (self.dl_settings >> 4) & 0x07
+    }
+
+    pub fn rx2_data_rate(&self) -> u8 {
+        self.dl_settings & 0x0F
+    }
+}
+
+/// MHDR | DevAddr | FCtrl | FCnt | FOpts | [FPort | FRMPayload]
+///
+/// FOpts and FRMPayload are taken as they are on air: encrypted, except
+/// for LoRaWAN 1.0 FOpts. [`fopts_range`](Self::fopts_range) and
+/// [`payload_range`](Self::payload_range) locate them in the encoded frame
+/// for in-place encryption.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataFrame<'a> {
+    pub mtype: MType,
+    pub dev_addr: u32,
+    /// FCtrl without the FOptsLen bits
+    pub fctrl: u8,
+    /// Low 16 bits of the frame counter
+    pub fcnt: u16,
+    pub fopts: &'a [u8],
+    pub port: Option<u8>,
+    pub payload: &'a [u8],
+}
+
+impl<'a> DataFrame<'a> {
+    /// Decode a data frame from the message its MIC covers (see [`split_mic`])
+    pub fn decode(msg: &'a [u8]) -> Result<Self, FrameError> {
+        let mtype = decode_mhdr(msg)?;
+        if !mtype.is_data() {
+            return Err(FrameError::UnexpectedType(mtype));
+        }
+        if msg.len() < FOPTS_START {
+            return Err(FrameError::TooShort);
+        }
+
+        let fctrl = msg[5];
+        let fopts_end = FOPTS_START + (fctrl & FCTRL_FOPTS_LEN) as usize;
+        let fopts = msg.get(FOPTS_START..fopts_end).ok_or(FrameError::FOptsOverrun)?;
+        let (port, payload) = match msg[fopts_end..].split_first() {
+            Some((&port, payload)) => (Some(port), payload),
+            None => (None, &[][..]),
+        };
+        if port == Some(0) && !fopts.is_empty() {
+            return Err(FrameError::FOptsWithPort0);
+        }
+
+        Ok(Self {
+            mtype,
+            dev_addr: u32_at(msg, 1),
+            fctrl: fctrl & !FCTRL_FOPTS_LEN,
+            fcnt: u16_at(msg, 6),
+            fopts,
+            port,
+            payload,
+        })
+    }
+
+    /// Encode the frame up to the MIC, returning the length; the caller
+    /// encrypts FOpts and FRMPayload in place and appends the MIC
+    pub fn encode(&self, frame: &mut [u8]) -> Result<usize, FrameError> {
+        if !self.mtype.is_data() {
+            return Err(FrameError::UnexpectedType(self.mtype));
+        }
+        if self.fopts.len() > MAX_FOPTS_LEN {
+            return Err(FrameError::FOptsTooLong);
+        }
+        if self.port.is_none() && !self.payload.is_empty() {
+            return Err(FrameError::PayloadWithoutPort);
+        }
+        if self.port == Some(0) && !self.fopts.is_empty() {
+            return Err(FrameError::FOptsWithPort0);
+        }
+
+        let fopts = self.fopts_range();
+        let payload = self.payload_range();
+        let out = output(frame, payload.end)?;
+        out[0] = self.mtype.mhdr();
+        out[1..5].copy_from_slice(&self.dev_addr.to_le_bytes());
+        out[5] = (self.fctrl & !FCTRL_FOPTS_LEN) | self.fopts.len() as u8;
+        out[6..8].copy_from_slice(&self.fcnt.to_le_bytes());
+        out[fopts.clone()].copy_from_slice(self.fopts);
+        if let Some(port) = self.port {
+            out[fopts.end] = port;
+            out[payload.clone()].copy_from_slice(self.payload);
+        }
+        Ok(payload.end)
+    }
+
+    /// Position of FOpts in the encoded frame
+    pub fn fopts_range(&self) -> Range<usize> {
+        FOPTS_START..FOPTS_START + self.fopts.len()
+    }
+
+    /// Position of FRMPayload in the encoded frame
+    pub fn payload_range(&self) -> Range<usize> {
+        let start = self.fopts_range().end + self.port.map_or(0, |_| 1);
+        start..start + self.payload.len()
+    }
+
+    /// The MAC commands of the frame: the port 0 payload, or else FOpts
+    ///
+    /// Both are encrypted in LoRaWAN 1.1 (and a port 0 payload in 1.0 as
+    /// well); decrypt them and decode again before parsing.
+    pub fn mac_commands(&self) -> MacCommands<'a> {
+        if self.port == Some(0) {
+            MacCommands::new(self.payload)
+        } else {
+            MacCommands::new(self.fopts)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lora::crypto::{self, Direction};
+    use crate::lora::mac::MacCommand;
+
+    /// NwkSKey of the lora-packet test vectors
+    const NWK_SKEY: [u8; 16] = [
+        0x44, 0x02, 0x42, 0x41, 0xED, 0x4C, 0xE9, 0xA6, 0x8C, 0x6A, 0x8B, 0xC0, 0x55, 0x23, 0x3F, 0xD3,
+    ];
+
+    /// Encode `frame`, check it gives back `msg`, and decode it again
+    fn round_trip(frame: &DataFrame<'_>, msg: &[u8]) {
+        let mut out = [0u8; 64];
+        assert_eq!(frame.encode(&mut out), Ok(msg.len()));
+        assert_eq!(out[..msg.len()], *msg);
+        assert_eq!(DataFrame::decode(&out[..msg.len()]).as_ref(), Ok(frame));
+    }
+
+    #[test]
+    fn unconfirmed_uplink_vector() {
+        // lora-packet: "test" on FPort 1, FCnt 2
+        let phy = [
+            0x40, 0xF1, 0x7D, 0xBE, 0x49, 0x00, 0x02, 0x00, 0x01, 0x95, 0x43, 0x78, 0x76, 0x2B, 0x11, 0xFF, 0x0D,
+        ];
+        let (msg, mic) = split_mic(&phy).unwrap();
+        let frame = DataFrame::decode(msg).unwrap();
+        assert_eq!(frame.mtype, MType::UnconfirmedDataUp);
+        assert_eq!((frame.dev_addr, frame.fctrl, frame.fcnt), (0x49BE_7DF1, 0, 2));
+        assert_eq!((frame.fopts, frame.port), (&[][..], Some(1)));
+        assert_eq!(frame.payload, &[0x95, 0x43, 0x78, 0x76]);
+        assert_eq!(frame.payload_range(), 9..13);
+        assert_eq!(crypto::data_mic(&NWK_SKEY, Direction::Uplink, frame.dev_addr, 2, msg), mic);
+        round_trip(&frame, msg);
+    }
+
+    #[test]
+    fn confirmed_frames() {
+        // Confirmed uplink with ADR, FCnt 3, nothing after the FHDR
+        let phy = [0x80, 0xF1, 0x7D, 0xBE, 0x49, 0x80, 0x03, 0x00, 0xB1, 0xBD, 0x36, 0x45];
+        let (msg, mic) = split_mic(&phy).unwrap();
+        let frame = DataFrame::decode(msg).unwrap();
+        assert_eq!(frame.mtype, MType::ConfirmedDataUp);
+        assert!(frame.mtype.is_confirmed() && frame.mtype.is_uplink());
+        assert_eq!((frame.fctrl, frame.fcnt, frame.port), (FCTRL_ADR, 3, None));
+        assert_eq!(crypto::data_mic(&NWK_SKEY, Direction::Uplink, frame.dev_addr, 3, msg), mic);
+        round_trip(&frame, msg);
+
+        // Confirmed downlink with ACK, FPending, LinkCheckAns in FOpts and
+        // two bytes on FPort 5
+        let phy = [
+            0xA0, 0x34, 0x12, 0x01, 0x26, 0x33, 0x02, 0x01, 0x02, 0x14, 0x01, 0x05, 0xAA, 0xBB, 0x07, 0x47, 0xD9,
+            0x90,
+        ];
+        let (msg, mic) = split_mic(&phy).unwrap();
+        let frame = DataFrame::decode(msg).unwrap();
+        assert_eq!(frame.mtype, MType::ConfirmedDataDown);
+        assert!(!frame.mtype.is_uplink());
+        assert_eq!((frame.dev_addr, frame.fctrl, frame.fcnt), (0x2601_1234, FCTRL_ACK | FCTRL_FPENDING, 0x0102));
+        assert_eq!((frame.fopts_range(), frame.payload_range()), (8..11, 12..14));
+        assert_eq!(
+            frame.mac_commands().next(),
+            Some(Ok(MacCommand::LinkCheckAns { margin: 0x14, gateway_count: 1 }))
+        );
+        assert_eq!(crypto::data_mic(&NWK_SKEY, Direction::Downlink, frame.dev_addr, 0x0102, msg), mic);
+        round_trip(&frame, msg);
+    }
+
+    #[test]
+    fn data_frame_errors() {
+        let msg = [0x60, 0x04, 0x03, 0x02, 0x01, 0x23, 0x01, 0x00, 0x02, 0x14, 0x01, 0x05, 0xAA, 0xBB];
+        assert_eq!(DataFrame::decode(&msg[..10]), Err(FrameError::FOptsOverrun));
+        assert_eq!(DataFrame::decode(&msg[..7]), Err(FrameError::TooShort));
+        let mut port0 = msg;
+        port0[11] = 0;
+        assert_eq!(DataFrame::decode(&port0), Err(FrameError::FOptsWithPort0));
+        assert_eq!(split_mic(&[1, 2, 3]), Err(FrameError::TooShort));
+
+        let frame = DataFrame {
+            mtype: MType::UnconfirmedDataUp,
+            dev_addr: 1,
+            fctrl: 0,
+            fcnt: 0,
+            fopts: &[0; 16],
+            port: None,
+            payload: &[],
+        };
+        assert_eq!(frame.encode(&mut [0; 64]), Err(FrameError::FOptsTooLong));
+        let frame = DataFrame { fopts: &[], payload: &[1], ..frame };
+        assert_eq!(frame.encode(&mut [0; 64]), Err(FrameError::PayloadWithoutPort));
+        let frame = DataFrame { port: Some(1), ..frame };
+        assert_eq!(frame.encode(&mut [0; 9]), Err(FrameError::BufferTooSmall));
+    }
+
+    #[test]
+    fn join_request_vector() {
+        let phy = [
+            0x00, 0x04, 0x03, 0x02, 0x01, 0x04, 0x03, 0x02, 0x01, 0x05, 0x04, 0x03, 0x02, 0x05, 0x04, 0x03, 0x02,
+            0x2D, 0x10, 0x6A, 0x99, 0x0E, 0x12,
+        ];
+        let request = JoinRequest::decode(&phy).unwrap();
+        assert_eq!(request.join_eui, [1, 2, 3, 4, 1, 2, 3, 4]);
+        assert_eq!(request.dev_eui, [2, 3, 4, 5, 2, 3, 4, 5]);
+        assert_eq!(request.dev_nonce, 0x102D);
+
+        let mut out = [0u8; JOIN_REQUEST_LEN];
+        assert_eq!(request.encode(&mut out), Ok(JOIN_REQUEST_LEN - MIC_LEN));
+        assert_eq!(out[..19], phy[..19]);
+        assert_eq!(crypto::join_request_mic(&[1; 16], &out[..19]), phy[19..]);
+
+        assert_eq!(JoinRequest::decode(&phy[..22]), Err(FrameError::Length(22)));
+        let mut bad = phy;
+        bad[0] = 0x01;
+        assert_eq!(JoinRequest::decode(&bad), Err(FrameError::UnsupportedMajor(1)));
+        bad[0] = MType::UnconfirmedDataUp.mhdr();
+        assert_eq!(JoinRequest::decode(&bad), Err(FrameError::UnexpectedType(MType::UnconfirmedDataUp)));
+    }
+
+    #[test]
+    fn join_accept_with_cf_list_vector() {
+        // EU868 JoinAccept listing 867.1 to 867.9 MHz, encrypted with the
+        // RFC 4493 key
+        let app_key = [
+            0x2B, 0x7E, 0x15, 0x16, 0x28, 0xAE, 0xD2, 0xA6, 0xAB, 0xF7, 0x15, 0x88, 0x09, 0xCF, 0x4F, 0x3C,
+        ];
+        let mut phy = [
+            0x20, 0x61, 0x03, 0x39, 0x1A, 0x8B, 0x67, 0x5C, 0x23, 0x58, 0xE3, 0xA4, 0xDA, 0xF5, 0x1B, 0xB1, 0x7E,
+            0x8D, 0xE4, 0x40, 0x66, 0x05, 0x17, 0xD6, 0x86, 0xC2, 0x72, 0x87, 0x95, 0xDD, 0x98, 0x01, 0x98,
+        ];
+        assert_eq!(JoinAccept::check(&phy), Ok(()));
+        crypto::decrypt_join_accept(&app_key, &mut phy[1..]);
+
+        let accept = JoinAccept::decode(&phy).unwrap();
+        assert_eq!((accept.join_nonce, accept.net_id, accept.dev_addr), (0x563412, 0x000013, 0x2601_1234));
+        assert_eq!((accept.rx1_dr_offset(), accept.rx2_data_rate(), accept.rx_delay), (0, 3, 1));
+        assert!(!accept.opt_neg());
+        assert_eq!(
+            accept.cf_list,
+            Some([
+                0x18, 0x4F, 0x84, 0xE8, 0x56, 0x84, 0xB8, 0x5E, 0x84, 0x88, 0x66, 0x84, 0x58, 0x6E, 0x84, 0x00
+            ])
+        );
+
+        let mut out = [0u8; JOIN_ACCEPT_CF_LIST_LEN];
+        assert_eq!(accept.encode(&mut out), Ok(JOIN_ACCEPT_CF_LIST_LEN - MIC_LEN));
+        assert_eq!(out[..29], phy[..29]);
+        assert_eq!(crypto::join_accept_mic(&app_key, &out[..29]), phy[29..]);
+
+        let short = JoinAccept { cf_list: None, ..accept };
+        assert_eq!(short.encode(&mut out), Ok(JOIN_ACCEPT_LEN - MIC_LEN));
+        assert_eq!(JoinAccept::decode(&out[..JOIN_ACCEPT_LEN]), Ok(short));
+        assert_eq!(JoinAccept::check(&out[..20]), Err(FrameError::Length(20)));
+    }
+}
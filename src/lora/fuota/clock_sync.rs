@@ -0,0 +1,257 @@
+//! Application Layer Clock Synchronization (TS003)
+//!
+//! Keeps an application clock in GPS seconds on FPort 202. The device sends
+//! AppTimeReq with its own idea of the time and the server answers with a
+//! correction. Multicast sessions are scheduled against this clock.
+
+use embassy_time::{Duration, Instant};
+
+use super::AnswerWriter;
+use crate::lora::time::{NetworkTime, WallClock};
+
+/// FPort of the package
+pub const PORT: u8 = 202;
+
+const PACKAGE_ID: u8 = 1;
+const PACKAGE_VERSION: u8 = 1;
+
+const CID_PACKAGE_VERSION: u8 = 0x00;
+const CID_APP_TIME: u8 = 0x01;
+const CID_DEVICE_APP_TIME_PERIODICITY: u8 = 0x02;
+const CID_FORCE_DEVICE_RESYNC: u8 = 0x03;
+
+/// AppTimeReq: CID, DeviceTime, Param
+pub const APP_TIME_REQ_LEN: usize = 6;
+
+/// Delay between the AppTimeReqs of a forced resync
+const RESYNC_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Clock synchronization state
+pub struct ClockSync {
+    /// Application clock, uptime until the first correction
+    time: NetworkTime,
+    /// TokenReq of the next AppTimeReq
+    token: u8,
+    /// Periodic AppTimeReq interval from DeviceAppTimePeriodicityReq
+    period: Option<Duration>,
+    next_periodic: Option<Instant>,
+    /// AppTimeReqs left from ForceDeviceResyncReq
+    resync_left: u8,
+    next_resync: Instant,
+    synced: bool,
+    clock: Option<&'static dyn WallClock>,
+}
+
+impl ClockSync {
+    pub const fn new() -> Self {
+        Self {
+            time: NetworkTime {
+                at: Instant::from_ticks(0),
+                gps_ms: 0,
+            },
+            token: 0,
+            period: None,
+            next_periodic: None,
+            resync_left: 0,
+            next_resync: Instant::from_ticks(0),
+            synced: false,
+            clock: None,
+        }
+    }
+
+    /// Attach a clock to set on every AppTimeAns
+    pub fn attach_clock(&mut self, clock: &'static dyn WallClock) {
+        self.clock = Some(clock);
+    }
+
+    /// Start from the network time of a DeviceTimeAns
+    pub fn set_network_time(&mut self, time: NetworkTime) {
+        self.time = time;
+        self.synced = true;
+    }
+
+    /// Whether an AppTimeAns or DeviceTimeAns set the clock
+    pub fn is_synced(&self) -> bool {
+        self.synced
+    }
+
+    /// Application time in GPS seconds at `now`
+    pub fn gps_seconds(&self, now: Instant) -> u32 {
+        (self.time.gps_ms_at(now) / 1000) as u32
+    }
+
+    /// Application time at `now`
+    pub fn network_time(&self, now: Instant) -> NetworkTime {
+        NetworkTime {
+            at: now,
+            gps_ms: self.time.gps_ms_at(now),
+        }
+    }
+
+    /// When the next AppTimeReq is due, if any
+    pub fn next_request_at(&self) -> Option<Instant> {
+        let resync = (self.resync_left > 0).then_some(self.next_resync);
+        match (resync, self.next_periodic) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Build an AppTimeReq into `out` if one is due at `now`
+    pub fn poll(&mut self, now: Instant, out: &mut [u8]) -> usize {
+        if self.resync_left > 0 && now >= self.next_resync {
+            self.resync_left -= 1;
+            self.next_resync = now + RESYNC_INTERVAL;
+            return self.app_time_req(now, false, out);
+        }
+        match self.next_periodic {
+            Some(at) if now >= at => {
+                self.next_periodic = self.period.map(|period| now + period);
+                self.app_time_req(now, false, out)
+            }
+            _ => 0,
+        }
+    }
+
+    /// Build an AppTimeReq into `out`
+    ///
+    /// With `ans_required` the server answers even if no correction is
+    /// needed.
+    pub fn app_time_req(&mut self, now: Instant, ans_required: bool, out: &mut [u8]) -> usize {
+        let mut answer = AnswerWriter::new(out);
+        let time = self.gps_seconds(now).to_le_bytes();
+        let param = self.token | if ans_required { 0x10 } else { 0x00 };
+        answer.push(&[CID_APP_TIME, time[0], time[1], time[2], time[3], param]);
+        answer.len()
+    }
+
+    /// Process a downlink on [`PORT`], writing the answers to `out`
+    pub fn process(&mut self, now: Instant, payload: &[u8], out: &mut [u8]) -> usize {
+        let mut answer = AnswerWriter::new(out);
+        let mut rest = payload;
+        while let Some((&cid, p)) = rest.split_first() {
+            let len = match cid {
+                CID_PACKAGE_VERSION => 0,
+                CID_APP_TIME => 5,
+                CID_DEVICE_APP_TIME_PERIODICITY => 1,
+                CID_FORCE_DEVICE_RESYNC => 1,
+                _ => {
+                    defmt::warn!("Unknown clock sync command 0x{:02x}", cid);
+                    break;
+                }
+            };
+            if p.len() < len {
+                defmt::warn!("Truncated clock sync command 0x{:02x}", cid);
+                break;
+            }
+            let (p, next) = p.split_at(len);
+            rest = next;
+
+            match cid {
+                CID_PACKAGE_VERSION => {
+                    answer.push(&[CID_PACKAGE_VERSION, PACKAGE_ID, PACKAGE_VERSION]);
+                }
+                CID_APP_TIME => {
+                    let correction = i32::from_le_bytes([p[0], p[1], p[2], p[3]]);
+                    if p[4] & 0x0F != self.token {
+                        defmt::warn!("AppTimeAns for an old AppTimeReq ignored");
+                        continue;
+                    }
+                    self.apply_correction(now, correction);
+                }
+                CID_DEVICE_APP_TIME_PERIODICITY => {
+                    let period = Duration::from_secs(128 << (p[0] & 0x0F));
+                    self.period = Some(period);
+                    self.next_periodic = Some(now + period);
+                    let time = self.gps_seconds(now).to_le_bytes();
+                    answer.push(&[CID_DEVICE_APP_TIME_PERIODICITY, 0x00, time[0], time[1], time[2], time[3]]);
+                }
+                _ => {
+                    self.resync_left = p[0] & 0x07;
+                    self.next_resync = now;
+                }
+            }
+        }
+        answer.len()
+    }
+
+    fn apply_correction(&mut self, now: Instant, correction: i32) {
+        let gps_ms = self.time.gps_ms_at(now) as i64 + correction as i64 * 1000;
+        self.time = NetworkTime {
+            at: now,
+            gps_ms: gps_ms.max(0) as u64,
+        };
+        self.token = (self.token + 1) & 0x0F;
+        self.resync_left = 0;
+        self.synced = true;
+        defmt::info!("Clock corrected by {} s", correction);
+
+        if let Some(clock) = self.clock {
+            clock.set_unix_ms(self.time.unix_ms_at(now));
+        }
+    }
+}
+
+impl Default for ClockSync {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GPS_TIME: u32 = 1_400_000_000;
+
+    fn app_time_ans(correction: i32, token: u8) -> Vec<u8> {
+        let mut ans = vec![CID_APP_TIME];
+        ans.extend_from_slice(&correction.to_le_bytes());
+        ans.push(token);
+        ans
+    }
+
+    #[test]
+    fn app_time_ans_correction() {
+        let now = Instant::from_secs(10);
+        let mut sync = ClockSync::new();
+        let mut out = [0u8; 16];
+
+        // DeviceTime is the uptime until the first correction
+        let len = sync.app_time_req(now, true, &mut out);
+        assert_eq!(out[..len], [CID_APP_TIME, 10, 0, 0, 0, 0x10]);
+
+        assert_eq!(sync.process(now, &app_time_ans((GPS_TIME - 10) as i32, 0), &mut out), 0);
+        assert!(sync.is_synced());
+        assert_eq!(sync.gps_seconds(now), GPS_TIME);
+        assert_eq!(sync.gps_seconds(now + Duration::from_secs(5)), GPS_TIME + 5);
+
+        // The next request carries the next token
+        let len = sync.app_time_req(now, false, &mut out);
+        let time = GPS_TIME.to_le_bytes();
+        assert_eq!(out[..len], [CID_APP_TIME, time[0], time[1], time[2], time[3], 0x01]);
+
+        // An answer to the previous request is stale
+        sync.process(now, &app_time_ans(100, 0), &mut out);
+        assert_eq!(sync.gps_seconds(now), GPS_TIME);
+
+        let later = now + Duration::from_secs(60);
+        sync.process(later, &app_time_ans(-20, 1), &mut out);
+        assert_eq!(sync.gps_seconds(later), GPS_TIME + 60 - 20);
+    }
+
+    #[test]
+    fn force_resync_until_answered() {
+        let now = Instant::from_secs(10);
+        let mut sync = ClockSync::new();
+        let mut out = [0u8; 16];
+        sync.process(now, &[CID_FORCE_DEVICE_RESYNC, 0x03], &mut out);
+        assert_eq!(sync.next_request_at(), Some(now));
+        assert_eq!(sync.poll(now, &mut out), APP_TIME_REQ_LEN);
+        assert_eq!(sync.poll(now, &mut out), 0);
+        assert_eq!(sync.next_request_at(), Some(now + RESYNC_INTERVAL));
+
+        sync.process(now, &app_time_ans(GPS_TIME as i32, 0), &mut out);
+        assert_eq!(sync.next_request_at(), None);
+    }
+}
@@ -0,0 +1,408 @@
+//! Forward error correction for fragmented data blocks (TS004)
+//!
+//! A data block of M fragments is sent as the M uncoded fragments followed
+//! by coded fragments, each the XOR of about M/2 uncoded fragments picked by
+//! a pseudo-random parity matrix row. Any M linearly independent fragments
+//! rebuild the block.
+//!
+//! The decoder writes uncoded fragments straight to their slot in storage.
+//! Once the first coded fragment arrives, every fragment still missing is
+//! declared lost and coded fragments are reduced online (Gaussian
+//! elimination over GF(2)) against the lost fragments only. A reduced row
+//! whose leading lost fragment has no row yet is kept, and its data parked
+//! in that lost fragment's still-empty slot. When every lost fragment has a
+//! row, back-substitution leaves the original data in every slot.
+//!
+//! RAM use is one bit per fragment plus a [`MAX_LOST`] x [`MAX_LOST`] bit
+//! matrix; fragment data only ever lives in storage and a stack buffer.
+
+use crate::lora::persist::{NvError, NvStorage};
+
+/// Largest data block, in fragments
+pub const MAX_FRAGMENTS: usize = 2048;
+
+/// Largest number of lost fragments that can be recovered
+pub const MAX_LOST: usize = 128;
+
+/// Largest fragment size in bytes
+pub const MAX_FRAG_SIZE: usize = 240;
+
+const FRAG_WORDS: usize = MAX_FRAGMENTS / 32;
+const LOST_WORDS: usize = MAX_LOST / 32;
+
+/// FEC decoder errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum FecError {
+    /// The block has more than [`MAX_FRAGMENTS`] fragments or fragments
+    /// larger than [`MAX_FRAG_SIZE`]
+    Unsupported,
+    /// Fragment counter 0, or a fragment of the wrong size
+    InvalidFragment,
+    /// More than [`MAX_LOST`] fragments were lost; the block cannot be rebuilt
+    TooManyLost,
+    /// Reading or writing the block storage failed
+    Storage(NvError),
+}
+
+impl From<NvError> for FecError {
+    fn from(e: NvError) -> Self {
+        FecError::Storage(e)
+    }
+}
+
+/// Reassembles a data block in storage
+pub struct FragDecoder {
+    /// Fragments in the block (M)
+    nb_frag: usize,
+    frag_size: usize,
+    /// Storage offset of the first fragment
+    offset: usize,
+    /// Uncoded fragments written to their own slot
+    received: [u32; FRAG_WORDS],
+    nb_received: usize,
+    /// Lost fragments in ascending order, by index in the block
+    lost: [u16; MAX_LOST],
+    nb_lost: usize,
+    /// Reduced row of each lost fragment, over lost fragment positions
+    rows: [[u32; LOST_WORDS]; MAX_LOST],
+    /// Lost fragments that have a row
+    pivots: [u32; LOST_WORDS],
+    nb_pivots: usize,
+    /// A coded fragment was received, so `lost` is fixed
+    coded: bool,
+    complete: bool,
+}
+
+impl FragDecoder {
+    /// Decoder for `nb_frag` fragments of `frag_size` bytes stored from `offset`
+    pub fn new(nb_frag: usize, frag_size: usize, offset: usize) -> Result<Self, FecError> {
+        if nb_frag == 0 || nb_frag > MAX_FRAGMENTS || frag_size == 0 || frag_size > MAX_FRAG_SIZE {
+            return Err(FecError::Unsupported);
+        }
+        Ok(Self {
+            nb_frag,
+            frag_size,
+            offset,
+            received: [0; FRAG_WORDS],
+            nb_received: 0,
+            lost: [0; MAX_LOST],
+            nb_lost: 0,
+            rows: [[0; LOST_WORDS]; MAX_LOST],
+            pivots: [0; LOST_WORDS],
+            nb_pivots: 0,
+            coded: false,
+            complete: false,
+        })
+    }
+
+    /// Fragments in the block
+    pub fn nb_frag(&self) -> usize {
+        self.nb_frag
+    }
+
+    /// Fragment size in bytes
+    pub fn frag_size(&self) -> usize {
+        self.frag_size
+    }
+
+    /// Uncoded fragments received so far
+    pub fn nb_received(&self) -> usize {
+        self.nb_received
+    }
+
+    /// Fragments still needed to rebuild the block
+    pub fn missing(&self) -> usize {
+        if self.complete {
+            0
+        } else if self.coded {
+            self.nb_lost - self.nb_pivots
+        } else {
+            self.nb_frag - self.nb_received
+        }
+    }
+
+    /// Whether the whole block is in storage
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Process fragment `counter` (1-based; above M for coded fragments)
+    ///
+    /// Returns whether the block is complete. Duplicates and coded
+    /// fragments that add no information are ignored.
+    pub fn process(
+        &mut self,
+        storage: &mut dyn NvStorage,
+        counter: u16,
+        data: &[u8],
+    ) -> Result<bool, FecError> {
+        if self.complete {
+            return Ok(true);
+        }
+        if counter == 0 || data.len() != self.frag_size {
+            return Err(FecError::InvalidFragment);
+        }
+
+        let mut buf = [0u8; MAX_FRAG_SIZE];
+        let buf = &mut buf[..self.frag_size];
+        buf.copy_from_slice(data);
+        let mut row = [0u32; LOST_WORDS];
+
+        let index = counter as usize - 1;
+        if index < self.nb_frag {
+            if get(&self.received, index) {
+                return Ok(false);
+            }
+            match self.lost_position(index) {
+                Some(position) => set(&mut row, position),
+                None => {
+                    storage.write(self.slot(index), buf)?;
+                    set(&mut self.received, index);
+                    self.nb_received += 1;
+                    if self.nb_received == self.nb_frag {
+                        self.complete = true;
+                    }
+                    return Ok(self.complete);
+                }
+            }
+        } else {
+            if !self.coded {
+                self.declare_lost()?;
+            }
+            let mut parity = [0u32; FRAG_WORDS];
+            parity_row(counter as usize - self.nb_frag, self.nb_frag, &mut parity);
+
+            let mut other = [0u8; MAX_FRAG_SIZE];
+            let other = &mut other[..self.frag_size];
+            for fragment in (0..self.nb_frag).filter(|&i| get(&parity, i)) {
+                if get(&self.received, fragment) {
+                    storage.read(self.slot(fragment), other)?;
+                    xor(buf, other);
+                } else if let Some(position) = self.lost_position(fragment) {
+                    set(&mut row, position);
+                }
+            }
+        }
+
+        self.insert(storage, row, buf)?;
+        if self.nb_pivots == self.nb_lost {
+            self.solve(storage)?;
+        }
+        Ok(self.complete)
+    }
+
+    /// Fix the set of lost fragments when coded fragments start
+    fn declare_lost(&mut self) -> Result<(), FecError> {
+        self.coded = true;
+        for index in (0..self.nb_frag).filter(|&i| !get(&self.received, i)) {
+            if self.nb_lost == MAX_LOST {
+                return Err(FecError::TooManyLost);
+            }
+            self.lost[self.nb_lost] = index as u16;
+            self.nb_lost += 1;
+        }
+        Ok(())
+    }
+
+    /// Reduce `row` against the stored rows and keep it if anything is left
+    fn insert(
+        &mut self,
+        storage: &mut dyn NvStorage,
+        mut row: [u32; LOST_WORDS],
+        buf: &mut [u8],
+    ) -> Result<(), FecError> {
+        let mut other = [0u8; MAX_FRAG_SIZE];
+        let other = &mut other[..self.frag_size];
+        while let Some(position) = lowest(&row, self.nb_lost) {
+            let slot = self.slot(self.lost[position] as usize);
+            if !get(&self.pivots, position) {
+                self.rows[position] = row;
+                set(&mut self.pivots, position);
+                self.nb_pivots += 1;
+                storage.write(slot, buf)?;
+                return Ok(());
+            }
+            for (word, pivot) in row.iter_mut().zip(self.rows[position].iter()) {
+                *word ^= pivot;
+            }
+            storage.read(slot, other)?;
+            xor(buf, other);
+        }
+        Ok(())
+    }
+
+    /// Back-substitute from the last lost fragment to the first
+    fn solve(&mut self, storage: &mut dyn NvStorage) -> Result<(), FecError> {
+        let mut buf = [0u8; MAX_FRAG_SIZE];
+        let buf = &mut buf[..self.frag_size];
+        let mut other = [0u8; MAX_FRAG_SIZE];
+        let other = &mut other[..self.frag_size];
+
+        for position in (0..self.nb_lost).rev() {
+            let slot = self.slot(self.lost[position] as usize);
+            storage.read(slot, buf)?;
+            for later in (position + 1..self.nb_lost).filter(|&q| get(&self.rows[position], q)) {
+                storage.read(self.slot(self.lost[later] as usize), other)?;
+                xor(buf, other);
+            }
+            storage.write(slot, buf)?;
+        }
+        self.complete = true;
+        Ok(())
+    }
+
+    fn lost_position(&self, index: usize) -> Option<usize> {
+        self.lost[..self.nb_lost].binary_search(&(index as u16)).ok()
+    }
+
+    fn slot(&self, index: usize) -> usize {
+        self.offset + index * self.frag_size
+    }
+}
+
+/// Parity matrix row `n` (1-based coded fragment number) for a block of `m`
+/// fragments, as a bit set over the uncoded fragments
+///
+/// This is the generator from TS004, so a sender can use it to build coded
+/// fragments.
+pub fn parity_row(n: usize, m: usize, row: &mut [u32]) {
+    row.fill(0);
+    let m_temp = if m.is_power_of_two() { 1 } else { 0 };
+    let mut x = 1 + 1001 * n as u32;
+    for _ in 0..m / 2 {
+        let mut r = m;
+        while r >= m {
+            x = prbs23(x);
+            r = x as usize % (m + m_temp);
+        }
+        set(row, r);
+    }
+}
+
+/// One step of the 23-bit PRBS of TS004
+fn prbs23(x: u32) -> u32 {
+    let b0 = x & 1;
+    let b1 = (x >> 5) & 1;
+    (x >> 1) + ((b0 ^ b1) << 22)
+}
+
+fn xor(buf: &mut [u8], other: &[u8]) {
+    for (b, o) in buf.iter_mut().zip(other) {
+        *b ^= o;
+    }
+}
+
+fn get(bits: &[u32], i: usize) -> bool {
+    bits[i / 32] & (1 << (i % 32)) != 0
+}
+
+fn set(bits: &mut [u32], i: usize) {
+    bits[i / 32] |= 1 << (i % 32);
+}
+
+/// Lowest set bit below `len`
+fn lowest(bits: &[u32], len: usize) -> Option<usize> {
+    bits.iter()
+        .enumerate()
+        .find(|(_, w)| **w != 0)
+        .map(|(i, w)| i * 32 + w.trailing_zeros() as usize)
+        .filter(|&i| i < len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFSET: usize = 16;
+
+    struct Memory(Vec<u8>);
+
+    impl NvStorage for Memory {
+        fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), NvError> {
+            let src = self.0.get(offset..offset + buf.len()).ok_or(NvError::OutOfRange)?;
+            buf.copy_from_slice(src);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), NvError> {
+            let dest = self.0.get_mut(offset..offset + data.len()).ok_or(NvError::OutOfRange)?;
+            dest.copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    /// xorshift32, enough to pick data and lost fragments
+    fn next(seed: &mut u32) -> u32 {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 17;
+        *seed ^= *seed << 5;
+        *seed
+    }
+
+    /// Coded fragment `n` of `block`, built the way a sender would
+    fn encode(block: &[u8], m: usize, frag_size: usize, n: usize) -> Vec<u8> {
+        let mut parity = [0u32; FRAG_WORDS];
+        parity_row(n, m, &mut parity);
+        let mut coded = vec![0u8; frag_size];
+        for fragment in (0..m).filter(|&i| get(&parity, i)) {
+            xor(&mut coded, &block[fragment * frag_size..][..frag_size]);
+        }
+        coded
+    }
+
+    /// Send a block of `m` fragments losing `nb_lost` random uncoded ones,
+    /// then coded fragments until the decoder is done
+    fn transfer(m: usize, frag_size: usize, nb_lost: usize, mut seed: u32) -> Result<(), FecError> {
+        let block: Vec<u8> = (0..m * frag_size).map(|_| next(&mut seed) as u8).collect();
+        let mut lost = vec![false; m];
+        let mut remaining = nb_lost;
+        while remaining > 0 {
+            let i = next(&mut seed) as usize % m;
+            if !lost[i] {
+                lost[i] = true;
+                remaining -= 1;
+            }
+        }
+
+        let mut storage = Memory(vec![0xFF; OFFSET + m * frag_size]);
+        let mut decoder = FragDecoder::new(m, frag_size, OFFSET)?;
+        for i in (0..m).filter(|&i| !lost[i]) {
+            decoder.process(&mut storage, i as u16 + 1, &block[i * frag_size..][..frag_size])?;
+        }
+        for n in 1..=m {
+            let coded = encode(&block, m, frag_size, n);
+            if decoder.process(&mut storage, (m + n) as u16, &coded)? {
+                break;
+            }
+        }
+        assert!(decoder.is_complete(), "{} lost fragments not recovered", nb_lost);
+        assert_eq!(storage.0[..OFFSET], [0xFF; OFFSET]);
+        assert_eq!(storage.0[OFFSET..], block[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn parity_row_matches_reference_generator() {
+        let mut row = [0u32; 2];
+        parity_row(1, 40, &mut row);
+        assert_eq!(row, [0x8341_812C, 0x81]);
+        parity_row(3, 16, &mut row);
+        assert_eq!(row, [0x3507, 0]);
+    }
+
+    #[test]
+    fn recovers_lost_fragments() {
+        for (seed, nb_lost) in [(1, 0), (2, 1), (3, 7), (4, 20)] {
+            assert!(transfer(40, 8, nb_lost, seed).is_ok());
+        }
+        for seed in [5, 6] {
+            assert!(transfer(300, 4, MAX_LOST / 2, seed).is_ok());
+            assert!(transfer(300, 4, MAX_LOST, seed).is_ok());
+        }
+    }
+
+    #[test]
+    fn too_many_lost() {
+        assert_eq!(transfer(300, 4, MAX_LOST + 1, 7).err(), Some(FecError::TooManyLost));
+    }
+}
@@ -0,0 +1,468 @@
+//! Fragmented Data Block Transport (TS004)
+//!
+//! Receives a firmware image as numbered fragments on FPort 201, uncoded
+//! and coded, and rebuilds it with the [`FragDecoder`]. The image lands in a
+//! storage region laid out for the bootloader:
+//!
+//! - `0..IMAGE_HEADER_LEN`: [`ImageHeader`], written once the image is
+//!   complete and cleared when a new session starts
+//! - `IMAGE_HEADER_LEN..`: the image
+//!
+//! One fragmentation session (FragIndex 0) is supported at a time.
+
+use embassy_time::{Duration, Instant};
+
+use super::fec::{FecError, FragDecoder, MAX_FRAGMENTS, MAX_FRAG_SIZE};
+use super::AnswerWriter;
+use crate::lora::persist::{NvError, NvStorage};
+
+/// FPort of the package
+pub const PORT: u8 = 201;
+
+/// Space reserved for the header at the start of the region
+pub const IMAGE_HEADER_LEN: usize = 128;
+
+/// Header marker of a complete image
+pub const IMAGE_MAGIC: [u8; 4] = *b"AEFW";
+
+const PACKAGE_ID: u8 = 3;
+const PACKAGE_VERSION: u8 = 1;
+
+const CID_PACKAGE_VERSION: u8 = 0x00;
+const CID_FRAG_SESSION_STATUS: u8 = 0x01;
+const CID_FRAG_SESSION_SETUP: u8 = 0x02;
+const CID_FRAG_SESSION_DELETE: u8 = 0x03;
+const CID_DATA_FRAGMENT: u8 = 0x08;
+
+const SETUP_ENCODING_UNSUPPORTED: u8 = 0x01;
+const SETUP_NOT_ENOUGH_MEMORY: u8 = 0x02;
+const SETUP_INDEX_UNSUPPORTED: u8 = 0x04;
+const DELETE_NO_SESSION: u8 = 0x04;
+const STATUS_NOT_ENOUGH_MATRIX_MEMORY: u8 = 0x01;
+
+/// FragSessionStatusAns: CID, ReceivedAndIndex, MissingFrag, Status
+const STATUS_ANS_LEN: usize = 5;
+
+/// Header of a complete image, for the bootloader
+///
+/// Little endian: magic, image size, CRC-32 (IEEE) of the image, and the
+/// Descriptor field of FragSessionSetupReq.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct ImageHeader {
+    /// Image size in bytes, padding removed
+    pub size: u32,
+    /// CRC-32 of the image
+    pub crc: u32,
+    /// Descriptor from the server, e.g. the firmware version
+    pub descriptor: u32,
+}
+
+impl ImageHeader {
+    /// Read the header of a complete image, `None` if there is none
+    pub fn load(storage: &mut dyn NvStorage) -> Result<Option<Self>, NvError> {
+        let mut b = [0u8; 16];
+        storage.read(0, &mut b)?;
+        if b[0..4] != IMAGE_MAGIC {
+            return Ok(None);
+        }
+        let word = |i: usize| u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+        Ok(Some(Self {
+            size: word(4),
+            crc: word(8),
+            descriptor: word(12),
+        }))
+    }
+
+    fn save(&self, storage: &mut dyn NvStorage) -> Result<(), NvError> {
+        let mut b = [0u8; 16];
+        b[0..4].copy_from_slice(&IMAGE_MAGIC);
+        b[4..8].copy_from_slice(&self.size.to_le_bytes());
+        b[8..12].copy_from_slice(&self.crc.to_le_bytes());
+        b[12..16].copy_from_slice(&self.descriptor.to_le_bytes());
+        storage.write(0, &b)
+    }
+
+    /// Mark the region as holding no image
+    pub fn erase(storage: &mut dyn NvStorage) -> Result<(), NvError> {
+        storage.write(0, &[0u8; 4])
+    }
+}
+
+/// Parameters of a FragSessionSetupReq
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct FragSession {
+    /// Multicast groups the fragments may arrive on
+    pub mc_group_mask: u8,
+    pub nb_frag: u16,
+    pub frag_size: u8,
+    /// FragSessionStatusAns is sent after a random delay up to 2^(n+4) s
+    pub block_ack_delay: u8,
+    /// Bytes appended to the image to fill the last fragment
+    pub padding: u8,
+    pub descriptor: u32,
+}
+
+/// Fragmentation session state
+pub struct Fragmentation {
+    storage: &'static mut dyn NvStorage,
+    /// Usable size of the storage region
+    capacity: usize,
+    session: Option<FragSession>,
+    decoder: Option<FragDecoder>,
+    /// The decoder gave up; answered in FragSessionStatusAns
+    failed: bool,
+    /// Deferred FragSessionStatusAns and when to send it
+    status_due: Option<Instant>,
+    complete: Option<ImageHeader>,
+}
+
+impl Fragmentation {
+    /// Package state writing images to `storage`, a region of `capacity` bytes
+    pub fn new(storage: &'static mut dyn NvStorage, capacity: usize) -> Self {
+        Self {
+            storage,
+            capacity,
+            session: None,
+            decoder: None,
+            failed: false,
+            status_due: None,
+            complete: None,
+        }
+    }
+
+    /// Current session parameters
+    pub fn session(&self) -> Option<&FragSession> {
+        self.session.as_ref()
+    }
+
+    /// Header of the image completed in this session, if any
+    pub fn completed(&self) -> Option<ImageHeader> {
+        self.complete
+    }
+
+    /// When the deferred FragSessionStatusAns is due, if any
+    pub fn next_answer_at(&self) -> Option<Instant> {
+        self.status_due
+    }
+
+    /// Build the deferred FragSessionStatusAns into `out` if it is due at `now`
+    pub fn poll(&mut self, now: Instant, out: &mut [u8]) -> usize {
+        match self.status_due {
+            Some(at) if now >= at => {
+                self.status_due = None;
+                let mut answer = AnswerWriter::new(out);
+                self.status_answer(&mut answer);
+                answer.len()
+            }
+            _ => 0,
+        }
+    }
+
+    /// Process a downlink on [`PORT`], writing the answers to `out`
+    pub fn process(&mut self, now: Instant, payload: &[u8], out: &mut [u8]) -> usize {
+        let mut answer = AnswerWriter::new(out);
+        let mut rest = payload;
+        while let Some((&cid, p)) = rest.split_first() {
+            let len = match cid {
+                CID_PACKAGE_VERSION => 0,
+                CID_FRAG_SESSION_STATUS => 1,
+                CID_FRAG_SESSION_SETUP => 10,
+                CID_FRAG_SESSION_DELETE => 1,
+                // The fragment takes the rest of the frame
+                CID_DATA_FRAGMENT => p.len(),
+                _ => {
+                    defmt::warn!("Unknown fragmentation command 0x{:02x}", cid);
+                    break;
+                }
+            };
+            if p.len() < len {
+                defmt::warn!("Truncated fragmentation command 0x{:02x}", cid);
+                break;
+            }
+            let (p, next) = p.split_at(len);
+            rest = next;
+
+            match cid {
+                CID_PACKAGE_VERSION => {
+                    answer.push(&[CID_PACKAGE_VERSION, PACKAGE_ID, PACKAGE_VERSION]);
+                }
+                CID_FRAG_SESSION_STATUS => self.status_request(now, p[0]),
+                CID_FRAG_SESSION_SETUP => {
+                    let status = self.setup(p);
+                    answer.push(&[CID_FRAG_SESSION_SETUP, status]);
+                }
+                CID_FRAG_SESSION_DELETE => {
+                    let index = p[0] & 0x03;
+                    let status = if index == 0 && self.session.take().is_some() {
+                        self.decoder = None;
+                        self.status_due = None;
+                        index
+                    } else {
+                        index | DELETE_NO_SESSION
+                    };
+                    answer.push(&[CID_FRAG_SESSION_DELETE, status]);
+                }
+                _ => self.data_fragment(p),
+            }
+        }
+        answer.len()
+    }
+
+    /// FragSessionSetupReq: returns the StatusBitMask of the answer
+    fn setup(&mut self, p: &[u8]) -> u8 {
+        let index = (p[0] >> 4) & 0x03;
+        let session = FragSession {
+            mc_group_mask: p[0] & 0x0F,
+            nb_frag: u16::from_le_bytes([p[1], p[2]]),
+            frag_size: p[3],
+            block_ack_delay: p[4] & 0x07,
+            padding: p[5],
+            descriptor: u32::from_le_bytes([p[6], p[7], p[8], p[9]]),
+        };
+        let algorithm = (p[4] >> 3) & 0x07;
+
+        let mut status = index << 6;
+        if algorithm != 0 {
+            status |= SETUP_ENCODING_UNSUPPORTED;
+        }
+        let image_len = session.nb_frag as usize * session.frag_size as usize;
+        if session.nb_frag as usize > MAX_FRAGMENTS
+            || session.frag_size as usize > MAX_FRAG_SIZE
+            || IMAGE_HEADER_LEN + image_len > self.capacity
+        {
+            status |= SETUP_NOT_ENOUGH_MEMORY;
+        }
+        if index != 0 {
+            status |= SETUP_INDEX_UNSUPPORTED;
+        }
+        if status != index << 6 {
+            return status;
+        }
+
+        let nb_frag = session.nb_frag as usize;
+        let decoder = match FragDecoder::new(nb_frag, session.frag_size as usize, IMAGE_HEADER_LEN) {
+            Ok(decoder) => decoder,
+            Err(_) => return status | SETUP_NOT_ENOUGH_MEMORY,
+        };
+        if let Err(e) = ImageHeader::erase(self.storage) {
+            defmt::warn!("Failed to clear the image header: {}", e);
+            return status | SETUP_NOT_ENOUGH_MEMORY;
+        }
+
+        defmt::info!("Fragmentation session: {}", session);
+        self.session = Some(session);
+        self.decoder = Some(decoder);
+        self.failed = false;
+        self.status_due = None;
+        self.complete = None;
+        status
+    }
+
+    /// FragSessionStatusReq: schedule the answer after the BlockAckDelay
+    fn status_request(&mut self, now: Instant, param: u8) {
+        let all = param & 0x01 != 0;
+        let index = (param >> 1) & 0x03;
+        let (Some(session), Some(decoder)) = (self.session.as_ref(), self.decoder.as_ref()) else {
+            return;
+        };
+        if index != 0 || (!all && decoder.missing() == 0) {
+            return;
+        }
+        // Spread the answers of a multicast group over the window
+        let window_ms = 1000u64 << (session.block_ack_delay + 4);
+        let random = (now.as_ticks() ^ (now.as_ticks() >> 7)).wrapping_mul(2_654_435_761);
+        self.status_due = Some(now + Duration::from_millis(random % window_ms));
+    }
+
+    fn status_answer(&self, answer: &mut AnswerWriter<'_>) {
+        let (received, missing) = match self.decoder.as_ref() {
+            Some(decoder) => (decoder.nb_received(), decoder.missing()),
+            None => (0, 0),
+        };
+        let received = (received as u16 & 0x3FFF).to_le_bytes();
+        let status = if self.failed { STATUS_NOT_ENOUGH_MATRIX_MEMORY } else { 0 };
+        let ans: [u8; STATUS_ANS_LEN] =
+            [CID_FRAG_SESSION_STATUS, received[0], received[1], missing.min(255) as u8, status];
+        answer.push(&ans);
+    }
+
+    fn data_fragment(&mut self, p: &[u8]) {
+        if p.len() < 2 {
+            return;
+        }
+        let index_and_n = u16::from_le_bytes([p[0], p[1]]);
+        let (index, n) = (index_and_n >> 14, index_and_n & 0x3FFF);
+        let (Some(decoder), false) = (self.decoder.as_mut(), self.failed) else {
+            return;
+        };
+        if index != 0 || decoder.is_complete() {
+            return;
+        }
+
+        match decoder.process(self.storage, n, &p[2..]) {
+            Ok(true) => self.finish(),
+            Ok(false) => {}
+            Err(FecError::InvalidFragment) => defmt::warn!("Invalid fragment {} ignored", n),
+            Err(e) => {
+                defmt::warn!("Fragmentation session failed: {}", e);
+                self.failed = true;
+            }
+        }
+    }
+
+    /// Check the rebuilt image and write its header
+    fn finish(&mut self) {
+        let Some(session) = self.session else {
+            return;
+        };
+        let size = (session.nb_frag as usize * session.frag_size as usize)
+            .saturating_sub(session.padding as usize);
+
+        let mut crc = Crc32::new();
+        let mut chunk = [0u8; 64];
+        let mut offset = 0;
+        while offset < size {
+            let len = chunk.len().min(size - offset);
+            if self.storage.read(IMAGE_HEADER_LEN + offset, &mut chunk[..len]).is_err() {
+                self.failed = true;
+                return;
+            }
+            crc.update(&chunk[..len]);
+            offset += len;
+        }
+
+        let header = ImageHeader {
+            size: size as u32,
+            crc: crc.finish(),
+            descriptor: session.descriptor,
+        };
+        if let Err(e) = header.save(self.storage) {
+            defmt::warn!("Failed to write the image header: {}", e);
+            self.failed = true;
+            return;
+        }
+        defmt::info!("Image complete: {}", header);
+        self.complete = Some(header);
+    }
+}
+
+/// CRC-32 (IEEE 802.3), as checked by the bootloader
+struct Crc32(u32);
+
+impl Crc32 {
+    fn new() -> Self {
+        Self(0xFFFF_FFFF)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 ^= byte as u32;
+            for _ in 0..8 {
+                self.0 = if self.0 & 1 != 0 { (self.0 >> 1) ^ 0xEDB8_8320 } else { self.0 >> 1 };
+            }
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lora::persist::tests::Memory;
+
+    const NB_FRAG: u16 = 4;
+    const FRAG_SIZE: u8 = 8;
+
+    /// A session with room for [`NB_FRAG`] fragments of [`FRAG_SIZE`] bytes
+    fn fragmentation() -> Fragmentation {
+        let capacity = IMAGE_HEADER_LEN + NB_FRAG as usize * FRAG_SIZE as usize;
+        Fragmentation::new(Box::leak(Box::new(Memory::blank(capacity))), capacity)
+    }
+
+    fn setup_req(param: u8, nb_frag: u16, control: u8) -> Vec<u8> {
+        let mut req = vec![CID_FRAG_SESSION_SETUP, param];
+        req.extend_from_slice(&nb_frag.to_le_bytes());
+        req.extend_from_slice(&[FRAG_SIZE, control, 3]);
+        req.extend_from_slice(&0x0102_0304u32.to_le_bytes());
+        req
+    }
+
+    /// DataFragment `n` of FragIndex 0; the image is the bytes 0, 1, 2, ...
+    fn fragment(n: u16) -> Vec<u8> {
+        let mut req = vec![CID_DATA_FRAGMENT];
+        req.extend_from_slice(&n.to_le_bytes());
+        req.extend((0..FRAG_SIZE).map(|i| (n - 1) as u8 * FRAG_SIZE + i));
+        req
+    }
+
+    fn answer(fragmentation: &mut Fragmentation, now: Instant, payload: &[u8]) -> Vec<u8> {
+        let mut out = [0u8; 16];
+        let len = fragmentation.process(now, payload, &mut out);
+        out[..len].to_vec()
+    }
+
+    /// Send the deferred FragSessionStatusAns
+    fn status_answer(fragmentation: &mut Fragmentation) -> Vec<u8> {
+        let at = fragmentation.next_answer_at().unwrap();
+        let mut out = [0u8; 16];
+        let len = fragmentation.poll(at, &mut out);
+        assert!(fragmentation.next_answer_at().is_none());
+        out[..len].to_vec()
+    }
+
+    #[test]
+    fn setup_status_bits() {
+        let now = Instant::from_secs(100);
+        let mut fragmentation = fragmentation();
+        assert_eq!(answer(&mut fragmentation, now, &setup_req(0x01, NB_FRAG, 0x00)), [0x02, 0x00]);
+        let session = *fragmentation.session().unwrap();
+        assert_eq!((session.mc_group_mask, session.nb_frag, session.padding), (0x01, NB_FRAG, 3));
+        assert_eq!(session.descriptor, 0x0102_0304);
+
+        // FragIndex 1 is echoed in bits 6-7
+        assert_eq!(answer(&mut fragmentation, now, &setup_req(0x11, NB_FRAG, 0x00)), [0x02, 0x40 | 0x04]);
+        // FragAlgo 1
+        assert_eq!(answer(&mut fragmentation, now, &setup_req(0x01, NB_FRAG, 0x08)), [0x02, 0x01]);
+        // One fragment more than the storage holds
+        assert_eq!(answer(&mut fragmentation, now, &setup_req(0x01, NB_FRAG + 1, 0x00)), [0x02, 0x02]);
+        assert_eq!(answer(&mut fragmentation, now, &setup_req(0x21, NB_FRAG + 1, 0x08)), [0x02, 0x80 | 0x07]);
+
+        // Refused setups leave the session alone
+        assert_eq!(fragmentation.session(), Some(&session));
+    }
+
+    #[test]
+    fn session_status_answer() {
+        let now = Instant::from_secs(100);
+        let mut fragmentation = fragmentation();
+        answer(&mut fragmentation, now, &setup_req(0x01, NB_FRAG, 0x02));
+        answer(&mut fragmentation, now, &fragment(1));
+        answer(&mut fragmentation, now, &fragment(3));
+
+        // Answered after a random delay under 2^(BlockAckDelay + 4) s
+        assert!(answer(&mut fragmentation, now, &[CID_FRAG_SESSION_STATUS, 0x00]).is_empty());
+        let at = fragmentation.next_answer_at().unwrap();
+        assert!(at >= now && at < now + Duration::from_secs(64));
+        // ReceivedAndIndex 2, MissingFrag 2, Status 0
+        assert_eq!(status_answer(&mut fragmentation), [0x01, 0x02, 0x00, 0x02, 0x00]);
+
+        answer(&mut fragmentation, now, &fragment(2));
+        answer(&mut fragmentation, now, &fragment(4));
+        let header = fragmentation.completed().unwrap();
+        // CRC-32 of the bytes 0..29, the last fragment's 3 padding bytes removed
+        assert_eq!(header, ImageHeader { size: 29, crc: 0xD30E_9683, descriptor: 0x0102_0304 });
+
+        // Without the "all" bit only devices still missing fragments answer
+        answer(&mut fragmentation, now, &[CID_FRAG_SESSION_STATUS, 0x00]);
+        assert!(fragmentation.next_answer_at().is_none());
+        answer(&mut fragmentation, now, &[CID_FRAG_SESSION_STATUS, 0x01]);
+        assert_eq!(status_answer(&mut fragmentation), [0x01, 0x04, 0x00, 0x00, 0x00]);
+
+        // Another FragIndex gets no answer
+        answer(&mut fragmentation, now, &[CID_FRAG_SESSION_STATUS, 0x03]);
+        assert!(fragmentation.next_answer_at().is_none());
+    }
+}
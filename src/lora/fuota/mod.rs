@@ -0,0 +1,204 @@
+//! Firmware updates over the air
+//!
+//! The LoRaWAN application-layer packages used for FUOTA, each on its own
+//! FPort:
+//!
+//! - [`clock_sync`]: Application Layer Clock Synchronization (TS003)
+//! - [`multicast_setup`]: Remote Multicast Setup (TS005)
+//! - [`fragmentation`]: Fragmented Data Block Transport (TS004), with the
+//!   forward error correction in [`fec`]
+//!
+//! The packages are plain state machines: they take downlink payloads and
+//! write the answers to send on the same FPort. [`fuota_task`] wires them to
+//! the [`LoRaWANChannels`] of the LoRaWAN task:
+//!
+//! ```ignore
+//! static IMAGE: StaticCell<ImageFlash> = StaticCell::new();
+//! static FUOTA: StaticCell<Fuota> = StaticCell::new();
+//!
+//! let image = IMAGE.init(board.image_flash);
+//! let fuota = FUOTA.init(Fuota::new(
+//!     MulticastSetup::new(Region::EU868, crypto::mc_root_key_v1_1(&APP_KEY)),
+//!     Fragmentation::new(image, IMAGE_REGION_SIZE),
+//! ));
+//! spawner.spawn(fuota_task(fuota, &LORAWAN)).unwrap();
+//! ```
+
+pub mod clock_sync;
+pub mod fec;
+pub mod fragmentation;
+pub mod multicast_setup;
+
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Instant, Timer};
+
+use self::clock_sync::ClockSync;
+use self::fragmentation::{Fragmentation, ImageHeader};
+use self::multicast_setup::{MulticastSetup, SessionEvent, MAX_MC_GROUPS};
+use super::task::LoRaWANChannels;
+
+/// Largest answer to one downlink
+pub const MAX_ANSWER_LEN: usize = 64;
+
+/// How long the task sleeps when no package has anything scheduled
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Appends package answers to an uplink buffer, dropping what does not fit
+pub(crate) struct AnswerWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> AnswerWriter<'a> {
+    pub(crate) fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, len: 0 }
+    }
+
+    pub(crate) fn push(&mut self, answer: &[u8]) -> bool {
+        let Some(dest) = self.buf.get_mut(self.len..self.len + answer.len()) else {
+            defmt::warn!("Package answer dropped, uplink full");
+            return false;
+        };
+        dest.copy_from_slice(answer);
+        self.len += answer.len();
+        true
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+}
+
+/// The FUOTA packages of one device
+pub struct Fuota {
+    pub clock_sync: ClockSync,
+    pub multicast: MulticastSetup,
+    pub fragmentation: Fragmentation,
+}
+
+impl Fuota {
+    pub fn new(multicast: MulticastSetup, fragmentation: Fragmentation) -> Self {
+        Self {
+            clock_sync: ClockSync::new(),
+            multicast,
+            fragmentation,
+        }
+    }
+
+    /// Dispatch a downlink to its package
+    ///
+    /// Returns the number of answer bytes written to `out`, to be sent on
+    /// the same FPort; 0 if there is nothing to send or the port belongs to
+    /// no package.
+    pub fn process(&mut self, now: Instant, port: u8, payload: &[u8], out: &mut [u8]) -> usize {
+        match port {
+            clock_sync::PORT => self.clock_sync.process(now, payload, out),
+            multicast_setup::PORT => {
+                let gps = self.clock_sync.gps_seconds(now);
+                self.multicast.process(gps, payload, out)
+            }
+            fragmentation::PORT => self.fragmentation.process(now, payload, out),
+            _ => 0,
+        }
+    }
+
+    /// Header of the image received in this session, once complete
+    pub fn completed_image(&self) -> Option<ImageHeader> {
+        self.fragmentation.completed()
+    }
+
+    /// Earliest time a package wants to send something unprompted, or a
+    /// multicast session starts or ends
+    fn next_wakeup(&self, now: Instant) -> Option<Instant> {
+        let gps = self.clock_sync.gps_seconds(now);
+        let session = self
+            .multicast
+            .next_event()
+            .map(|at| now + Duration::from_secs(at.saturating_sub(gps).into()));
+        [self.clock_sync.next_request_at(), self.fragmentation.next_answer_at(), session]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+}
+
+/// Background task running the FUOTA packages
+///
+/// Answers downlinks on the package ports, passes multicast groups to the
+/// LoRaWAN task and switches it to Class C for their sessions, sends
+/// scheduled AppTimeReqs and FragSessionStatusAns, and logs when an image
+/// is complete. Applying the image (rebooting into the bootloader) is left
+/// to the application, which can check [`Fuota::completed_image`].
+#[embassy_executor::task]
+pub async fn fuota_task(fuota: &'static mut Fuota, channels: &'static LoRaWANChannels) {
+    let mut handler = match channels.subscribe(None) {
+        Ok(handler) => handler,
+        Err(e) => {
+            defmt::error!("FUOTA task has no downlink handler: {:?}", defmt::Debug2Format(&e));
+            return;
+        }
+    };
+    defmt::info!("FUOTA task started");
+
+    // Ask for the time right away so multicast sessions can be scheduled
+    let mut out = [0u8; MAX_ANSWER_LEN];
+    let len = fuota.clock_sync.app_time_req(Instant::now(), true, &mut out);
+    send(channels, clock_sync::PORT, &out[..len]).await;
+
+    loop {
+        let now = Instant::now();
+        let wakeup = fuota.next_wakeup(now).unwrap_or(now + IDLE_POLL_INTERVAL);
+
+        match select(handler.next(), Timer::at(wakeup)).await {
+            Either::First(downlink) => {
+                let Some(port) = downlink.port else {
+                    continue;
+                };
+                let had_image = fuota.completed_image().is_some();
+                let len = fuota.process(Instant::now(), port, downlink.payload(), &mut out);
+                send(channels, port, &out[..len]).await;
+
+                // Hand new or deleted multicast groups to the stack
+                let changes = fuota.multicast.take_changes();
+                for id in (0..MAX_MC_GROUPS as u8).filter(|id| changes & (1 << id) != 0) {
+                    let group = fuota.multicast.group(id).map(Into::into);
+                    channels.set_multicast_group(id, group).await;
+                }
+                update_session(fuota, channels, Instant::now()).await;
+                if !had_image && fuota.completed_image().is_some() {
+                    defmt::info!("Firmware image ready for the bootloader");
+                }
+            }
+            Either::Second(()) => {
+                let now = Instant::now();
+                let len = fuota.clock_sync.poll(now, &mut out);
+                send(channels, clock_sync::PORT, &out[..len]).await;
+                let len = fuota.fragmentation.poll(now, &mut out);
+                send(channels, fragmentation::PORT, &out[..len]).await;
+                update_session(fuota, channels, now).await;
+            }
+        }
+    }
+}
+
+/// Start or end multicast sessions that are due
+async fn update_session(fuota: &mut Fuota, channels: &LoRaWANChannels, now: Instant) {
+    let gps = fuota.clock_sync.gps_seconds(now);
+    while let Some(event) = fuota.multicast.poll(gps) {
+        match event {
+            SessionEvent::Start(session) => {
+                channels.start_class_c_session(session.frequency, session.data_rate).await;
+            }
+            SessionEvent::End => channels.end_class_c_session().await,
+        }
+    }
+}
+
+async fn send(channels: &LoRaWANChannels, port: u8, answer: &[u8]) {
+    if answer.is_empty() {
+        return;
+    }
+    if let Err(e) = channels.send(port, answer, false).await {
+        defmt::warn!("Package answer not queued: {:?}", defmt::Debug2Format(&e));
+    }
+}
@@ -0,0 +1,451 @@
+//! Remote Multicast Setup (TS005)
+//!
+//! Lets the server define up to [`MAX_MC_GROUPS`] multicast groups on
+//! FPort 200 and schedule Class B or Class C sessions for them. Group keys
+//! arrive encrypted with the McKEKey, which is derived from the device's
+//! McRootKey (see [`crypto::mc_root_key_v1_0`] and
+//! [`crypto::mc_root_key_v1_1`]).
+//!
+//! Class C sessions run one at a time: [`MulticastSetup::poll`] says when
+//! the device has to move to the session's frequency and data rate and when
+//! to go back. Class B sessions need ping slots of the group's own
+//! periodicity, which the stack does not open, so McClassBSessionReq is
+//! refused.
+
+use super::AnswerWriter;
+use crate::lora::crypto::{self, AesKey};
+use crate::lora::lorawan::MulticastGroup;
+use crate::lora::region::Region;
+
+/// FPort of the package
+pub const PORT: u8 = 200;
+
+/// Multicast groups a device can hold
+pub const MAX_MC_GROUPS: usize = 4;
+
+const PACKAGE_ID: u8 = 2;
+const PACKAGE_VERSION: u8 = 1;
+
+const CID_PACKAGE_VERSION: u8 = 0x00;
+const CID_MC_GROUP_STATUS: u8 = 0x01;
+const CID_MC_GROUP_SETUP: u8 = 0x02;
+const CID_MC_GROUP_DELETE: u8 = 0x03;
+const CID_MC_CLASS_C_SESSION: u8 = 0x04;
+const CID_MC_CLASS_B_SESSION: u8 = 0x05;
+
+const ID_ERROR: u8 = 0x04;
+const SESSION_DR_ERROR: u8 = 0x04;
+const SESSION_FREQ_ERROR: u8 = 0x08;
+const SESSION_GROUP_UNDEFINED: u8 = 0x10;
+
+/// A multicast group set up by McGroupSetupReq
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct McGroup {
+    /// McGroupID, 0..=3
+    pub id: u8,
+    /// Multicast address
+    pub addr: u32,
+    /// Payload key of the group
+    pub app_s_key: AesKey,
+    /// MIC key of the group
+    pub nwk_s_key: AesKey,
+    /// First acceptable McFCount
+    pub min_fcnt: u32,
+    /// Last acceptable McFCount; the group expires after it
+    pub max_fcnt: u32,
+}
+
+impl From<&McGroup> for MulticastGroup {
+    fn from(group: &McGroup) -> Self {
+        Self {
+            addr: group.addr,
+            app_s_key: group.app_s_key,
+            nwk_s_key: group.nwk_s_key,
+            fcnt_down: group.min_fcnt,
+            max_fcnt: group.max_fcnt,
+        }
+    }
+}
+
+/// Device class of a multicast session
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum McClass {
+    ClassB {
+        /// Ping slots every 2^periodicity seconds
+        periodicity: u8,
+    },
+    ClassC,
+}
+
+/// A multicast session scheduled by McClassCSessionReq or McClassBSessionReq
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct McSession {
+    /// McGroupID the session belongs to
+    pub group: u8,
+    pub class: McClass,
+    /// Start of the session in GPS seconds
+    pub start: u32,
+    /// Session length in seconds
+    pub timeout: u32,
+    /// Downlink frequency in Hz
+    pub frequency: u32,
+    /// Downlink data rate
+    pub data_rate: u8,
+}
+
+impl McSession {
+    /// End of the session in GPS seconds
+    pub fn end(&self) -> u32 {
+        self.start.saturating_add(self.timeout)
+    }
+}
+
+/// Change of the running multicast session, from [`MulticastSetup::poll`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum SessionEvent {
+    /// Listen on the session's frequency and data rate in Class C
+    Start(McSession),
+    /// Return to the device's own class and RX2
+    End,
+}
+
+/// Remote multicast setup state
+pub struct MulticastSetup {
+    region: Region,
+    mc_root_key: AesKey,
+    groups: [Option<McGroup>; MAX_MC_GROUPS],
+    sessions: [Option<McSession>; MAX_MC_GROUPS],
+    /// Session running
+    active: Option<McSession>,
+    /// Groups set up or deleted since the last [`take_changes`](Self::take_changes)
+    changed: u8,
+}
+
+impl MulticastSetup {
+    /// Package state for `region` with the device's McRootKey
+    pub fn new(region: Region, mc_root_key: AesKey) -> Self {
+        Self {
+            region,
+            mc_root_key,
+            groups: [None; MAX_MC_GROUPS],
+            sessions: [None; MAX_MC_GROUPS],
+            active: None,
+            changed: 0,
+        }
+    }
+
+    /// Group `id`, if defined
+    pub fn group(&self, id: u8) -> Option<&McGroup> {
+        self.groups.get(id as usize)?.as_ref()
+    }
+
+    /// Defined groups
+    pub fn groups(&self) -> impl Iterator<Item = &McGroup> {
+        self.groups.iter().flatten()
+    }
+
+    /// Session scheduled for group `id`, if any
+    pub fn session(&self, id: u8) -> Option<&McSession> {
+        self.sessions.get(id as usize)?.as_ref()
+    }
+
+    /// Bit mask of the groups set up or deleted since the last call
+    ///
+    /// The changed groups are to be handed to the LoRaWAN stack.
+    pub fn take_changes(&mut self) -> u8 {
+        core::mem::take(&mut self.changed)
+    }
+
+    /// GPS second of the next session start or end, if any
+    pub fn next_event(&self) -> Option<u32> {
+        if let Some(active) = self.active {
+            return Some(active.end());
+        }
+        self.sessions.iter().flatten().map(|s| s.start).min()
+    }
+
+    /// Start or end the running session at GPS second `now`
+    ///
+    /// Returns the change to apply to the stack; call again until `None`,
+    /// since one session can end and the next start at the same time. A
+    /// session ends at its timeout, when its group is deleted or set up
+    /// again, or when another session replaces it.
+    pub fn poll(&mut self, now: u32) -> Option<SessionEvent> {
+        if let Some(active) = self.active {
+            let slot = &mut self.sessions[active.group as usize];
+            if *slot == Some(active) && now < active.end() {
+                return None;
+            }
+            if *slot == Some(active) {
+                *slot = None;
+            }
+            defmt::info!("Multicast session of group {} ended", active.group);
+            self.active = None;
+            return Some(SessionEvent::End);
+        }
+
+        let (id, session) = self
+            .sessions
+            .iter()
+            .enumerate()
+            .filter_map(|(id, s)| Some((id, (*s)?)))
+            .filter(|(_, s)| s.start <= now)
+            .min_by_key(|(_, s)| s.start)?;
+        if now >= session.end() {
+            defmt::warn!("Multicast session of group {} missed", id);
+            self.sessions[id] = None;
+            return self.poll(now);
+        }
+        defmt::info!("Multicast session of group {} started", id);
+        self.active = Some(session);
+        Some(SessionEvent::Start(session))
+    }
+
+    /// Process a downlink on [`PORT`], writing the answers to `out`
+    ///
+    /// `now` is the application time in GPS seconds, from the clock
+    /// synchronization package.
+    pub fn process(&mut self, now: u32, payload: &[u8], out: &mut [u8]) -> usize {
+        let mut answer = AnswerWriter::new(out);
+        let mut rest = payload;
+        while let Some((&cid, p)) = rest.split_first() {
+            let len = match cid {
+                CID_PACKAGE_VERSION => 0,
+                CID_MC_GROUP_STATUS => 1,
+                CID_MC_GROUP_SETUP => 29,
+                CID_MC_GROUP_DELETE => 1,
+                CID_MC_CLASS_C_SESSION | CID_MC_CLASS_B_SESSION => 10,
+                _ => {
+                    defmt::warn!("Unknown multicast setup command 0x{:02x}", cid);
+                    break;
+                }
+            };
+            if p.len() < len {
+                defmt::warn!("Truncated multicast setup command 0x{:02x}", cid);
+                break;
+            }
+            let (p, next) = p.split_at(len);
+            rest = next;
+
+            match cid {
+                CID_PACKAGE_VERSION => {
+                    answer.push(&[CID_PACKAGE_VERSION, PACKAGE_ID, PACKAGE_VERSION]);
+                }
+                CID_MC_GROUP_STATUS => self.group_status(p[0] & 0x0F, &mut answer),
+                CID_MC_GROUP_SETUP => {
+                    let status = self.group_setup(p);
+                    answer.push(&[CID_MC_GROUP_SETUP, status]);
+                }
+                CID_MC_GROUP_DELETE => {
+                    let id = p[0] & 0x03;
+                    let status = if self.groups[id as usize].take().is_some() {
+                        self.sessions[id as usize] = None;
+                        self.changed |= 1 << id;
+                        defmt::info!("Multicast group {} deleted", id);
+                        id
+                    } else {
+                        id | ID_ERROR
+                    };
+                    answer.push(&[CID_MC_GROUP_DELETE, status]);
+                }
+                _ => {
+                    let (status, time_to_start) = self.session_setup(cid, now, p);
+                    match time_to_start {
+                        Some(t) => {
+                            let t = t.to_le_bytes();
+                            answer.push(&[cid, status, t[0], t[1], t[2]])
+                        }
+                        None => answer.push(&[cid, status]),
+                    };
+                }
+            }
+        }
+        answer.len()
+    }
+
+    fn group_status(&self, mask: u8, answer: &mut AnswerWriter<'_>) {
+        let total = self.groups().count() as u8;
+        let reported = self
+            .groups()
+            .filter(|g| mask & (1 << g.id) != 0)
+            .fold(0u8, |m, g| m | 1 << g.id);
+        answer.push(&[CID_MC_GROUP_STATUS, total << 4 | reported]);
+        for group in self.groups().filter(|g| reported & (1 << g.id) != 0) {
+            let addr = group.addr.to_le_bytes();
+            answer.push(&[group.id, addr[0], addr[1], addr[2], addr[3]]);
+        }
+    }
+
+    /// McGroupSetupReq: returns McGroupIDHeader of the answer
+    fn group_setup(&mut self, p: &[u8]) -> u8 {
+        let id = p[0] & 0x03;
+        let addr = u32::from_le_bytes([p[1], p[2], p[3], p[4]]);
+        let mut encrypted = [0u8; 16];
+        encrypted.copy_from_slice(&p[5..21]);
+        let min_fcnt = u32::from_le_bytes([p[21], p[22], p[23], p[24]]);
+        let max_fcnt = u32::from_le_bytes([p[25], p[26], p[27], p[28]]);
+        if min_fcnt > max_fcnt {
+            return id | ID_ERROR;
+        }
+
+        let mc_key = crypto::decrypt_mc_key(&self.mc_root_key, &encrypted);
+        let (app_s_key, nwk_s_key) = crypto::derive_mc_session_keys(&mc_key, addr);
+        self.groups[id as usize] = Some(McGroup {
+            id,
+            addr,
+            app_s_key,
+            nwk_s_key,
+            min_fcnt,
+            max_fcnt,
+        });
+        self.sessions[id as usize] = None;
+        self.changed |= 1 << id;
+        defmt::info!("Multicast group {} set up: McAddr {:08x}", id, addr);
+        id
+    }
+
+    /// McClassC/BSessionReq: returns the status and TimeToStart
+    fn session_setup(&mut self, cid: u8, now: u32, p: &[u8]) -> (u8, Option<u32>) {
+        let id = p[0] & 0x03;
+        let start = u32::from_le_bytes([p[1], p[2], p[3], p[4]]);
+        let timeout = 1u32 << (p[5] & 0x0F);
+        let frequency = u32::from_le_bytes([p[6], p[7], p[8], 0]) * 100;
+        let data_rate = p[9];
+
+        let mut status = id;
+        if self.region.data_rate(data_rate).is_none() {
+            status |= SESSION_DR_ERROR;
+        }
+        if !self.region.is_valid_frequency(frequency) {
+            status |= SESSION_FREQ_ERROR;
+        }
+        // TS005 has no status for an unsupported class; a Class B session
+        // is refused as if the group did not exist
+        if self.groups[id as usize].is_none() || cid == CID_MC_CLASS_B_SESSION {
+            status |= SESSION_GROUP_UNDEFINED;
+        }
+        if status != id {
+            return (status, None);
+        }
+
+        let session = McSession {
+            group: id,
+            class: McClass::ClassC,
+            start,
+            timeout,
+            frequency,
+            data_rate,
+        };
+        defmt::info!("Multicast session: {}", session);
+        self.sessions[id as usize] = Some(session);
+        (status, Some(start.saturating_sub(now).min(0xFF_FFFF)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const APP_KEY: AesKey = [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F,
+    ];
+    const MC_ADDR: u32 = 0x01FF_EE11;
+    /// McKey 0123456789ABCDEF0123456789ABCDEF encrypted with the McKEKey
+    /// of [`APP_KEY`]
+    const MC_KEY_ENCRYPTED: AesKey = [
+        0x77, 0x2B, 0xA5, 0x6C, 0x03, 0x3E, 0xA8, 0x45, 0x08, 0xBC, 0xDB, 0xB6, 0x21, 0xC5, 0x37, 0xFD,
+    ];
+
+    fn group_setup_req(id: u8, min_fcnt: u32, max_fcnt: u32) -> Vec<u8> {
+        let mut req = vec![CID_MC_GROUP_SETUP, id];
+        req.extend_from_slice(&MC_ADDR.to_le_bytes());
+        req.extend_from_slice(&MC_KEY_ENCRYPTED);
+        req.extend_from_slice(&min_fcnt.to_le_bytes());
+        req.extend_from_slice(&max_fcnt.to_le_bytes());
+        req
+    }
+
+    #[test]
+    fn mc_root_keys() {
+        assert_eq!(
+            crypto::mc_root_key_v1_0(&APP_KEY),
+            [0xC6, 0xA1, 0x3B, 0x37, 0x87, 0x8F, 0x5B, 0x82, 0x6F, 0x4F, 0x81, 0x62, 0xA1, 0xC8, 0xD8, 0x79]
+        );
+        assert_eq!(
+            crypto::mc_root_key_v1_1(&APP_KEY),
+            [0x43, 0x0B, 0xFF, 0x9B, 0x04, 0x9F, 0x19, 0x27, 0x94, 0x55, 0xBD, 0x56, 0x41, 0x33, 0xC7, 0x3B]
+        );
+    }
+
+    #[test]
+    fn group_setup_decrypts_and_derives_keys() {
+        let mut setup = MulticastSetup::new(Region::EU868, crypto::mc_root_key_v1_1(&APP_KEY));
+        let mut out = [0u8; 16];
+        let len = setup.process(0, &group_setup_req(1, 0, 0xFF), &mut out);
+        assert_eq!(out[..len], [CID_MC_GROUP_SETUP, 0x01]);
+        assert_eq!(setup.take_changes(), 0b0010);
+
+        let group = setup.group(1).unwrap();
+        assert_eq!(group.addr, MC_ADDR);
+        assert_eq!((group.min_fcnt, group.max_fcnt), (0, 0xFF));
+        // aes128_encrypt(McKey, 0x01 | McAddr | pad16)
+        assert_eq!(
+            group.app_s_key,
+            [0x32, 0x26, 0xBB, 0xEE, 0x0B, 0xFB, 0xB0, 0x25, 0xB9, 0x3D, 0x0D, 0x27, 0xF4, 0xBB, 0x7D, 0xC2]
+        );
+        // aes128_encrypt(McKey, 0x02 | McAddr | pad16)
+        assert_eq!(
+            group.nwk_s_key,
+            [0x93, 0xBD, 0x05, 0xA7, 0xCC, 0xCC, 0xB7, 0x70, 0xF6, 0xF4, 0x9C, 0xB5, 0x49, 0x41, 0x29, 0x17]
+        );
+    }
+
+    #[test]
+    fn group_setup_rejects_inverted_fcnt_range() {
+        let mut setup = MulticastSetup::new(Region::EU868, crypto::mc_root_key_v1_1(&APP_KEY));
+        let mut out = [0u8; 16];
+        let len = setup.process(0, &group_setup_req(2, 10, 5), &mut out);
+        assert_eq!(out[..len], [CID_MC_GROUP_SETUP, 0x02 | ID_ERROR]);
+        assert!(setup.group(2).is_none());
+        assert_eq!(setup.take_changes(), 0);
+    }
+
+    fn session_req(cid: u8, id: u8, start: u32, timeout_exp: u8) -> Vec<u8> {
+        let mut req = vec![cid, id];
+        req.extend_from_slice(&start.to_le_bytes());
+        req.push(timeout_exp);
+        // 869.525 MHz in 100 Hz steps, DR0
+        req.extend_from_slice(&8_695_250u32.to_le_bytes()[..3]);
+        req.push(0);
+        req
+    }
+
+    #[test]
+    fn class_c_session_runs_and_class_b_is_refused() {
+        let mut setup = MulticastSetup::new(Region::EU868, crypto::mc_root_key_v1_1(&APP_KEY));
+        let mut out = [0u8; 16];
+        setup.process(0, &group_setup_req(0, 0, 0xFF), &mut out);
+
+        // Starts in 10 s and lasts 2^4 s
+        let len = setup.process(90, &session_req(CID_MC_CLASS_C_SESSION, 0, 100, 4), &mut out);
+        assert_eq!(out[..len], [CID_MC_CLASS_C_SESSION, 0x00, 10, 0, 0]);
+        let len = setup.process(90, &session_req(CID_MC_CLASS_B_SESSION, 0, 100, 4), &mut out);
+        assert_eq!(out[..len], [CID_MC_CLASS_B_SESSION, SESSION_GROUP_UNDEFINED]);
+        let session = *setup.session(0).unwrap();
+        assert_eq!((session.class, session.frequency, session.data_rate), (McClass::ClassC, 869_525_000, 0));
+
+        assert_eq!(setup.next_event(), Some(100));
+        assert_eq!(setup.poll(99), None);
+        assert_eq!(setup.poll(100), Some(SessionEvent::Start(session)));
+        assert_eq!(setup.next_event(), Some(116));
+        assert_eq!(setup.poll(115), None);
+        assert_eq!(setup.poll(116), Some(SessionEvent::End));
+        assert_eq!(setup.poll(116), None);
+        assert!(setup.session(0).is_none());
+
+        // A session whose end has passed is dropped without starting
+        setup.process(200, &session_req(CID_MC_CLASS_C_SESSION, 0, 210, 4), &mut out);
+        assert_eq!(setup.poll(300), None);
+        assert_eq!(setup.next_event(), None);
+    }
+}
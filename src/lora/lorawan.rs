@@ -1,27 +1,196 @@
 //! LoRaWAN protocol stack implementation
 
-use super::sx1276::{SX1276, SX1276Error};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal::digital::OutputPin;
+use embedded_hal_async::digital::Wait;
+use embedded_hal_async::spi::SpiDevice;
+
+use super::class_b::{self, Beacon, BeaconSlot, BeaconState, BeaconTracker};
+use super::crypto::{self, AesKey, Direction, SessionKeys};
+use super::duty_cycle::{DutyCycle, JoinBackoff};
+use super::frame::{
+    self, eui_on_air, DataFrame, FrameError, JoinAccept, JoinRequest, MType, RejoinRequest, FCTRL_ACK, FCTRL_ADR,
+    FCTRL_ADR_ACK_REQ, FCTRL_CLASS_B, FCTRL_FPENDING, MIC_LEN,
+};
+use super::persist::{self, CounterLog, Counters, JoinContext, NvStorage, SessionRecord, COUNTER_STRIDE};
+use super::mac::{MacAnswer, MacAnswerQueue, MacCommand, MacCommands, MAX_FOPTS_LEN};
+use super::region::{Channel, ChannelPlan, Region};
+use super::sx1276::{LoRaConfig, RadioState, SX1276, SX1276Error};
+use super::time::{NetworkTime, WallClock};
+
+#[cfg(feature = "power")]
+use crate::power::{BatteryStatus, PowerState};
+
+/// JoinReqType of a JoinAccept answering a JoinRequest
+const JOIN_REQ_TYPE: u8 = 0xFF;
+/// Minor version reported in ResetInd and RekeyInd
+const LORAWAN_1_1_MINOR: u8 = 1;
+/// Base interval between retries of a forced Rejoin-request
+const REJOIN_RETRY_PERIOD: Duration = Duration::from_secs(32);
+
+/// DevStatusAns battery byte: the device is on external power
+pub const BATTERY_EXTERNAL_POWER: u8 = 0;
+/// DevStatusAns battery byte: the level could not be measured
+pub const BATTERY_UNKNOWN: u8 = 255;
+
+/// Largest application payload of any region and data rate
+pub(crate) const MAX_PAYLOAD: usize = 242;
+
+/// Multicast groups the device can receive at once
+pub const MAX_MULTICAST_GROUPS: usize = 4;
+
+/// MHDR + FHDR (without FOpts) + FPort + MIC
+const FRAME_OVERHEAD: usize = 1 + 7 + 1 + 4;
+/// Highest FPort available to applications (224 is the test port)
+pub(crate) const MAX_APP_PORT: u8 = 223;
+
+/// ADRParamSetupReq payload in effect until the network sends one:
+/// ADR_ACK_LIMIT = 2^6 uplinks without a downlink before ADRACKReq is set,
+/// ADR_ACK_DELAY = 2^5 further uplinks before each ADR back-off step
+const DEFAULT_ADR_PARAM: u8 = 0x65;
+
+/// Delay from the end of a JoinRequest to the first join-accept window
+const JOIN_ACCEPT_DELAY1: Duration = Duration::from_secs(5);
+/// Delay from the end of a JoinRequest to the second join-accept window
+const JOIN_ACCEPT_DELAY2: Duration = Duration::from_secs(6);
+/// Upper bound of the random delay before each JoinRequest, so that devices
+/// reset at the same moment do not all transmit at once
+const JOIN_DITHER_MS: u32 = 5_000;
+/// DevNonce values available to a device (0..=65535)
+const DEV_NONCE_SPACE: u32 = 0x1_0000;
+/// RX2 opens one second after RX1
+const RX2_DELAY_OFFSET: Duration = Duration::from_secs(1);
+
+/// Preamble symbols the radio must see inside a receive window
+const RX_WINDOW_SYMBOLS: u32 = 8;
+/// Allowance for timer wake-up latency around a receive window
+const RX_WINDOW_MARGIN: Duration = Duration::from_millis(20);
+
+/// Preamble of data frames, in symbols
+const PREAMBLE_SYMBOLS: u16 = 8;
+/// Preamble of Class B beacons, in symbols
+const BEACON_PREAMBLE_SYMBOLS: u16 = 10;
+/// Beacon windows opened while looking for the first beacon
+const BEACON_ACQUISITION_ATTEMPTS: u32 = 3;
 
 /// LoRaWAN device class
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
 pub enum DeviceClass {
     /// Class A: Lowest power, bidirectional with scheduled receive slots
     ClassA,
+    /// Class B: Class A plus ping slots synchronized to network beacons
+    ClassB,
     /// Class C: Continuously listening, highest power consumption
+    ///
+    /// Outside uplinks the radio stays in reception on the RX2 parameters;
+    /// [`LoRaWAN::listen`] returns what arrived.
     ClassC,
 }
 
+/// LoRaWAN protocol version implemented by the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, defmt::Format)]
+pub enum LoRaWANVersion {
+    /// LoRaWAN 1.0.x: one root key and one network session key
+    V1_0,
+    /// LoRaWAN 1.1: separate network and application root keys, three
+    /// network session keys, encrypted FOpts and Rejoin-requests
+    V1_1,
+}
+
+/// How the device obtains its session
+///
+/// EUIs are given most significant byte first, as printed on device labels
+/// and network server consoles; they are reversed on air.
+#[derive(Clone)]
+pub enum Activation {
+    /// Over-the-air activation: session keys are derived by [`LoRaWAN::join`]
+    ///
+    /// LoRaWAN 1.0.x devices have a single root key, `app_key`; `nwk_key`
+    /// is then ignored. A 1.1 device joining a 1.0 network falls back to
+    /// 1.0 keys derived from `nwk_key`.
+    Otaa {
+        /// Device EUI (8 bytes)
+        dev_eui: [u8; 8],
+        /// Join EUI, called AppEUI in LoRaWAN 1.0 (8 bytes)
+        join_eui: [u8; 8],
+        /// Network root key (16 bytes, LoRaWAN 1.1)
+        nwk_key: [u8; 16],
+        /// Application root key (16 bytes)
+        app_key: [u8; 16],
+    },
+    /// Activation by personalization: the session is provisioned up front
+    ///
+    /// For LoRaWAN 1.0.x use [`SessionKeys::v1_0`] with the NwkSKey.
+    Abp {
+        /// Device address
+        dev_addr: u32,
+        /// Session keys
+        keys: SessionKeys,
+    },
+}
+
+/// Rejoin-request types (LoRaWAN 1.1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum RejoinType {
+    /// Reset the device context, including radio parameters
+    Type0 = 0,
+    /// Restore a lost session context (signed with JSIntKey)
+    Type1 = 1,
+    /// Rekey the session keys and change the DevAddr
+    Type2 = 2,
+}
+
 /// LoRaWAN configuration
 #[derive(Clone)]
 pub struct LoRaWANConfig {
-    /// Device EUI (8 bytes)
-    pub dev_eui: [u8; 8],
-    /// Application EUI (8 bytes)
-    pub app_eui: [u8; 8],
-    /// Application Key (16 bytes)
-    pub app_key: [u8; 16],
+    /// Protocol version
+    pub version: LoRaWANVersion,
+    /// Activation mode and credentials
+    pub activation: Activation,
     /// Device class
+    ///
+    /// Class B needs a session and a beacon, so the stack starts in Class A
+    /// and [`lorawan_task`](super::task::lorawan_task) switches once joined.
     pub device_class: DeviceClass,
+    /// Class B ping slot every 2^periodicity seconds (0-7)
+    pub ping_slot_periodicity: u8,
+    /// Regional parameters (channel plan, data rates, limits)
+    pub region: Region,
+    /// US915/AU915 sub-band (1-8) to use; `None` uses all 72 channels
+    pub sub_band: Option<u8>,
+    /// Let the network control data rate and TX power (ADR)
+    pub adr: bool,
+    /// Retransmission of confirmed uplinks
+    pub retry: RetryPolicy,
+    /// Wait for duty-cycle time-off instead of failing with
+    /// [`LoRaWANError::DutyCycleRestricted`]
+    pub wait_for_duty_cycle: bool,
+}
+
+/// How a confirmed uplink is retransmitted until it is acknowledged
+///
+/// Every attempt carries the same frame and FCnt.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Transmissions including the first one (1-15)
+    pub max_attempts: u8,
+    /// Minimum wait after RX2 before retransmitting (ACK_TIMEOUT)
+    pub ack_timeout: Duration,
+    /// Random extra wait on top of `ack_timeout`
+    pub ack_timeout_jitter: Duration,
+    /// Lower the data rate by one step every second retransmission
+    pub data_rate_step_down: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 8,
+            ack_timeout: Duration::from_secs(1),
+            ack_timeout_jitter: Duration::from_secs(2),
+            data_rate_step_down: true,
+        }
+    }
 }
 
 /// LoRaWAN errors
@@ -31,6 +200,75 @@ pub enum LoRaWANError {
     NotJoined,
     PayloadTooLarge,
     NoAck,
+    /// No valid JoinAccept was received in either join-accept window
+    NoJoinAccept,
+    /// FCntUp is exhausted; the device must rejoin (OTAA) or be re-provisioned (ABP)
+    FrameCounterExhausted,
+    /// The stored ABP session has no valid frame counter checkpoint; the
+    /// device must be re-provisioned
+    CountersLost,
+    /// FPort outside 1..=223
+    InvalidPort,
+    /// Multicast group ID outside 0..MAX_MULTICAST_GROUPS, or McAddr equal
+    /// to our DevAddr
+    InvalidGroup,
+    /// No LinkCheckAns was received in either receive window
+    NoLinkCheckAns,
+    /// No PingSlotInfoAns was received in either receive window
+    NoPingSlotInfoAns,
+    /// Neither DeviceTimeAns nor BeaconTimingAns told when beacons are due
+    NoBeaconTiming,
+    /// No beacon was received while switching to Class B, or for too long
+    /// in Class B
+    NoBeacon,
+    /// Every downlink handler slot is taken
+    TooManyHandlers,
+    /// Every DevNonce (or RJcount) has been used; the device can no longer
+    /// join (or send that Rejoin-request)
+    DevNonceExhausted,
+    /// Rejoin-requests need a LoRaWAN 1.1 OTAA session
+    NotSupported,
+    /// The operation is not available in the current device class
+    InvalidClass,
+    /// The data rate is not defined for the region
+    InvalidDataRate,
+    /// The frequency is outside the region's band
+    InvalidFrequency,
+    /// No enabled channel supports the current data rate
+    NoChannel,
+    /// Every usable channel is in duty-cycle time-off; transmitting is
+    /// allowed again after the given delay
+    DutyCycleRestricted(Duration),
+    /// An uplink frame could not be encoded
+    FrameError(FrameError),
+}
+
+/// Application or MAC data received in a receive window
+#[derive(Clone)]
+pub struct Downlink {
+    /// FPort, `None` when the frame carried no application payload
+    pub port: Option<u8>,
+    /// The network acknowledged our confirmed uplink
+    pub ack: bool,
+    /// The network has more data queued for us
+    pub fpending: bool,
+    /// The downlink was confirmed; it is acknowledged on the next uplink
+    pub confirmed: bool,
+    /// Multicast group the frame was addressed to, `None` for unicast
+    pub multicast: Option<u8>,
+    /// RSSI of the packet in dBm
+    pub rssi: i16,
+    /// SNR of the packet in dB
+    pub snr: i8,
+    len: usize,
+    data: [u8; MAX_PAYLOAD],
+}
+
+impl Downlink {
+    /// Decrypted FRMPayload
+    pub fn payload(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
 }
 
 impl From<SX1276Error> for LoRaWANError {
@@ -39,74 +277,2525 @@ impl From<SX1276Error> for LoRaWANError {
     }
 }
 
+impl From<FrameError> for LoRaWANError {
+    fn from(e: FrameError) -> Self {
+        LoRaWANError::FrameError(e)
+    }
+}
+
+/// State of an activated session
+#[derive(Clone)]
+pub struct Session {
+    /// Device address assigned by the network
+    pub dev_addr: u32,
+    /// Session keys
+    pub keys: SessionKeys,
+    /// Protocol version of the session, negotiated at join
+    pub version: LoRaWANVersion,
+    /// NetID from the JoinAccept (0 for ABP)
+    pub net_id: u32,
+    /// Uplink frame counter
+    pub fcnt_up: u32,
+    /// Next expected downlink frame counter (NFCntDown in 1.1)
+    pub fcnt_down: u32,
+    /// Next expected application downlink frame counter (AFCntDown, 1.1 only)
+    pub afcnt_down: u32,
+    /// RX1 data rate offset from DLSettings
+    pub rx1_dr_offset: u8,
+    /// RX2 data rate from DLSettings
+    pub rx2_data_rate: u8,
+    /// RX2 frequency in Hz
+    pub rx2_frequency: u32,
+    /// Delay from end of uplink to RX1
+    pub rx_delay: Duration,
+}
+
+/// A multicast group the device receives on
+///
+/// Multicast frames are unconfirmed, carry no MAC commands and have their
+/// own frame counter.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct MulticastGroup {
+    /// McAddr
+    pub addr: u32,
+    /// McAppSKey (payload encryption)
+    pub app_s_key: AesKey,
+    /// McNwkSKey (MIC)
+    pub nwk_s_key: AesKey,
+    /// Next expected McFCount
+    pub fcnt_down: u32,
+    /// Last McFCount accepted; later frames are dropped
+    pub max_fcnt: u32,
+}
+
+impl MulticastGroup {
+    /// Group with the full McFCount range
+    pub const fn new(addr: u32, app_s_key: AesKey, nwk_s_key: AesKey) -> Self {
+        Self {
+            addr,
+            app_s_key,
+            nwk_s_key,
+            fcnt_down: 0,
+            max_fcnt: u32::MAX,
+        }
+    }
+}
+
+/// Answer to a LinkCheckReq
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct LinkCheck {
+    /// Demodulation margin of the uplink in dB
+    pub margin: u8,
+    /// Number of gateways that received the uplink
+    pub gateway_count: u8,
+}
+
+/// Rejoin-request asked for by ForceRejoinReq
+#[derive(Clone, Copy)]
+struct ForcedRejoin {
+    rejoin_type: RejoinType,
+    data_rate: u8,
+    /// Retransmissions after the first Rejoin-request
+    max_retries: u8,
+    /// Retries are 32 s * 2^period apart, plus jitter
+    period: u8,
+}
+
+/// Channel and data rate an uplink went out on
+#[derive(Clone, Copy)]
+struct TxParams {
+    channel: usize,
+    frequency: u32,
+    data_rate: u8,
+}
+
 /// LoRaWAN protocol handler
-pub struct LoRaWAN<'d> {
-    radio: SX1276<'d>,
+pub struct LoRaWAN<SPI, RESET, DIO0> {
+    radio: SX1276<SPI, RESET, DIO0>,
     config: LoRaWANConfig,
-    joined: bool,
+    session: Option<Session>,
+    /// Active device class, initially `config.device_class`
+    class: DeviceClass,
+    /// Enabled uplink channels
+    channels: ChannelPlan,
+    /// Uplink data rate
+    data_rate: u8,
+    /// Uplink TXPower index (0 = max EIRP)
+    tx_power: u8,
+    /// The 400 ms uplink dwell time limit applies
+    dwell_time: bool,
+    /// The 400 ms downlink dwell time limit applies
+    downlink_dwell_time: bool,
+    /// MaxEIRP in dBm, lowered by TxParamSetupReq
+    max_eirp: i8,
+    /// Transmissions of each unconfirmed uplink (NbTrans)
+    nb_trans: u8,
+    /// ADR is enabled, initially `config.adr`
+    adr: bool,
+    /// Uplinks since the last downlink (ADR_ACK_CNT)
+    adr_ack_cnt: u32,
+    /// Limit_exp and Delay_exp, see [`adr_ack_limit`](Self::adr_ack_limit)
+    adr_param: u8,
+    /// Aggregated duty cycle limit, 1 / 2^max_duty_cycle
+    max_duty_cycle: u8,
+    /// Per sub-band time-off
+    duty_cycle: DutyCycle,
+    /// FCnt of a confirmed downlink waiting for the ACK bit on the next uplink
+    ack_pending: Option<u16>,
+    /// MAC answers waiting for the next uplink
+    mac_answers: MacAnswerQueue,
+    /// SNR of the last downlink, reported in DevStatusAns
+    last_snr: i8,
+    /// Battery byte reported in DevStatusAns
+    battery_level: u8,
+    /// Last LinkCheckAns received
+    link_check: Option<LinkCheck>,
+    /// Non-volatile storage for the session and frame counters
+    storage: Option<&'static mut dyn NvStorage>,
+    /// Clock set from DeviceTimeAns
+    clock: Option<&'static dyn WallClock>,
+    /// End of the last transmission, the reference of DeviceTimeAns
+    last_tx_end: Instant,
+    /// GPS time from the last DeviceTimeAns
+    network_time: Option<NetworkTime>,
+    /// Multicast groups by McGroupID
+    multicast: [Option<MulticastGroup>; MAX_MULTICAST_GROUPS],
+    /// Class C reception (frequency, data rate) of a multicast session,
+    /// `None` for RX2
+    class_c_channel: Option<(u32, u8)>,
+    /// Class B beacon timing
+    beacon: BeaconTracker,
+    /// Beacon frequency from BeaconFreqReq, `None` for the region default
+    beacon_frequency: Option<u32>,
+    /// Ping slot periodicity confirmed by PingSlotInfoAns
+    ping_periodicity: Option<u8>,
+    /// Periodicity of the PingSlotInfoReq waiting for PingSlotInfoAns
+    ping_periodicity_req: Option<u8>,
+    /// Ping slot frequency from PingSlotChannelReq, `None` for the region default
+    ping_frequency: Option<u32>,
+    /// Ping slot data rate
+    ping_data_rate: u8,
+    /// Frame counter checkpoints in `storage`
+    counter_log: CounterLog,
+    /// FCntUp limit of the last checkpoint
+    fcnt_up_limit: u32,
+    /// FCntDown of the last checkpoint
+    fcnt_down_saved: u32,
+    /// AFCntDown of the last checkpoint
+    afcnt_down_saved: u32,
+    /// DevNonce of the last JoinRequest
+    dev_nonce: u16,
+    /// Nonces that must not repeat, persisted before use
+    join_context: JoinContext,
+    /// Time-off between JoinRequests
+    join_backoff: JoinBackoff,
+    /// ResetInd or RekeyInd, sent in every uplink until confirmed
+    version_ind: Option<MacAnswer>,
+    /// RJcount0 of the next type 0 or 2 Rejoin-request
+    rj_count0: u16,
+    /// Rejoin-request asked for by the network
+    forced_rejoin: Option<ForcedRejoin>,
+    /// Uplinks between type 0 Rejoin-requests, from RejoinParamSetupReq
+    rejoin_max_count: Option<u32>,
+    /// Uplinks since the last type 0 Rejoin-request
+    uplinks_since_rejoin: u32,
 }
 
-impl<'d> LoRaWAN<'d> {
+impl<SPI, RESET, DIO0> LoRaWAN<SPI, RESET, DIO0>
+where
+    SPI: SpiDevice,
+    RESET: OutputPin,
+    DIO0: Wait,
+{
     /// Create a new LoRaWAN instance
-    pub fn new(radio: SX1276<'d>, config: LoRaWANConfig) -> Self {
+    ///
+    /// With [`Activation::Abp`] the stack starts out joined with the
+    /// provisioned session.
+    pub fn new(radio: SX1276<SPI, RESET, DIO0>, config: LoRaWANConfig) -> Self {
+        let region = config.region;
+        let (rx2_frequency, rx2_data_rate) = region.rx2_default();
+        let session = match config.activation {
+            Activation::Abp { dev_addr, keys } => Some(Session {
+                dev_addr,
+                keys,
+                version: config.version,
+                net_id: 0,
+                fcnt_up: 0,
+                fcnt_down: 0,
+                afcnt_down: 0,
+                rx1_dr_offset: 0,
+                rx2_data_rate,
+                rx2_frequency,
+                rx_delay: Duration::from_secs(1),
+            }),
+            Activation::Otaa { .. } => None,
+        };
+        // A 1.1 ABP device announces every restart
+        let version_ind = (session.is_some() && config.version == LoRaWANVersion::V1_1)
+            .then_some(MacAnswer::ResetInd { minor: LORAWAN_1_1_MINOR });
+
         Self {
             radio,
+            // Class B starts with set_class, once joined
+            class: match config.device_class {
+                DeviceClass::ClassB => DeviceClass::ClassA,
+                class => class,
+            },
+            channels: ChannelPlan::new(region, config.sub_band),
+            data_rate: region.default_data_rate(),
+            tx_power: 0,
+            dwell_time: region.default_dwell_time(),
+            downlink_dwell_time: region.default_dwell_time(),
+            max_eirp: region.max_eirp(),
+            nb_trans: 1,
+            adr: config.adr,
+            adr_ack_cnt: 0,
+            adr_param: DEFAULT_ADR_PARAM,
+            max_duty_cycle: 0,
+            duty_cycle: DutyCycle::new(region),
             config,
-            joined: false,
+            session,
+            ack_pending: None,
+            mac_answers: MacAnswerQueue::new(),
+            last_snr: 0,
+            battery_level: BATTERY_UNKNOWN,
+            link_check: None,
+            storage: None,
+            clock: None,
+            last_tx_end: Instant::from_ticks(0),
+            network_time: None,
+            multicast: [None; MAX_MULTICAST_GROUPS],
+            class_c_channel: None,
+            beacon: BeaconTracker::new(),
+            beacon_frequency: None,
+            ping_periodicity: None,
+            ping_periodicity_req: None,
+            ping_frequency: None,
+            ping_data_rate: region.ping_slot_data_rate(),
+            counter_log: CounterLog::new(),
+            fcnt_up_limit: 0,
+            fcnt_down_saved: 0,
+            afcnt_down_saved: 0,
+            dev_nonce: 0,
+            join_context: JoinContext::default(),
+            join_backoff: JoinBackoff::new(),
+            version_ind,
+            rj_count0: 0,
+            forced_rejoin: None,
+            rejoin_max_count: None,
+            uplinks_since_rejoin: 0,
+        }
+    }
+
+    /// Attach non-volatile storage and resume the session stored in it
+    ///
+    /// `storage` must provide at least [`persist::STORAGE_LEN`](super::persist::STORAGE_LEN)
+    /// bytes. Returns `true` if a session for the configured credentials and
+    /// region was restored; [`join`](Self::join) then returns immediately.
+    /// From now on the session, MAC state and frame counters are saved as
+    /// they change.
+    ///
+    /// A stored session without a valid frame counter checkpoint is not
+    /// resumed: OTAA devices join again, ABP devices refuse to transmit
+    /// until re-provisioned, see [`LoRaWANError::CountersLost`].
+    pub fn attach_storage(&mut self, storage: &'static mut dyn NvStorage) -> bool {
+        self.storage = Some(storage);
+        match self.restore() {
+            Ok(restored) => restored,
+            Err(e) => {
+                defmt::warn!("Failed to read stored session: {:?}", e);
+                false
+            }
         }
     }
 
+    /// Attach a clock to set whenever a DeviceTimeAns arrives
+    pub fn attach_clock(&mut self, clock: &'static dyn WallClock) {
+        self.clock = Some(clock);
+    }
+
     /// Join the LoRaWAN network (OTAA)
+    ///
+    /// Sends a JoinRequest on a random join channel and data rate and listens
+    /// in both join-accept windows. Returns [`LoRaWANError::NoJoinAccept`] if
+    /// neither window yields a JoinAccept with a valid MIC. ABP devices, and
+    /// OTAA devices that resumed a stored session, are already joined and
+    /// return immediately.
+    ///
+    /// DevNonce increments with every JoinRequest and is saved to storage
+    /// first, if attached; without storage it restarts at 0 after a reset.
+    /// Repeated calls are spaced by the join back-off, after a random delay
+    /// of up to 5 s; the back-off is stored with the DevNonce, so a reset
+    /// does not start it over. If the back-off is running, the call waits or returns
+    /// [`LoRaWANError::DutyCycleRestricted`] per
+    /// [`LoRaWANConfig::wait_for_duty_cycle`].
+    ///
+    /// With [`LoRaWANVersion::V1_1`] the JoinRequest is signed with NwkKey.
+    /// A 1.1 network derives the 1.1 session keys, after which RekeyInd is
+    /// sent in every uplink until the network confirms it.
     pub async fn join(&mut self) -> Result<(), LoRaWANError> {
-        // TODO: Implement OTAA join procedure
-        // - Send Join Request
-        // - Wait for Join Accept
-        // - Derive session keys
-        
+        let Activation::Otaa { dev_eui, join_eui, nwk_key, app_key } = self.config.activation else {
+            if self.session.is_none() {
+                return Err(LoRaWANError::CountersLost);
+            }
+            defmt::info!("ABP activation, no join required");
+            return Ok(());
+        };
+        if self.session.is_some() {
+            defmt::info!("Session already active, no join required");
+            return Ok(());
+        }
+
+        if self.config.version == LoRaWANVersion::V1_1 && self.storage.is_none() {
+            defmt::warn!("No storage attached: DevNonce restarts at 0 after a reset, which 1.1 join servers reject");
+        }
+
+        if self.join_context.next_dev_nonce >= DEV_NONCE_SPACE {
+            return Err(LoRaWANError::DevNonceExhausted);
+        }
+
+        let now = Instant::now();
+        let ready = self.join_backoff.ready_at();
+        if ready > now {
+            let delay = ready - now;
+            if !self.config.wait_for_duty_cycle {
+                return Err(LoRaWANError::DutyCycleRestricted(delay));
+            }
+            defmt::info!("Join back-off: waiting {} s", delay.as_secs());
+            Timer::at(ready).await;
+        }
+
+        let random = self.radio.random_u32().await?;
+        Timer::after(Duration::from_millis((random % JOIN_DITHER_MS) as u64)).await;
+
         defmt::info!("Attempting to join LoRaWAN network...");
-        embassy_time::Timer::after(embassy_time::Duration::from_secs(2)).await;
-        
-        self.joined = true;
-        defmt::info!("Successfully joined LoRaWAN network");
-        Ok(())
+
+        let data_rate = self.join_data_rate(random >> 16);
+        let tx = self
+            .configure_uplink(data_rate, self.config.wait_for_duty_cycle)
+            .await?;
+
+        // The network rejects a DevNonce it has seen, so commit it before use
+        let dev_nonce = self.join_context.next_dev_nonce as u16;
+        self.join_context.next_dev_nonce += 1;
+        self.save_join_context();
+        self.dev_nonce = dev_nonce;
+        // LoRaWAN 1.0 has a single root key
+        let root_key = match self.config.version {
+            LoRaWANVersion::V1_0 => app_key,
+            LoRaWANVersion::V1_1 => nwk_key,
+        };
+        let request = Self::build_join_request(&dev_eui, &join_eui, &root_key, dev_nonce)?;
+
+        let airtime = Duration::from_micros(self.radio.config().time_on_air_us(request.len()) as u64);
+        let tx_end = self.transmit(&request, tx).await?;
+        self.join_backoff.record(tx_end, airtime);
+        self.join_context.join_backoff = self.join_backoff.progress();
+        self.save_join_context();
+
+        if self.receive_join_accept(tx_end, tx, JOIN_REQ_TYPE, dev_nonce).await? {
+            Ok(())
+        } else {
+            Err(LoRaWANError::NoJoinAccept)
+        }
+    }
+
+    /// Drop the current session, stored copy included, and join again
+    ///
+    /// For when the network no longer answers the resumed session, e.g. after
+    /// repeated [`LoRaWANError::NoAck`]. ABP sessions cannot be renewed.
+    pub async fn rejoin(&mut self) -> Result<(), LoRaWANError> {
+        if matches!(self.config.activation, Activation::Abp { .. }) {
+            return Ok(());
+        }
+        self.drop_session();
+        self.join().await
+    }
+
+    /// Send a LoRaWAN 1.1 Rejoin-request at the current data rate
+    ///
+    /// The current session stays active until a JoinAccept arrives in one of
+    /// the join-accept windows; it is then replaced as after [`join`](Self::join).
+    /// Returns whether a JoinAccept was received. The network only answers
+    /// type 0 and 2 requests when it wants to reset or rekey the session.
+    pub async fn rejoin_request(&mut self, rejoin_type: RejoinType) -> Result<bool, LoRaWANError> {
+        let data_rate = self.data_rate;
+        self.send_rejoin_request(rejoin_type, data_rate).await
     }
 
     /// Send uplink data
-    pub async fn send(&mut self, port: u8, data: &[u8], confirmed: bool) -> Result<(), LoRaWANError> {
-        if !self.joined {
+    ///
+    /// Builds a data frame on `port`, encrypting `data` with AppSKey and
+    /// signing it with NwkSKey, then opens the Class A RX1 and RX2 windows.
+    /// Pending MAC answers are piggybacked in FOpts when they fit. Any
+    /// downlink received is returned.
+    ///
+    /// Unconfirmed uplinks are repeated NbTrans times unless a downlink
+    /// arrives. Confirmed uplinks are retransmitted according to
+    /// [`LoRaWANConfig::retry`] and fail with [`LoRaWANError::NoAck`] only
+    /// once the last attempt went unacknowledged.
+    pub async fn send(
+        &mut self,
+        port: u8,
+        data: &[u8],
+        confirmed: bool,
+    ) -> Result<Option<Downlink>, LoRaWANError> {
+        if port == 0 || port > MAX_APP_PORT {
+            return Err(LoRaWANError::InvalidPort);
+        }
+        self.uplink(Some(port), data, confirmed).await
+    }
+
+    /// Send an empty uplink carrying only the pending MAC answers
+    ///
+    /// Also used to acknowledge a confirmed downlink when the application
+    /// has nothing to send.
+    ///
+    /// Rejoin-requests asked for by ForceRejoinReq or RejoinParamSetupReq go
+    /// out first; the empty uplink is skipped if nothing else is pending.
+    pub async fn flush_mac(&mut self) -> Result<Option<Downlink>, LoRaWANError> {
+        if self.forced_rejoin.is_some() || self.rejoin_due() {
+            self.service_rejoin().await?;
+            if !self.mac_answers.has_unsent() && self.ack_pending.is_none() {
+                return Ok(None);
+            }
+        }
+        self.uplink(None, &[], false).await
+    }
+
+    /// Whether MAC answers, a downlink ACK or a Rejoin-request are waiting
+    /// for an uplink
+    pub fn has_pending_mac(&self) -> bool {
+        self.mac_answers.has_unsent()
+            || self.ack_pending.is_some()
+            || self.forced_rejoin.is_some()
+            || self.rejoin_due()
+    }
+
+    /// Ask the network for a link check on the next uplink
+    pub fn request_link_check(&mut self) {
+        if !self.mac_answers.push(MacAnswer::LinkCheckReq) {
+            defmt::warn!("MAC answer queue full, LinkCheckReq dropped");
+        }
+    }
+
+    /// Check the link with an empty uplink carrying a LinkCheckReq
+    ///
+    /// Returns the demodulation margin and gateway count from the
+    /// LinkCheckAns, or [`LoRaWANError::NoLinkCheckAns`] if RX1 and RX2 pass
+    /// without one. Pending MAC answers go out on the same uplink.
+    pub async fn link_check(&mut self) -> Result<LinkCheck, LoRaWANError> {
+        if !self.is_joined() {
             return Err(LoRaWANError::NotJoined);
         }
+        if !self.mac_answers.push(MacAnswer::LinkCheckReq) {
+            // Make room by sending what is queued first
+            self.uplink(None, &[], false).await?;
+            if !self.mac_answers.push(MacAnswer::LinkCheckReq) {
+                defmt::warn!("MAC answer queue full, LinkCheckReq dropped");
+                return Err(LoRaWANError::NoLinkCheckAns);
+            }
+        }
+
+        self.link_check = None;
+        self.uplink(None, &[], false).await?;
+        self.link_check.ok_or(LoRaWANError::NoLinkCheckAns)
+    }
+
+    /// Ask the network for the current time on the next uplink
+    ///
+    /// The answer sets the attached clock (see [`attach_clock`](Self::attach_clock))
+    /// and [`network_time`](Self::network_time).
+    pub fn request_device_time(&mut self) {
+        if !self.mac_answers.push(MacAnswer::DeviceTimeReq) {
+            defmt::warn!("MAC answer queue full, DeviceTimeReq dropped");
+        }
+    }
+
+    /// GPS time from the last DeviceTimeAns, if any
+    pub fn network_time(&self) -> Option<NetworkTime> {
+        self.network_time
+    }
+
+    /// Ask the network when the next beacon is due on the next uplink
+    ///
+    /// For networks older than LoRaWAN 1.0.3; newer ones answer the
+    /// DeviceTimeReq sent when switching to Class B instead.
+    pub fn request_beacon_timing(&mut self) {
+        if !self.mac_answers.push(MacAnswer::BeaconTimingReq) {
+            defmt::warn!("MAC answer queue full, BeaconTimingReq dropped");
+        }
+    }
+
+    /// How well the beacon timing is known
+    pub fn beacon_state(&self) -> BeaconState {
+        self.beacon.state()
+    }
+
+    /// Last beacon received, with its GPS time and gateway information
+    pub fn last_beacon(&self) -> Option<Beacon> {
+        self.beacon.last_beacon()
+    }
+
+    /// Receive multicast group `id` from now on, replacing any previous one
+    ///
+    /// Multicast frames arrive in Class C reception (see [`listen`](Self::listen))
+    /// and come out of the normal downlink path with
+    /// [`Downlink::multicast`] set.
+    pub fn set_multicast_group(&mut self, id: u8, group: MulticastGroup) -> Result<(), LoRaWANError> {
+        let own_addr = self.session.as_ref().map(|s| s.dev_addr);
+        if id as usize >= MAX_MULTICAST_GROUPS || own_addr == Some(group.addr) {
+            return Err(LoRaWANError::InvalidGroup);
+        }
+        defmt::info!("Multicast group {}: McAddr {:08x}", id, group.addr);
+        self.multicast[id as usize] = Some(group);
+        Ok(())
+    }
 
-        if data.len() > 242 {
+    /// Stop receiving multicast group `id`
+    pub fn remove_multicast_group(&mut self, id: u8) -> Option<MulticastGroup> {
+        self.multicast.get_mut(id as usize)?.take()
+    }
+
+    /// Multicast group `id`, with its current frame counter
+    pub fn multicast_group(&self, id: u8) -> Option<&MulticastGroup> {
+        self.multicast.get(id as usize)?.as_ref()
+    }
+
+    /// Current uplink data rate
+    pub fn data_rate(&self) -> u8 {
+        self.data_rate
+    }
+
+    /// Current TXPower index (0 = max EIRP, each step 2 dB less)
+    pub fn tx_power(&self) -> u8 {
+        self.tx_power
+    }
+
+    /// Transmissions of each unconfirmed uplink, as set by LinkADRReq
+    pub fn nb_trans(&self) -> u8 {
+        self.nb_trans
+    }
+
+    /// Whether ADR is enabled
+    pub fn adr_enabled(&self) -> bool {
+        self.adr
+    }
+
+    /// Enable or disable ADR
+    ///
+    /// With ADR off the data rate stays where [`set_data_rate`](Self::set_data_rate)
+    /// or the network last put it, and no back-off happens.
+    pub fn set_adr(&mut self, enabled: bool) {
+        self.adr = enabled;
+        self.adr_ack_cnt = 0;
+    }
+
+    /// Set the uplink data rate, e.g. when ADR is off
+    pub fn set_data_rate(&mut self, data_rate: u8) -> Result<(), LoRaWANError> {
+        let region = self.config.region;
+        if data_rate > region.max_data_rate()
+            || region.max_payload(data_rate, self.dwell_time).is_none()
+        {
+            return Err(LoRaWANError::InvalidDataRate);
+        }
+        if !self.channels.supports_data_rate(data_rate) {
+            return Err(LoRaWANError::NoChannel);
+        }
+        self.data_rate = data_rate;
+        Ok(())
+    }
+
+    /// Set the battery byte reported in DevStatusAns
+    ///
+    /// [`BATTERY_EXTERNAL_POWER`], 1 (empty) to 254 (full), or
+    /// [`BATTERY_UNKNOWN`].
+    pub fn set_battery_level(&mut self, level: u8) {
+        self.battery_level = level;
+    }
+
+    /// Report the latest [`BatteryStatus`] in DevStatusAns
+    ///
+    /// A charging node counts as externally powered; otherwise the 0-100 %
+    /// level is scaled to 1..254.
+    #[cfg(feature = "power")]
+    pub fn update_battery(&mut self, status: &BatteryStatus) {
+        self.battery_level = match status.state {
+            PowerState::Charging => BATTERY_EXTERNAL_POWER,
+            _ => 1 + (status.percentage.min(100) as u16 * 253 / 100) as u8,
+        };
+    }
+
+    /// SNR of the last downlink, reported as the DevStatusAns margin
+    pub fn last_snr(&self) -> i8 {
+        self.last_snr
+    }
+
+    /// Result of the last LinkCheckReq, if the network answered one
+    pub fn last_link_check(&self) -> Option<LinkCheck> {
+        self.link_check
+    }
+
+    /// Build one data uplink and transmit it until it is acknowledged (confirmed)
+    /// or NbTrans times (unconfirmed), opening RX1/RX2 after each transmission
+    async fn uplink(
+        &mut self,
+        port: Option<u8>,
+        data: &[u8],
+        confirmed: bool,
+    ) -> Result<Option<Downlink>, LoRaWANError> {
+        match self.session.as_ref() {
+            None => return Err(LoRaWANError::NotJoined),
+            // Frame counters must never wrap within a session
+            Some(session) if session.fcnt_up == u32::MAX => {
+                return Err(LoRaWANError::FrameCounterExhausted)
+            }
+            Some(_) => {}
+        }
+
+        // A 1.1 device that never got RekeyConf must join again
+        let rekey_pending = matches!(self.version_ind, Some(MacAnswer::RekeyInd { .. }));
+        let adr_ack_limit = self.adr_ack_limit();
+        if rekey_pending && self.session.as_ref().is_some_and(|s| s.fcnt_up >= adr_ack_limit) {
+            defmt::warn!("No RekeyConf after {} uplinks, dropping the session", adr_ack_limit);
+            self.drop_session();
+            return Err(LoRaWANError::NotJoined);
+        }
+
+        let mut fctrl = if self.ack_pending.is_some() { FCTRL_ACK } else { 0x00 };
+        if self.class == DeviceClass::ClassB {
+            fctrl |= FCTRL_CLASS_B;
+        }
+        if self.adr {
+            self.adr_backoff();
+            fctrl |= FCTRL_ADR;
+            if self.adr_ack_cnt >= self.adr_ack_limit() && !self.at_adr_defaults() {
+                fctrl |= FCTRL_ADR_ACK_REQ;
+            }
+        }
+        let Some(session) = self.session.as_ref() else {
+            return Err(LoRaWANError::NotJoined);
+        };
+
+        let max_payload = self
+            .config
+            .region
+            .max_payload(self.data_rate, self.dwell_time)
+            .ok_or(LoRaWANError::InvalidDataRate)?;
+        if data.len() > max_payload {
             return Err(LoRaWANError::PayloadTooLarge);
         }
 
-        // TODO: Implement uplink transmission
-        // - Build MAC payload
-        // - Encrypt payload
-        // - Add MIC
-        // - Transmit via radio
-        // - Handle RX windows for Class A
-        
-        defmt::info!("Sending {} bytes on port {}", data.len(), port);
-        self.radio.transmit(data).await?;
-        
+        // FOpts count against the payload limit; answers that do not fit wait
+        let mut fopts = [0u8; MAX_FOPTS_LEN];
+        let room = (max_payload - data.len()).min(MAX_FOPTS_LEN);
+        let ind_len = match self.version_ind {
+            Some(ind) if ind.encoded_len() <= room => ind.encode(&mut fopts),
+            _ => 0,
+        };
+        let answers_len = self.mac_answers.encode(&mut fopts[ind_len..room]);
+        let fopts_len = ind_len + answers_len;
+
+        let mut frame = [0u8; MAX_PAYLOAD + FRAME_OVERHEAD + MAX_FOPTS_LEN];
+        let msg_len = Self::build_data_frame(
+            session,
+            port,
+            &fopts[..fopts_len],
+            data,
+            confirmed,
+            fctrl,
+            &mut frame,
+        )?;
+        let fcnt = session.fcnt_up;
+        let conf_fcnt = self.ack_pending.unwrap_or(0);
+
+        // FCntUp must be covered by a checkpoint before it goes on air
+        self.checkpoint_counters();
+
+        let retry = self.config.retry;
+        let attempts = if confirmed { retry.max_attempts.max(1) } else { self.nb_trans.max(1) };
+        let mut data_rate = self.data_rate;
+        let mut downlink = None;
+
+        for attempt in 1..=attempts {
+            if attempt > 1 && confirmed {
+                // ACK_TIMEOUT: wait a random 1-3 s after RX2 before retrying
+                let random = self.radio.random_u32().await?;
+                let backoff = retry.ack_timeout
+                    + Duration::from_millis(random as u64 % (retry.ack_timeout_jitter.as_millis() + 1));
+                Timer::after(backoff).await;
+
+                // Every second retransmission goes out one data rate lower
+                if retry.data_rate_step_down && attempt % 2 == 1 {
+                    data_rate = self.step_down_data_rate(data_rate, data.len() + fopts_len);
+                }
+            }
+
+            // Only the first attempt may give up on duty-cycle time-off
+            let wait = attempt > 1 || self.config.wait_for_duty_cycle;
+            let tx = self.configure_uplink(data_rate, wait).await?;
+            defmt::info!(
+                "Sending {} bytes on port {} (FCnt {}, {} Hz, DR{}, {} bytes FOpts, attempt {}/{})",
+                data.len(),
+                port,
+                fcnt,
+                tx.frequency,
+                tx.data_rate,
+                fopts_len,
+                attempt,
+                attempts
+            );
+            // 1.1 MICs cover the channel and data rate, so sign every attempt
+            let Some(session) = self.session.as_ref() else {
+                return Err(LoRaWANError::NotJoined);
+            };
+            let len = Self::sign_uplink(session, fcnt, conf_fcnt, tx, &mut frame, msg_len);
+            let tx_end = self.transmit(&frame[..len], tx).await?;
+
+            // Retransmissions reuse the frame, and with it the FCnt
+            if attempt == 1 {
+                self.ack_pending = None;
+                self.adr_ack_cnt = self.adr_ack_cnt.saturating_add(1);
+                self.uplinks_since_rejoin = self.uplinks_since_rejoin.saturating_add(1);
+                self.mac_answers.uplink_sent(answers_len);
+                if let Some(session) = self.session.as_mut() {
+                    session.fcnt_up += 1;
+                }
+            }
+
+            let received = self.receive_windows(tx_end, tx).await?;
+            self.radio.sleep().await?;
+
+            if let Some(received) = received {
+                let acked = received.ack;
+                downlink = Some(received);
+                // Any downlink ends the repetitions of an unconfirmed uplink
+                if acked || !confirmed {
+                    break;
+                }
+            }
+        }
+
+        self.resume_class_c().await?;
+
+        if confirmed && !downlink.as_ref().is_some_and(|d| d.ack) {
+            defmt::warn!("Confirmed uplink was not acknowledged after {} attempts", attempts);
+            return Err(LoRaWANError::NoAck);
+        }
+
+        Ok(downlink)
+    }
+
+    /// Next lower data rate that still carries `len` bytes, or `data_rate`
+    fn step_down_data_rate(&self, data_rate: u8, len: usize) -> u8 {
+        let region = self.config.region;
+        match data_rate.checked_sub(1) {
+            Some(lower)
+                if lower >= self.min_usable_data_rate()
+                    && self.channels.supports_data_rate(lower)
+                    && region.max_payload(lower, self.dwell_time).is_some_and(|n| n >= len) =>
+            {
+                lower
+            }
+            _ => data_rate,
+        }
+    }
+
+    /// Wait for a Class B or Class C downlink
+    ///
+    /// Class C keeps the radio in continuous reception on the RX2 parameters
+    /// (or those of [`set_class_c_channel`](Self::set_class_c_channel)),
+    /// also between calls, and returns the next valid downlink, including
+    /// one that arrived while nothing was listening. Class B sleeps between the beacons
+    /// and the device's ping slots, following the beacons; multicast frames
+    /// are accepted in those ping slots too, but the groups' own ping slots
+    /// are not opened. After [`class_b::BEACONLESS_TIMEOUT`] without a
+    /// beacon the device falls back to Class A and this returns
+    /// [`LoRaWANError::NoBeacon`].
+    ///
+    /// The future may be dropped at any time (for example in a `select` with
+    /// the next uplink timer); the following [`send`](Self::send) takes the
+    /// radio out of RX, and a Class C device goes back to RX2 afterwards.
+    pub async fn listen(&mut self) -> Result<Downlink, LoRaWANError> {
+        if self.class == DeviceClass::ClassA {
+            return Err(LoRaWANError::InvalidClass);
+        }
+        if !self.is_joined() {
+            return Err(LoRaWANError::NotJoined);
+        }
+        if self.class == DeviceClass::ClassB {
+            return self.listen_class_b().await;
+        }
+
+        if self.radio.state() != RadioState::Receiving {
+            self.resume_class_c().await?;
+        }
+
+        let mut buffer = [0u8; 256];
+        loop {
+            let received = self.radio.wait_receive(&mut buffer).await;
+            // Keep listening while the frame is checked
+            self.radio.start_receive().await?;
+            match received {
+                Ok(len) => {
+                    if let Some(downlink) = self.accept_downlink(&mut buffer[..len]) {
+                        return Ok(downlink);
+                    }
+                }
+                Err(SX1276Error::CrcError) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Listen on `frequency` and `data_rate` instead of RX2 in Class C, or
+    /// on RX2 again with `None`
+    ///
+    /// Used for the Class C sessions of multicast groups; takes effect right
+    /// away if the device is already in Class C.
+    pub async fn set_class_c_channel(&mut self, channel: Option<(u32, u8)>) -> Result<(), LoRaWANError> {
+        if let Some((frequency, data_rate)) = channel {
+            if !self.config.region.is_valid_frequency(frequency) {
+                return Err(LoRaWANError::InvalidFrequency);
+            }
+            self.downlink_config(frequency, data_rate)?;
+        }
+        self.class_c_channel = channel;
+        self.resume_class_c().await
+    }
+
+    /// Put a joined Class C device back into continuous reception
+    async fn resume_class_c(&mut self) -> Result<(), LoRaWANError> {
+        if self.class != DeviceClass::ClassC || !self.is_joined() {
+            return Ok(());
+        }
+        let config = match self.class_c_channel {
+            Some((frequency, data_rate)) => self.downlink_config(frequency, data_rate)?,
+            None => self.rx2_config()?,
+        };
+        self.radio.configure(config).await?;
+        self.radio.start_receive().await?;
+        Ok(())
+    }
+
+    /// Open the beacon windows and the device's ping slots until a downlink arrives
+    async fn listen_class_b(&mut self) -> Result<Downlink, LoRaWANError> {
+        let dev_addr = self.session.as_ref().ok_or(LoRaWANError::NotJoined)?.dev_addr;
+        let periodicity = self.ping_periodicity.unwrap_or(7);
+        let mut buffer = [0u8; 256];
+
+        loop {
+            let now = Instant::now();
+            let next_beacon = self.beacon.next_beacon(now).ok_or(LoRaWANError::NoBeaconTiming)?;
+            let ping_slot = self.beacon.current_period(now).and_then(|period| {
+                let time = period.time?;
+                let offset = class_b::ping_offset(time, dev_addr, periodicity);
+                let at = class_b::next_ping_slot(period.start, offset, periodicity, now)?;
+                Some((at, time))
+            });
+
+            match ping_slot {
+                Some((at, beacon_time)) if at < next_beacon.start => {
+                    let config = self.ping_slot_config(beacon_time, dev_addr)?;
+                    let widening = self.beacon.widening(at);
+                    if let Some(len) = self.receive_window_widened(at, widening, config, &mut buffer).await? {
+                        if let Some(downlink) = self.accept_downlink(&mut buffer[..len]) {
+                            self.radio.sleep().await?;
+                            return Ok(downlink);
+                        }
+                    }
+                }
+                _ => {
+                    let received = self.receive_beacon(next_beacon, &mut buffer).await?;
+                    if received.is_none() && !self.beacon.beacon_missed(next_beacon.start) {
+                        defmt::warn!("Beacon lost, switching back to Class A");
+                        self.class = DeviceClass::ClassA;
+                        self.radio.sleep().await?;
+                        return Err(LoRaWANError::NoBeacon);
+                    }
+                }
+            }
+            self.radio.sleep().await?;
+        }
+    }
+
+    /// Switch device class at runtime
+    ///
+    /// Dropping back to Class A puts the radio to sleep between uplinks, e.g.
+    /// when the battery reaches `PowerState::Critical`.
+    ///
+    /// Switching to Class B sends PingSlotInfoReq with
+    /// [`LoRaWANConfig::ping_slot_periodicity`], plus DeviceTimeReq while
+    /// the beacon timing is unknown, and then waits for a beacon, which can
+    /// take a few beacon periods. On failure the class is unchanged. From
+    /// then on uplinks carry the Class B bit, which tells the network the
+    /// ping slots are open.
+    pub async fn set_class(&mut self, class: DeviceClass) -> Result<(), LoRaWANError> {
+        if class == self.class {
+            return Ok(());
+        }
+        if class == DeviceClass::ClassB {
+            if let Err(e) = self.start_class_b().await {
+                self.resume_class_c().await?;
+                return Err(e);
+            }
+        }
+
+        defmt::info!("Switching device class to {:?}", class);
+        self.class = class;
+        match class {
+            DeviceClass::ClassA => self.radio.sleep().await?,
+            DeviceClass::ClassB => {}
+            DeviceClass::ClassC => self.resume_class_c().await?,
+        }
         Ok(())
     }
 
+    /// Agree on the ping slot periodicity and find the beacon
+    async fn start_class_b(&mut self) -> Result<(), LoRaWANError> {
+        if !self.is_joined() {
+            return Err(LoRaWANError::NotJoined);
+        }
+
+        let periodicity = self.config.ping_slot_periodicity.min(7);
+        let need_timing = self.beacon.state() == BeaconState::Unsynced;
+        if self.ping_periodicity != Some(periodicity) || need_timing {
+            if self.ping_periodicity != Some(periodicity) {
+                self.queue_answer(MacAnswer::PingSlotInfoReq { periodicity });
+                self.ping_periodicity_req = Some(periodicity);
+            }
+            if need_timing {
+                self.queue_answer(MacAnswer::DeviceTimeReq);
+            }
+            self.uplink(None, &[], false).await?;
+            if self.ping_periodicity != Some(periodicity) {
+                return Err(LoRaWANError::NoPingSlotInfoAns);
+            }
+        }
+
+        let result = self.acquire_beacon().await;
+        self.radio.sleep().await?;
+        result.map(|_| ())
+    }
+
+    /// Wait for a beacon where the beacon timing says one is due
+    async fn acquire_beacon(&mut self) -> Result<Beacon, LoRaWANError> {
+        let mut buffer = [0u8; 256];
+        for _ in 0..BEACON_ACQUISITION_ATTEMPTS {
+            // Skip a beacon whose window may already have opened
+            let after = Instant::now() + class_b::MAX_WIDENING + RX_WINDOW_MARGIN;
+            let slot = self.beacon.next_beacon(after).ok_or(LoRaWANError::NoBeaconTiming)?;
+            if let Some(beacon) = self.receive_beacon(slot, &mut buffer).await? {
+                return Ok(beacon);
+            }
+        }
+        Err(LoRaWANError::NoBeacon)
+    }
+
+    /// Open the beacon window of `slot`, returning the beacon if one arrives
+    async fn receive_beacon(
+        &mut self,
+        slot: BeaconSlot,
+        buffer: &mut [u8],
+    ) -> Result<Option<Beacon>, LoRaWANError> {
+        let region = self.config.region;
+        let format = region.beacon_format();
+        let frequency = self.beacon_frequency.unwrap_or_else(|| {
+            let channel = match slot.time {
+                Some(time) => region.beacon_channel(time),
+                None => slot.channel.unwrap_or(0),
+            };
+            region.beacon_frequency(channel)
+        });
+        let config = LoRaConfig {
+            preamble_length: BEACON_PREAMBLE_SYMBOLS,
+            implicit_header: true,
+            invert_iq: false,
+            ..self.downlink_config(frequency, format.data_rate)?
+        };
+
+        let widening = self.beacon.widening(slot.start);
+        self.radio.set_payload_length(format.len() as u8).await?;
+        let Some(len) = self.receive_window_widened(slot.start, widening, config, buffer).await? else {
+            defmt::info!("No beacon at {} Hz", frequency);
+            return Ok(None);
+        };
+        let rx_end = Instant::now();
+
+        match class_b::parse_beacon(&format, &buffer[..len]) {
+            Ok(beacon) => {
+                let airtime = Duration::from_micros(config.time_on_air_us(len) as u64);
+                self.beacon.beacon_received(rx_end - airtime, &beacon);
+                defmt::info!("Beacon at GPS {} s", beacon.time);
+                Ok(Some(beacon))
+            }
+            Err(e) => {
+                defmt::warn!("Invalid beacon: {:?}", e);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Receive parameters for the ping slots of the beacon period at `beacon_time`
+    fn ping_slot_config(&self, beacon_time: u32, dev_addr: u32) -> Result<LoRaConfig, LoRaWANError> {
+        let region = self.config.region;
+        let frequency = self
+            .ping_frequency
+            .unwrap_or_else(|| region.beacon_frequency(region.ping_slot_channel(beacon_time, dev_addr)));
+        self.downlink_config(frequency, self.ping_data_rate)
+    }
+
+    /// Forget the Class B settings of a previous session
+    fn reset_class_b(&mut self) {
+        if self.class == DeviceClass::ClassB {
+            self.class = DeviceClass::ClassA;
+        }
+        self.beacon_frequency = None;
+        self.ping_periodicity = None;
+        self.ping_periodicity_req = None;
+        self.ping_frequency = None;
+        self.ping_data_rate = self.config.region.ping_slot_data_rate();
+    }
+
+    /// Get the active device class
+    pub fn device_class(&self) -> DeviceClass {
+        self.class
+    }
+
     /// Check if device is joined to network
     pub fn is_joined(&self) -> bool {
-        self.joined
+        self.session.is_some()
+    }
+
+    /// Get the configuration the stack was created with
+    pub fn config(&self) -> &LoRaWANConfig {
+        &self.config
     }
+
+    /// Get the active session, if joined
+    pub fn session(&self) -> Option<&Session> {
+        self.session.as_ref()
+    }
+
+    /// Get the uplink channel plan
+    pub fn channels(&self) -> &ChannelPlan {
+        &self.channels
+    }
+
+    /// Pick a channel for `data_rate` and configure the radio for an uplink
+    ///
+    /// Only channels whose sub-band is out of time-off are considered. If
+    /// none is, either wait for the first one (`wait`) or fail with
+    /// [`LoRaWANError::DutyCycleRestricted`].
+    async fn configure_uplink(&mut self, data_rate: u8, wait: bool) -> Result<TxParams, LoRaWANError> {
+        let region = self.config.region;
+        let random = self.radio.random_u32().await?;
+        let now = Instant::now();
+        let (channel, ch) = match self
+            .channels
+            .select(data_rate, random, |c| self.duty_cycle.ready_at(c.frequency) <= now)
+        {
+            Some(selected) => selected,
+            None => {
+                let ready = self
+                    .channels
+                    .usable(data_rate)
+                    .map(|(_, c)| self.duty_cycle.ready_at(c.frequency))
+                    .min()
+                    .ok_or(LoRaWANError::NoChannel)?;
+                let delay = ready.saturating_duration_since(now);
+                if !wait {
+                    return Err(LoRaWANError::DutyCycleRestricted(delay));
+                }
+                defmt::info!("Duty cycle: waiting {} ms for a free channel", delay.as_millis());
+                Timer::at(ready).await;
+                self.channels
+                    .select(data_rate, random, |c| self.duty_cycle.ready_at(c.frequency) <= ready)
+                    .ok_or(LoRaWANError::NoChannel)?
+            }
+        };
+        let dr = region.data_rate(data_rate).ok_or(LoRaWANError::InvalidDataRate)?;
+        let tx_power = region
+            .tx_power_dbm(self.tx_power, self.max_eirp)
+            .ok_or(LoRaWANError::InvalidDataRate)?;
+
+        let config = LoRaConfig {
+            frequency: ch.frequency,
+            spreading_factor: dr.spreading_factor,
+            bandwidth: dr.bandwidth,
+            tx_power,
+            preamble_length: PREAMBLE_SYMBOLS,
+            implicit_header: false,
+            invert_iq: false,
+            crc_on: true,
+            ..*self.radio.config()
+        };
+        self.radio.configure(config).await?;
+
+        Ok(TxParams {
+            channel,
+            frequency: ch.frequency,
+            data_rate,
+        })
+    }
+
+    /// Transmit `frame` and start the time-off of its sub-band, returning the TX end
+    async fn transmit(&mut self, frame: &[u8], tx: TxParams) -> Result<Instant, LoRaWANError> {
+        let airtime = Duration::from_micros(self.radio.config().time_on_air_us(frame.len()) as u64);
+        self.radio.transmit(frame).await?;
+        let tx_end = Instant::now();
+        self.last_tx_end = tx_end;
+
+        self.duty_cycle
+            .record(tx.frequency, tx_end, airtime, self.max_duty_cycle);
+        Ok(tx_end)
+    }
+
+    /// Receive parameters for a downlink on `frequency` at `data_rate`
+    fn downlink_config(&self, frequency: u32, data_rate: u8) -> Result<LoRaConfig, LoRaWANError> {
+        let dr = self
+            .config
+            .region
+            .data_rate(data_rate)
+            .ok_or(LoRaWANError::InvalidDataRate)?;
+        Ok(LoRaConfig {
+            frequency,
+            spreading_factor: dr.spreading_factor,
+            bandwidth: dr.bandwidth,
+            preamble_length: PREAMBLE_SYMBOLS,
+            implicit_header: false,
+            invert_iq: true,
+            crc_on: false,
+            ..*self.radio.config()
+        })
+    }
+
+    /// Receive parameters for RX2 (and Class C continuous reception)
+    fn rx2_config(&self) -> Result<LoRaConfig, LoRaWANError> {
+        let session = self.session.as_ref().ok_or(LoRaWANError::NotJoined)?;
+        self.downlink_config(session.rx2_frequency, session.rx2_data_rate)
+    }
+
+    /// MHDR | JoinEUI | DevEUI | DevNonce | MIC, signed with AppKey (1.0) or NwkKey (1.1)
+    fn build_join_request(
+        dev_eui: &[u8; 8],
+        join_eui: &[u8; 8],
+        root_key: &AesKey,
+        dev_nonce: u16,
+    ) -> Result<[u8; frame::JOIN_REQUEST_LEN], LoRaWANError> {
+        let mut frame = [0u8; frame::JOIN_REQUEST_LEN];
+        let request = JoinRequest { join_eui: *join_eui, dev_eui: *dev_eui, dev_nonce };
+        let msg_len = request.encode(&mut frame)?;
+        let mic = crypto::join_request_mic(root_key, &frame[..msg_len]);
+        frame[msg_len..].copy_from_slice(&mic);
+        Ok(frame)
+    }
+
+    /// Open RX1 and RX2 after an uplink that ended at `tx_end`
+    async fn receive_windows(
+        &mut self,
+        tx_end: Instant,
+        tx: TxParams,
+    ) -> Result<Option<Downlink>, LoRaWANError> {
+        let Some(session) = self.session.as_ref() else {
+            return Ok(None);
+        };
+
+        let region = self.config.region;
+        let rx_delay = session.rx_delay;
+        let rx1_data_rate =
+            region.rx1_data_rate(tx.data_rate, session.rx1_dr_offset, self.downlink_dwell_time);
+        let rx1 = self.downlink_config(self.channels.rx1_frequency(tx.channel, tx.frequency), rx1_data_rate)?;
+        let rx2 = self.rx2_config()?;
+        let windows = [(rx_delay, rx1), (rx_delay + RX2_DELAY_OFFSET, rx2)];
+
+        let mut buffer = [0u8; 256];
+
+        // Class C already listens on the RX2 parameters until RX1 opens
+        if self.class == DeviceClass::ClassC {
+            self.radio.configure(rx2).await?;
+            let until = tx_end + rx_delay - RX_WINDOW_MARGIN;
+            let timeout = until.saturating_duration_since(Instant::now());
+            match self.radio.receive_timeout(&mut buffer, timeout).await {
+                Ok(len) => {
+                    if let Some(downlink) = self.accept_downlink(&mut buffer[..len]) {
+                        return Ok(Some(downlink));
+                    }
+                }
+                Err(SX1276Error::Timeout) | Err(SX1276Error::CrcError) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        for (delay, window) in windows {
+            if let Some(len) = self.receive_window(tx_end + delay, window, &mut buffer).await? {
+                if let Some(downlink) = self.accept_downlink(&mut buffer[..len]) {
+                    return Ok(Some(downlink));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Verify and decrypt a data downlink addressed to this device
+    fn accept_downlink(&mut self, frame: &mut [u8]) -> Option<Downlink> {
+        let header = match frame::split_mic(frame).and_then(|(msg, _)| DataFrame::decode(msg)) {
+            Ok(header) => header,
+            Err(e) => {
+                defmt::debug!("Undecodable downlink: {}", e);
+                return None;
+            }
+        };
+        let confirmed = match header.mtype {
+            MType::UnconfirmedDataDown => false,
+            MType::ConfirmedDataDown => true,
+            _ => return None,
+        };
+
+        let dev_addr = header.dev_addr;
+        if let Some(id) = self.multicast.iter().position(|g| g.is_some_and(|g| g.addr == dev_addr)) {
+            return self.accept_multicast(id, frame);
+        }
+        let session = self.session.as_mut()?;
+        if dev_addr != session.dev_addr {
+            return None;
+        }
+
+        let fctrl = header.fctrl;
+        let port = header.port;
+        let fopts_range = header.fopts_range();
+        let fopts_len = fopts_range.len();
+        let payload_range = header.payload_range();
+
+        // LoRaWAN 1.1 counts application downlinks separately (AFCntDown)
+        let v1_1 = session.version == LoRaWANVersion::V1_1;
+        let app_frame = v1_1 && port.is_some_and(|port| port != 0);
+        let next_fcnt = if app_frame { session.afcnt_down } else { session.fcnt_down };
+
+        // Rebuild the 32-bit counter from its low 16 bits
+        let fcnt16 = header.fcnt as u32;
+        let mut fcnt = (next_fcnt & 0xFFFF_0000) | fcnt16;
+        if fcnt < next_fcnt {
+            fcnt = fcnt.wrapping_add(0x1_0000);
+        }
+
+        let ack = fctrl & FCTRL_ACK != 0;
+        let (msg, mic) = frame.split_at_mut(frame.len() - MIC_LEN);
+        let expected = if v1_1 {
+            // ConfFCnt: the FCntUp of the uplink this downlink acknowledges
+            let conf_fcnt = if ack { session.fcnt_up.wrapping_sub(1) as u16 } else { 0 };
+            crypto::downlink_mic_v1_1(&session.keys.s_nwk_s_int_key, conf_fcnt, dev_addr, fcnt, msg)
+        } else {
+            crypto::data_mic(&session.keys.s_nwk_s_int_key, Direction::Downlink, dev_addr, fcnt, msg)
+        };
+        if expected != *mic {
+            defmt::warn!("Downlink MIC mismatch");
+            return None;
+        }
+        if app_frame {
+            session.afcnt_down = fcnt.wrapping_add(1);
+        } else {
+            session.fcnt_down = fcnt.wrapping_add(1);
+        }
+
+        let mut fopts = [0u8; MAX_FOPTS_LEN];
+        fopts[..fopts_len].copy_from_slice(&msg[fopts_range]);
+        if v1_1 {
+            let key = &session.keys.nwk_s_enc_key;
+            crypto::crypt_fopts(key, Direction::Downlink, dev_addr, fcnt, &mut fopts[..fopts_len]);
+        }
+
+        let status = self.radio.packet_status();
+        let mut downlink = Downlink {
+            port: None,
+            ack,
+            fpending: fctrl & FCTRL_FPENDING != 0,
+            confirmed,
+            multicast: None,
+            rssi: status.rssi,
+            snr: status.snr,
+            len: 0,
+            data: [0u8; MAX_PAYLOAD],
+        };
+
+        let mut mac_payload = false;
+        if let Some(port) = port {
+            let payload = &mut msg[payload_range];
+            let key = if port == 0 { &session.keys.nwk_s_enc_key } else { &session.keys.app_skey };
+            crypto::crypt_frm_payload(key, Direction::Downlink, dev_addr, fcnt, payload);
+
+            downlink.len = payload.len().min(MAX_PAYLOAD);
+            downlink.data[..downlink.len].copy_from_slice(&payload[..downlink.len]);
+            if port == 0 {
+                mac_payload = true;
+            } else {
+                downlink.port = Some(port);
+            }
+        }
+
+        // Cleared by the next uplink, which carries the ACK
+        if confirmed {
+            self.ack_pending = Some(fcnt as u16);
+        }
+        self.adr_ack_cnt = 0;
+        self.last_snr = status.snr;
+        self.mac_answers.downlink_received();
+
+        self.process_mac_commands(&fopts[..fopts_len]);
+        if mac_payload {
+            // Port 0 payloads are MAC commands, not application data
+            self.process_mac_commands(&downlink.data[..downlink.len]);
+            downlink.len = 0;
+        }
+        if fopts_len > 0 || mac_payload {
+            self.save_session();
+        }
+        self.checkpoint_counters();
+        Some(downlink)
+    }
+
+    /// Check and decrypt a frame addressed to multicast group `id`
+    fn accept_multicast(&mut self, id: usize, frame: &mut [u8]) -> Option<Downlink> {
+        let status = self.radio.packet_status();
+        let group = self.multicast[id].as_mut()?;
+
+        // Unconfirmed, no ACK, no FOpts, and an FPort
+        let header = frame::split_mic(frame).and_then(|(msg, _)| DataFrame::decode(msg)).ok()?;
+        let valid =
+            header.mtype == MType::UnconfirmedDataDown && header.fctrl & FCTRL_ACK == 0 && header.fopts.is_empty();
+        let (Some(port), true) = (header.port, valid) else {
+            defmt::warn!("Invalid multicast frame for group {}", id);
+            return None;
+        };
+        let fctrl = header.fctrl;
+        let payload_range = header.payload_range();
+
+        let fcnt16 = header.fcnt as u32;
+        let mut fcnt = (group.fcnt_down & 0xFFFF_0000) | fcnt16;
+        if fcnt < group.fcnt_down {
+            fcnt = fcnt.wrapping_add(0x1_0000);
+        }
+        if fcnt > group.max_fcnt {
+            defmt::warn!("Multicast group {} McFCount {} past its end", id, fcnt);
+            return None;
+        }
+
+        let (msg, mic) = frame.split_at_mut(frame.len() - MIC_LEN);
+        if crypto::data_mic(&group.nwk_s_key, Direction::Downlink, group.addr, fcnt, msg) != *mic {
+            defmt::warn!("Multicast MIC mismatch");
+            return None;
+        }
+        group.fcnt_down = fcnt.wrapping_add(1);
+
+        if port == 0 {
+            return None;
+        }
+        let payload = &mut msg[payload_range];
+        crypto::crypt_frm_payload(&group.app_s_key, Direction::Downlink, group.addr, fcnt, payload);
+
+        let mut downlink = Downlink {
+            port: Some(port),
+            ack: false,
+            fpending: fctrl & FCTRL_FPENDING != 0,
+            confirmed: false,
+            multicast: Some(id as u8),
+            rssi: status.rssi,
+            snr: status.snr,
+            len: payload.len().min(MAX_PAYLOAD),
+            data: [0u8; MAX_PAYLOAD],
+        };
+        downlink.data[..downlink.len].copy_from_slice(&payload[..downlink.len]);
+        Some(downlink)
+    }
+
+    /// Pick a random join data rate that some enabled channel supports
+    fn join_data_rate(&self, random: u32) -> u8 {
+        let region = self.config.region;
+        let usable = |dr: &u8| {
+            region.max_payload(*dr, self.dwell_time).is_some() && self.channels.supports_data_rate(*dr)
+        };
+        let count = region.join_data_rates().filter(usable).count();
+        if count == 0 {
+            return region.default_data_rate();
+        }
+        region
+            .join_data_rates()
+            .filter(usable)
+            .nth(random as usize % count)
+            .unwrap_or(region.default_data_rate())
+    }
+
+    /// Load the stored session for the configured credentials and region
+    fn restore(&mut self) -> Result<bool, persist::NvError> {
+        let id = self.storage_id();
+        let region = self.config.region;
+        let Some(storage) = self.storage.as_deref_mut() else {
+            return Ok(false);
+        };
+
+        self.join_context = JoinContext::load(storage)?;
+        self.join_backoff = JoinBackoff::resume(self.join_context.join_backoff, Instant::now());
+
+        let (log, counters) = CounterLog::load(storage)?;
+        self.counter_log = log;
+        // A 1.1 device may hold a 1.0 session from a 1.0 network, not the reverse
+        let version = self.config.version;
+        let Some(record) = SessionRecord::load(storage, region)?
+            .filter(|r| r.id == id && r.session.version <= version)
+        else {
+            // ABP: start saving the provisioned session
+            self.checkpoint_counters();
+            self.save_session();
+            return Ok(false);
+        };
+
+        // Resuming at FCntUp 0 would replay frame counters the network has seen
+        let Some(counters) = counters else {
+            if matches!(self.config.activation, Activation::Abp { .. }) {
+                defmt::error!("No valid frame counter checkpoint, ABP device must be re-provisioned");
+                self.session = None;
+            } else {
+                defmt::warn!("No valid frame counter checkpoint, joining again");
+            }
+            return Ok(false);
+        };
+        let mut session = record.session;
+        session.fcnt_up = counters.fcnt_up;
+        session.fcnt_down = counters.fcnt_down;
+        session.afcnt_down = counters.afcnt_down;
+        defmt::info!(
+            "Resumed session DevAddr {:08x} at FCntUp {}",
+            session.dev_addr,
+            session.fcnt_up
+        );
+
+        self.session = Some(session);
+        self.fcnt_up_limit = counters.fcnt_up;
+        self.fcnt_down_saved = counters.fcnt_down;
+        self.afcnt_down_saved = counters.afcnt_down;
+        self.dev_nonce = record.dev_nonce;
+        self.data_rate = record.data_rate;
+        self.tx_power = record.tx_power;
+        self.nb_trans = record.nb_trans.max(1);
+        self.max_duty_cycle = record.max_duty_cycle;
+        self.dwell_time = record.dwell_time;
+        self.downlink_dwell_time = record.downlink_dwell_time;
+        self.max_eirp = record.max_eirp;
+        self.adr_param = record.adr_param;
+        self.channels = record.channels;
+        Ok(true)
+    }
+
+    /// DevEUI (OTAA) or DevAddr (ABP) identifying the stored session
+    fn storage_id(&self) -> [u8; 8] {
+        match self.config.activation {
+            Activation::Otaa { dev_eui, .. } => dev_eui,
+            Activation::Abp { dev_addr, .. } => {
+                let mut id = [0u8; 8];
+                id[..4].copy_from_slice(&dev_addr.to_le_bytes());
+                id
+            }
+        }
+    }
+
+    /// Save the session record; unchanged bytes are not rewritten
+    fn save_session(&mut self) {
+        let Some(session) = self.session.clone() else {
+            return;
+        };
+        let record = SessionRecord {
+            id: self.storage_id(),
+            session,
+            dev_nonce: self.dev_nonce,
+            data_rate: self.data_rate,
+            tx_power: self.tx_power,
+            nb_trans: self.nb_trans,
+            max_duty_cycle: self.max_duty_cycle,
+            dwell_time: self.dwell_time,
+            downlink_dwell_time: self.downlink_dwell_time,
+            max_eirp: self.max_eirp,
+            adr_param: self.adr_param,
+            channels: self.channels.clone(),
+        };
+        if let Some(storage) = self.storage.as_deref_mut() {
+            if let Err(e) = record.save(storage) {
+                defmt::warn!("Failed to save session: {:?}", e);
+            }
+        }
+    }
+
+    /// Save the join context; nonces must be stored before they go on air
+    fn save_join_context(&mut self) {
+        if let Some(storage) = self.storage.as_deref_mut() {
+            if let Err(e) = self.join_context.save(storage) {
+                defmt::warn!("Failed to save join context: {:?}", e);
+            }
+        }
+    }
+
+    /// Forget the session, stored copy included
+    fn drop_session(&mut self) {
+        self.session = None;
+        self.version_ind = None;
+        self.forced_rejoin = None;
+        if let Some(storage) = self.storage.as_deref_mut() {
+            if let Err(e) = SessionRecord::erase(storage) {
+                defmt::warn!("Failed to erase stored session: {:?}", e);
+            }
+        }
+    }
+
+    /// Write a counter checkpoint once FCntUp reaches the stored limit or
+    /// a downlink counter moved a stride past the stored value
+    fn checkpoint_counters(&mut self) {
+        let Some(session) = self.session.as_ref() else {
+            return;
+        };
+        let (fcnt_up, fcnt_down, afcnt_down) = (session.fcnt_up, session.fcnt_down, session.afcnt_down);
+        if fcnt_up < self.fcnt_up_limit
+            && fcnt_down < self.fcnt_down_saved.saturating_add(COUNTER_STRIDE)
+            && afcnt_down < self.afcnt_down_saved.saturating_add(COUNTER_STRIDE)
+        {
+            return;
+        }
+        let Some(storage) = self.storage.as_deref_mut() else {
+            return;
+        };
+
+        let counters = Counters {
+            fcnt_up: fcnt_up.saturating_add(COUNTER_STRIDE),
+            fcnt_down,
+            afcnt_down,
+        };
+        match self.counter_log.append(storage, counters) {
+            Ok(()) => {
+                self.fcnt_up_limit = counters.fcnt_up;
+                self.fcnt_down_saved = fcnt_down;
+                self.afcnt_down_saved = afcnt_down;
+            }
+            Err(e) => defmt::warn!("Failed to save frame counters: {:?}", e),
+        }
+    }
+
+    /// Apply the MAC commands in `data` and queue their answers
+    fn process_mac_commands(&mut self, data: &[u8]) {
+        let mut commands = MacCommands::new(data).peekable();
+        while let Some(command) = commands.next() {
+            let command = match command {
+                Ok(command) => command,
+                Err(e) => {
+                    defmt::warn!("Stopped parsing MAC commands: {:?}", e);
+                    break;
+                }
+            };
+
+            let answer = match command {
+                MacCommand::ResetConf { .. } => {
+                    if matches!(self.version_ind, Some(MacAnswer::ResetInd { .. })) {
+                        self.version_ind = None;
+                    }
+                    continue;
+                }
+                MacCommand::DeviceTimeAns { seconds, fraction } => {
+                    // The answer is the time at the end of our uplink
+                    let time = NetworkTime::from_device_time_ans(self.last_tx_end, seconds, fraction);
+                    defmt::info!("Network time: GPS {} ms", time.gps_ms);
+                    if let Some(clock) = self.clock {
+                        clock.set_unix_ms(time.unix_ms_at(Instant::now()));
+                    }
+                    self.network_time = Some(time);
+                    self.beacon.set_network_time(time);
+                    continue;
+                }
+                MacCommand::PingSlotInfoAns => {
+                    if let Some(periodicity) = self.ping_periodicity_req.take() {
+                        defmt::info!("Ping slot periodicity {} accepted", periodicity);
+                        self.ping_periodicity = Some(periodicity);
+                    }
+                    continue;
+                }
+                MacCommand::PingSlotChannelReq { frequency, data_rate } => {
+                    let region = self.config.region;
+                    let mut status = 0;
+                    // Frequency 0 returns to the default ping slot channels
+                    if frequency == 0 || region.is_valid_frequency(frequency) {
+                        status |= 0x01;
+                    }
+                    if region.data_rate(data_rate).is_some() {
+                        status |= 0x02;
+                    }
+                    if status == 0x03 {
+                        self.ping_frequency = (frequency != 0).then_some(frequency);
+                        self.ping_data_rate = data_rate;
+                    }
+                    MacAnswer::PingSlotChannelAns { status }
+                }
+                MacCommand::BeaconTimingAns { delay, channel } => {
+                    // The beacon starts within the 30 ms step after `delay` steps
+                    let next = Instant::now() + Duration::from_millis(delay as u64 * 30 + 15);
+                    self.beacon.set_beacon_timing(next, channel);
+                    continue;
+                }
+                MacCommand::BeaconFreqReq { frequency } => {
+                    let valid = frequency == 0 || self.config.region.is_valid_frequency(frequency);
+                    if valid {
+                        self.beacon_frequency = (frequency != 0).then_some(frequency);
+                    }
+                    MacAnswer::BeaconFreqAns { status: valid as u8 }
+                }
+                MacCommand::RekeyConf { .. } => {
+                    if matches!(self.version_ind, Some(MacAnswer::RekeyInd { .. })) {
+                        defmt::info!("RekeyConf received");
+                        self.version_ind = None;
+                    }
+                    continue;
+                }
+                MacCommand::ForceRejoinReq { period, max_retries, rejoin_type, data_rate } => {
+                    let rejoin_type = match rejoin_type {
+                        0 | 1 => RejoinType::Type0,
+                        2 => RejoinType::Type2,
+                        _ => continue,
+                    };
+                    let data_rate = if self.config.region.data_rate(data_rate).is_some() {
+                        data_rate
+                    } else {
+                        self.data_rate
+                    };
+                    self.forced_rejoin = Some(ForcedRejoin { rejoin_type, data_rate, max_retries, period });
+                    continue;
+                }
+                MacCommand::AdrParamSetupReq { limit_exp, delay_exp } => {
+                    self.adr_param = limit_exp << 4 | delay_exp;
+                    defmt::info!(
+                        "ADR_ACK_LIMIT {}, ADR_ACK_DELAY {}",
+                        self.adr_ack_limit(),
+                        self.adr_ack_delay()
+                    );
+                    self.save_session();
+                    MacAnswer::AdrParamSetupAns
+                }
+                MacCommand::RejoinParamSetupReq { max_count_n, .. } => {
+                    self.rejoin_max_count = Some(1 << (max_count_n + 4));
+                    self.uplinks_since_rejoin = 0;
+                    // Only the uplink count is supported, not MaxTimeN
+                    MacAnswer::RejoinParamSetupAns { status: 0 }
+                }
+                MacCommand::LinkCheckAns { margin, gateway_count } => {
+                    defmt::info!("Link check: {} dB margin, {} gateways", margin, gateway_count);
+                    self.link_check = Some(LinkCheck { margin, gateway_count });
+                    continue;
+                }
+                MacCommand::LinkAdrReq { .. } => {
+                    // Contiguous LinkADRReqs form one block, answered together
+                    let mut block = [command; 8];
+                    let mut count = 1;
+                    while count < block.len() {
+                        match commands.peek() {
+                            Some(Ok(next @ MacCommand::LinkAdrReq { .. })) => {
+                                block[count] = *next;
+                                count += 1;
+                                commands.next();
+                            }
+                            _ => break,
+                        }
+                    }
+                    let status = self.apply_link_adr(&block[..count]);
+                    for _ in 0..count {
+                        self.queue_answer(MacAnswer::LinkAdrAns { status });
+                    }
+                    continue;
+                }
+                MacCommand::DutyCycleReq { max_duty_cycle } => {
+                    self.max_duty_cycle = max_duty_cycle;
+                    MacAnswer::DutyCycleAns
+                }
+                MacCommand::RxParamSetupReq { rx1_dr_offset, rx2_data_rate, frequency } => {
+                    let region = self.config.region;
+                    let mut status = 0;
+                    if region.is_valid_frequency(frequency) {
+                        status |= 0x01;
+                    }
+                    if region.data_rate(rx2_data_rate).is_some() {
+                        status |= 0x02;
+                    }
+                    if rx1_dr_offset <= region.max_rx1_dr_offset() {
+                        status |= 0x04;
+                    }
+                    if status == 0x07 {
+                        if let Some(session) = self.session.as_mut() {
+                            session.rx1_dr_offset = rx1_dr_offset;
+                            session.rx2_data_rate = rx2_data_rate;
+                            session.rx2_frequency = frequency;
+                        }
+                    }
+                    MacAnswer::RxParamSetupAns { status }
+                }
+                MacCommand::DevStatusReq => MacAnswer::DevStatusAns {
+                    battery: self.battery_level,
+                    margin: self.last_snr,
+                },
+                MacCommand::NewChannelReq { index, frequency, min_dr, max_dr } => {
+                    let region = self.config.region;
+                    // Fixed channel plans do not answer NewChannelReq
+                    if region.is_fixed_plan() {
+                        continue;
+                    }
+                    let mut status = 0;
+                    if min_dr <= max_dr && region.data_rate(max_dr).is_some() {
+                        status |= 0x01;
+                    }
+                    // Frequency 0 removes the channel
+                    if frequency == 0 || region.is_valid_frequency(frequency) {
+                        status |= 0x02;
+                    }
+                    if status == 0x03 {
+                        let channel = (frequency != 0).then_some(Channel { frequency, min_dr, max_dr });
+                        if !self.channels.set_channel(index as usize, channel) {
+                            status = 0;
+                        }
+                    }
+                    MacAnswer::NewChannelAns { status }
+                }
+                MacCommand::RxTimingSetupReq { delay } => {
+                    if let Some(session) = self.session.as_mut() {
+                        session.rx_delay = Duration::from_secs(delay.max(1) as u64);
+                    }
+                    MacAnswer::RxTimingSetupAns
+                }
+                MacCommand::TxParamSetupReq { downlink_dwell_time, uplink_dwell_time, max_eirp } => {
+                    if !self.config.region.supports_tx_param_setup() {
+                        continue;
+                    }
+                    self.downlink_dwell_time = downlink_dwell_time;
+                    self.dwell_time = uplink_dwell_time;
+                    self.max_eirp = max_eirp;
+                    // DR0/DR1 exceed 400 ms once the dwell time limit applies
+                    self.data_rate = self.data_rate.max(self.min_usable_data_rate());
+                    MacAnswer::TxParamSetupAns
+                }
+                MacCommand::DlChannelReq { index, frequency } => {
+                    if self.config.region.is_fixed_plan() {
+                        continue;
+                    }
+                    let mut status = 0;
+                    if self.config.region.is_valid_frequency(frequency) {
+                        status |= 0x01;
+                    }
+                    if self.channels.channel(index as usize).is_some() {
+                        status |= 0x02;
+                    }
+                    if status == 0x03 {
+                        self.channels.set_downlink_frequency(index as usize, frequency);
+                    }
+                    MacAnswer::DlChannelAns { status }
+                }
+            };
+            self.queue_answer(answer);
+        }
+    }
+
+    /// Apply a block of LinkADRReqs atomically, returning the LinkADRAns status
+    ///
+    /// Channel masks are applied in order; data rate, TX power and NbTrans
+    /// come from the last command. Nothing changes unless all three are valid.
+    fn apply_link_adr(&mut self, block: &[MacCommand]) -> u8 {
+        let region = self.config.region;
+        let mut plan = self.channels.clone();
+        let mut mask_ok = true;
+        let mut last = (0x0F, 0x0F, 0);
+        for command in block {
+            if let MacCommand::LinkAdrReq { data_rate, tx_power, ch_mask, ch_mask_cntl, nb_trans } = *command {
+                mask_ok &= plan.apply_ch_mask(ch_mask_cntl, ch_mask);
+                last = (data_rate, tx_power, nb_trans);
+            }
+        }
+        let (data_rate, tx_power, nb_trans) = last;
+
+        let mut status = 0;
+        if mask_ok && plan.any_enabled() {
+            status |= 0x01;
+        }
+        // 0xF keeps the current value
+        let data_rate = if data_rate == 0x0F { self.data_rate } else { data_rate };
+        // The data rate must also carry a frame under the dwell time limit
+        if region.max_payload(data_rate, self.dwell_time).is_some()
+            && plan.supports_data_rate(data_rate)
+        {
+            status |= 0x02;
+        }
+        let tx_power = if tx_power == 0x0F { self.tx_power } else { tx_power };
+        if tx_power <= region.max_tx_power_index() {
+            status |= 0x04;
+        }
+
+        if status == 0x07 {
+            self.channels = plan;
+            self.data_rate = data_rate;
+            self.tx_power = tx_power;
+            self.nb_trans = nb_trans.max(1);
+            defmt::info!("LinkADRReq: DR{}, TXPower {}, NbTrans {}", data_rate, tx_power, self.nb_trans);
+        } else {
+            defmt::warn!("LinkADRReq rejected, status {:03b}", status);
+        }
+        status
+    }
+
+    /// Uplinks without a downlink before ADRACKReq is set (ADR_ACK_LIMIT)
+    fn adr_ack_limit(&self) -> u32 {
+        1 << (self.adr_param >> 4)
+    }
+
+    /// Further uplinks without a downlink before each ADR back-off step
+    /// (ADR_ACK_DELAY)
+    fn adr_ack_delay(&self) -> u32 {
+        1 << (self.adr_param & 0x0F)
+    }
+
+    /// Step back towards a robust link after ADR_ACK_LIMIT + ADR_ACK_DELAY
+    /// uplinks without any downlink
+    ///
+    /// Max TX power first, then one data rate step per ADR_ACK_DELAY
+    /// uplinks, and finally all default channels with NbTrans 1.
+    fn adr_backoff(&mut self) {
+        let limit = self.adr_ack_limit();
+        if self.adr_ack_cnt < limit.saturating_add(self.adr_ack_delay()) {
+            return;
+        }
+        self.adr_ack_cnt = limit;
+
+        let min_dr = self.min_usable_data_rate();
+        if self.tx_power != 0 {
+            self.tx_power = 0;
+            defmt::info!("ADR back-off: max TX power");
+        } else if self.data_rate > min_dr {
+            self.data_rate -= 1;
+            defmt::info!("ADR back-off: DR{}", self.data_rate);
+        } else if !self.at_adr_defaults() {
+            let region = self.config.region;
+            if region.is_fixed_plan() {
+                self.channels = ChannelPlan::new(region, self.config.sub_band);
+            } else {
+                // ChMaskCntl 6 enables every defined channel
+                self.channels.apply_ch_mask(6, 0);
+            }
+            self.nb_trans = 1;
+            defmt::info!("ADR back-off: default channels");
+        }
+        self.save_session();
+    }
+
+    /// Whether ADR back-off has nothing left to restore
+    fn at_adr_defaults(&self) -> bool {
+        let region = self.config.region;
+        let defaults = if region.is_fixed_plan() {
+            ChannelPlan::new(region, self.config.sub_band)
+        } else {
+            let mut plan = self.channels.clone();
+            plan.apply_ch_mask(6, 0);
+            plan
+        };
+        self.tx_power == 0
+            && self.data_rate <= self.min_usable_data_rate()
+            && self.nb_trans == 1
+            && (0..defaults.slots()).all(|i| !defaults.is_enabled(i) || self.channels.is_enabled(i))
+    }
+
+    /// Lowest data rate that can carry a frame under the current dwell time
+    fn min_usable_data_rate(&self) -> u8 {
+        let region = self.config.region;
+        (region.min_data_rate()..=region.max_data_rate())
+            .find(|&dr| region.max_payload(dr, self.dwell_time).is_some())
+            .unwrap_or(region.min_data_rate())
+    }
+
+    /// Queue a MAC answer for the next uplink
+    fn queue_answer(&mut self, answer: MacAnswer) {
+        if !self.mac_answers.push(answer) {
+            defmt::warn!("MAC answer queue full, answer dropped");
+        }
+    }
+
+    /// Encode and encrypt a data uplink up to the MIC, returning the length;
+    /// [`sign_uplink`](Self::sign_uplink) appends the MIC
+    fn build_data_frame(
+        session: &Session,
+        port: Option<u8>,
+        fopts: &[u8],
+        data: &[u8],
+        confirmed: bool,
+        fctrl: u8,
+        frame: &mut [u8],
+    ) -> Result<usize, LoRaWANError> {
+        let uplink = DataFrame {
+            mtype: if confirmed { MType::ConfirmedDataUp } else { MType::UnconfirmedDataUp },
+            dev_addr: session.dev_addr,
+            fctrl,
+            // Only the low 16 bits of FCnt are sent
+            fcnt: session.fcnt_up as u16,
+            fopts,
+            port,
+            payload: data,
+        };
+        let msg_len = uplink.encode(frame)?;
+        if session.version == LoRaWANVersion::V1_1 {
+            let key = &session.keys.nwk_s_enc_key;
+            let fopts = &mut frame[uplink.fopts_range()];
+            crypto::crypt_fopts(key, Direction::Uplink, session.dev_addr, session.fcnt_up, fopts);
+        }
+        if let Some(port) = port {
+            let payload = &mut frame[uplink.payload_range()];
+            let key = if port == 0 { &session.keys.nwk_s_enc_key } else { &session.keys.app_skey };
+            crypto::crypt_frm_payload(key, Direction::Uplink, session.dev_addr, session.fcnt_up, payload);
+        }
+        Ok(msg_len)
+    }
+
+    /// Append the MIC of the first `msg_len` bytes for transmission with
+    /// `tx`, returning the frame length
+    fn sign_uplink(
+        session: &Session,
+        fcnt: u32,
+        conf_fcnt: u16,
+        tx: TxParams,
+        frame: &mut [u8],
+        msg_len: usize,
+    ) -> usize {
+        let msg = &frame[..msg_len];
+        let mic = match session.version {
+            LoRaWANVersion::V1_0 => crypto::data_mic(
+                &session.keys.f_nwk_s_int_key,
+                Direction::Uplink,
+                session.dev_addr,
+                fcnt,
+                msg,
+            ),
+            LoRaWANVersion::V1_1 => crypto::uplink_mic_v1_1(
+                &session.keys,
+                conf_fcnt,
+                tx.data_rate,
+                tx.channel as u8,
+                session.dev_addr,
+                fcnt,
+                msg,
+            ),
+        };
+        frame[msg_len..msg_len + MIC_LEN].copy_from_slice(&mic);
+        msg_len + MIC_LEN
+    }
+
+    /// Decrypt and verify a JoinAccept, deriving the session on success
+    ///
+    /// `join_req_type` and `nonce` identify the request being answered: 0xFF
+    /// and the DevNonce for a JoinRequest, the rejoin type and RJcount for a
+    /// Rejoin-request. Returns the session, the CFList if the JoinAccept
+    /// carried one, and the JoinNonce of a 1.1 JoinAccept.
+    fn accept_join(
+        &self,
+        frame: &mut [u8],
+        join_req_type: u8,
+        nonce: u16,
+    ) -> Option<(Session, Option<[u8; 16]>, Option<u32>)> {
+        let Activation::Otaa { dev_eui, join_eui, nwk_key, app_key } = self.config.activation else {
+            return None;
+        };
+        // The length must be checked before decrypting whole AES blocks
+        if let Err(e) = JoinAccept::check(frame) {
+            defmt::debug!("Undecodable JoinAccept: {}", e);
+            return None;
+        }
+
+        let v1_1 = self.config.version == LoRaWANVersion::V1_1;
+        let root_key = if v1_1 { nwk_key } else { app_key };
+        let join_eui = eui_on_air(&join_eui);
+        let join_keys = crypto::derive_join_keys(&nwk_key, &eui_on_air(&dev_eui));
+
+        // Answers to Rejoin-requests are encrypted with JSEncKey
+        let enc_key = if join_req_type == JOIN_REQ_TYPE { root_key } else { join_keys.js_enc_key };
+        crypto::decrypt_join_accept(&enc_key, &mut frame[1..]);
+
+        let accept = JoinAccept::decode(frame).ok()?;
+        let (msg, mic) = frame::split_mic(frame).ok()?;
+        // OptNeg: the network speaks 1.1; otherwise fall back to 1.0
+        let opt_neg = v1_1 && accept.opt_neg();
+        let expected = if opt_neg {
+            crypto::join_accept_mic_v1_1(&join_keys.js_int_key, join_req_type, &join_eui, nonce, msg)
+        } else {
+            crypto::join_accept_mic(&root_key, msg)
+        };
+        if expected != mic {
+            defmt::warn!("JoinAccept MIC mismatch");
+            return None;
+        }
+
+        // A 1.1 JoinNonce only ever increases; an old one is a replay
+        let join_nonce = accept.join_nonce;
+        if opt_neg && self.join_context.join_nonce.is_some_and(|last| join_nonce <= last) {
+            defmt::warn!("JoinAccept with stale JoinNonce {} ignored", join_nonce);
+            return None;
+        }
+
+        let app_nonce = u24_on_air(join_nonce);
+        let net_id = u24_on_air(accept.net_id);
+        let (keys, version) = if opt_neg {
+            let keys = crypto::derive_session_keys_v1_1(&nwk_key, &app_key, &app_nonce, &join_eui, nonce);
+            (keys, LoRaWANVersion::V1_1)
+        } else {
+            let keys = crypto::derive_session_keys(&root_key, &app_nonce, &net_id, nonce);
+            (keys, LoRaWANVersion::V1_0)
+        };
+
+        let session = Session {
+            dev_addr: accept.dev_addr,
+            keys,
+            version,
+            net_id: accept.net_id,
+            fcnt_up: 0,
+            fcnt_down: 0,
+            afcnt_down: 0,
+            rx1_dr_offset: accept.rx1_dr_offset(),
+            rx2_data_rate: accept.rx2_data_rate(),
+            rx2_frequency: self.config.region.rx2_default().0,
+            // A RxDelay of 0 means 1 second
+            rx_delay: Duration::from_secs(accept.rx_delay.max(1) as u64),
+        };
+        Some((session, accept.cf_list, opt_neg.then_some(join_nonce)))
+    }
+
+    /// Open the join-accept windows after a JoinRequest or Rejoin-request
+    /// and start the new session if a JoinAccept arrives
+    async fn receive_join_accept(
+        &mut self,
+        tx_end: Instant,
+        tx: TxParams,
+        join_req_type: u8,
+        nonce: u16,
+    ) -> Result<bool, LoRaWANError> {
+        let region = self.config.region;
+        let (rx2_frequency, rx2_data_rate) = region.rx2_default();
+        let rx1_data_rate = region.rx1_data_rate(tx.data_rate, 0, self.downlink_dwell_time);
+        let rx1 = self.downlink_config(region.rx1_frequency(tx.channel, tx.frequency), rx1_data_rate)?;
+        let rx2 = self.downlink_config(rx2_frequency, rx2_data_rate)?;
+        let windows = [(JOIN_ACCEPT_DELAY1, rx1), (JOIN_ACCEPT_DELAY2, rx2)];
+
+        let mut buffer = [0u8; 64];
+        let mut accepted = false;
+        for (delay, window) in windows {
+            let Some(len) = self.receive_window(tx_end + delay, window, &mut buffer).await? else {
+                continue;
+            };
+            let answer = self.accept_join(&mut buffer[..len], join_req_type, nonce);
+            let Some((session, cf_list, join_nonce)) = answer else {
+                continue;
+            };
+
+            defmt::info!(
+                "Successfully joined LoRaWAN network, DevAddr {:08x} ({:?})",
+                session.dev_addr,
+                session.version
+            );
+            // A type 2 rejoin only rekeys; the radio parameters stay
+            if join_req_type != RejoinType::Type2 as u8 {
+                self.reset_mac_state(tx.frequency);
+            }
+            if let Some(cf_list) = cf_list {
+                self.channels.apply_cf_list(&cf_list);
+            }
+            if join_nonce.is_some() {
+                self.join_context.join_nonce = join_nonce;
+                self.version_ind = Some(MacAnswer::RekeyInd { minor: LORAWAN_1_1_MINOR });
+            } else {
+                self.version_ind = None;
+            }
+            self.session = Some(session);
+            self.rj_count0 = 0;
+            self.uplinks_since_rejoin = 0;
+            self.forced_rejoin = None;
+            self.reset_class_b();
+            self.join_backoff.reset();
+            self.join_context.join_backoff = self.join_backoff.progress();
+            self.save_join_context();
+            // Counters first, so a stale log never pairs with the new session
+            self.fcnt_up_limit = 0;
+            self.fcnt_down_saved = 0;
+            self.afcnt_down_saved = 0;
+            self.checkpoint_counters();
+            self.save_session();
+            accepted = true;
+            break;
+        }
+
+        self.radio.sleep().await?;
+        self.resume_class_c().await?;
+        Ok(accepted)
+    }
+
+    /// Return the MAC and ADR state to the region defaults for a new session
+    ///
+    /// Nothing the previous session's network set survives a join or rejoin:
+    /// the channel plan, data rate, TX power, NbTrans, duty cycle and dwell
+    /// time limits, queued MAC answers and a pending downlink ACK.
+    fn reset_mac_state(&mut self, tx_frequency: u32) {
+        let region = self.config.region;
+        self.channels = ChannelPlan::new(region, self.config.sub_band);
+        self.data_rate = region.default_data_rate();
+        self.tx_power = 0;
+        self.nb_trans = 1;
+        self.adr_ack_cnt = 0;
+        self.adr_param = DEFAULT_ADR_PARAM;
+        self.max_duty_cycle = 0;
+        self.duty_cycle.restart(tx_frequency);
+        self.dwell_time = region.default_dwell_time();
+        self.downlink_dwell_time = region.default_dwell_time();
+        self.max_eirp = region.max_eirp();
+        self.mac_answers = MacAnswerQueue::new();
+        self.ack_pending = None;
+    }
+
+    /// Build and send a Rejoin-request, then listen for a JoinAccept
+    async fn send_rejoin_request(
+        &mut self,
+        rejoin_type: RejoinType,
+        data_rate: u8,
+    ) -> Result<bool, LoRaWANError> {
+        let Activation::Otaa { dev_eui, join_eui, nwk_key, .. } = self.config.activation else {
+            return Err(LoRaWANError::NotSupported);
+        };
+        let Some(session) = self.session.as_ref() else {
+            return Err(LoRaWANError::NotJoined);
+        };
+        if session.version != LoRaWANVersion::V1_1 {
+            return Err(LoRaWANError::NotSupported);
+        }
+
+        let mut frame = [0u8; frame::REJOIN_REQUEST_TYPE1_LEN];
+        let (len, nonce) = match rejoin_type {
+            // Types 0 and 2 are signed with SNwkSIntKey
+            RejoinType::Type0 | RejoinType::Type2 => {
+                let nonce = self.rj_count0;
+                let request = RejoinRequest::NetId {
+                    rejoin_type: rejoin_type as u8,
+                    net_id: session.net_id,
+                    dev_eui,
+                    rj_count0: nonce,
+                };
+                let msg_len = request.encode(&mut frame)?;
+                self.rj_count0 = nonce.checked_add(1).ok_or(LoRaWANError::DevNonceExhausted)?;
+                let mic = crypto::rejoin_request_mic(&session.keys.s_nwk_s_int_key, &frame[..msg_len]);
+                frame[msg_len..msg_len + MIC_LEN].copy_from_slice(&mic);
+                (msg_len + MIC_LEN, nonce)
+            }
+            // Type 1 is signed with JSIntKey
+            RejoinType::Type1 => {
+                let nonce = self.join_context.rj_count1;
+                let request = RejoinRequest::JoinEui { join_eui, dev_eui, rj_count1: nonce };
+                let msg_len = request.encode(&mut frame)?;
+                let join_keys = crypto::derive_join_keys(&nwk_key, &eui_on_air(&dev_eui));
+                let mic = crypto::rejoin_request_mic(&join_keys.js_int_key, &frame[..msg_len]);
+                frame[msg_len..msg_len + MIC_LEN].copy_from_slice(&mic);
+                self.join_context.rj_count1 = nonce.checked_add(1).ok_or(LoRaWANError::DevNonceExhausted)?;
+                self.save_join_context();
+                (msg_len + MIC_LEN, nonce)
+            }
+        };
+        if rejoin_type == RejoinType::Type0 {
+            self.uplinks_since_rejoin = 0;
+        }
+
+        defmt::info!("Sending type {} Rejoin-request", rejoin_type as u8);
+        let tx = self.configure_uplink(data_rate, self.config.wait_for_duty_cycle).await?;
+        let tx_end = self.transmit(&frame[..len], tx).await?;
+        self.receive_join_accept(tx_end, tx, rejoin_type as u8, nonce).await
+    }
+
+    /// Send the Rejoin-requests asked for by ForceRejoinReq or RejoinParamSetupReq
+    async fn service_rejoin(&mut self) -> Result<(), LoRaWANError> {
+        if let Some(forced) = self.forced_rejoin.take() {
+            for attempt in 0..=forced.max_retries {
+                if attempt > 0 {
+                    let random = self.radio.random_u32().await?;
+                    let jitter = Duration::from_millis(random as u64 % REJOIN_RETRY_PERIOD.as_millis());
+                    Timer::after(REJOIN_RETRY_PERIOD * (1 << forced.period) + jitter).await;
+                }
+                if self.send_rejoin_request(forced.rejoin_type, forced.data_rate).await? {
+                    break;
+                }
+            }
+        } else if self.rejoin_due() {
+            let data_rate = self.data_rate;
+            self.send_rejoin_request(RejoinType::Type0, data_rate).await?;
+        }
+        Ok(())
+    }
+
+    /// Whether RejoinParamSetupReq's uplink count calls for a type 0 Rejoin-request
+    fn rejoin_due(&self) -> bool {
+        self.rejoin_max_count.is_some_and(|max| self.uplinks_since_rejoin >= max)
+            && self.session.as_ref().is_some_and(|s| s.version == LoRaWANVersion::V1_1)
+    }
+
+    /// Open a receive window at `at`, returning the received length if any
+    async fn receive_window(
+        &mut self,
+        at: Instant,
+        config: LoRaConfig,
+        buffer: &mut [u8],
+    ) -> Result<Option<usize>, LoRaWANError> {
+        self.receive_window_widened(at, Duration::from_ticks(0), config, buffer).await
+    }
+
+    /// Open a receive window at `at`, `widening` earlier and longer on
+    /// account of clock drift (Class B)
+    async fn receive_window_widened(
+        &mut self,
+        at: Instant,
+        widening: Duration,
+        config: LoRaConfig,
+        buffer: &mut [u8],
+    ) -> Result<Option<usize>, LoRaWANError> {
+        self.radio.configure(config).await?;
+
+        // Open slightly early so the preamble is not missed
+        let timeout = Duration::from_micros((config.symbol_time_us() * RX_WINDOW_SYMBOLS) as u64)
+            + (RX_WINDOW_MARGIN + widening) * 2;
+        Timer::at(at - RX_WINDOW_MARGIN - widening).await;
+
+        match self.radio.receive_timeout(buffer, timeout).await {
+            Ok(len) => Ok(Some(len)),
+            Err(SX1276Error::Timeout) | Err(SX1276Error::CrcError) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// The low three bytes of a 24-bit JoinAccept field, as sent
+fn u24_on_air(value: u32) -> [u8; 3] {
+    let [a, b, c, _] = value.to_le_bytes();
+    [a, b, c]
 }
 
-/// Background task for LoRaWAN stack management
-#[embassy_executor::task]
-pub async fn lorawan_task() {
-    defmt::info!("LoRaWAN task started");
-    
-    loop {
-        // Handle MAC commands, ADR, etc.
-        embassy_time::Timer::after(embassy_time::Duration::from_secs(10)).await;
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::lora::persist::tests::Memory;
+    use crate::lora::persist::STORAGE_LEN;
+    use crate::lora::sx1276::tests::{simulated_radio, Chip, ChipDio0, ChipReset, ChipSpi};
+    use core::cell::RefCell;
+    use core::future::Future;
+    use core::pin::pin;
+    use core::task::{Context, Poll, Waker};
+    use embassy_time::MockDriver;
+    use std::rc::Rc;
+
+    type Stack = LoRaWAN<ChipSpi, ChipReset, ChipDio0>;
+
+    impl Downlink {
+        /// Unicast downlink carrying `payload` on `port`
+        pub(crate) fn on_port(port: Option<u8>, payload: &[u8]) -> Self {
+            let mut data = [0u8; MAX_PAYLOAD];
+            data[..payload.len()].copy_from_slice(payload);
+            Self {
+                port,
+                ack: false,
+                fpending: false,
+                confirmed: false,
+                multicast: None,
+                rssi: -60,
+                snr: 8,
+                len: payload.len(),
+                data,
+            }
+        }
+    }
+
+    const DEV_ADDR: u32 = 0x2601_1F2A;
+
+    fn abp() -> Activation {
+        Activation::Abp { dev_addr: DEV_ADDR, keys: SessionKeys::v1_0([0x11; 16], [0x22; 16]) }
+    }
+
+    fn otaa() -> Activation {
+        Activation::Otaa {
+            dev_eui: [0x70, 0xB3, 0xD5, 0x7E, 0xD0, 0x00, 0x00, 0x01],
+            join_eui: [0; 8],
+            nwk_key: [0x33; 16],
+            app_key: [0x33; 16],
+        }
+    }
+
+    /// EU868 Class A stack on a simulated radio
+    fn stack(activation: Activation, adr: bool) -> (Stack, Rc<RefCell<Chip>>) {
+        let (radio, chip) = simulated_radio();
+        let config = LoRaWANConfig {
+            version: LoRaWANVersion::V1_0,
+            activation,
+            device_class: DeviceClass::ClassA,
+            ping_slot_periodicity: 0,
+            region: Region::EU868,
+            sub_band: None,
+            adr,
+            retry: RetryPolicy::default(),
+            wait_for_duty_cycle: true,
+        };
+        (LoRaWAN::new(radio, config), chip)
+    }
+
+    /// Drive `future` to completion, advancing the mock clock while it waits
+    ///
+    /// The simulated radio never receives, so every receive window times out.
+    fn run<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        // One simulated day at most
+        for _ in 0..8_640_000 {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            MockDriver::get().advance(Duration::from_millis(10));
+        }
+        panic!("future still pending after a simulated day");
+    }
+
+    /// Storage for one stack, with the contents of `from`
+    fn storage(from: Option<&mut dyn NvStorage>) -> &'static mut dyn NvStorage {
+        let mut memory = Memory::blank(STORAGE_LEN);
+        if let Some(from) = from {
+            from.read(0, &mut memory.0).unwrap();
+        }
+        Box::leak(Box::new(memory))
+    }
+
+    /// Copy of the storage of `lorawan`, with the counter log wiped if `wipe_counters`
+    fn snapshot(lorawan: &mut Stack, wipe_counters: bool) -> &'static mut dyn NvStorage {
+        let mut memory = Memory::blank(STORAGE_LEN);
+        lorawan.storage.as_deref_mut().unwrap().read(0, &mut memory.0).unwrap();
+        if wipe_counters {
+            memory.erase_counter_log();
+        }
+        Box::leak(Box::new(memory))
+    }
+
+    #[test]
+    fn restore_resumes_past_the_counter_checkpoint() {
+        let (mut first, _) = stack(abp(), false);
+        assert!(!first.attach_storage(storage(None)));
+        // The provisioned session is saved with FCntUp 0..16 reserved
+        assert_eq!(first.fcnt_up_limit, COUNTER_STRIDE);
+
+        first.session.as_mut().unwrap().fcnt_up = COUNTER_STRIDE - 1;
+        first.checkpoint_counters();
+        assert_eq!(first.fcnt_up_limit, COUNTER_STRIDE);
+        let (mut resumed, _) = stack(abp(), false);
+        assert!(resumed.attach_storage(snapshot(&mut first, false)));
+        assert_eq!(resumed.session().unwrap().fcnt_up, COUNTER_STRIDE);
+
+        // Reaching the limit reserves the next stride before the FCnt goes on air
+        first.session.as_mut().unwrap().fcnt_up = COUNTER_STRIDE;
+        first.checkpoint_counters();
+        assert_eq!(first.fcnt_up_limit, 2 * COUNTER_STRIDE);
+        let (mut resumed, _) = stack(abp(), false);
+        assert!(resumed.attach_storage(snapshot(&mut first, false)));
+        assert_eq!(resumed.session().unwrap().fcnt_up, 2 * COUNTER_STRIDE);
+    }
+
+    #[test]
+    fn restore_without_counter_checkpoint_rejoins_otaa() {
+        let (mut first, _) = stack(otaa(), false);
+        let (rx2_frequency, rx2_data_rate) = Region::EU868.rx2_default();
+        first.session = Some(Session {
+            dev_addr: DEV_ADDR,
+            keys: SessionKeys::v1_0([0x11; 16], [0x22; 16]),
+            version: LoRaWANVersion::V1_0,
+            net_id: 0x13,
+            fcnt_up: 100,
+            fcnt_down: 10,
+            afcnt_down: 0,
+            rx1_dr_offset: 0,
+            rx2_data_rate,
+            rx2_frequency,
+            rx_delay: Duration::from_secs(1),
+        });
+        // Saves the session and a checkpoint, but restores nothing
+        assert!(!first.attach_storage(storage(None)));
+
+        let (mut resumed, _) = stack(otaa(), false);
+        assert!(resumed.attach_storage(snapshot(&mut first, false)));
+        assert_eq!(resumed.session().unwrap().fcnt_up, 100 + COUNTER_STRIDE);
+
+        let (mut lost, _) = stack(otaa(), false);
+        assert!(!lost.attach_storage(snapshot(&mut first, true)));
+        assert!(!lost.is_joined());
+    }
+
+    #[test]
+    fn restore_without_counter_checkpoint_stops_abp() {
+        let (mut first, _) = stack(abp(), false);
+        first.attach_storage(storage(None));
+
+        let (mut lost, chip) = stack(abp(), false);
+        assert!(!lost.attach_storage(snapshot(&mut first, true)));
+        assert!(!lost.is_joined());
+        assert!(matches!(run(lost.join()), Err(LoRaWANError::CountersLost)));
+        assert!(matches!(run(lost.send(1, b"x", false)), Err(LoRaWANError::NotJoined)));
+        assert!(chip.borrow().sent.is_empty());
+    }
+
+    #[test]
+    fn adr_backoff_order() {
+        let (mut lorawan, _) = stack(abp(), true);
+        lorawan.tx_power = 3;
+        lorawan.data_rate = 2;
+        lorawan.nb_trans = 2;
+        lorawan.channels.apply_ch_mask(0, 0b001);
+        let (limit, delay) = (lorawan.adr_ack_limit(), lorawan.adr_ack_delay());
+
+        let state = |l: &Stack| (l.tx_power, l.data_rate, l.nb_trans, l.channels.is_enabled(1));
+        lorawan.adr_ack_cnt = limit + delay - 1;
+        lorawan.adr_backoff();
+        assert_eq!(state(&lorawan), (3, 2, 2, false));
+
+        // One step per ADR_ACK_DELAY uplinks: TX power, each data rate, then the channels
+        let mut steps = Vec::new();
+        for _ in 0..5 {
+            lorawan.adr_ack_cnt = limit + delay;
+            lorawan.adr_backoff();
+            assert_eq!(lorawan.adr_ack_cnt, limit);
+            steps.push(state(&lorawan));
+        }
+        assert_eq!(
+            steps,
+            [(0, 2, 2, false), (0, 1, 2, false), (0, 0, 2, false), (0, 0, 1, true), (0, 0, 1, true)]
+        );
+        assert!(lorawan.at_adr_defaults());
+    }
+
+    #[test]
+    fn adr_ack_req_after_adr_ack_limit() {
+        let bits = |chip: &Rc<RefCell<Chip>>| {
+            let sent = &chip.borrow().sent;
+            sent.last().unwrap().frame[5] & (FCTRL_ADR | FCTRL_ADR_ACK_REQ)
+        };
+        let (mut lorawan, chip) = stack(abp(), true);
+        lorawan.data_rate = 5;
+        lorawan.adr_ack_cnt = lorawan.adr_ack_limit() - 1;
+        run(lorawan.send(1, b"a", false)).unwrap();
+        assert_eq!(bits(&chip), FCTRL_ADR);
+        run(lorawan.send(1, b"b", false)).unwrap();
+        assert_eq!(bits(&chip), FCTRL_ADR | FCTRL_ADR_ACK_REQ);
+
+        // A downlink resets the count
+        lorawan.adr_ack_cnt = 0;
+        run(lorawan.send(1, b"c", false)).unwrap();
+        assert_eq!(bits(&chip), FCTRL_ADR);
+
+        // Nothing to ask for when back-off could not make the link any more robust
+        let (mut lorawan, chip) = stack(abp(), true);
+        lorawan.data_rate = 0;
+        lorawan.adr_ack_cnt = lorawan.adr_ack_limit();
+        run(lorawan.send(1, b"a", false)).unwrap();
+        assert_eq!(bits(&chip), FCTRL_ADR);
+    }
+
+    #[test]
+    fn unconfirmed_uplink_repeats_nb_trans_times() {
+        let (mut lorawan, chip) = stack(abp(), false);
+        lorawan.data_rate = 5;
+        lorawan.nb_trans = 3;
+        assert!(matches!(run(lorawan.send(1, b"hello", false)), Ok(None)));
+        assert_eq!(lorawan.session().unwrap().fcnt_up, 1);
+
+        let sent = &chip.borrow().sent;
+        assert_eq!(sent.len(), 3);
+        // The same frame, FCnt 0, at the same data rate every time
+        assert_eq!(sent[0].frame[0], MType::UnconfirmedDataUp.mhdr());
+        assert_eq!(sent[0].frame[6..8], [0, 0]);
+        assert!(sent.iter().all(|s| s.frame == sent[0].frame && s.spreading_factor == 7));
+        assert!(sent.iter().all(|s| [868_100_000, 868_300_000, 868_500_000].contains(&s.frequency)));
+    }
+
+    #[test]
+    fn confirmed_uplink_steps_down_every_second_retry() {
+        let (mut lorawan, chip) = stack(abp(), false);
+        lorawan.data_rate = 5;
+        assert!(matches!(run(lorawan.send(1, b"hello", true)), Err(LoRaWANError::NoAck)));
+
+        {
+            let sent = &chip.borrow().sent;
+            assert_eq!(sent.len(), RetryPolicy::default().max_attempts as usize);
+            assert!(sent.iter().all(|s| s.frame[0] == MType::ConfirmedDataUp.mhdr() && s.frame[6..8] == [0, 0]));
+            // DR5 (SF7) drops to DR4 on attempt 3, DR3 on 5 and DR2 on 7
+            let spreading_factors: Vec<u8> = sent.iter().map(|s| s.spreading_factor).collect();
+            assert_eq!(spreading_factors, [7, 7, 8, 8, 9, 9, 10, 10]);
+        }
+        // The retries leave the data rate of the next uplink alone
+        assert_eq!(lorawan.data_rate(), 5);
+
+        let (mut lorawan, chip) = stack(abp(), false);
+        lorawan.data_rate = 5;
+        lorawan.config.retry = RetryPolicy { max_attempts: 4, data_rate_step_down: false, ..RetryPolicy::default() };
+        assert!(matches!(run(lorawan.send(1, b"hello", true)), Err(LoRaWANError::NoAck)));
+        let sent = &chip.borrow().sent;
+        assert_eq!(sent.len(), 4);
+        assert!(sent.iter().all(|s| s.spreading_factor == 7));
     }
 }
@@ -0,0 +1,620 @@
+//! LoRaWAN MAC commands
+//!
+//! Parsing of network-originated MAC commands (from FOpts or a port 0
+//! payload) and encoding of the device's answers. Applying a command to the
+//! stack state is left to [`super::lorawan::LoRaWAN`].
+
+/// Maximum length of the FOpts field
+pub const MAX_FOPTS_LEN: usize = 15;
+
+/// Number of answers that can wait for the next uplink
+const ANSWER_QUEUE_LEN: usize = 8;
+
+// Command identifiers (CIDs)
+const CID_RESET: u8 = 0x01;
+const CID_LINK_CHECK: u8 = 0x02;
+const CID_LINK_ADR: u8 = 0x03;
+const CID_DUTY_CYCLE: u8 = 0x04;
+const CID_RX_PARAM_SETUP: u8 = 0x05;
+const CID_DEV_STATUS: u8 = 0x06;
+const CID_NEW_CHANNEL: u8 = 0x07;
+const CID_RX_TIMING_SETUP: u8 = 0x08;
+const CID_TX_PARAM_SETUP: u8 = 0x09;
+const CID_DL_CHANNEL: u8 = 0x0A;
+const CID_REKEY: u8 = 0x0B;
+const CID_ADR_PARAM_SETUP: u8 = 0x0C;
+const CID_DEVICE_TIME: u8 = 0x0D;
+const CID_FORCE_REJOIN: u8 = 0x0E;
+const CID_REJOIN_PARAM_SETUP: u8 = 0x0F;
+const CID_PING_SLOT_INFO: u8 = 0x10;
+const CID_PING_SLOT_CHANNEL: u8 = 0x11;
+const CID_BEACON_TIMING: u8 = 0x12;
+const CID_BEACON_FREQ: u8 = 0x13;
+
+/// MaxEIRP values selected by TxParamSetupReq, in dBm
+const TX_PARAM_MAX_EIRP: [i8; 16] = [8, 10, 12, 13, 14, 16, 18, 20, 21, 24, 26, 27, 29, 30, 33, 36];
+
+/// A MAC command sent by the network
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MacCommand {
+    /// Answer to our ResetInd (LoRaWAN 1.1 ABP)
+    ResetConf {
+        /// Minor version of the network server
+        minor: u8,
+    },
+    /// Answer to our LinkCheckReq
+    LinkCheckAns {
+        /// Demodulation margin in dB above the demodulation floor
+        margin: u8,
+        /// Number of gateways that received the LinkCheckReq
+        gateway_count: u8,
+    },
+    /// Change data rate, TX power, channel mask and repetitions
+    LinkAdrReq {
+        data_rate: u8,
+        tx_power: u8,
+        ch_mask: u16,
+        ch_mask_cntl: u8,
+        nb_trans: u8,
+    },
+    /// Limit the aggregated duty cycle to 1 / 2^max_duty_cycle
+    DutyCycleReq { max_duty_cycle: u8 },
+    /// Change RX1 data rate offset and RX2 parameters
+    RxParamSetupReq {
+        rx1_dr_offset: u8,
+        rx2_data_rate: u8,
+        frequency: u32,
+    },
+    /// Request battery level and demodulation margin
+    DevStatusReq,
+    /// Create, modify or remove a channel
+    NewChannelReq {
+        index: u8,
+        frequency: u32,
+        min_dr: u8,
+        max_dr: u8,
+    },
+    /// Change the delay between TX and RX1 (seconds, 0 means 1)
+    RxTimingSetupReq { delay: u8 },
+    /// Change dwell time limits and MaxEIRP
+    TxParamSetupReq {
+        downlink_dwell_time: bool,
+        uplink_dwell_time: bool,
+        max_eirp: i8,
+    },
+    /// Use a different downlink frequency for RX1 on a channel
+    DlChannelReq { index: u8, frequency: u32 },
+    /// Set ADR_ACK_LIMIT to 2^limit_exp and ADR_ACK_DELAY to 2^delay_exp
+    /// uplinks (LoRaWAN 1.1)
+    AdrParamSetupReq { limit_exp: u8, delay_exp: u8 },
+    /// Answer to our DeviceTimeReq: GPS time at the end of that uplink
+    DeviceTimeAns {
+        /// Whole seconds since the GPS epoch
+        seconds: u32,
+        /// Fractional second in 1/256 s
+        fraction: u8,
+    },
+    /// Answer to our RekeyInd (LoRaWAN 1.1 OTAA)
+    RekeyConf {
+        /// Minor version of the network server
+        minor: u8,
+    },
+    /// Send a Rejoin-request now, retrying every 32 s * 2^period
+    ForceRejoinReq {
+        period: u8,
+        max_retries: u8,
+        rejoin_type: u8,
+        data_rate: u8,
+    },
+    /// Send a type 0 Rejoin-request every 2^(max_count_n + 4) uplinks or
+    /// 2^(max_time_n + 10) seconds
+    RejoinParamSetupReq { max_time_n: u8, max_count_n: u8 },
+    /// Answer to our PingSlotInfoReq: the network uses the new periodicity
+    PingSlotInfoAns,
+    /// Receive ping slots on `frequency` (0: region default) at `data_rate`
+    PingSlotChannelReq { frequency: u32, data_rate: u8 },
+    /// Answer to our BeaconTimingReq
+    BeaconTimingAns {
+        /// The next beacon starts `delay` to `delay + 1` ping slot lengths
+        /// (30 ms) after the end of this downlink
+        delay: u16,
+        /// Beacon channel of the next beacon
+        channel: u8,
+    },
+    /// Receive beacons on `frequency` (0: region default)
+    BeaconFreqReq { frequency: u32 },
+}
+
+/// MAC command parse errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum MacError {
+    /// Unknown CID; the rest of the buffer cannot be interpreted
+    UnknownCommand(u8),
+    /// The buffer ends inside a command
+    Truncated,
+}
+
+/// Iterator over the MAC commands in a FOpts field or port 0 payload
+///
+/// Iteration stops after the first error, as later commands cannot be
+/// located once a CID is not understood.
+pub struct MacCommands<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> MacCommands<'a> {
+    /// Parse MAC commands from `data`
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+}
+
+impl Iterator for MacCommands<'_> {
+    type Item = Result<MacCommand, MacError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (&cid, rest) = self.data.split_first()?;
+
+        let len = match cid {
+            CID_RESET => 1,
+            CID_LINK_CHECK => 2,
+            CID_LINK_ADR => 4,
+            CID_DUTY_CYCLE => 1,
+            CID_RX_PARAM_SETUP => 4,
+            CID_DEV_STATUS => 0,
+            CID_NEW_CHANNEL => 5,
+            CID_RX_TIMING_SETUP => 1,
+            CID_TX_PARAM_SETUP => 1,
+            CID_DL_CHANNEL => 4,
+            CID_REKEY => 1,
+            CID_ADR_PARAM_SETUP => 1,
+            CID_DEVICE_TIME => 5,
+            CID_FORCE_REJOIN => 2,
+            CID_REJOIN_PARAM_SETUP => 1,
+            CID_PING_SLOT_INFO => 0,
+            CID_PING_SLOT_CHANNEL => 4,
+            CID_BEACON_TIMING => 3,
+            CID_BEACON_FREQ => 3,
+            _ => {
+                self.data = &[];
+                return Some(Err(MacError::UnknownCommand(cid)));
+            }
+        };
+        if rest.len() < len {
+            self.data = &[];
+            return Some(Err(MacError::Truncated));
+        }
+        let (p, rest) = rest.split_at(len);
+        self.data = rest;
+
+        let command = match cid {
+            CID_RESET => MacCommand::ResetConf { minor: p[0] & 0x0F },
+            CID_LINK_CHECK => MacCommand::LinkCheckAns {
+                margin: p[0],
+                gateway_count: p[1],
+            },
+            CID_LINK_ADR => MacCommand::LinkAdrReq {
+                data_rate: p[0] >> 4,
+                tx_power: p[0] & 0x0F,
+                ch_mask: u16::from_le_bytes([p[1], p[2]]),
+                ch_mask_cntl: (p[3] >> 4) & 0x07,
+                nb_trans: p[3] & 0x0F,
+            },
+            CID_DUTY_CYCLE => MacCommand::DutyCycleReq {
+                max_duty_cycle: p[0] & 0x0F,
+            },
+            CID_RX_PARAM_SETUP => MacCommand::RxParamSetupReq {
+                rx1_dr_offset: (p[0] >> 4) & 0x07,
+                rx2_data_rate: p[0] & 0x0F,
+                frequency: frequency(&p[1..4]),
+            },
+            CID_DEV_STATUS => MacCommand::DevStatusReq,
+            CID_NEW_CHANNEL => MacCommand::NewChannelReq {
+                index: p[0],
+                frequency: frequency(&p[1..4]),
+                min_dr: p[4] & 0x0F,
+                max_dr: p[4] >> 4,
+            },
+            CID_RX_TIMING_SETUP => MacCommand::RxTimingSetupReq { delay: p[0] & 0x0F },
+            CID_TX_PARAM_SETUP => MacCommand::TxParamSetupReq {
+                downlink_dwell_time: p[0] & 0x20 != 0,
+                uplink_dwell_time: p[0] & 0x10 != 0,
+                max_eirp: TX_PARAM_MAX_EIRP[(p[0] & 0x0F) as usize],
+            },
+            CID_DL_CHANNEL => MacCommand::DlChannelReq {
+                index: p[0],
+                frequency: frequency(&p[1..4]),
+            },
+            CID_REKEY => MacCommand::RekeyConf { minor: p[0] & 0x0F },
+            CID_ADR_PARAM_SETUP => MacCommand::AdrParamSetupReq {
+                limit_exp: p[0] >> 4,
+                delay_exp: p[0] & 0x0F,
+            },
+            CID_DEVICE_TIME => MacCommand::DeviceTimeAns {
+                seconds: u32::from_le_bytes([p[0], p[1], p[2], p[3]]),
+                fraction: p[4],
+            },
+            CID_FORCE_REJOIN => {
+                let v = u16::from_le_bytes([p[0], p[1]]);
+                MacCommand::ForceRejoinReq {
+                    period: ((v >> 11) & 0x07) as u8,
+                    max_retries: ((v >> 8) & 0x07) as u8,
+                    rejoin_type: ((v >> 4) & 0x07) as u8,
+                    data_rate: (v & 0x0F) as u8,
+                }
+            }
+            CID_REJOIN_PARAM_SETUP => MacCommand::RejoinParamSetupReq {
+                max_time_n: p[0] >> 4,
+                max_count_n: p[0] & 0x0F,
+            },
+            CID_PING_SLOT_INFO => MacCommand::PingSlotInfoAns,
+            CID_PING_SLOT_CHANNEL => MacCommand::PingSlotChannelReq {
+                frequency: frequency(&p[0..3]),
+                data_rate: p[3] & 0x0F,
+            },
+            CID_BEACON_TIMING => MacCommand::BeaconTimingAns {
+                delay: u16::from_le_bytes([p[0], p[1]]),
+                channel: p[2],
+            },
+            _ => MacCommand::BeaconFreqReq {
+                frequency: frequency(&p[0..3]),
+            },
+        };
+        Some(Ok(command))
+    }
+}
+
+/// 24-bit little endian frequency in units of 100 Hz
+fn frequency(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]) * 100
+}
+
+/// A MAC command sent by the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MacAnswer {
+    /// LoRaWAN 1.1 ABP device restarted; repeated until ResetConf
+    ResetInd { minor: u8 },
+    /// Ask the network for link margin and gateway count
+    LinkCheckReq,
+    /// Bit 0: channel mask ACK, bit 1: data rate ACK, bit 2: power ACK
+    LinkAdrAns { status: u8 },
+    DutyCycleAns,
+    /// Bit 0: channel ACK, bit 1: RX2 data rate ACK, bit 2: RX1 offset ACK
+    RxParamSetupAns { status: u8 },
+    DevStatusAns {
+        /// 0 = external power, 1..254 = level, 255 = unknown
+        battery: u8,
+        /// SNR of the last downlink, -32..31 dB
+        margin: i8,
+    },
+    /// Bit 0: data rate range OK, bit 1: frequency OK
+    NewChannelAns { status: u8 },
+    RxTimingSetupAns,
+    TxParamSetupAns,
+    /// Bit 0: frequency OK, bit 1: uplink frequency exists
+    DlChannelAns { status: u8 },
+    /// LoRaWAN 1.1 OTAA device joined; repeated until RekeyConf
+    RekeyInd { minor: u8 },
+    /// ADRParamSetupReq applied
+    AdrParamSetupAns,
+    /// Bit 0: time-based periodicity accepted
+    RejoinParamSetupAns { status: u8 },
+    /// Ask the network for the current GPS time
+    DeviceTimeReq,
+    /// Class B ping slots every 2^periodicity seconds (0-7)
+    PingSlotInfoReq { periodicity: u8 },
+    /// Bit 0: frequency OK, bit 1: data rate OK
+    PingSlotChannelAns { status: u8 },
+    /// Ask the network when the next beacon is due (deprecated since
+    /// LoRaWAN 1.0.3 in favour of DeviceTimeReq)
+    BeaconTimingReq,
+    /// Bit 0: frequency OK
+    BeaconFreqAns { status: u8 },
+}
+
+impl MacAnswer {
+    /// Encoded length including the CID
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            MacAnswer::LinkCheckReq
+            | MacAnswer::DeviceTimeReq
+            | MacAnswer::BeaconTimingReq
+            | MacAnswer::DutyCycleAns
+            | MacAnswer::RxTimingSetupAns
+            | MacAnswer::TxParamSetupAns
+            | MacAnswer::AdrParamSetupAns => 1,
+            MacAnswer::ResetInd { .. }
+            | MacAnswer::LinkAdrAns { .. }
+            | MacAnswer::RxParamSetupAns { .. }
+            | MacAnswer::NewChannelAns { .. }
+            | MacAnswer::DlChannelAns { .. }
+            | MacAnswer::RekeyInd { .. }
+            | MacAnswer::RejoinParamSetupAns { .. }
+            | MacAnswer::PingSlotInfoReq { .. }
+            | MacAnswer::PingSlotChannelAns { .. }
+            | MacAnswer::BeaconFreqAns { .. } => 2,
+            MacAnswer::DevStatusAns { .. } => 3,
+        }
+    }
+
+    /// Sticky answers are repeated in every uplink until a downlink arrives
+    pub fn is_sticky(&self) -> bool {
+        matches!(
+            self,
+            MacAnswer::RxParamSetupAns { .. }
+                | MacAnswer::RxTimingSetupAns
+                | MacAnswer::DlChannelAns { .. }
+                | MacAnswer::PingSlotChannelAns { .. }
+        )
+    }
+
+    /// Write the answer to `buf`, returning the number of bytes written
+    pub fn encode(&self, buf: &mut [u8]) -> usize {
+        let (cid, payload): (u8, &[u8]) = match self {
+            MacAnswer::ResetInd { minor } => (CID_RESET, &[*minor]),
+            MacAnswer::LinkCheckReq => (CID_LINK_CHECK, &[]),
+            MacAnswer::LinkAdrAns { status } => (CID_LINK_ADR, &[*status]),
+            MacAnswer::DutyCycleAns => (CID_DUTY_CYCLE, &[]),
+            MacAnswer::RxParamSetupAns { status } => (CID_RX_PARAM_SETUP, &[*status]),
+            MacAnswer::DevStatusAns { battery, margin } => {
+                buf[0] = CID_DEV_STATUS;
+                buf[1] = *battery;
+                // 6-bit signed margin
+                buf[2] = (*margin).clamp(-32, 31) as u8 & 0x3F;
+                return 3;
+            }
+            MacAnswer::NewChannelAns { status } => (CID_NEW_CHANNEL, &[*status]),
+            MacAnswer::RxTimingSetupAns => (CID_RX_TIMING_SETUP, &[]),
+            MacAnswer::TxParamSetupAns => (CID_TX_PARAM_SETUP, &[]),
+            MacAnswer::DlChannelAns { status } => (CID_DL_CHANNEL, &[*status]),
+            MacAnswer::RekeyInd { minor } => (CID_REKEY, &[*minor]),
+            MacAnswer::AdrParamSetupAns => (CID_ADR_PARAM_SETUP, &[]),
+            MacAnswer::RejoinParamSetupAns { status } => (CID_REJOIN_PARAM_SETUP, &[*status]),
+            MacAnswer::DeviceTimeReq => (CID_DEVICE_TIME, &[]),
+            MacAnswer::PingSlotInfoReq { periodicity } => (CID_PING_SLOT_INFO, &[*periodicity & 0x07]),
+            MacAnswer::PingSlotChannelAns { status } => (CID_PING_SLOT_CHANNEL, &[*status]),
+            MacAnswer::BeaconTimingReq => (CID_BEACON_TIMING, &[]),
+            MacAnswer::BeaconFreqAns { status } => (CID_BEACON_FREQ, &[*status]),
+        };
+        buf[0] = cid;
+        buf[1..1 + payload.len()].copy_from_slice(payload);
+        1 + payload.len()
+    }
+}
+
+/// Answers waiting to be piggybacked on the next uplink
+#[derive(Clone, Default)]
+pub struct MacAnswerQueue {
+    answers: [Option<MacAnswer>; ANSWER_QUEUE_LEN],
+    /// Some answers have not been sent in any uplink yet
+    unsent: bool,
+}
+
+impl MacAnswerQueue {
+    /// Create an empty queue
+    pub const fn new() -> Self {
+        Self {
+            answers: [None; ANSWER_QUEUE_LEN],
+            unsent: false,
+        }
+    }
+
+    /// Queue an answer, returns `false` if the queue is full
+    pub fn push(&mut self, answer: MacAnswer) -> bool {
+        match self.answers.iter_mut().find(|a| a.is_none()) {
+            Some(slot) => {
+                *slot = Some(answer);
+                self.unsent = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether anything is waiting to be sent
+    pub fn is_empty(&self) -> bool {
+        self.answers.iter().all(Option::is_none)
+    }
+
+    /// Whether some answers still need an uplink (sticky answers that were
+    /// already sent once do not count)
+    pub fn has_unsent(&self) -> bool {
+        self.unsent
+    }
+
+    /// Encode as many queued answers as fit into `buf`, in queue order
+    pub fn encode(&self, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+        for answer in self.answers.iter().flatten() {
+            if len + answer.encoded_len() > buf.len() {
+                break;
+            }
+            len += answer.encode(&mut buf[len..]);
+        }
+        len
+    }
+
+    /// Drop the non-sticky answers that fit in `sent` bytes after an uplink
+    pub fn uplink_sent(&mut self, sent: usize) {
+        let mut len = 0;
+        self.unsent = false;
+        for slot in self.answers.iter_mut() {
+            let Some(answer) = slot else { continue };
+            if len + answer.encoded_len() > sent {
+                self.unsent = true;
+                break;
+            }
+            len += answer.encoded_len();
+            if !answer.is_sticky() {
+                *slot = None;
+            }
+        }
+        self.compact();
+    }
+
+    /// Drop sticky answers once the network has sent a downlink
+    pub fn downlink_received(&mut self) {
+        for slot in self.answers.iter_mut() {
+            if slot.is_some_and(|a| a.is_sticky()) {
+                *slot = None;
+            }
+        }
+        self.compact();
+    }
+
+    /// Keep queued answers contiguous and in order
+    fn compact(&mut self) {
+        let mut next = 0;
+        for i in 0..ANSWER_QUEUE_LEN {
+            if let Some(answer) = self.answers[i].take() {
+                self.answers[next] = Some(answer);
+                next += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_lengths_and_fields() {
+        let data = [
+            0x02, 0x14, 0x03, // LinkCheckAns: 20 dB, 3 gateways
+            0x03, 0x53, 0xFF, 0x00, 0x61, // LinkADRReq
+            0x04, 0xF7, // DutyCycleReq, RFU bits set
+            0x05, 0xB3, 0xD2, 0xAD, 0x84, // RXParamSetupReq, RFU bit set
+            0x06, // DevStatusReq
+            0x07, 0x03, 0x18, 0x4F, 0x84, 0x50, // NewChannelReq
+            0x08, 0xF5, // RXTimingSetupReq, RFU bits set
+            0x09, 0x2B, // TxParamSetupReq
+            0x0A, 0x03, 0x18, 0x4F, 0x84, // DlChannelReq
+        ];
+        let commands: Vec<_> = MacCommands::new(&data).collect();
+        assert_eq!(
+            commands,
+            [
+                Ok(MacCommand::LinkCheckAns { margin: 20, gateway_count: 3 }),
+                Ok(MacCommand::LinkAdrReq { data_rate: 5, tx_power: 3, ch_mask: 0x00FF, ch_mask_cntl: 6, nb_trans: 1 }),
+                Ok(MacCommand::DutyCycleReq { max_duty_cycle: 7 }),
+                Ok(MacCommand::RxParamSetupReq { rx1_dr_offset: 3, rx2_data_rate: 3, frequency: 869_525_000 }),
+                Ok(MacCommand::DevStatusReq),
+                Ok(MacCommand::NewChannelReq { index: 3, frequency: 867_100_000, min_dr: 0, max_dr: 5 }),
+                Ok(MacCommand::RxTimingSetupReq { delay: 5 }),
+                Ok(MacCommand::TxParamSetupReq { downlink_dwell_time: true, uplink_dwell_time: false, max_eirp: 27 }),
+                Ok(MacCommand::DlChannelReq { index: 3, frequency: 867_100_000 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn link_adr_req_block() {
+        // US915 sub-band 2: all 125 kHz channels off, then 8-15 on, as one block
+        let data = [0x03, 0x30, 0x00, 0x00, 0x72, 0x03, 0x30, 0x00, 0xFF, 0x03, 0x09, 0x10];
+        let mut commands = MacCommands::new(&data);
+        assert_eq!(
+            commands.next(),
+            Some(Ok(MacCommand::LinkAdrReq { data_rate: 3, tx_power: 0, ch_mask: 0, ch_mask_cntl: 7, nb_trans: 2 }))
+        );
+        assert_eq!(
+            commands.next(),
+            Some(Ok(MacCommand::LinkAdrReq {
+                data_rate: 3,
+                tx_power: 0,
+                ch_mask: 0xFF00,
+                ch_mask_cntl: 0,
+                nb_trans: 3,
+            }))
+        );
+        // TxParamSetupReq: uplink dwell time only, MaxEIRP 8 dBm
+        assert_eq!(
+            commands.next(),
+            Some(Ok(MacCommand::TxParamSetupReq { downlink_dwell_time: false, uplink_dwell_time: true, max_eirp: 8 }))
+        );
+        assert_eq!(commands.next(), None);
+    }
+
+    #[test]
+    fn truncated_command_stops_parsing() {
+        for (cid, len) in [(0x02, 2), (0x03, 4), (0x04, 1), (0x05, 4), (0x07, 5), (0x08, 1), (0x09, 1), (0x0A, 4)] {
+            let mut data = vec![0x06, cid];
+            data.resize(1 + len, 0);
+            let mut commands = MacCommands::new(&data);
+            assert_eq!(commands.next(), Some(Ok(MacCommand::DevStatusReq)));
+            assert_eq!(commands.next(), Some(Err(MacError::Truncated)), "CID {:02x}", cid);
+            assert_eq!(commands.next(), None);
+        }
+    }
+
+    #[test]
+    fn sticky_answers_repeat_until_downlink() {
+        let mut queue = MacAnswerQueue::new();
+        assert!(!queue.has_unsent());
+        queue.push(MacAnswer::RxParamSetupAns { status: 0x07 });
+        queue.push(MacAnswer::LinkAdrAns { status: 0x07 });
+        queue.push(MacAnswer::RxTimingSetupAns);
+        queue.push(MacAnswer::DlChannelAns { status: 0x03 });
+        assert!(queue.has_unsent());
+
+        let mut buf = [0u8; MAX_FOPTS_LEN];
+        let len = queue.encode(&mut buf);
+        assert_eq!(buf[..len], [0x05, 0x07, 0x03, 0x07, 0x08, 0x0A, 0x03]);
+        queue.uplink_sent(len);
+        assert!(!queue.has_unsent());
+
+        // Every uplink repeats the sticky answers until the network answers
+        for _ in 0..2 {
+            let len = queue.encode(&mut buf);
+            assert_eq!(buf[..len], [0x05, 0x07, 0x08, 0x0A, 0x03]);
+            queue.uplink_sent(len);
+            assert!(!queue.has_unsent());
+        }
+        queue.downlink_received();
+        assert!(queue.is_empty());
+
+        // Answers that did not fit are still unsent
+        queue.push(MacAnswer::DutyCycleAns);
+        queue.push(MacAnswer::DlChannelAns { status: 0x01 });
+        let len = queue.encode(&mut buf[..2]);
+        assert_eq!(buf[..len], [0x04]);
+        queue.uplink_sent(len);
+        assert!(queue.has_unsent());
+        let len = queue.encode(&mut buf);
+        assert_eq!(buf[..len], [0x0A, 0x01]);
+    }
+
+    #[test]
+    fn adr_param_setup_keeps_later_commands() {
+        // ADRParamSetupReq Limit_exp 7 Delay_exp 4, DevStatusReq, LinkADRReq
+        let data = [0x0C, 0x74, 0x06, 0x03, 0x51, 0x07, 0x00, 0x01];
+        let mut commands = MacCommands::new(&data);
+        assert_eq!(commands.next(), Some(Ok(MacCommand::AdrParamSetupReq { limit_exp: 7, delay_exp: 4 })));
+        assert_eq!(commands.next(), Some(Ok(MacCommand::DevStatusReq)));
+        assert_eq!(
+            commands.next(),
+            Some(Ok(MacCommand::LinkAdrReq {
+                data_rate: 5,
+                tx_power: 1,
+                ch_mask: 0x0007,
+                ch_mask_cntl: 0,
+                nb_trans: 1,
+            }))
+        );
+        assert_eq!(commands.next(), None);
+
+        let mut buf = [0u8; 2];
+        assert_eq!(MacAnswer::AdrParamSetupAns.encoded_len(), 1);
+        assert_eq!(MacAnswer::AdrParamSetupAns.encode(&mut buf), 1);
+        assert_eq!(buf[0], 0x0C);
+    }
+
+    #[test]
+    fn unknown_command_stops_parsing() {
+        let data = [0x06, 0x42, 0x06];
+        let mut commands = MacCommands::new(&data);
+        assert_eq!(commands.next(), Some(Ok(MacCommand::DevStatusReq)));
+        assert_eq!(commands.next(), Some(Err(MacError::UnknownCommand(0x42))));
+        assert_eq!(commands.next(), None);
+    }
+}
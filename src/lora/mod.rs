@@ -4,7 +4,28 @@
 //! and LoRaWAN protocol implementation.
 
 pub mod sx1276;
+pub mod crypto;
+pub mod region;
+pub mod duty_cycle;
+pub mod mac;
+pub mod frame;
+pub mod persist;
+pub mod time;
+pub mod class_b;
+pub mod fuota;
 pub mod lorawan;
+pub mod uplink_queue;
+pub mod task;
 
-pub use sx1276::SX1276;
-pub use lorawan::{LoRaWAN, LoRaWANConfig};
+pub use sx1276::{LoRaConfig, SX1276};
+pub use crypto::SessionKeys;
+pub use region::Region;
+pub use class_b::{Beacon, BeaconState};
+pub use lorawan::{
+    Activation, DeviceClass, Downlink, LinkCheck, LoRaWAN, LoRaWANConfig, LoRaWANVersion, MulticastGroup,
+    RejoinType, RetryPolicy,
+};
+pub use task::{LoRaWANChannels, PortHandler};
+#[cfg(target_os = "none")]
+pub use task::{lorawan_task, BoardLoRaWAN};
+pub use uplink_queue::{Priority, UplinkOptions, UplinkQueue};
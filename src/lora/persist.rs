@@ -0,0 +1,530 @@
+//! Non-volatile LoRaWAN state
+//!
+//! The stack keeps its session in a small storage partition so that a reboot
+//! resumes the session instead of rejoining. The partition holds:
+//!
+//! - `0..SESSION_AREA_LEN`: the session record (DevAddr, keys, DevNonce, RX
+//!   parameters, ADR state and channel plan), written when it changes, and
+//!   the join context (next DevNonce, last JoinNonce, RJcount1, join
+//!   back-off), which outlives sessions
+//! - `SESSION_AREA_LEN..STORAGE_LEN`: a round-robin log of frame counters
+//!
+//! Frame counters change on every uplink, so they are checkpointed ahead of
+//! use: a slot stores an FCntUp *limit* that the device will not reach
+//! before writing the next slot. After a reset the limit becomes the next
+//! FCntUp, skipping at most [`COUNTER_STRIDE`] values but never reusing one.
+//! With 32 slots and a stride of 16 each word sees one write every 512
+//! uplinks.
+
+use embassy_time::Duration;
+
+use super::crypto::{AesKey, SessionKeys};
+use super::duty_cycle::JoinBackoffProgress;
+use super::lorawan::{LoRaWANVersion, Session};
+use super::region::{ChannelPlan, Region, CHANNEL_PLAN_LEN};
+
+/// Bytes of storage the LoRaWAN stack needs
+pub const STORAGE_LEN: usize = SESSION_AREA_LEN + COUNTER_SLOTS * COUNTER_SLOT_LEN;
+
+/// FCntUp values reserved by each counter checkpoint
+pub const COUNTER_STRIDE: u32 = 16;
+
+/// Space reserved for the session record and the join context
+const SESSION_AREA_LEN: usize = 276;
+/// Session record version marker
+const SESSION_MAGIC: [u8; 4] = *b"AEN3";
+/// Fixed fields of the session record, before the channel plan
+const SESSION_HEADER_LEN: usize = 101;
+/// Serialized session record, CRC included
+const SESSION_RECORD_LEN: usize = SESSION_HEADER_LEN + CHANNEL_PLAN_LEN + 2;
+/// Join context, after the session record
+const JOIN_CONTEXT_OFFSET: usize = 260;
+/// Next DevNonce, last JoinNonce, RJcount1, join back-off, CRC
+const JOIN_CONTEXT_LEN: usize = 16;
+
+const COUNTER_SLOTS: usize = 32;
+/// Sequence, FCntUp limit, FCntDown, AFCntDown, CRC, padding
+const COUNTER_SLOT_LEN: usize = 20;
+
+/// Storage errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum NvError {
+    /// Access outside the storage partition
+    OutOfRange,
+    /// The storage could not be written
+    WriteFailed,
+}
+
+/// Byte-addressable non-volatile storage, such as the STM32L0 data EEPROM
+pub trait NvStorage {
+    /// Read `buf.len()` bytes at `offset`
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), NvError>;
+    /// Write `data` at `offset`
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), NvError>;
+}
+
+/// Session and MAC state saved across resets
+#[derive(Clone)]
+pub struct SessionRecord {
+    /// DevEUI (OTAA) or DevAddr (ABP) the session belongs to
+    pub id: [u8; 8],
+    /// Activated session; the frame counters live in the counter log
+    pub session: Session,
+    /// DevNonce of the last JoinRequest
+    pub dev_nonce: u16,
+    /// Uplink data rate
+    pub data_rate: u8,
+    /// TXPower index
+    pub tx_power: u8,
+    /// NbTrans from the last LinkADRReq
+    pub nb_trans: u8,
+    /// DutyCycleReq limit
+    pub max_duty_cycle: u8,
+    /// Uplink dwell time limit from TxParamSetupReq
+    pub dwell_time: bool,
+    /// Downlink dwell time limit from TxParamSetupReq
+    pub downlink_dwell_time: bool,
+    /// MaxEIRP from TxParamSetupReq
+    pub max_eirp: i8,
+    /// Limit_exp and Delay_exp from ADRParamSetupReq, as sent
+    pub adr_param: u8,
+    /// Channels and mask
+    pub channels: ChannelPlan,
+}
+
+impl SessionRecord {
+    /// Serialize with a trailing CRC
+    pub fn encode(&self) -> [u8; SESSION_RECORD_LEN] {
+        let mut b = [0u8; SESSION_RECORD_LEN];
+        let s = &self.session;
+        b[0..4].copy_from_slice(&SESSION_MAGIC);
+        b[4..12].copy_from_slice(&self.id);
+        b[12] = self.channels.region() as u8;
+        b[13..17].copy_from_slice(&s.dev_addr.to_le_bytes());
+        b[17..33].copy_from_slice(&s.keys.f_nwk_s_int_key);
+        b[33..49].copy_from_slice(&s.keys.s_nwk_s_int_key);
+        b[49..65].copy_from_slice(&s.keys.nwk_s_enc_key);
+        b[65..81].copy_from_slice(&s.keys.app_skey);
+        b[81..83].copy_from_slice(&self.dev_nonce.to_le_bytes());
+        b[83] = s.rx1_dr_offset;
+        b[84] = s.rx2_data_rate;
+        b[85..89].copy_from_slice(&s.rx2_frequency.to_le_bytes());
+        b[89] = s.rx_delay.as_secs() as u8;
+        b[90] = self.data_rate;
+        b[91] = self.tx_power;
+        b[92] = self.nb_trans;
+        b[93] = self.max_duty_cycle;
+        b[94] = self.dwell_time as u8 | (self.downlink_dwell_time as u8) << 1;
+        b[95] = self.max_eirp as u8;
+        b[96] = s.version as u8;
+        b[97..100].copy_from_slice(&s.net_id.to_le_bytes()[..3]);
+        b[100] = self.adr_param;
+        let plan = SESSION_HEADER_LEN..SESSION_HEADER_LEN + CHANNEL_PLAN_LEN;
+        b[plan].copy_from_slice(&self.channels.to_bytes());
+
+        let crc = crc16(&b[..SESSION_RECORD_LEN - 2]);
+        b[SESSION_RECORD_LEN - 2..].copy_from_slice(&crc.to_le_bytes());
+        b
+    }
+
+    /// Parse a record for `region`, `None` if it is blank, corrupt or for another region
+    pub fn decode(b: &[u8; SESSION_RECORD_LEN], region: Region) -> Option<Self> {
+        let crc = u16::from_le_bytes([b[SESSION_RECORD_LEN - 2], b[SESSION_RECORD_LEN - 1]]);
+        if b[0..4] != SESSION_MAGIC || crc16(&b[..SESSION_RECORD_LEN - 2]) != crc {
+            return None;
+        }
+        if b[12] != region as u8 {
+            return None;
+        }
+
+        let key = |at: usize| -> AesKey {
+            let mut key = [0u8; 16];
+            key.copy_from_slice(&b[at..at + 16]);
+            key
+        };
+        let u32_at = |at: usize| u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]]);
+
+        let version = match b[96] {
+            0 => LoRaWANVersion::V1_0,
+            1 => LoRaWANVersion::V1_1,
+            _ => return None,
+        };
+
+        let mut id = [0u8; 8];
+        id.copy_from_slice(&b[4..12]);
+        let mut plan = [0u8; CHANNEL_PLAN_LEN];
+        plan.copy_from_slice(&b[SESSION_HEADER_LEN..SESSION_HEADER_LEN + CHANNEL_PLAN_LEN]);
+
+        Some(Self {
+            id,
+            session: Session {
+                dev_addr: u32_at(13),
+                keys: SessionKeys {
+                    f_nwk_s_int_key: key(17),
+                    s_nwk_s_int_key: key(33),
+                    nwk_s_enc_key: key(49),
+                    app_skey: key(65),
+                },
+                version,
+                net_id: u32::from_le_bytes([b[97], b[98], b[99], 0]),
+                fcnt_up: 0,
+                fcnt_down: 0,
+                afcnt_down: 0,
+                rx1_dr_offset: b[83],
+                rx2_data_rate: b[84],
+                rx2_frequency: u32_at(85),
+                rx_delay: Duration::from_secs(b[89].max(1) as u64),
+            },
+            dev_nonce: u16::from_le_bytes([b[81], b[82]]),
+            data_rate: b[90],
+            tx_power: b[91],
+            nb_trans: b[92],
+            max_duty_cycle: b[93],
+            dwell_time: b[94] & 0x01 != 0,
+            downlink_dwell_time: b[94] & 0x02 != 0,
+            max_eirp: b[95] as i8,
+            adr_param: b[100],
+            channels: ChannelPlan::from_bytes(region, &plan),
+        })
+    }
+
+    /// Load the stored record for `region`, if any
+    pub fn load(storage: &mut dyn NvStorage, region: Region) -> Result<Option<Self>, NvError> {
+        let mut bytes = [0u8; SESSION_RECORD_LEN];
+        storage.read(0, &mut bytes)?;
+        Ok(Self::decode(&bytes, region))
+    }
+
+    /// Store the record
+    pub fn save(&self, storage: &mut dyn NvStorage) -> Result<(), NvError> {
+        storage.write(0, &self.encode())
+    }
+
+    /// Invalidate the stored record, forcing a join after the next reset
+    pub fn erase(storage: &mut dyn NvStorage) -> Result<(), NvError> {
+        storage.write(0, &[0u8; 4])
+    }
+}
+
+/// Join state that must never go backwards, independent of any session
+///
+/// Each field is written before the value goes on air (DevNonce, RJcount1)
+/// or right after it was accepted (JoinNonce, join back-off).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct JoinContext {
+    /// Next DevNonce to use; 0x1_0000 marks the DevNonce space as used up
+    pub next_dev_nonce: u32,
+    /// JoinNonce of the last accepted LoRaWAN 1.1 JoinAccept
+    pub join_nonce: Option<u32>,
+    /// Next RJcount1 for type 1 Rejoin-requests
+    pub rj_count1: u16,
+    /// Join back-off at the last JoinRequest
+    pub join_backoff: JoinBackoffProgress,
+}
+
+impl JoinContext {
+    /// Load the join context, all zero if none was stored
+    pub fn load(storage: &mut dyn NvStorage) -> Result<Self, NvError> {
+        let mut b = [0u8; JOIN_CONTEXT_LEN];
+        storage.read(JOIN_CONTEXT_OFFSET, &mut b)?;
+        let crc = u16::from_le_bytes([b[14], b[15]]);
+        if crc16(&b[..14]) != crc {
+            return Ok(Self::default());
+        }
+        let join_nonce = u32::from_le_bytes([b[4], b[5], b[6], b[7]]);
+        Ok(Self {
+            next_dev_nonce: u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            join_nonce: (join_nonce != u32::MAX).then_some(join_nonce),
+            rj_count1: u16::from_le_bytes([b[8], b[9]]),
+            join_backoff: JoinBackoffProgress {
+                elapsed_min: u16::from_le_bytes([b[10], b[11]]),
+                time_off_s: u16::from_le_bytes([b[12], b[13]]),
+            },
+        })
+    }
+
+    /// Store the join context
+    pub fn save(&self, storage: &mut dyn NvStorage) -> Result<(), NvError> {
+        let mut b = [0u8; JOIN_CONTEXT_LEN];
+        b[0..4].copy_from_slice(&self.next_dev_nonce.to_le_bytes());
+        b[4..8].copy_from_slice(&self.join_nonce.unwrap_or(u32::MAX).to_le_bytes());
+        b[8..10].copy_from_slice(&self.rj_count1.to_le_bytes());
+        b[10..12].copy_from_slice(&self.join_backoff.elapsed_min.to_le_bytes());
+        b[12..14].copy_from_slice(&self.join_backoff.time_off_s.to_le_bytes());
+        let crc = crc16(&b[..14]);
+        b[14..16].copy_from_slice(&crc.to_le_bytes());
+        storage.write(JOIN_CONTEXT_OFFSET, &b)
+    }
+}
+
+/// Frame counters restored from the counter log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Counters {
+    /// First FCntUp that is certainly unused
+    pub fcnt_up: u32,
+    /// Next expected FCntDown (NFCntDown in 1.1) at the last checkpoint
+    pub fcnt_down: u32,
+    /// Next expected AFCntDown at the last checkpoint (1.1 only)
+    pub afcnt_down: u32,
+}
+
+/// Round-robin frame counter log
+///
+/// The newest slot wins, so a new session simply appends; stale slots of an
+/// earlier session are never read back. Sequence numbers are compared as
+/// serial numbers, so the log keeps working when they wrap.
+#[derive(Clone, Default)]
+pub struct CounterLog {
+    /// Sequence number of the newest slot (0 = log empty)
+    seq: u32,
+}
+
+impl CounterLog {
+    /// Log state before [`load`](Self::load)
+    pub const fn new() -> Self {
+        Self { seq: 0 }
+    }
+
+    /// Find the newest valid slot
+    pub fn load(storage: &mut dyn NvStorage) -> Result<(Self, Option<Counters>), NvError> {
+        let mut newest: Option<(u32, Counters)> = None;
+        for slot in 0..COUNTER_SLOTS {
+            let mut b = [0u8; COUNTER_SLOT_LEN];
+            storage.read(Self::offset(slot), &mut b)?;
+            let Some((seq, counters)) = Self::decode_slot(&b) else {
+                continue;
+            };
+            if newest.is_none_or(|(newest_seq, _)| (seq.wrapping_sub(newest_seq) as i32) > 0) {
+                newest = Some((seq, counters));
+            }
+        }
+
+        let seq = newest.map_or(0, |(seq, _)| seq);
+        Ok((Self { seq }, newest.map(|(_, counters)| counters)))
+    }
+
+    /// Append a checkpoint in the next slot
+    pub fn append(&mut self, storage: &mut dyn NvStorage, counters: Counters) -> Result<(), NvError> {
+        let seq = self.seq.wrapping_add(1).max(1);
+        let slot = seq as usize % COUNTER_SLOTS;
+
+        let mut b = [0u8; COUNTER_SLOT_LEN];
+        b[0..4].copy_from_slice(&seq.to_le_bytes());
+        b[4..8].copy_from_slice(&counters.fcnt_up.to_le_bytes());
+        b[8..12].copy_from_slice(&counters.fcnt_down.to_le_bytes());
+        b[12..16].copy_from_slice(&counters.afcnt_down.to_le_bytes());
+        let crc = crc16(&b[..16]);
+        b[16..18].copy_from_slice(&crc.to_le_bytes());
+
+        storage.write(Self::offset(slot), &b)?;
+        self.seq = seq;
+        Ok(())
+    }
+
+    fn offset(slot: usize) -> usize {
+        SESSION_AREA_LEN + slot * COUNTER_SLOT_LEN
+    }
+
+    fn decode_slot(b: &[u8; COUNTER_SLOT_LEN]) -> Option<(u32, Counters)> {
+        let seq = u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+        let crc = u16::from_le_bytes([b[16], b[17]]);
+        if seq == 0 || crc16(&b[..16]) != crc {
+            return None;
+        }
+        let counters = Counters {
+            fcnt_up: u32::from_le_bytes([b[4], b[5], b[6], b[7]]),
+            fcnt_down: u32::from_le_bytes([b[8], b[9], b[10], b[11]]),
+            afcnt_down: u32::from_le_bytes([b[12], b[13], b[14], b[15]]),
+        };
+        Some((seq, counters))
+    }
+}
+
+/// CRC-16/CCITT-FALSE
+pub(crate) fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::lora::region::Channel;
+
+    pub(crate) struct Memory(pub(crate) Vec<u8>);
+
+    impl Memory {
+        /// `len` bytes of erased storage
+        pub(crate) fn blank(len: usize) -> Self {
+            Self(vec![0xFF; len])
+        }
+
+        /// Lose every frame counter checkpoint
+        pub(crate) fn erase_counter_log(&mut self) {
+            self.0[SESSION_AREA_LEN..].fill(0xFF);
+        }
+    }
+
+    impl NvStorage for Memory {
+        fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), NvError> {
+            let src = self.0.get(offset..offset + buf.len()).ok_or(NvError::OutOfRange)?;
+            buf.copy_from_slice(src);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), NvError> {
+            let dest = self.0.get_mut(offset..offset + data.len()).ok_or(NvError::OutOfRange)?;
+            dest.copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn join_context_round_trip() {
+        let mut storage = Memory::blank(STORAGE_LEN);
+        assert_eq!(JoinContext::load(&mut storage), Ok(JoinContext::default()));
+
+        let context = JoinContext {
+            next_dev_nonce: 12,
+            join_nonce: Some(0xABCDEF),
+            rj_count1: 3,
+            join_backoff: JoinBackoffProgress { elapsed_min: 125, time_off_s: 2_465 },
+        };
+        context.save(&mut storage).unwrap();
+        SessionRecord::erase(&mut storage).unwrap();
+        assert_eq!(JoinContext::load(&mut storage), Ok(context));
+
+        storage.0[JOIN_CONTEXT_OFFSET + 10] ^= 1;
+        assert_eq!(JoinContext::load(&mut storage), Ok(JoinContext::default()));
+    }
+
+    fn record() -> SessionRecord {
+        let mut channels = ChannelPlan::new(Region::EU868, None);
+        channels.set_channel(3, Some(Channel { frequency: 867_100_000, min_dr: 0, max_dr: 5 }));
+        SessionRecord {
+            id: [1, 2, 3, 4, 5, 6, 7, 8],
+            session: Session {
+                dev_addr: 0x2601_1F2A,
+                keys: SessionKeys {
+                    f_nwk_s_int_key: [0x11; 16],
+                    s_nwk_s_int_key: [0x22; 16],
+                    nwk_s_enc_key: [0x33; 16],
+                    app_skey: [0x44; 16],
+                },
+                version: LoRaWANVersion::V1_1,
+                net_id: 0x00_0013,
+                fcnt_up: 0,
+                fcnt_down: 0,
+                afcnt_down: 0,
+                rx1_dr_offset: 2,
+                rx2_data_rate: 3,
+                rx2_frequency: 869_525_000,
+                rx_delay: Duration::from_secs(5),
+            },
+            dev_nonce: 0x0102,
+            data_rate: 4,
+            tx_power: 2,
+            nb_trans: 3,
+            max_duty_cycle: 7,
+            dwell_time: true,
+            downlink_dwell_time: false,
+            max_eirp: 14,
+            adr_param: 0x45,
+            channels,
+        }
+    }
+
+    fn counters(fcnt_up: u32) -> Counters {
+        Counters { fcnt_up, fcnt_down: fcnt_up / 2, afcnt_down: fcnt_up / 4 }
+    }
+
+    #[test]
+    fn session_record_round_trip() {
+        let record = record();
+        let bytes = record.encode();
+        let decoded = SessionRecord::decode(&bytes, Region::EU868).unwrap();
+        assert_eq!(decoded.encode(), bytes);
+
+        let s = &decoded.session;
+        assert_eq!(decoded.id, record.id);
+        assert_eq!(s.dev_addr, 0x2601_1F2A);
+        assert!(s.keys == record.session.keys);
+        assert_eq!(s.version, LoRaWANVersion::V1_1);
+        assert_eq!(s.net_id, 0x13);
+        assert_eq!((s.rx1_dr_offset, s.rx2_data_rate, s.rx2_frequency), (2, 3, 869_525_000));
+        assert_eq!(s.rx_delay, Duration::from_secs(5));
+        assert_eq!(decoded.dev_nonce, 0x0102);
+        assert_eq!((decoded.data_rate, decoded.tx_power, decoded.nb_trans), (4, 2, 3));
+        assert_eq!(decoded.max_duty_cycle, 7);
+        assert!(decoded.dwell_time && !decoded.downlink_dwell_time);
+        assert_eq!((decoded.max_eirp, decoded.adr_param), (14, 0x45));
+        assert_eq!(decoded.channels.channel(3).map(|c| c.frequency), Some(867_100_000));
+        assert!(decoded.channels.is_enabled(3));
+
+        // Kept in storage, and gone once erased
+        let mut storage = Memory::blank(STORAGE_LEN);
+        assert!(SessionRecord::load(&mut storage, Region::EU868).unwrap().is_none());
+        record.save(&mut storage).unwrap();
+        assert!(SessionRecord::load(&mut storage, Region::EU868).unwrap().is_some());
+        assert!(SessionRecord::load(&mut storage, Region::US915).unwrap().is_none());
+        SessionRecord::erase(&mut storage).unwrap();
+        assert!(SessionRecord::load(&mut storage, Region::EU868).unwrap().is_none());
+    }
+
+    #[test]
+    fn session_record_crc_rejects_corruption() {
+        let bytes = record().encode();
+        for at in [4, 17, 90, SESSION_HEADER_LEN + 1, SESSION_RECORD_LEN - 1] {
+            let mut corrupt = bytes;
+            corrupt[at] ^= 0x04;
+            assert!(SessionRecord::decode(&corrupt, Region::EU868).is_none(), "byte {}", at);
+        }
+    }
+
+    #[test]
+    fn counter_log_newest_valid_slot_wins() {
+        let mut storage = Memory::blank(STORAGE_LEN);
+        let (mut log, restored) = CounterLog::load(&mut storage).unwrap();
+        assert_eq!(restored, None);
+
+        // More checkpoints than slots: the log goes round, the last one wins
+        for fcnt_up in 1..=COUNTER_SLOTS as u32 + 5 {
+            log.append(&mut storage, counters(fcnt_up * COUNTER_STRIDE)).unwrap();
+        }
+        let newest = counters((COUNTER_SLOTS as u32 + 5) * COUNTER_STRIDE);
+        let (mut log, restored) = CounterLog::load(&mut storage).unwrap();
+        assert_eq!(restored, Some(newest));
+
+        // A torn write of the newest slot falls back to the one before
+        log.append(&mut storage, counters(1_000)).unwrap();
+        let slot = log.seq as usize % COUNTER_SLOTS;
+        storage.0[CounterLog::offset(slot) + 5] ^= 0x01;
+        let (log, restored) = CounterLog::load(&mut storage).unwrap();
+        assert_eq!(restored, Some(newest));
+
+        // The next checkpoint goes after the one that was restored
+        let mut log = log;
+        log.append(&mut storage, counters(2_000)).unwrap();
+        assert_eq!(CounterLog::load(&mut storage).unwrap().1, Some(counters(2_000)));
+    }
+
+    #[test]
+    fn counter_log_sequence_wraps() {
+        let mut storage = Memory::blank(STORAGE_LEN);
+        let mut log = CounterLog { seq: u32::MAX - 2 };
+        for fcnt_up in [100, 200, 300, 400, 500] {
+            log.append(&mut storage, counters(fcnt_up)).unwrap();
+        }
+        // Sequence numbers u32::MAX - 1, u32::MAX, 1, 2, 3; 0 marks a blank slot
+        assert_eq!(log.seq, 3);
+        let (log, restored) = CounterLog::load(&mut storage).unwrap();
+        assert_eq!(log.seq, 3);
+        assert_eq!(restored, Some(counters(500)));
+    }
+}
@@ -0,0 +1,722 @@
+//! LoRaWAN regional parameters
+//!
+//! Channel plans, data rate tables, payload limits and RX2 defaults for the
+//! supported regions, following the LoRaWAN Regional Parameters (RP002).
+//! Nothing here touches the radio, so it can be exercised on the host.
+
+/// Antenna gain assumed when converting EIRP limits to conducted power
+pub const ANTENNA_GAIN_DBI: i8 = 2;
+
+/// Maximum number of channels in a dynamic (EU868-style) channel plan
+pub const MAX_DYNAMIC_CHANNELS: usize = 16;
+
+/// Number of uplink channels in a fixed (US915/AU915) channel plan
+pub const FIXED_CHANNELS: usize = 72;
+
+/// Number of duty-cycle sub-bands of any region
+pub const MAX_BANDS: usize = 6;
+
+/// Size of a serialized [`ChannelPlan`]: mask, then per channel
+/// frequency, DR range and downlink frequency
+pub const CHANNEL_PLAN_LEN: usize = 10 + MAX_DYNAMIC_CHANNELS * 9;
+
+/// LoRaWAN region
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Region {
+    /// Europe 863-870 MHz
+    EU868,
+    /// United States 902-928 MHz
+    US915,
+    /// Australia 915-928 MHz
+    AU915,
+    /// Asia 923 MHz (AS923-1, including Hong Kong)
+    AS923,
+    /// India 865-867 MHz
+    IN865,
+    /// South Korea 920-923 MHz
+    KR920,
+}
+
+/// LoRa modulation parameters of a data rate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataRate {
+    /// Spreading factor (7-12)
+    pub spreading_factor: u8,
+    /// Bandwidth in Hz
+    pub bandwidth: u32,
+}
+
+const fn dr(spreading_factor: u8, bandwidth: u32) -> Option<DataRate> {
+    Some(DataRate { spreading_factor, bandwidth })
+}
+
+/// DR0-DR6 for EU868 and AS923
+const DR_TABLE_DYNAMIC: [Option<DataRate>; 16] = [
+    dr(12, 125_000), dr(11, 125_000), dr(10, 125_000), dr(9, 125_000),
+    dr(8, 125_000), dr(7, 125_000), dr(7, 250_000), None,
+    None, None, None, None, None, None, None, None,
+];
+
+/// DR0-DR5 for IN865 and KR920
+const DR_TABLE_DYNAMIC_125: [Option<DataRate>; 16] = [
+    dr(12, 125_000), dr(11, 125_000), dr(10, 125_000), dr(9, 125_000),
+    dr(8, 125_000), dr(7, 125_000), None, None,
+    None, None, None, None, None, None, None, None,
+];
+
+const DR_TABLE_US915: [Option<DataRate>; 16] = [
+    dr(10, 125_000), dr(9, 125_000), dr(8, 125_000), dr(7, 125_000),
+    dr(8, 500_000), None, None, None,
+    dr(12, 500_000), dr(11, 500_000), dr(10, 500_000), dr(9, 500_000),
+    dr(8, 500_000), dr(7, 500_000), None, None,
+];
+
+const DR_TABLE_AU915: [Option<DataRate>; 16] = [
+    dr(12, 125_000), dr(11, 125_000), dr(10, 125_000), dr(9, 125_000),
+    dr(8, 125_000), dr(7, 125_000), dr(8, 500_000), None,
+    dr(12, 500_000), dr(11, 500_000), dr(10, 500_000), dr(9, 500_000),
+    dr(8, 500_000), dr(7, 500_000), None, None,
+];
+
+/// Maximum application payload (N) per data rate; 0 marks an unusable data rate
+const N_DYNAMIC: [u8; 16] = [51, 51, 51, 115, 242, 242, 242, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+const N_DYNAMIC_DWELL: [u8; 16] = [0, 0, 11, 53, 125, 242, 242, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+const N_US915: [u8; 16] = [11, 53, 125, 242, 242, 0, 0, 0, 53, 129, 242, 242, 242, 242, 0, 0];
+const N_AU915: [u8; 16] = [51, 51, 51, 115, 242, 242, 242, 0, 53, 129, 242, 242, 242, 242, 0, 0];
+const N_AU915_DWELL: [u8; 16] = [0, 0, 11, 53, 125, 242, 242, 0, 53, 129, 242, 242, 242, 242, 0, 0];
+
+/// An uplink channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Channel {
+    /// Uplink frequency in Hz
+    pub frequency: u32,
+    /// Lowest data rate allowed on the channel
+    pub min_dr: u8,
+    /// Highest data rate allowed on the channel
+    pub max_dr: u8,
+}
+
+const fn ch(frequency: u32) -> Channel {
+    Channel { frequency, min_dr: 0, max_dr: 5 }
+}
+
+const DEFAULT_CHANNELS_EU868: [Channel; 3] = [ch(868_100_000), ch(868_300_000), ch(868_500_000)];
+const DEFAULT_CHANNELS_AS923: [Channel; 2] = [ch(923_200_000), ch(923_400_000)];
+const DEFAULT_CHANNELS_IN865: [Channel; 3] = [ch(865_062_500), ch(865_402_500), ch(865_985_000)];
+const DEFAULT_CHANNELS_KR920: [Channel; 3] = [ch(922_100_000), ch(922_300_000), ch(922_500_000)];
+
+/// Class B beacon of a region
+///
+/// BCNPayload = RFU | Time | CRC | GwSpecific | RFU | CRC, sent with
+/// implicit header, no PHY CRC and non-inverted IQ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BeaconFormat {
+    /// Beacon data rate
+    pub data_rate: u8,
+    /// RFU bytes before the time field
+    pub rfu1_len: usize,
+    /// RFU bytes after the gateway-specific field
+    pub rfu2_len: usize,
+}
+
+impl BeaconFormat {
+    /// Length of the beacon payload
+    pub fn len(&self) -> usize {
+        self.rfu1_len + 4 + 2 + 7 + self.rfu2_len + 2
+    }
+
+    /// Beacons are never empty
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+}
+
+impl Region {
+    /// Fixed channel plans have 64 + 8 predefined uplink channels
+    pub fn is_fixed_plan(&self) -> bool {
+        matches!(self, Region::US915 | Region::AU915)
+    }
+
+    /// Modulation parameters for `dr`
+    pub fn data_rate(&self, dr: u8) -> Option<DataRate> {
+        let table = match self {
+            Region::EU868 | Region::AS923 => &DR_TABLE_DYNAMIC,
+            Region::IN865 | Region::KR920 => &DR_TABLE_DYNAMIC_125,
+            Region::US915 => &DR_TABLE_US915,
+            Region::AU915 => &DR_TABLE_AU915,
+        };
+        table.get(dr as usize).copied().flatten()
+    }
+
+    /// Lowest uplink data rate
+    pub fn min_data_rate(&self) -> u8 {
+        0
+    }
+
+    /// Highest LoRa uplink data rate
+    pub fn max_data_rate(&self) -> u8 {
+        match self {
+            Region::EU868 | Region::AS923 | Region::AU915 => 6,
+            Region::US915 => 4,
+            Region::IN865 | Region::KR920 => 5,
+        }
+    }
+
+    /// Data rate used after join until the network says otherwise (SF10/125 kHz)
+    pub fn default_data_rate(&self) -> u8 {
+        match self {
+            Region::US915 => 0,
+            _ => 2,
+        }
+    }
+
+    /// Data rates a JoinRequest may use; the stack picks one at random
+    pub fn join_data_rates(&self) -> core::ops::RangeInclusive<u8> {
+        match self {
+            Region::US915 => 0..=4,
+            Region::AU915 => 2..=6,
+            _ => 0..=5,
+        }
+    }
+
+    /// Maximum application payload for `dr`, `None` if `dr` cannot carry data
+    ///
+    /// `dwell_time` selects the 400 ms uplink dwell time tables (AS923, AU915).
+    pub fn max_payload(&self, dr: u8, dwell_time: bool) -> Option<usize> {
+        let table = match (self, dwell_time) {
+            (Region::US915, _) => &N_US915,
+            (Region::AU915, false) => &N_AU915,
+            (Region::AU915, true) => &N_AU915_DWELL,
+            (Region::AS923, true) => &N_DYNAMIC_DWELL,
+            (Region::IN865 | Region::KR920, _) if dr > 5 => return None,
+            _ => &N_DYNAMIC,
+        };
+        match table.get(dr as usize) {
+            Some(&n) if n > 0 => Some(n as usize),
+            _ => None,
+        }
+    }
+
+    /// Whether the uplink dwell time limit applies until TxParamSetupReq says otherwise
+    pub fn default_dwell_time(&self) -> bool {
+        matches!(self, Region::AS923)
+    }
+
+    /// Default RX2 frequency (Hz) and data rate
+    pub fn rx2_default(&self) -> (u32, u8) {
+        match self {
+            Region::EU868 => (869_525_000, 0),
+            Region::US915 | Region::AU915 => (923_300_000, 8),
+            Region::AS923 => (923_200_000, 2),
+            Region::IN865 => (866_550_000, 2),
+            Region::KR920 => (921_900_000, 0),
+        }
+    }
+
+    /// Layout and data rate of the Class B beacon
+    pub fn beacon_format(&self) -> BeaconFormat {
+        match self {
+            Region::EU868 | Region::AS923 | Region::KR920 => {
+                BeaconFormat { data_rate: 3, rfu1_len: 2, rfu2_len: 0 }
+            }
+            Region::US915 | Region::AU915 => BeaconFormat { data_rate: 8, rfu1_len: 5, rfu2_len: 3 },
+            Region::IN865 => BeaconFormat { data_rate: 4, rfu1_len: 1, rfu2_len: 3 },
+        }
+    }
+
+    /// Beacon channel of the beacon sent at `beacon_time` (GPS seconds)
+    ///
+    /// US915 and AU915 hop over 8 channels, one per beacon period; the
+    /// other regions use a single channel 0.
+    pub fn beacon_channel(&self, beacon_time: u32) -> u8 {
+        match self {
+            Region::US915 | Region::AU915 => ((beacon_time / 128) % 8) as u8,
+            _ => 0,
+        }
+    }
+
+    /// Default ping slot channel of `dev_addr` in the beacon period starting at `beacon_time`
+    pub fn ping_slot_channel(&self, beacon_time: u32, dev_addr: u32) -> u8 {
+        match self {
+            Region::US915 | Region::AU915 => ((beacon_time / 128).wrapping_add(dev_addr) % 8) as u8,
+            _ => 0,
+        }
+    }
+
+    /// Frequency (Hz) of beacon and default ping slot channel `channel`
+    pub fn beacon_frequency(&self, channel: u8) -> u32 {
+        match self {
+            Region::EU868 => 869_525_000,
+            Region::US915 | Region::AU915 => 923_300_000 + 600_000 * (channel % 8) as u32,
+            Region::AS923 => 923_400_000,
+            Region::IN865 => 866_550_000,
+            Region::KR920 => 923_100_000,
+        }
+    }
+
+    /// Default ping slot data rate
+    pub fn ping_slot_data_rate(&self) -> u8 {
+        self.beacon_format().data_rate
+    }
+
+    /// Maximum EIRP in dBm
+    pub fn max_eirp(&self) -> i8 {
+        match self {
+            Region::EU868 | Region::AS923 => 16,
+            Region::US915 | Region::AU915 | Region::IN865 => 30,
+            Region::KR920 => 14,
+        }
+    }
+
+    /// Highest valid TXPower index
+    pub fn max_tx_power_index(&self) -> u8 {
+        match self {
+            Region::US915 | Region::AU915 => 14,
+            Region::IN865 => 10,
+            _ => 7,
+        }
+    }
+
+    /// EIRP in dBm for a TXPower index (MaxEIRP - 2 dB per step)
+    pub fn tx_power_eirp(&self, index: u8) -> Option<i8> {
+        if index > self.max_tx_power_index() {
+            return None;
+        }
+        Some(self.max_eirp() - 2 * index as i8)
+    }
+
+    /// Conducted power in dBm for a TXPower index, clamped to the SX1276 range
+    ///
+    /// `max_eirp` is the regional limit unless TxParamSetupReq lowered it.
+    pub fn tx_power_dbm(&self, index: u8, max_eirp: i8) -> Option<i8> {
+        if index > self.max_tx_power_index() {
+            return None;
+        }
+        Some((max_eirp - 2 * index as i8 - ANTENNA_GAIN_DBI).clamp(2, 20))
+    }
+
+    /// Duty-cycle sub-band of `frequency` as (index, 1 / duty cycle)
+    ///
+    /// `None` where the region has no duty-cycle limit. EU868 follows the
+    /// ETSI EN 300 220 sub-bands; frequencies between them get the strictest
+    /// 0.1 % limit.
+    pub fn duty_cycle_band(&self, frequency: u32) -> Option<(usize, u32)> {
+        if *self != Region::EU868 {
+            return None;
+        }
+        let band = match frequency {
+            865_000_000..=867_999_999 => (1, 100),
+            868_000_000..=868_600_000 => (2, 100),
+            868_700_000..=869_200_000 => (3, 1000),
+            869_400_000..=869_650_000 => (4, 10),
+            869_700_000..=870_000_000 => (5, 100),
+            _ => (0, 1000),
+        };
+        Some(band)
+    }
+
+    /// Whether `frequency` lies inside the region's band
+    pub fn is_valid_frequency(&self, frequency: u32) -> bool {
+        let band = match self {
+            Region::EU868 => 863_000_000..=870_000_000,
+            Region::US915 => 902_000_000..=928_000_000,
+            Region::AU915 | Region::AS923 => 915_000_000..=928_000_000,
+            Region::IN865 => 865_000_000..=867_000_000,
+            Region::KR920 => 920_900_000..=923_300_000,
+        };
+        band.contains(&frequency)
+    }
+
+    /// RX1 data rate for an uplink at `dr` with the given RX1DROffset
+    pub fn rx1_data_rate(&self, dr: u8, offset: u8, dwell_time: bool) -> u8 {
+        match self {
+            Region::US915 => {
+                let base = if dr == 4 { 13 } else { 10 + dr.min(3) };
+                base.saturating_sub(offset).max(8)
+            }
+            Region::AU915 => {
+                let base = (8 + dr).min(13);
+                base.saturating_sub(offset).max(8)
+            }
+            Region::AS923 | Region::IN865 => {
+                // Offsets 6 and 7 raise the data rate
+                let min = if dwell_time && *self == Region::AS923 { 2 } else { 0 };
+                let shifted = match offset {
+                    6 => dr as i8 + 1,
+                    7 => dr as i8 + 2,
+                    o => dr as i8 - o as i8,
+                };
+                shifted.clamp(min, 5) as u8
+            }
+            Region::EU868 | Region::KR920 => dr.saturating_sub(offset),
+        }
+    }
+
+    /// Highest RX1DROffset accepted by RXParamSetupReq
+    pub fn max_rx1_dr_offset(&self) -> u8 {
+        match self {
+            Region::US915 => 3,
+            Region::AS923 | Region::IN865 => 7,
+            _ => 5,
+        }
+    }
+
+    /// Whether the region implements TxParamSetupReq
+    pub fn supports_tx_param_setup(&self) -> bool {
+        matches!(self, Region::AS923 | Region::AU915)
+    }
+
+    /// RX1 frequency for an uplink on `channel` at `frequency`
+    pub fn rx1_frequency(&self, channel: usize, frequency: u32) -> u32 {
+        if self.is_fixed_plan() {
+            923_300_000 + (channel % 8) as u32 * 600_000
+        } else {
+            frequency
+        }
+    }
+
+    /// Channels every device knows without a CFList
+    pub fn default_channels(&self) -> &'static [Channel] {
+        match self {
+            Region::EU868 => &DEFAULT_CHANNELS_EU868,
+            Region::AS923 => &DEFAULT_CHANNELS_AS923,
+            Region::IN865 => &DEFAULT_CHANNELS_IN865,
+            Region::KR920 => &DEFAULT_CHANNELS_KR920,
+            Region::US915 | Region::AU915 => &[],
+        }
+    }
+
+    /// Uplink channel `index` of a fixed channel plan
+    fn fixed_channel(&self, index: usize) -> Option<Channel> {
+        let (base_125, base_500, max_125, dr_500) = match self {
+            Region::US915 => (902_300_000, 903_000_000, 3, 4),
+            Region::AU915 => (915_200_000, 915_900_000, 5, 6),
+            _ => return None,
+        };
+        match index {
+            0..=63 => Some(Channel {
+                frequency: base_125 + index as u32 * 200_000,
+                min_dr: 0,
+                max_dr: max_125,
+            }),
+            64..=71 => Some(Channel {
+                frequency: base_500 + (index - 64) as u32 * 1_600_000,
+                min_dr: dr_500,
+                max_dr: dr_500,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Set of uplink channels the device may use
+#[derive(Clone)]
+pub struct ChannelPlan {
+    region: Region,
+    /// Dynamic plans: defined channels
+    channels: [Option<Channel>; MAX_DYNAMIC_CHANNELS],
+    /// Dynamic plans: RX1 frequency set by DlChannelReq (0 = uplink frequency)
+    dl_frequencies: [u32; MAX_DYNAMIC_CHANNELS],
+    /// Enabled channels, one bit per channel (72 for fixed plans)
+    mask: [u16; 5],
+}
+
+impl ChannelPlan {
+    /// Create the default plan for `region`
+    ///
+    /// `sub_band` (1-8) limits US915/AU915 devices to one block of eight
+    /// 125 kHz channels plus the matching 500 kHz channel, as most gateways
+    /// only listen on one sub-band.
+    pub fn new(region: Region, sub_band: Option<u8>) -> Self {
+        let mut plan = Self {
+            region,
+            channels: [None; MAX_DYNAMIC_CHANNELS],
+            dl_frequencies: [0; MAX_DYNAMIC_CHANNELS],
+            mask: [0; 5],
+        };
+
+        if region.is_fixed_plan() {
+            match sub_band {
+                Some(band @ 1..=8) => {
+                    let band = (band - 1) as usize;
+                    for i in band * 8..band * 8 + 8 {
+                        plan.set_enabled(i, true);
+                    }
+                    plan.set_enabled(64 + band, true);
+                }
+                _ => {
+                    for i in 0..FIXED_CHANNELS {
+                        plan.set_enabled(i, true);
+                    }
+                }
+            }
+        } else {
+            for (i, channel) in region.default_channels().iter().enumerate() {
+                plan.channels[i] = Some(*channel);
+                plan.set_enabled(i, true);
+            }
+        }
+
+        plan
+    }
+
+    /// Region of this plan
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Number of channel slots (16 dynamic, 72 fixed)
+    pub fn slots(&self) -> usize {
+        if self.region.is_fixed_plan() {
+            FIXED_CHANNELS
+        } else {
+            MAX_DYNAMIC_CHANNELS
+        }
+    }
+
+    /// Channel definition at `index`, enabled or not
+    pub fn channel(&self, index: usize) -> Option<Channel> {
+        if self.region.is_fixed_plan() {
+            self.region.fixed_channel(index)
+        } else {
+            self.channels.get(index).copied().flatten()
+        }
+    }
+
+    /// Whether `index` is defined and enabled
+    pub fn is_enabled(&self, index: usize) -> bool {
+        index < self.slots()
+            && self.mask[index / 16] & (1 << (index % 16)) != 0
+            && self.channel(index).is_some()
+    }
+
+    /// Enable or disable channel `index`
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) {
+        if index >= self.slots() {
+            return;
+        }
+        if enabled {
+            self.mask[index / 16] |= 1 << (index % 16);
+        } else {
+            self.mask[index / 16] &= !(1 << (index % 16));
+        }
+    }
+
+    /// Define (or remove, with `None`) a channel of a dynamic plan
+    ///
+    /// The default channels cannot be modified.
+    pub fn set_channel(&mut self, index: usize, channel: Option<Channel>) -> bool {
+        if self.region.is_fixed_plan()
+            || index < self.region.default_channels().len()
+            || index >= MAX_DYNAMIC_CHANNELS
+        {
+            return false;
+        }
+        self.channels[index] = channel;
+        self.dl_frequencies[index] = 0;
+        self.set_enabled(index, channel.is_some());
+        true
+    }
+
+    /// Set the RX1 downlink frequency of a defined channel (dynamic plans only)
+    pub fn set_downlink_frequency(&mut self, index: usize, frequency: u32) -> bool {
+        if self.region.is_fixed_plan() || self.channel(index).is_none() {
+            return false;
+        }
+        self.dl_frequencies[index] = frequency;
+        true
+    }
+
+    /// RX1 frequency for an uplink on channel `index`
+    pub fn rx1_frequency(&self, index: usize, uplink_frequency: u32) -> u32 {
+        match self.dl_frequencies.get(index) {
+            Some(&f) if f != 0 && !self.region.is_fixed_plan() => f,
+            _ => self.region.rx1_frequency(index, uplink_frequency),
+        }
+    }
+
+    /// Apply one ChMask/ChMaskCntl pair from a LinkADRReq
+    ///
+    /// Returns `false` if the pair is invalid for the region. The caller
+    /// must still check that at least one channel is left enabled.
+    pub fn apply_ch_mask(&mut self, ch_mask_cntl: u8, ch_mask: u16) -> bool {
+        if self.region.is_fixed_plan() {
+            match ch_mask_cntl {
+                0..=3 => self.mask[ch_mask_cntl as usize] = ch_mask,
+                4 => self.mask[4] = ch_mask & 0x00FF,
+                5 => {
+                    // Each of the 8 LSBs enables a bank of eight 125 kHz channels
+                    // together with the matching 500 kHz channel
+                    for bank in 0..8 {
+                        let on = ch_mask & (1 << bank) != 0;
+                        for i in bank * 8..bank * 8 + 8 {
+                            self.set_enabled(i, on);
+                        }
+                        self.set_enabled(64 + bank, on);
+                    }
+                }
+                6 | 7 => {
+                    let all = if ch_mask_cntl == 6 { 0xFFFF } else { 0x0000 };
+                    self.mask[..4].fill(all);
+                    self.mask[4] = ch_mask & 0x00FF;
+                }
+                _ => return false,
+            }
+        } else {
+            match ch_mask_cntl {
+                0 => {
+                    for i in 0..MAX_DYNAMIC_CHANNELS {
+                        let on = ch_mask & (1 << i) != 0;
+                        if on && self.channels[i].is_none() {
+                            return false;
+                        }
+                        self.set_enabled(i, on);
+                    }
+                }
+                6 => {
+                    for i in 0..MAX_DYNAMIC_CHANNELS {
+                        self.set_enabled(i, self.channels[i].is_some());
+                    }
+                }
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    /// Whether at least one channel is enabled
+    pub fn any_enabled(&self) -> bool {
+        (0..self.slots()).any(|i| self.is_enabled(i))
+    }
+
+    /// Whether some enabled channel supports `dr`
+    pub fn supports_data_rate(&self, dr: u8) -> bool {
+        self.usable(dr).next().is_some()
+    }
+
+    /// Enabled channels that support `dr`
+    pub fn usable(&self, dr: u8) -> impl Iterator<Item = (usize, Channel)> + '_ {
+        (0..self.slots())
+            .filter(move |&i| self.is_enabled(i))
+            .filter_map(move |i| {
+                self.channel(i)
+                    .filter(|c| (c.min_dr..=c.max_dr).contains(&dr))
+                    .map(|c| (i, c))
+            })
+    }
+
+    /// Serialize the mask and the dynamic channels for non-volatile storage
+    pub fn to_bytes(&self) -> [u8; CHANNEL_PLAN_LEN] {
+        let mut bytes = [0u8; CHANNEL_PLAN_LEN];
+        for (i, mask) in self.mask.iter().enumerate() {
+            bytes[2 * i..2 * i + 2].copy_from_slice(&mask.to_le_bytes());
+        }
+        for i in 0..MAX_DYNAMIC_CHANNELS {
+            let entry = &mut bytes[10 + 9 * i..19 + 9 * i];
+            if let Some(channel) = self.channels[i] {
+                entry[0..4].copy_from_slice(&channel.frequency.to_le_bytes());
+                entry[4] = channel.min_dr | channel.max_dr << 4;
+            }
+            entry[5..9].copy_from_slice(&self.dl_frequencies[i].to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Restore a plan saved with [`to_bytes`](Self::to_bytes)
+    pub fn from_bytes(region: Region, bytes: &[u8; CHANNEL_PLAN_LEN]) -> Self {
+        let mut plan = Self::new(region, None);
+        for i in 0..5 {
+            plan.mask[i] = u16::from_le_bytes([bytes[2 * i], bytes[2 * i + 1]]);
+        }
+        if !region.is_fixed_plan() {
+            for i in 0..MAX_DYNAMIC_CHANNELS {
+                let entry = &bytes[10 + 9 * i..19 + 9 * i];
+                let frequency = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]);
+                plan.channels[i] = (frequency != 0).then_some(Channel {
+                    frequency,
+                    min_dr: entry[4] & 0x0F,
+                    max_dr: entry[4] >> 4,
+                });
+                plan.dl_frequencies[i] = u32::from_le_bytes([entry[5], entry[6], entry[7], entry[8]]);
+            }
+        }
+        plan
+    }
+
+    /// Apply the CFList of a JoinAccept
+    pub fn apply_cf_list(&mut self, cf_list: &[u8; 16]) {
+        if self.region.is_fixed_plan() {
+            // CFListType 1: channel mask for channels 0..79
+            if cf_list[15] != 1 {
+                return;
+            }
+            for i in 0..5 {
+                self.mask[i] = u16::from_le_bytes([cf_list[2 * i], cf_list[2 * i + 1]]);
+            }
+            self.mask[4] &= 0x00FF;
+        } else {
+            // CFListType 0: frequencies of the five channels after the
+            // default ones (3..7, or 2..6 in AS923) in units of 100 Hz
+            if cf_list[15] != 0 {
+                return;
+            }
+            let first = self.region.default_channels().len();
+            for (i, f) in cf_list[..15].chunks_exact(3).enumerate() {
+                let frequency = u32::from_le_bytes([f[0], f[1], f[2], 0]) * 100;
+                let channel = (frequency != 0).then_some(Channel {
+                    frequency,
+                    min_dr: 0,
+                    max_dr: 5,
+                });
+                self.set_channel(first + i, channel);
+            }
+        }
+    }
+
+    /// Pick a channel usable at `dr` that `available` accepts, using `random` to choose
+    pub fn select(
+        &self,
+        dr: u8,
+        random: u32,
+        available: impl Fn(&Channel) -> bool,
+    ) -> Option<(usize, Channel)> {
+        let count = self.usable(dr).filter(|(_, c)| available(c)).count();
+        if count == 0 {
+            return None;
+        }
+        self.usable(dr)
+            .filter(|(_, c)| available(c))
+            .nth(random as usize % count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// CFListType 0 with the given frequencies
+    fn cf_list(frequencies: &[u32]) -> [u8; 16] {
+        let mut cf_list = [0u8; 16];
+        for (entry, frequency) in cf_list.chunks_exact_mut(3).zip(frequencies) {
+            entry.copy_from_slice(&(frequency / 100).to_le_bytes()[..3]);
+        }
+        cf_list
+    }
+
+    #[test]
+    fn cf_list_follows_default_channels() {
+        let mut eu868 = ChannelPlan::new(Region::EU868, None);
+        eu868.apply_cf_list(&cf_list(&[867_100_000, 867_300_000]));
+        assert_eq!(eu868.channel(3).map(|c| c.frequency), Some(867_100_000));
+        assert_eq!(eu868.channel(4).map(|c| c.frequency), Some(867_300_000));
+        assert!(eu868.channel(5).is_none());
+
+        // AS923 has two default channels, so the CFList starts at channel 2
+        let mut as923 = ChannelPlan::new(Region::AS923, None);
+        as923.apply_cf_list(&cf_list(&[923_600_000, 923_800_000, 924_000_000, 924_200_000, 924_400_000]));
+        assert_eq!(as923.channel(1).map(|c| c.frequency), Some(923_400_000));
+        assert_eq!(as923.channel(2).map(|c| c.frequency), Some(923_600_000));
+        assert_eq!(as923.channel(6).map(|c| c.frequency), Some(924_400_000));
+        assert!(as923.channel(7).is_none());
+    }
+}
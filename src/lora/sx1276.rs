@@ -1,12 +1,82 @@
 //! SX1276 LoRa transceiver driver
 //!
 //! Async driver for the Semtech SX1276 LoRa module
+//!
+//! The driver is generic over an `embedded-hal-async` [`SpiDevice`] (which owns
+//! chip select), an [`OutputPin`] for RESET and a [`Wait`] input for DIO0, so
+//! it runs on any board, on a shared SPI bus, or against a mock on the host.
+
+use embassy_time::{with_timeout, Duration, Timer};
+use embedded_hal::digital::OutputPin;
+use embedded_hal_async::digital::Wait;
+use embedded_hal_async::spi::{Operation, SpiDevice};
+
+// SX1276 register map (LoRa mode)
+const REG_FIFO: u8 = 0x00;
+const REG_OP_MODE: u8 = 0x01;
+const REG_FRF_MSB: u8 = 0x06;
+const REG_FRF_MID: u8 = 0x07;
+const REG_FRF_LSB: u8 = 0x08;
+const REG_PA_CONFIG: u8 = 0x09;
+const REG_OCP: u8 = 0x0B;
+const REG_LNA: u8 = 0x0C;
+const REG_FIFO_ADDR_PTR: u8 = 0x0D;
+const REG_FIFO_TX_BASE_ADDR: u8 = 0x0E;
+const REG_FIFO_RX_BASE_ADDR: u8 = 0x0F;
+const REG_FIFO_RX_CURRENT_ADDR: u8 = 0x10;
+const REG_IRQ_FLAGS: u8 = 0x12;
+const REG_RX_NB_BYTES: u8 = 0x13;
+const REG_MODEM_STAT: u8 = 0x18;
+const REG_PKT_SNR_VALUE: u8 = 0x19;
+const REG_PKT_RSSI_VALUE: u8 = 0x1A;
+const REG_RSSI_WIDEBAND: u8 = 0x2C;
+const REG_MODEM_CONFIG_1: u8 = 0x1D;
+const REG_MODEM_CONFIG_2: u8 = 0x1E;
+const REG_PREAMBLE_MSB: u8 = 0x20;
+const REG_PREAMBLE_LSB: u8 = 0x21;
+const REG_PAYLOAD_LENGTH: u8 = 0x22;
+const REG_MAX_PAYLOAD_LENGTH: u8 = 0x23;
+const REG_MODEM_CONFIG_3: u8 = 0x26;
+const REG_DETECTION_OPTIMIZE: u8 = 0x31;
+const REG_INVERT_IQ: u8 = 0x33;
+const REG_DETECTION_THRESHOLD: u8 = 0x37;
+const REG_SYNC_WORD: u8 = 0x39;
+const REG_INVERT_IQ_2: u8 = 0x3B;
+const REG_DIO_MAPPING_1: u8 = 0x40;
+const REG_VERSION: u8 = 0x42;
+const REG_PA_DAC: u8 = 0x4D;
+
+// RegOpMode values
+const MODE_LONG_RANGE: u8 = 0x80;
+const MODE_SLEEP: u8 = 0x00;
+const MODE_STANDBY: u8 = 0x01;
+const MODE_TX: u8 = 0x03;
+const MODE_RX_CONTINUOUS: u8 = 0x05;
+
+// RegIrqFlags bits
+const IRQ_RX_DONE: u8 = 0x40;
+const IRQ_PAYLOAD_CRC_ERROR: u8 = 0x20;
+const IRQ_TX_DONE: u8 = 0x08;
+
+// RegDioMapping1 values for DIO0
+const DIO0_RX_DONE: u8 = 0x00;
+const DIO0_TX_DONE: u8 = 0x40;
 
-use embassy_stm32::{
-    gpio::{Input, Output},
-    spi::Spi,
-};
-use embedded_hal_async::spi::SpiDevice;
+// RegModemStat bits
+const MODEM_STAT_SIGNAL_DETECTED: u8 = 0x01;
+const MODEM_STAT_SIGNAL_SYNCHRONIZED: u8 = 0x02;
+
+/// Expected value of RegVersion for an SX1276
+const SX1276_VERSION: u8 = 0x12;
+
+/// SX1276 crystal oscillator frequency in Hz
+const FXOSC: u64 = 32_000_000;
+
+/// Upper bound for a single transmission (SF12, 255 bytes at 125 kHz is ~9.2 s)
+const TX_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Extra time granted to a packet whose preamble was detected before the RX timeout
+const RX_GRACE: Duration = Duration::from_secs(3);
 
 /// SX1276 LoRa radio configuration
 #[derive(Debug, Clone, Copy)]
@@ -21,6 +91,16 @@ pub struct LoRaConfig {
     pub coding_rate: u8,
     /// TX power in dBm (2-20)
     pub tx_power: i8,
+    /// Preamble length in symbols
+    pub preamble_length: u16,
+    /// Implicit header mode (no PHY header on air)
+    pub implicit_header: bool,
+    /// Append/check the payload CRC
+    pub crc_on: bool,
+    /// Invert I and Q signals (LoRaWAN downlinks are sent inverted)
+    pub invert_iq: bool,
+    /// Sync word (0x34 for public LoRaWAN networks, 0x12 for private)
+    pub sync_word: u8,
 }
 
 impl Default for LoRaConfig {
@@ -31,6 +111,93 @@ impl Default for LoRaConfig {
             bandwidth: 125_000,
             coding_rate: 5,
             tx_power: 14,
+            preamble_length: 8,
+            implicit_header: false,
+            crc_on: true,
+            invert_iq: false,
+            sync_word: 0x34,
+        }
+    }
+}
+
+impl LoRaConfig {
+    /// Duration of one LoRa symbol in microseconds
+    pub fn symbol_time_us(&self) -> u32 {
+        ((1u64 << self.spreading_factor) * 1_000_000 / self.bandwidth as u64) as u32
+    }
+
+    /// Low data rate optimization is mandated when the symbol time exceeds 16 ms
+    pub fn low_data_rate_optimize(&self) -> bool {
+        self.symbol_time_us() > 16_000
+    }
+
+    /// Time on air in microseconds of a packet with `payload_len` bytes
+    ///
+    /// Follows the SX1276 datasheet (section 4.1.1.7):
+    ///
+    /// ```text
+    /// Tpreamble = (preamble_length + 4.25) * Tsym
+    /// Npayload  = 8 + max(ceil((8 PL - 4 SF + 28 + 16 CRC - 20 IH) / (4 (SF - 2 DE))) * (CR + 4), 0)
+    /// ```
+    ///
+    /// with CR = 1..4 for coding rates 4/5..4/8, IH = 1 in implicit header
+    /// mode and DE = 1 with low data rate optimization. The result is
+    /// rounded up to the next microsecond.
+    pub fn time_on_air_us(&self, payload_len: usize) -> u32 {
+        let sf = self.spreading_factor as i64;
+        let crc = self.crc_on as i64;
+        let ih = self.implicit_header as i64;
+        let de = self.low_data_rate_optimize() as i64;
+        let cr = self.coding_rate as i64 - 4;
+
+        let numerator = 8 * payload_len as i64 - 4 * sf + 28 + 16 * crc - 20 * ih;
+        let denominator = 4 * (sf - 2 * de);
+        let blocks = if numerator > 0 {
+            (numerator + denominator - 1) / denominator
+        } else {
+            0
+        };
+        let payload_symbols = 8 + blocks * (cr + 4);
+
+        // Count in quarter symbols so the 4.25 preamble symbols stay exact
+        let quarter_symbols = (4 * self.preamble_length as u64 + 17) + 4 * payload_symbols as u64;
+        let divisor = 4 * self.bandwidth as u64;
+        let us = (quarter_symbols * (1u64 << self.spreading_factor) * 1_000_000).div_ceil(divisor);
+        us as u32
+    }
+
+    /// RegModemConfig1 bandwidth field
+    fn bandwidth_bits(&self) -> Option<u8> {
+        let bits = match self.bandwidth {
+            7_800 => 0,
+            10_400 => 1,
+            15_600 => 2,
+            20_800 => 3,
+            31_250 => 4,
+            41_700 => 5,
+            62_500 => 6,
+            125_000 => 7,
+            250_000 => 8,
+            500_000 => 9,
+            _ => return None,
+        };
+        Some(bits)
+    }
+
+    /// Check the configuration against the SX1276 limits
+    fn validate(&self) -> Result<(), SX1276Error> {
+        let valid = (137_000_000..=1_020_000_000).contains(&self.frequency)
+            && (6..=12).contains(&self.spreading_factor)
+            && (5..=8).contains(&self.coding_rate)
+            && (2..=20).contains(&self.tx_power)
+            && self.bandwidth_bits().is_some()
+            // SF6 only works in implicit header mode
+            && (self.spreading_factor != 6 || self.implicit_header);
+
+        if valid {
+            Ok(())
+        } else {
+            Err(SX1276Error::InvalidConfig)
         }
     }
 }
@@ -44,6 +211,15 @@ pub enum RadioState {
     Sleep,
 }
 
+/// Signal quality of the last received packet
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PacketStatus {
+    /// Packet RSSI in dBm
+    pub rssi: i16,
+    /// Packet SNR in dB
+    pub snr: i8,
+}
+
 /// SX1276 errors
 #[derive(Debug)]
 pub enum SX1276Error {
@@ -51,95 +227,618 @@ pub enum SX1276Error {
     Timeout,
     InvalidConfig,
     NotReady,
+    CrcError,
+    PinError,
 }
 
 /// SX1276 LoRa transceiver driver
-pub struct SX1276<'d> {
-    spi: Spi<'d, embassy_stm32::peripherals::SPI1, embassy_stm32::peripherals::DMA1_CH2, embassy_stm32::peripherals::DMA1_CH3>,
-    nss: Output<'d>,
-    reset: Output<'d>,
-    dio0: Input<'d>,
+pub struct SX1276<SPI, RESET, DIO0> {
+    spi: SPI,
+    reset: RESET,
+    dio0: DIO0,
     state: RadioState,
     config: LoRaConfig,
+    packet_status: PacketStatus,
 }
 
-impl<'d> SX1276<'d> {
+impl<SPI, RESET, DIO0> SX1276<SPI, RESET, DIO0>
+where
+    SPI: SpiDevice,
+    RESET: OutputPin,
+    DIO0: Wait,
+{
     /// Create a new SX1276 driver instance
-    pub fn new(
-        spi: Spi<'d, embassy_stm32::peripherals::SPI1, embassy_stm32::peripherals::DMA1_CH2, embassy_stm32::peripherals::DMA1_CH3>,
-        nss: Output<'d>,
-        reset: Output<'d>,
-        dio0: Input<'d>,
-        config: LoRaConfig,
-    ) -> Self {
+    pub fn new(spi: SPI, reset: RESET, dio0: DIO0, config: LoRaConfig) -> Self {
         Self {
             spi,
-            nss,
             reset,
             dio0,
             state: RadioState::Idle,
             config,
+            packet_status: PacketStatus::default(),
         }
     }
 
     /// Initialize the SX1276 radio
     pub async fn init(&mut self) -> Result<(), SX1276Error> {
         // Hardware reset
-        self.reset.set_low();
-        embassy_time::Timer::after(embassy_time::Duration::from_millis(10)).await;
-        self.reset.set_high();
-        embassy_time::Timer::after(embassy_time::Duration::from_millis(10)).await;
+        self.reset.set_low().map_err(|_| SX1276Error::PinError)?;
+        Timer::after(Duration::from_millis(10)).await;
+        self.reset.set_high().map_err(|_| SX1276Error::PinError)?;
+        Timer::after(Duration::from_millis(10)).await;
+
+        if self.read_register(REG_VERSION).await? != SX1276_VERSION {
+            return Err(SX1276Error::NotReady);
+        }
+
+        // LoRa mode can only be selected while the chip is asleep
+        self.write_register(REG_OP_MODE, MODE_SLEEP).await?;
+        self.write_register(REG_OP_MODE, MODE_LONG_RANGE | MODE_SLEEP).await?;
+
+        // Use the whole 256-byte FIFO for both directions
+        self.write_register(REG_FIFO_TX_BASE_ADDR, 0x00).await?;
+        self.write_register(REG_FIFO_RX_BASE_ADDR, 0x00).await?;
+        self.write_register(REG_MAX_PAYLOAD_LENGTH, 0xFF).await?;
 
-        // TODO: Implement register configuration
-        // - Set LoRa mode
-        // - Configure frequency
-        // - Set spreading factor, bandwidth, coding rate
-        // - Configure PA settings for TX power
+        // Maximum LNA gain with boost for the HF port
+        self.write_register(REG_LNA, 0x23).await?;
 
+        let config = self.config;
+        self.apply_config(&config).await?;
+
+        self.set_mode(MODE_STANDBY).await?;
+        self.state = RadioState::Idle;
+        Ok(())
+    }
+
+    /// Change the radio configuration (frequency, modulation, power)
+    pub async fn configure(&mut self, config: LoRaConfig) -> Result<(), SX1276Error> {
+        self.set_mode(MODE_STANDBY).await?;
+        self.apply_config(&config).await?;
+        self.config = config;
         self.state = RadioState::Idle;
         Ok(())
     }
 
+    /// Set the length of the packets to receive in implicit header mode
+    ///
+    /// [`transmit`](Self::transmit) overwrites it with the length it sends.
+    pub async fn set_payload_length(&mut self, len: u8) -> Result<(), SX1276Error> {
+        self.write_register(REG_PAYLOAD_LENGTH, len).await
+    }
+
     /// Transmit data packet
     pub async fn transmit(&mut self, data: &[u8]) -> Result<(), SX1276Error> {
         if data.len() > 255 {
             return Err(SX1276Error::InvalidConfig);
         }
 
-        // TODO: Implement transmission
-        // - Write data to FIFO
-        // - Set TX mode
-        // - Wait for DIO0 interrupt (TxDone)
-        
+        self.set_mode(MODE_STANDBY).await?;
+
+        // Write data to FIFO
+        self.write_register(REG_FIFO_ADDR_PTR, 0x00).await?;
+        self.write_burst(REG_FIFO, data).await?;
+        self.write_register(REG_PAYLOAD_LENGTH, data.len() as u8).await?;
+
+        // Route TxDone to DIO0 and start the transmission
+        self.write_register(REG_DIO_MAPPING_1, DIO0_TX_DONE).await?;
+        self.write_register(REG_IRQ_FLAGS, 0xFF).await?;
+        self.set_mode(MODE_TX).await?;
         self.state = RadioState::Transmitting;
-        
-        // Simulate transmission delay
-        embassy_time::Timer::after(embassy_time::Duration::from_millis(100)).await;
-        
+
+        let waited = with_timeout(TX_TIMEOUT, self.dio0.wait_for_high()).await;
+
+        let flags = self.read_register(REG_IRQ_FLAGS).await?;
+        self.write_register(REG_IRQ_FLAGS, 0xFF).await?;
+        self.set_mode(MODE_STANDBY).await?;
         self.state = RadioState::Idle;
+
+        waited
+            .map_err(|_| SX1276Error::Timeout)?
+            .map_err(|_| SX1276Error::PinError)?;
+        if flags & IRQ_TX_DONE == 0 {
+            return Err(SX1276Error::Timeout);
+        }
         Ok(())
     }
 
     /// Receive data packet
+    ///
+    /// Listens until a packet arrives and returns the number of bytes copied
+    /// into `buffer`.
     pub async fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, SX1276Error> {
-        // TODO: Implement reception
-        // - Set RX mode
-        // - Wait for DIO0 interrupt (RxDone)
-        // - Read data from FIFO
-        
-        self.state = RadioState::Receiving;
-        Ok(0)
+        self.start_receive().await?;
+        self.wait_receive(buffer).await
+    }
+
+    /// Wait for the packet of a reception started with
+    /// [`start_receive`](Self::start_receive)
+    ///
+    /// Returns at once with a packet that arrived in the meantime.
+    pub async fn wait_receive(&mut self, buffer: &mut [u8]) -> Result<usize, SX1276Error> {
+        self.dio0.wait_for_high().await.map_err(|_| SX1276Error::PinError)?;
+        self.finish_receive(buffer).await
+    }
+
+    /// Receive data packet, giving up after `timeout`
+    ///
+    /// A packet whose preamble was detected before the timeout is still
+    /// received to completion.
+    pub async fn receive_timeout(
+        &mut self,
+        buffer: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, SX1276Error> {
+        self.start_receive().await?;
+
+        // Pin errors surface as NotReady when the RxDone flag is checked
+        if with_timeout(timeout, self.dio0.wait_for_high()).await.is_err() {
+            let stat = self.read_register(REG_MODEM_STAT).await?;
+            let busy = stat & (MODEM_STAT_SIGNAL_DETECTED | MODEM_STAT_SIGNAL_SYNCHRONIZED) != 0;
+            if !busy || with_timeout(RX_GRACE, self.dio0.wait_for_high()).await.is_err() {
+                self.set_mode(MODE_STANDBY).await?;
+                self.state = RadioState::Idle;
+                return Err(SX1276Error::Timeout);
+            }
+        }
+
+        self.finish_receive(buffer).await
     }
 
     /// Enter sleep mode
     pub async fn sleep(&mut self) -> Result<(), SX1276Error> {
-        // TODO: Set sleep mode register
+        self.set_mode(MODE_SLEEP).await?;
         self.state = RadioState::Sleep;
         Ok(())
     }
 
+    /// Generate a random number from wideband RSSI noise
+    ///
+    /// The radio is left in standby afterwards.
+    pub async fn random_u32(&mut self) -> Result<u32, SX1276Error> {
+        self.set_mode(MODE_STANDBY).await?;
+        self.write_register(REG_IRQ_FLAGS, 0xFF).await?;
+        self.set_mode(MODE_RX_CONTINUOUS).await?;
+
+        let mut value = 0u32;
+        for _ in 0..32 {
+            Timer::after(Duration::from_micros(100)).await;
+            let bit = self.read_register(REG_RSSI_WIDEBAND).await? & 0x01;
+            value = (value << 1) | bit as u32;
+        }
+
+        self.set_mode(MODE_STANDBY).await?;
+        self.state = RadioState::Idle;
+        Ok(value)
+    }
+
     /// Get current radio state
     pub fn state(&self) -> RadioState {
         self.state
     }
+
+    /// Get the active radio configuration
+    pub fn config(&self) -> &LoRaConfig {
+        &self.config
+    }
+
+    /// RSSI and SNR of the last received packet
+    pub fn packet_status(&self) -> PacketStatus {
+        self.packet_status
+    }
+
+    /// Put the radio in continuous RX with RxDone routed to DIO0
+    ///
+    /// The radio keeps listening without anyone awaiting it; the first packet
+    /// is collected with [`wait_receive`](Self::wait_receive).
+    pub async fn start_receive(&mut self) -> Result<(), SX1276Error> {
+        self.set_mode(MODE_STANDBY).await?;
+        self.write_register(REG_FIFO_ADDR_PTR, 0x00).await?;
+        self.write_register(REG_DIO_MAPPING_1, DIO0_RX_DONE).await?;
+        self.write_register(REG_IRQ_FLAGS, 0xFF).await?;
+        self.set_mode(MODE_RX_CONTINUOUS).await?;
+        self.state = RadioState::Receiving;
+        Ok(())
+    }
+
+    /// Read the packet signalled by RxDone out of the FIFO
+    async fn finish_receive(&mut self, buffer: &mut [u8]) -> Result<usize, SX1276Error> {
+        let flags = self.read_register(REG_IRQ_FLAGS).await?;
+        self.write_register(REG_IRQ_FLAGS, 0xFF).await?;
+        self.set_mode(MODE_STANDBY).await?;
+        self.state = RadioState::Idle;
+
+        if flags & IRQ_RX_DONE == 0 {
+            return Err(SX1276Error::NotReady);
+        }
+        if flags & IRQ_PAYLOAD_CRC_ERROR != 0 {
+            return Err(SX1276Error::CrcError);
+        }
+
+        let len = (self.read_register(REG_RX_NB_BYTES).await? as usize).min(buffer.len());
+        let addr = self.read_register(REG_FIFO_RX_CURRENT_ADDR).await?;
+        self.write_register(REG_FIFO_ADDR_PTR, addr).await?;
+        self.read_burst(REG_FIFO, &mut buffer[..len]).await?;
+
+        // SNR is a signed value in 0.25 dB steps; the RSSI offset applies to the HF port
+        let snr = (self.read_register(REG_PKT_SNR_VALUE).await? as i8) / 4;
+        let mut rssi = -157 + self.read_register(REG_PKT_RSSI_VALUE).await? as i16;
+        if snr < 0 {
+            rssi += snr as i16;
+        }
+        self.packet_status = PacketStatus { rssi, snr };
+
+        Ok(len)
+    }
+
+    /// Program frequency, modem and PA registers (radio must not be in TX/RX)
+    async fn apply_config(&mut self, config: &LoRaConfig) -> Result<(), SX1276Error> {
+        config.validate()?;
+
+        // FRF = frequency * 2^19 / FXOSC
+        let frf = ((config.frequency as u64) << 19) / FXOSC;
+        self.write_register(REG_FRF_MSB, (frf >> 16) as u8).await?;
+        self.write_register(REG_FRF_MID, (frf >> 8) as u8).await?;
+        self.write_register(REG_FRF_LSB, frf as u8).await?;
+
+        let bw = config.bandwidth_bits().ok_or(SX1276Error::InvalidConfig)?;
+        self.write_register(
+            REG_MODEM_CONFIG_1,
+            (bw << 4) | ((config.coding_rate - 4) << 1) | config.implicit_header as u8,
+        )
+        .await?;
+        self.write_register(
+            REG_MODEM_CONFIG_2,
+            (config.spreading_factor << 4) | ((config.crc_on as u8) << 2),
+        )
+        .await?;
+        // LowDataRateOptimize plus AGC auto on
+        self.write_register(
+            REG_MODEM_CONFIG_3,
+            ((config.low_data_rate_optimize() as u8) << 3) | 0x04,
+        )
+        .await?;
+
+        // SF6 needs its own detection settings (datasheet section 4.1.1.2)
+        if config.spreading_factor == 6 {
+            self.write_register(REG_DETECTION_OPTIMIZE, 0xC5).await?;
+            self.write_register(REG_DETECTION_THRESHOLD, 0x0C).await?;
+        } else {
+            self.write_register(REG_DETECTION_OPTIMIZE, 0xC3).await?;
+            self.write_register(REG_DETECTION_THRESHOLD, 0x0A).await?;
+        }
+
+        self.write_register(REG_PREAMBLE_MSB, (config.preamble_length >> 8) as u8).await?;
+        self.write_register(REG_PREAMBLE_LSB, config.preamble_length as u8).await?;
+        self.write_register(REG_SYNC_WORD, config.sync_word).await?;
+
+        // Errata-recommended values for I/Q inversion
+        if config.invert_iq {
+            self.write_register(REG_INVERT_IQ, 0x66).await?;
+            self.write_register(REG_INVERT_IQ_2, 0x19).await?;
+        } else {
+            self.write_register(REG_INVERT_IQ, 0x27).await?;
+            self.write_register(REG_INVERT_IQ_2, 0x1D).await?;
+        }
+
+        self.apply_tx_power(config.tx_power).await
+    }
+
+    /// Configure the PA_BOOST output stage for the requested power
+    async fn apply_tx_power(&mut self, dbm: i8) -> Result<(), SX1276Error> {
+        if dbm > 17 {
+            // +20 dBm mode: high power DAC and raised over-current limit (140 mA)
+            self.write_register(REG_PA_DAC, 0x87).await?;
+            self.write_register(REG_OCP, 0x20 | 0x11).await?;
+            self.write_register(REG_PA_CONFIG, 0x80 | (dbm - 5) as u8).await?;
+        } else {
+            // Pout = 2 + OutputPower on PA_BOOST, default 100 mA limit
+            self.write_register(REG_PA_DAC, 0x84).await?;
+            self.write_register(REG_OCP, 0x20 | 0x0B).await?;
+            self.write_register(REG_PA_CONFIG, 0x80 | (dbm - 2) as u8).await?;
+        }
+        Ok(())
+    }
+
+    async fn set_mode(&mut self, mode: u8) -> Result<(), SX1276Error> {
+        self.write_register(REG_OP_MODE, MODE_LONG_RANGE | mode).await
+    }
+
+    async fn read_register(&mut self, addr: u8) -> Result<u8, SX1276Error> {
+        let mut value = [0u8];
+        self.read_burst(addr, &mut value).await?;
+        Ok(value[0])
+    }
+
+    async fn write_register(&mut self, addr: u8, value: u8) -> Result<(), SX1276Error> {
+        self.write_burst(addr, &[value]).await
+    }
+
+    async fn read_burst(&mut self, addr: u8, data: &mut [u8]) -> Result<(), SX1276Error> {
+        self.spi
+            .transaction(&mut [Operation::Write(&[addr & 0x7F]), Operation::Read(data)])
+            .await
+            .map_err(|_| SX1276Error::SpiError)
+    }
+
+    async fn write_burst(&mut self, addr: u8, data: &[u8]) -> Result<(), SX1276Error> {
+        self.spi
+            .transaction(&mut [Operation::Write(&[addr | 0x80]), Operation::Write(data)])
+            .await
+            .map_err(|_| SX1276Error::SpiError)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use core::cell::RefCell;
+    use core::convert::Infallible;
+    use embassy_futures::block_on;
+    use embedded_hal_mock::eh1::digital::{Mock as PinMock, State, Transaction as PinTransaction};
+    use embedded_hal_mock::eh1::spi::{Mock as SpiMock, Transaction as SpiTransaction};
+    use std::rc::Rc;
+
+    /// A transmission seen by [`Chip`]
+    pub(crate) struct Sent {
+        /// Frequency in Hz, rounded to 100 Hz
+        pub(crate) frequency: u32,
+        pub(crate) spreading_factor: u8,
+        pub(crate) frame: Vec<u8>,
+    }
+
+    /// Register file of a simulated SX1276 that transmits at once and never
+    /// receives anything, for tests of the layers above the driver
+    pub(crate) struct Chip {
+        registers: [u8; 0x80],
+        fifo: Vec<u8>,
+        /// Transmissions, oldest first
+        pub(crate) sent: Vec<Sent>,
+    }
+
+    impl Chip {
+        fn write(&mut self, addr: u8, data: &[u8]) {
+            let value = data[0];
+            match addr {
+                REG_FIFO => self.fifo.extend_from_slice(data),
+                REG_FIFO_ADDR_PTR => self.fifo.clear(),
+                REG_IRQ_FLAGS => self.registers[addr as usize] &= !value,
+                REG_OP_MODE if value & 0x07 == MODE_TX => {
+                    let r = &self.registers;
+                    let frf = [REG_FRF_MSB, REG_FRF_MID, REG_FRF_LSB].map(|reg| r[reg as usize]);
+                    let frf = u32::from_be_bytes([0, frf[0], frf[1], frf[2]]);
+                    let frequency = ((frf as u64 * FXOSC) >> 19) as u32;
+                    self.sent.push(Sent {
+                        frequency: (frequency + 50) / 100 * 100,
+                        spreading_factor: r[REG_MODEM_CONFIG_2 as usize] >> 4,
+                        frame: self.fifo.clone(),
+                    });
+                    self.registers[REG_IRQ_FLAGS as usize] |= IRQ_TX_DONE;
+                }
+                _ => {}
+            }
+            if addr != REG_IRQ_FLAGS {
+                self.registers[addr as usize] = value;
+            }
+        }
+    }
+
+    /// SPI side of a simulated [`Chip`]
+    pub(crate) struct ChipSpi(Rc<RefCell<Chip>>);
+
+    impl embedded_hal_async::spi::ErrorType for ChipSpi {
+        type Error = Infallible;
+    }
+
+    impl SpiDevice for ChipSpi {
+        async fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Infallible> {
+            let mut chip = self.0.borrow_mut();
+            match operations {
+                [Operation::Write(addr), Operation::Write(data)] if addr[0] & 0x80 != 0 => {
+                    chip.write(addr[0] & 0x7F, data)
+                }
+                [Operation::Write(addr), Operation::Read(data)] => data.fill(chip.registers[addr[0] as usize]),
+                _ => panic!("unexpected SPI transaction"),
+            }
+            Ok(())
+        }
+    }
+
+    /// DIO0 of a simulated [`Chip`], high once a transmission is done
+    pub(crate) struct ChipDio0(Rc<RefCell<Chip>>);
+
+    impl embedded_hal::digital::ErrorType for ChipDio0 {
+        type Error = Infallible;
+    }
+
+    impl Wait for ChipDio0 {
+        async fn wait_for_high(&mut self) -> Result<(), Infallible> {
+            if self.0.borrow().registers[REG_IRQ_FLAGS as usize] & IRQ_TX_DONE == 0 {
+                core::future::pending::<()>().await;
+            }
+            Ok(())
+        }
+
+        async fn wait_for_low(&mut self) -> Result<(), Infallible> {
+            core::future::pending().await
+        }
+
+        async fn wait_for_rising_edge(&mut self) -> Result<(), Infallible> {
+            self.wait_for_high().await
+        }
+
+        async fn wait_for_falling_edge(&mut self) -> Result<(), Infallible> {
+            core::future::pending().await
+        }
+
+        async fn wait_for_any_edge(&mut self) -> Result<(), Infallible> {
+            self.wait_for_high().await
+        }
+    }
+
+    /// RESET of a simulated [`Chip`]
+    pub(crate) struct ChipReset;
+
+    impl embedded_hal::digital::ErrorType for ChipReset {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for ChipReset {
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    pub(crate) type SimulatedRadio = SX1276<ChipSpi, ChipReset, ChipDio0>;
+
+    /// A driver on a simulated chip, and the chip to inspect
+    pub(crate) fn simulated_radio() -> (SimulatedRadio, Rc<RefCell<Chip>>) {
+        let chip = Rc::new(RefCell::new(Chip {
+            registers: [0; 0x80],
+            fifo: Vec::new(),
+            sent: Vec::new(),
+        }));
+        let radio = SX1276::new(ChipSpi(chip.clone()), ChipReset, ChipDio0(chip.clone()), LoRaConfig::default());
+        (radio, chip)
+    }
+
+    fn write(addr: u8, value: u8) -> [SpiTransaction<u8>; 4] {
+        [
+            SpiTransaction::transaction_start(),
+            SpiTransaction::write(addr | 0x80),
+            SpiTransaction::write(value),
+            SpiTransaction::transaction_end(),
+        ]
+    }
+
+    fn read(addr: u8, value: u8) -> [SpiTransaction<u8>; 4] {
+        [
+            SpiTransaction::transaction_start(),
+            SpiTransaction::write(addr),
+            SpiTransaction::read(value),
+            SpiTransaction::transaction_end(),
+        ]
+    }
+
+    fn expect(registers: &[[SpiTransaction<u8>; 4]]) -> Vec<SpiTransaction<u8>> {
+        registers.iter().flatten().cloned().collect()
+    }
+
+    fn modulation(spreading_factor: u8, bandwidth: u32) -> LoRaConfig {
+        LoRaConfig {
+            spreading_factor,
+            bandwidth,
+            ..LoRaConfig::default()
+        }
+    }
+
+    /// Values of the Semtech LoRa calculator, 8 preamble symbols, CRC on
+    #[test]
+    fn time_on_air_matches_semtech_calculator() {
+        assert_eq!(modulation(7, 125_000).time_on_air_us(13), 46_336);
+        // JoinRequest at SF10
+        assert_eq!(modulation(10, 125_000).time_on_air_us(23), 370_688);
+
+        let sf12 = modulation(12, 125_000);
+        assert!(sf12.low_data_rate_optimize());
+        assert_eq!(sf12.time_on_air_us(51), 2_465_792);
+        assert_eq!(sf12.time_on_air_us(13), 1_155_072);
+
+        let implicit = LoRaConfig {
+            implicit_header: true,
+            ..modulation(9, 500_000)
+        };
+        assert!(!implicit.low_data_rate_optimize());
+        assert_eq!(implicit.time_on_air_us(10), 30_976);
+        let coding_4_8 = LoRaConfig {
+            coding_rate: 8,
+            crc_on: false,
+            ..implicit
+        };
+        assert_eq!(coding_4_8.time_on_air_us(10), 37_120);
+    }
+
+    #[test]
+    fn configure_programs_modem_and_pa() {
+        let config = LoRaConfig {
+            frequency: 868_100_000,
+            ..LoRaConfig::default()
+        };
+        let mut spi = SpiMock::new(&expect(&[
+            write(REG_OP_MODE, MODE_LONG_RANGE | MODE_STANDBY),
+            // 868.1 MHz
+            write(REG_FRF_MSB, 0xD9),
+            write(REG_FRF_MID, 0x06),
+            write(REG_FRF_LSB, 0x66),
+            // 125 kHz, 4/5, explicit header; SF7 with CRC; AGC auto
+            write(REG_MODEM_CONFIG_1, 0x72),
+            write(REG_MODEM_CONFIG_2, 0x74),
+            write(REG_MODEM_CONFIG_3, 0x04),
+            write(REG_DETECTION_OPTIMIZE, 0xC3),
+            write(REG_DETECTION_THRESHOLD, 0x0A),
+            write(REG_PREAMBLE_MSB, 0x00),
+            write(REG_PREAMBLE_LSB, 0x08),
+            write(REG_SYNC_WORD, 0x34),
+            write(REG_INVERT_IQ, 0x27),
+            write(REG_INVERT_IQ_2, 0x1D),
+            // 14 dBm on PA_BOOST
+            write(REG_PA_DAC, 0x84),
+            write(REG_OCP, 0x2B),
+            write(REG_PA_CONFIG, 0x8C),
+        ]));
+        let mut reset = PinMock::new(&[]);
+        let mut dio0 = PinMock::new(&[]);
+        let mut radio = SX1276::new(spi.clone(), reset.clone(), dio0.clone(), LoRaConfig::default());
+
+        block_on(radio.configure(config)).unwrap();
+        assert_eq!(radio.config().frequency, 868_100_000);
+        assert_eq!(radio.state(), RadioState::Idle);
+
+        spi.done();
+        reset.done();
+        dio0.done();
+    }
+
+    #[test]
+    fn receive_reads_fifo_on_rx_done() {
+        let mut transactions = expect(&[
+            write(REG_OP_MODE, MODE_LONG_RANGE | MODE_STANDBY),
+            write(REG_FIFO_ADDR_PTR, 0x00),
+            write(REG_DIO_MAPPING_1, DIO0_RX_DONE),
+            write(REG_IRQ_FLAGS, 0xFF),
+            write(REG_OP_MODE, MODE_LONG_RANGE | MODE_RX_CONTINUOUS),
+            read(REG_IRQ_FLAGS, IRQ_RX_DONE),
+            write(REG_IRQ_FLAGS, 0xFF),
+            write(REG_OP_MODE, MODE_LONG_RANGE | MODE_STANDBY),
+            read(REG_RX_NB_BYTES, 3),
+            read(REG_FIFO_RX_CURRENT_ADDR, 0x20),
+            write(REG_FIFO_ADDR_PTR, 0x20),
+        ]);
+        transactions.extend([
+            SpiTransaction::transaction_start(),
+            SpiTransaction::write(REG_FIFO),
+            SpiTransaction::read_vec(vec![0xAA, 0xBB, 0xCC]),
+            SpiTransaction::transaction_end(),
+        ]);
+        // SNR -2 dB, RSSI -157 + 60 - 2 dBm
+        transactions.extend(expect(&[read(REG_PKT_SNR_VALUE, 0xF8), read(REG_PKT_RSSI_VALUE, 60)]));
+        let mut spi = SpiMock::new(&transactions);
+        let mut reset = PinMock::new(&[]);
+        let mut dio0 = PinMock::new(&[PinTransaction::wait_for_state(State::High)]);
+        let mut radio = SX1276::new(spi.clone(), reset.clone(), dio0.clone(), LoRaConfig::default());
+
+        let mut buffer = [0u8; 16];
+        assert_eq!(block_on(radio.receive(&mut buffer)).unwrap(), 3);
+        assert_eq!(buffer[..3], [0xAA, 0xBB, 0xCC]);
+        assert_eq!((radio.packet_status().rssi, radio.packet_status().snr), (-99, -2));
+        assert_eq!(radio.state(), RadioState::Idle);
+
+        spi.done();
+        reset.done();
+        dio0.done();
+    }
 }
@@ -0,0 +1,565 @@
+//! Background LoRaWAN task for the RAK3112 board
+//!
+//! The task owns the stack. Applications queue uplinks and receive
+//! downlinks through a shared [`LoRaWANChannels`]:
+//!
+//! ```ignore
+//! static LORAWAN: LoRaWANChannels = LoRaWANChannels::new();
+//!
+//! spawner.spawn(lorawan_task(lorawan, UplinkQueue::new(), &LORAWAN)).unwrap();
+//!
+//! // An alarm goes out ahead of any backlog, stamped with its time
+//! let alarm = UplinkOptions { priority: Priority::Alarm, confirmed: true, timestamp: true };
+//! LORAWAN.send_with(2, &[0x01], alarm).await?;
+//!
+//! // Port 10: configuration
+//! let mut config = LORAWAN.subscribe(Some(10)).unwrap();
+//! let downlink = config.next().await;
+//! ```
+
+use embassy_futures::select::{select, select3, select4, Either, Either3, Either4};
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::{Channel, TrySendError};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal::digital::OutputPin;
+use embedded_hal_async::digital::Wait;
+use embedded_hal_async::spi::SpiDevice;
+
+use super::lorawan::{DeviceClass, Downlink, LoRaWAN, LoRaWANError, MulticastGroup, MAX_APP_PORT, MAX_PAYLOAD};
+use super::uplink_queue::{QueuedUplink, UplinkOptions, UplinkQueue};
+
+#[cfg(target_os = "none")]
+pub use self::board::{lorawan_task, BoardLoRaWAN, BoardRadio, BoardSpi};
+
+/// How often the task checks for MAC answers to flush
+const MAC_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Delay before another join attempt after a failed one
+const JOIN_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Delay before another device class switch after a failed one
+const CLASS_RETRY_DELAY: Duration = Duration::from_secs(15 * 60);
+
+/// Delay before resending a confirmed uplink that went unacknowledged
+const UPLINK_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Delay before another DeviceTimeReq when the network did not answer
+const TIME_REQUEST_RETRY_DELAY: Duration = Duration::from_secs(15 * 60);
+
+/// Uplinks waiting for the task to move them to its backlog
+pub const UPLINK_QUEUE_LEN: usize = 4;
+
+/// Configuration requests waiting for the task
+const CONTROL_QUEUE_LEN: usize = 4;
+
+/// Downlinks buffered per handler; a slow handler loses the oldest
+///
+/// Each handler has its own queue holding only its ports' downlinks, so
+/// traffic on other ports never pushes them out. A queued downlink takes
+/// about 260 bytes of RAM.
+pub const DOWNLINK_QUEUE_LEN: usize = 2;
+
+/// Maximum number of downlink handlers
+pub const MAX_PORT_HANDLERS: usize = 4;
+
+type DownlinkQueue = Channel<CriticalSectionRawMutex, Downlink, DOWNLINK_QUEUE_LEN>;
+
+/// Downlink handler slot, and the ports its handler receives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HandlerSlot {
+    Free,
+    Port(u8),
+    AllPorts,
+}
+
+impl HandlerSlot {
+    fn accepts(self, port: u8) -> bool {
+        match self {
+            HandlerSlot::Free => false,
+            HandlerSlot::Port(p) => p == port,
+            HandlerSlot::AllPorts => true,
+        }
+    }
+}
+
+/// Configuration change for the stack, applied between uplinks
+enum Control {
+    MulticastGroup(u8, Option<MulticastGroup>),
+    Class(DeviceClass),
+    /// Class C multicast session on (frequency, data rate), or its end
+    ClassCSession(Option<(u32, u8)>),
+}
+
+/// Device class the task works towards
+struct ClassTarget {
+    class: DeviceClass,
+    /// Earliest time to retry a failed switch
+    retry_at: Instant,
+    /// Class to return to once the running Class C multicast session ends
+    after_session: Option<DeviceClass>,
+}
+
+/// Queues between the application and [`lorawan_task`]
+pub struct LoRaWANChannels {
+    uplinks: Channel<CriticalSectionRawMutex, QueuedUplink, UPLINK_QUEUE_LEN>,
+    control: Channel<CriticalSectionRawMutex, Control, CONTROL_QUEUE_LEN>,
+    handlers: Mutex<CriticalSectionRawMutex, Cell<[HandlerSlot; MAX_PORT_HANDLERS]>>,
+    downlinks: [DownlinkQueue; MAX_PORT_HANDLERS],
+}
+
+impl LoRaWANChannels {
+    pub const fn new() -> Self {
+        Self {
+            uplinks: Channel::new(),
+            control: Channel::new(),
+            handlers: Mutex::new(Cell::new([HandlerSlot::Free; MAX_PORT_HANDLERS])),
+            downlinks: [const { Channel::new() }; MAX_PORT_HANDLERS],
+        }
+    }
+
+    /// Queue an uplink with [`Priority::Periodic`](super::Priority::Periodic)
+    ///
+    /// See [`send_with`](Self::send_with).
+    pub async fn send(&self, port: u8, data: &[u8], confirmed: bool) -> Result<(), LoRaWANError> {
+        self.send_with(port, data, UplinkOptions { confirmed, ..UplinkOptions::default() }).await
+    }
+
+    /// Queue an uplink, waiting while the queue is full
+    ///
+    /// The task moves it to its [`UplinkQueue`] and sends it once joined and
+    /// out of duty-cycle time-off. An uplink that fails because the session
+    /// was lost, the channels are in time-off or no ACK came stays queued
+    /// and is retried; other transmission errors drop it and are logged by
+    /// the task. The reading is timestamped now.
+    pub async fn send_with(&self, port: u8, data: &[u8], options: UplinkOptions) -> Result<(), LoRaWANError> {
+        if port == 0 || port > MAX_APP_PORT {
+            return Err(LoRaWANError::InvalidPort);
+        }
+        let uplink = QueuedUplink::new(port, data, options, Instant::now()).ok_or(LoRaWANError::PayloadTooLarge)?;
+        self.uplinks.send(uplink).await;
+        Ok(())
+    }
+
+    /// Set (or with `None`, remove) multicast group `id`
+    ///
+    /// The task applies the change before its next uplink; frames of the
+    /// group then reach the handlers with [`Downlink::multicast`] set.
+    pub async fn set_multicast_group(&self, id: u8, group: Option<MulticastGroup>) {
+        self.control.send(Control::MulticastGroup(id, group)).await;
+    }
+
+    /// Switch the device class
+    ///
+    /// The task switches between uplinks and keeps retrying a failed switch
+    /// to Class B (no beacon found, or lost later) every 15 minutes.
+    pub async fn set_class(&self, class: DeviceClass) {
+        self.control.send(Control::Class(class)).await;
+    }
+
+    /// Start a Class C multicast session on `frequency` and `data_rate`
+    ///
+    /// The device switches to Class C and listens there instead of RX2
+    /// until [`end_class_c_session`](Self::end_class_c_session), then goes
+    /// back to its previous class. A class set in between takes effect when
+    /// the session ends.
+    pub async fn start_class_c_session(&self, frequency: u32, data_rate: u8) {
+        self.control.send(Control::ClassCSession(Some((frequency, data_rate)))).await;
+    }
+
+    /// End the Class C multicast session
+    pub async fn end_class_c_session(&self) {
+        self.control.send(Control::ClassCSession(None)).await;
+    }
+
+    /// Register a handler for application downlinks on `port`, or on every
+    /// port with `None`
+    ///
+    /// Fails with [`LoRaWANError::TooManyHandlers`] once
+    /// [`MAX_PORT_HANDLERS`] handlers exist; dropping a handler frees its
+    /// slot.
+    pub fn subscribe(&self, port: Option<u8>) -> Result<PortHandler<'_>, LoRaWANError> {
+        if matches!(port, Some(p) if p == 0 || p > MAX_APP_PORT) {
+            return Err(LoRaWANError::InvalidPort);
+        }
+        let slot = port.map_or(HandlerSlot::AllPorts, HandlerSlot::Port);
+        let index = self.handlers.lock(|handlers| {
+            let mut slots = handlers.get();
+            let index = slots.iter().position(|&s| s == HandlerSlot::Free)?;
+            // Downlinks the slot's previous handler left unread
+            while self.downlinks[index].try_receive().is_ok() {}
+            slots[index] = slot;
+            handlers.set(slots);
+            Some(index)
+        });
+        let index = index.ok_or(LoRaWANError::TooManyHandlers)?;
+        Ok(PortHandler { port, channels: self, index })
+    }
+
+    fn publish(&self, downlink: Downlink) {
+        // Only application data; MAC-only downlinks are handled by the stack
+        let Some(port) = downlink.port else {
+            return;
+        };
+        let slots = self.handlers.lock(Cell::get);
+        for (queue, _) in self.downlinks.iter().zip(slots).filter(|(_, s)| s.accepts(port)) {
+            if let Err(TrySendError::Full(downlink)) = queue.try_send(downlink.clone()) {
+                defmt::warn!("Downlink handler too slow, oldest port {} downlink dropped", port);
+                let _ = queue.try_receive();
+                let _ = queue.try_send(downlink);
+            }
+        }
+    }
+}
+
+impl Default for LoRaWANChannels {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Receives the application downlinks of one FPort (or all of them)
+pub struct PortHandler<'a> {
+    port: Option<u8>,
+    channels: &'a LoRaWANChannels,
+    /// Handler slot and downlink queue
+    index: usize,
+}
+
+impl PortHandler<'_> {
+    /// FPort this handler receives, `None` for every port
+    pub fn port(&self) -> Option<u8> {
+        self.port
+    }
+
+    /// Wait for the next downlink on this handler's port
+    ///
+    /// Once [`DOWNLINK_QUEUE_LEN`] downlinks are waiting, a new one drops
+    /// the oldest.
+    pub async fn next(&mut self) -> Downlink {
+        self.channels.downlinks[self.index].receive().await
+    }
+}
+
+impl Drop for PortHandler<'_> {
+    fn drop(&mut self) {
+        self.channels.handlers.lock(|handlers| {
+            let mut slots = handlers.get();
+            slots[self.index] = HandlerSlot::Free;
+            handlers.set(slots);
+        });
+    }
+}
+
+/// Body of [`lorawan_task`], for any wiring of the radio
+pub async fn run<SPI, RESET, DIO0>(
+    mut lorawan: LoRaWAN<SPI, RESET, DIO0>,
+    mut backlog: UplinkQueue,
+    channels: &LoRaWANChannels,
+) -> !
+where
+    SPI: SpiDevice,
+    RESET: OutputPin,
+    DIO0: Wait,
+{
+    defmt::info!("LoRaWAN task started");
+    let mut target = ClassTarget {
+        class: lorawan.config().device_class,
+        retry_at: Instant::now(),
+        after_session: None,
+    };
+    // Queued uplinks wait until then after a failed attempt
+    let mut hold_until = Instant::now();
+    // Earliest time for another DeviceTimeReq
+    let mut time_request_at = Instant::now();
+
+    loop {
+        if !lorawan.is_joined() {
+            let retry_at = match lorawan.join().await {
+                Ok(()) => {
+                    defmt::info!("Joined");
+                    continue;
+                }
+                Err(LoRaWANError::DutyCycleRestricted(delay)) => Instant::now() + delay,
+                Err(e) => {
+                    defmt::warn!("Join failed: {:?}", defmt::Debug2Format(&e));
+                    Instant::now() + JOIN_RETRY_DELAY
+                }
+            };
+            // Keep taking readings while the network is out of reach
+            while let Either::First(uplink) = select(channels.uplinks.receive(), Timer::at(retry_at)).await {
+                enqueue(&mut backlog, uplink, true);
+            }
+            continue;
+        }
+
+        if lorawan.device_class() != target.class && Instant::now() >= target.retry_at {
+            if let Err(e) = lorawan.set_class(target.class).await {
+                defmt::warn!("Switching to {:?} failed: {:?}", target.class, defmt::Debug2Format(&e));
+                target.retry_at = Instant::now() + CLASS_RETRY_DELAY;
+            }
+            continue;
+        }
+
+        if let Some(time) = lorawan.network_time() {
+            backlog.resolve_timestamps(&time);
+        }
+
+        let held = Instant::now() < hold_until;
+        if let Some(next) = backlog.peek().filter(|_| !held) {
+            if next.needs_time() && lorawan.network_time().is_none() && Instant::now() >= time_request_at {
+                // An empty uplink fetches the time for the reading's timestamp
+                time_request_at = Instant::now() + TIME_REQUEST_RETRY_DELAY;
+                lorawan.request_device_time();
+                match lorawan.flush_mac().await {
+                    Ok(Some(downlink)) => channels.publish(downlink),
+                    Ok(None) => {}
+                    Err(e) => {
+                        defmt::warn!("DeviceTimeReq failed: {:?}", defmt::Debug2Format(&e));
+                        // Ask again once the uplink can go out
+                        time_request_at = Instant::now()
+                            + match e {
+                                LoRaWANError::DutyCycleRestricted(delay) => delay,
+                                _ => UPLINK_RETRY_DELAY,
+                            };
+                    }
+                }
+                continue;
+            }
+
+            let mut payload = [0u8; MAX_PAYLOAD];
+            let len = next.encode(&mut payload);
+            let (port, confirmed) = (next.port, next.options.confirmed);
+            match lorawan.send(port, &payload[..len], confirmed).await {
+                Ok(downlink) => {
+                    backlog.pop();
+                    if let Some(downlink) = downlink {
+                        channels.publish(downlink);
+                    }
+                }
+                Err(e) => {
+                    let retry_in = match e {
+                        // The loop rejoins first
+                        LoRaWANError::NotJoined => Some(Duration::from_ticks(0)),
+                        LoRaWANError::NoAck => Some(UPLINK_RETRY_DELAY),
+                        LoRaWANError::DutyCycleRestricted(delay) => Some(delay),
+                        _ => None,
+                    };
+                    if let Some(delay) = retry_in {
+                        defmt::info!("Uplink held for {} ms: {:?}", delay.as_millis(), defmt::Debug2Format(&e));
+                        hold_until = Instant::now() + delay;
+                        spill(&mut backlog);
+                    } else {
+                        defmt::warn!("Uplink failed: {:?}", defmt::Debug2Format(&e));
+                        backlog.pop();
+                    }
+                }
+            }
+            continue;
+        }
+
+        // Wake up when held uplinks may go out
+        let wake = if backlog.is_empty() { Instant::MAX } else { hold_until };
+        if lorawan.device_class() != DeviceClass::ClassA {
+            let timer = Timer::at(wake);
+            match select4(channels.uplinks.receive(), channels.control.receive(), lorawan.listen(), timer).await {
+                Either4::First(uplink) => {
+                    enqueue(&mut backlog, uplink, held);
+                    continue;
+                }
+                Either4::Second(control) => {
+                    apply(&mut lorawan, control, &mut target).await;
+                    continue;
+                }
+                Either4::Third(Ok(downlink)) => {
+                    // Answer MAC commands or a confirmed downlink right away
+                    channels.publish(downlink);
+                }
+                Either4::Third(Err(e)) => {
+                    defmt::warn!("Reception failed: {:?}", defmt::Debug2Format(&e));
+                    Timer::after(MAC_FLUSH_INTERVAL).await;
+                }
+                Either4::Fourth(()) => continue,
+            }
+        } else {
+            let timer = Timer::at(wake.min(Instant::now() + MAC_FLUSH_INTERVAL));
+            match select3(channels.uplinks.receive(), channels.control.receive(), timer).await {
+                Either3::First(uplink) => {
+                    enqueue(&mut backlog, uplink, held);
+                    continue;
+                }
+                Either3::Second(control) => {
+                    apply(&mut lorawan, control, &mut target).await;
+                    continue;
+                }
+                Either3::Third(()) => {}
+            }
+        }
+
+        // Pending answers otherwise ride along with the held uplinks
+        if backlog.is_empty() && lorawan.has_pending_mac() {
+            defmt::info!("Flushing pending MAC answers");
+            match lorawan.flush_mac().await {
+                Ok(Some(downlink)) => channels.publish(downlink),
+                Ok(None) => {}
+                Err(e) => defmt::warn!("Uplink failed: {:?}", defmt::Debug2Format(&e)),
+            }
+        }
+    }
+}
+
+/// Add an uplink to the backlog, spilling it to storage if it has to wait
+fn enqueue(backlog: &mut UplinkQueue, uplink: QueuedUplink, held: bool) {
+    if let Some(dropped) = backlog.push(uplink) {
+        defmt::warn!("Uplink backlog full, {:?} uplink on port {} dropped", dropped.options.priority, dropped.port);
+    }
+    if held {
+        spill(backlog);
+    }
+}
+
+fn spill(backlog: &mut UplinkQueue) {
+    if let Err(e) = backlog.spill() {
+        defmt::warn!("Failed to spill the uplink backlog: {:?}", e);
+    }
+}
+
+async fn apply<SPI, RESET, DIO0>(lorawan: &mut LoRaWAN<SPI, RESET, DIO0>, control: Control, target: &mut ClassTarget)
+where
+    SPI: SpiDevice,
+    RESET: OutputPin,
+    DIO0: Wait,
+{
+    match control {
+        Control::MulticastGroup(id, Some(group)) => {
+            if let Err(e) = lorawan.set_multicast_group(id, group) {
+                defmt::warn!("Multicast group {} rejected: {:?}", id, defmt::Debug2Format(&e));
+            }
+        }
+        Control::MulticastGroup(id, None) => {
+            lorawan.remove_multicast_group(id);
+        }
+        Control::Class(class) if target.after_session.is_some() => {
+            target.after_session = Some(class);
+        }
+        Control::Class(class) => {
+            // The task loop switches before its next uplink
+            target.class = class;
+            target.retry_at = Instant::now();
+        }
+        Control::ClassCSession(channel) => {
+            if let Err(e) = lorawan.set_class_c_channel(channel).await {
+                defmt::warn!("Multicast session not applied: {:?}", defmt::Debug2Format(&e));
+                if channel.is_some() {
+                    return;
+                }
+            }
+            let class = match channel {
+                Some(_) => {
+                    target.after_session.get_or_insert(target.class);
+                    DeviceClass::ClassC
+                }
+                None => match target.after_session.take() {
+                    Some(class) => class,
+                    None => return,
+                },
+            };
+            target.class = class;
+            target.retry_at = Instant::now();
+        }
+    }
+}
+
+#[cfg(target_os = "none")]
+mod board {
+    use embassy_stm32::exti::ExtiInput;
+    use embassy_stm32::gpio::Output;
+    use embassy_stm32::peripherals::{DMA1_CH2, DMA1_CH3, SPI1};
+    use embassy_stm32::spi::Spi;
+    use embassy_time::Delay;
+    use embedded_hal_bus::spi::ExclusiveDevice;
+
+    use super::{run, LoRaWANChannels};
+    use crate::lora::lorawan::LoRaWAN;
+    use crate::lora::sx1276::SX1276;
+    use crate::lora::uplink_queue::UplinkQueue;
+
+    /// SPI device of the on-board SX1276
+    pub type BoardSpi = ExclusiveDevice<Spi<'static, SPI1, DMA1_CH2, DMA1_CH3>, Output<'static>, Delay>;
+
+    /// SX1276 as wired on the RAK3112
+    pub type BoardRadio = SX1276<BoardSpi, Output<'static>, ExtiInput<'static>>;
+
+    /// LoRaWAN stack on the RAK3112 radio
+    pub type BoardLoRaWAN = LoRaWAN<BoardSpi, Output<'static>, ExtiInput<'static>>;
+
+    /// Background task owning the LoRaWAN stack on the RAK3112 radio
+    ///
+    /// Joins (for OTAA) and rejoins after a lost session, sends the uplinks
+    /// queued on `channels` and publishes every application downlink to the
+    /// registered [`PortHandler`]s. In Class B and C the radio listens between
+    /// uplinks; the class configured in [`LoRaWANConfig`](super::LoRaWANConfig)
+    /// is entered once joined.
+    ///
+    /// Queued uplinks go through `backlog`, which holds them while the device
+    /// is not joined, during duty-cycle time-off and after an unacknowledged
+    /// confirmed uplink, and sends them later by priority and age. Attach
+    /// storage to `backlog` first to keep held readings across reboots.
+    /// Timestamped readings taken before the network time is known trigger a
+    /// DeviceTimeReq before they go out, repeated every 15 minutes while the
+    /// network does not answer.
+    ///
+    /// MAC answers normally ride along with application uplinks. When the
+    /// network asked for something and the application stays quiet, this task
+    /// sends an empty uplink so the answers (and any downlink ACK) still go out.
+    #[embassy_executor::task]
+    pub async fn lorawan_task(lorawan: BoardLoRaWAN, backlog: UplinkQueue, channels: &'static LoRaWANChannels) {
+        run(lorawan, backlog, channels).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embassy_futures::block_on;
+
+    #[test]
+    fn handlers_only_queue_their_ports() {
+        let channels = LoRaWANChannels::new();
+        let mut config = channels.subscribe(Some(10)).unwrap();
+        let mut all = channels.subscribe(None).unwrap();
+
+        channels.publish(Downlink::on_port(Some(10), &[0x10]));
+        for i in 0..4 {
+            channels.publish(Downlink::on_port(Some(20), &[i]));
+        }
+        // MAC-only downlinks stay with the stack
+        channels.publish(Downlink::on_port(None, &[]));
+
+        // Port 20 traffic did not push out the port 10 downlink
+        assert_eq!(block_on(config.next()).payload(), [0x10]);
+        assert!(channels.downlinks[config.index].try_receive().is_err());
+
+        // The slow all-ports handler kept the newest
+        assert_eq!(block_on(all.next()).payload(), [2]);
+        assert_eq!(block_on(all.next()).payload(), [3]);
+        assert!(channels.downlinks[all.index].try_receive().is_err());
+    }
+
+    #[test]
+    fn dropped_handler_frees_its_slot() {
+        let channels = LoRaWANChannels::new();
+        let mut handlers: Vec<_> =
+            (1..=MAX_PORT_HANDLERS as u8).map(|port| channels.subscribe(Some(port)).unwrap()).collect();
+        assert!(matches!(channels.subscribe(None), Err(LoRaWANError::TooManyHandlers)));
+        assert!(matches!(channels.subscribe(Some(0)), Err(LoRaWANError::InvalidPort)));
+
+        channels.publish(Downlink::on_port(Some(1), &[0x01]));
+        drop(handlers.remove(0));
+        // The new handler does not see the unread downlink of the old one
+        let handler = channels.subscribe(Some(1)).unwrap();
+        assert!(channels.downlinks[handler.index].try_receive().is_err());
+    }
+}
@@ -0,0 +1,59 @@
+//! Network time
+//!
+//! DeviceTimeAns carries the GPS time at the end of the uplink that held the
+//! DeviceTimeReq: whole seconds since the GPS epoch (1980-01-06 00:00:00
+//! UTC) and a fraction in 1/256 s. GPS time has no leap seconds, so it runs
+//! [`GPS_UTC_OFFSET_S`] ahead of UTC.
+
+use embassy_time::Instant;
+
+/// The GPS epoch in seconds since the Unix epoch
+pub const GPS_EPOCH_UNIX_S: u64 = 315_964_800;
+
+/// Leap seconds between GPS time and UTC (since 2017-01-01)
+pub const GPS_UTC_OFFSET_S: u64 = 18;
+
+/// A clock the stack sets from the network time, such as the STM32 RTC
+pub trait WallClock {
+    /// Set the current time, in milliseconds since the Unix epoch (UTC)
+    fn set_unix_ms(&self, unix_ms: u64);
+}
+
+/// GPS time at a local instant, from the last DeviceTimeAns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NetworkTime {
+    /// Local time the reference applies to
+    pub at: Instant,
+    /// Milliseconds since the GPS epoch at `at`
+    pub gps_ms: u64,
+}
+
+impl NetworkTime {
+    /// Reference from a DeviceTimeAns for an uplink that ended at `tx_end`
+    pub fn from_device_time_ans(tx_end: Instant, seconds: u32, fraction: u8) -> Self {
+        Self {
+            at: tx_end,
+            gps_ms: seconds as u64 * 1000 + fraction as u64 * 1000 / 256,
+        }
+    }
+
+    /// GPS time in milliseconds at `now`
+    pub fn gps_ms_at(&self, now: Instant) -> u64 {
+        self.gps_ms + now.saturating_duration_since(self.at).as_millis()
+    }
+
+    /// UTC in milliseconds since the Unix epoch at `now`
+    pub fn unix_ms_at(&self, now: Instant) -> u64 {
+        gps_to_unix_ms(self.gps_ms_at(now))
+    }
+}
+
+/// Convert milliseconds since the GPS epoch to milliseconds since the Unix epoch
+pub fn gps_to_unix_ms(gps_ms: u64) -> u64 {
+    gps_ms + (GPS_EPOCH_UNIX_S - GPS_UTC_OFFSET_S) * 1000
+}
+
+/// Convert milliseconds since the Unix epoch to milliseconds since the GPS epoch
+pub fn unix_to_gps_ms(unix_ms: u64) -> u64 {
+    unix_ms.saturating_sub((GPS_EPOCH_UNIX_S - GPS_UTC_OFFSET_S) * 1000)
+}
@@ -0,0 +1,535 @@
+//! Store-and-forward uplink queue
+//!
+//! Readings that cannot go out right away (the device is not joined, every
+//! channel is in duty-cycle time-off, or a confirmed uplink went
+//! unacknowledged) wait in an [`UplinkQueue`] and are sent later: highest
+//! [`Priority`] first, oldest first within a priority. When the queue is
+//! full, the oldest reading of the lowest priority makes room, but never one
+//! that outranks the newcomer.
+//!
+//! Every reading keeps the time it was taken. Until the network time is
+//! known that is a local [`Instant`]; [`UplinkQueue::resolve_timestamps`]
+//! turns it into Unix time once a DeviceTimeAns arrived. Readings sent with
+//! [`UplinkOptions::timestamp`] carry it in their last four bytes.
+//!
+//! With storage attached, readings that have to wait are also written to a
+//! spill area of [`SPILL_LEN`] bytes, one slot per queue entry, and are
+//! restored after a reboot:
+//!
+//! - `0..4`: sequence number (0 = free slot)
+//! - `4`: FPort
+//! - `5`: flags (bit 0 confirmed, bit 1 timestamped, bits 4-5 priority)
+//! - `6`: payload length
+//! - `8..12`: Unix time the reading was taken in seconds, 0 if unknown
+//! - `12..254`: payload
+//! - `254..256`: CRC
+
+use embassy_time::Instant;
+
+use super::lorawan::MAX_PAYLOAD;
+use super::persist::{self, NvError, NvStorage};
+use super::time::{self, NetworkTime};
+
+/// Readings the queue holds
+pub const BACKLOG_LEN: usize = 8;
+
+/// Bytes of storage the spill area needs
+pub const SPILL_LEN: usize = BACKLOG_LEN * SPILL_SLOT_LEN;
+
+/// Length of the timestamp appended to timestamped readings
+pub const TIMESTAMP_LEN: usize = 4;
+
+/// Sequence, port, flags, length, padding, Unix time, payload, CRC
+const SPILL_SLOT_LEN: usize = 12 + MAX_PAYLOAD + 2;
+
+const FLAG_CONFIRMED: u8 = 0x01;
+const FLAG_TIMESTAMP: u8 = 0x02;
+const FLAG_PRIORITY_SHIFT: u8 = 4;
+
+/// Order in which queued readings are sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, defmt::Format)]
+pub enum Priority {
+    /// Bulk data that can wait the longest
+    Telemetry = 0,
+    /// Regular measurements
+    #[default]
+    Periodic = 1,
+    /// Alarms and other events that must get through first
+    Alarm = 2,
+}
+
+impl Priority {
+    fn from_bits(bits: u8) -> Option<Self> {
+        match bits {
+            0 => Some(Self::Telemetry),
+            1 => Some(Self::Periodic),
+            2 => Some(Self::Alarm),
+            _ => None,
+        }
+    }
+}
+
+/// How a reading is queued and sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct UplinkOptions {
+    pub priority: Priority,
+    /// Send as a confirmed uplink
+    pub confirmed: bool,
+    /// Append the Unix time the reading was taken, in seconds as a little
+    /// endian `u32`, 0 when it could not be determined
+    pub timestamp: bool,
+}
+
+/// When a reading was taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timestamp {
+    /// Local time; the network time was not known yet
+    Local(Instant),
+    /// Seconds since the Unix epoch
+    Unix(u32),
+    /// Taken before a reboot, while the network time was unknown
+    Unknown,
+}
+
+impl Timestamp {
+    /// Convert a local timestamp to Unix time with the network time
+    pub fn resolve(&mut self, time: &NetworkTime) {
+        if let Self::Local(at) = *self {
+            // The reading may predate the DeviceTimeAns
+            let offset_ms = at.as_millis() as i64 - time.at.as_millis() as i64;
+            let gps_ms = (time.gps_ms as i64 + offset_ms).max(0) as u64;
+            *self = Self::Unix((time::gps_to_unix_ms(gps_ms) / 1000) as u32);
+        }
+    }
+
+    /// Seconds since the Unix epoch, if known
+    pub fn unix_s(&self) -> Option<u32> {
+        match *self {
+            Self::Unix(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+/// A reading waiting to be sent
+#[derive(Clone)]
+pub struct QueuedUplink {
+    pub port: u8,
+    pub options: UplinkOptions,
+    pub taken_at: Timestamp,
+    /// Queue order within a priority
+    seq: u32,
+    /// The entry's spill slot holds an up-to-date copy
+    spilled: bool,
+    len: usize,
+    data: [u8; MAX_PAYLOAD],
+}
+
+impl QueuedUplink {
+    /// A reading taken at `taken_at`, `None` if `data` (and the timestamp)
+    /// exceed the largest payload
+    pub fn new(port: u8, data: &[u8], options: UplinkOptions, taken_at: Instant) -> Option<Self> {
+        let extra = if options.timestamp { TIMESTAMP_LEN } else { 0 };
+        if data.len() + extra > MAX_PAYLOAD {
+            return None;
+        }
+        let mut uplink = Self {
+            port,
+            options,
+            taken_at: Timestamp::Local(taken_at),
+            seq: 0,
+            spilled: false,
+            len: data.len(),
+            data: [0u8; MAX_PAYLOAD],
+        };
+        uplink.data[..data.len()].copy_from_slice(data);
+        Some(uplink)
+    }
+
+    /// The reading as queued
+    pub fn payload(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    /// Build the FRMPayload into `out`, appending the timestamp if asked
+    /// for, and return its length
+    pub fn encode(&self, out: &mut [u8; MAX_PAYLOAD]) -> usize {
+        out[..self.len].copy_from_slice(self.payload());
+        if !self.options.timestamp {
+            return self.len;
+        }
+        let unix_s = self.taken_at.unix_s().unwrap_or(0);
+        out[self.len..self.len + TIMESTAMP_LEN].copy_from_slice(&unix_s.to_le_bytes());
+        self.len + TIMESTAMP_LEN
+    }
+
+    /// Whether the timestamp to send still waits for the network time
+    pub fn needs_time(&self) -> bool {
+        self.options.timestamp && matches!(self.taken_at, Timestamp::Local(_))
+    }
+
+    fn encode_slot(&self) -> [u8; SPILL_SLOT_LEN] {
+        let mut b = [0u8; SPILL_SLOT_LEN];
+        b[0..4].copy_from_slice(&self.seq.to_le_bytes());
+        b[4] = self.port;
+        b[5] = (self.options.priority as u8) << FLAG_PRIORITY_SHIFT;
+        if self.options.confirmed {
+            b[5] |= FLAG_CONFIRMED;
+        }
+        if self.options.timestamp {
+            b[5] |= FLAG_TIMESTAMP;
+        }
+        b[6] = self.len as u8;
+        b[8..12].copy_from_slice(&self.taken_at.unix_s().unwrap_or(0).to_le_bytes());
+        b[12..12 + self.len].copy_from_slice(self.payload());
+        let crc = persist::crc16(&b[..SPILL_SLOT_LEN - 2]);
+        b[SPILL_SLOT_LEN - 2..].copy_from_slice(&crc.to_le_bytes());
+        b
+    }
+
+    fn decode_slot(b: &[u8; SPILL_SLOT_LEN]) -> Option<Self> {
+        let seq = u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+        let crc = u16::from_le_bytes([b[SPILL_SLOT_LEN - 2], b[SPILL_SLOT_LEN - 1]]);
+        if seq == 0 || persist::crc16(&b[..SPILL_SLOT_LEN - 2]) != crc {
+            return None;
+        }
+        let len = b[6] as usize;
+        let options = UplinkOptions {
+            priority: Priority::from_bits(b[5] >> FLAG_PRIORITY_SHIFT)?,
+            confirmed: b[5] & FLAG_CONFIRMED != 0,
+            timestamp: b[5] & FLAG_TIMESTAMP != 0,
+        };
+        let extra = if options.timestamp { TIMESTAMP_LEN } else { 0 };
+        if len + extra > MAX_PAYLOAD {
+            return None;
+        }
+        let unix_s = u32::from_le_bytes([b[8], b[9], b[10], b[11]]);
+        let mut data = [0u8; MAX_PAYLOAD];
+        data[..len].copy_from_slice(&b[12..12 + len]);
+        Some(Self {
+            port: b[4],
+            options,
+            taken_at: if unix_s == 0 { Timestamp::Unknown } else { Timestamp::Unix(unix_s) },
+            seq,
+            spilled: true,
+            len,
+            data,
+        })
+    }
+}
+
+/// Bounded priority queue of readings, optionally spilled to storage
+pub struct UplinkQueue {
+    entries: [Option<QueuedUplink>; BACKLOG_LEN],
+    next_seq: u32,
+    storage: Option<&'static mut dyn NvStorage>,
+}
+
+impl UplinkQueue {
+    pub const fn new() -> Self {
+        Self {
+            entries: [const { None }; BACKLOG_LEN],
+            next_seq: 1,
+            storage: None,
+        }
+    }
+
+    /// Attach storage for the spill area and restore the readings in it
+    ///
+    /// `storage` must provide at least [`SPILL_LEN`] bytes, apart from the
+    /// LoRaWAN session storage. Returns the number of readings restored.
+    pub fn attach_storage(&mut self, storage: &'static mut dyn NvStorage) -> usize {
+        let mut restored = 0;
+        for (slot, entry) in self.entries.iter_mut().enumerate() {
+            let mut b = [0u8; SPILL_SLOT_LEN];
+            if let Err(e) = storage.read(slot * SPILL_SLOT_LEN, &mut b) {
+                defmt::warn!("Failed to read spilled uplink: {:?}", e);
+                continue;
+            }
+            match (entry.as_mut(), QueuedUplink::decode_slot(&b)) {
+                (None, Some(uplink)) => {
+                    self.next_seq = self.next_seq.max(uplink.seq.wrapping_add(1));
+                    *entry = Some(uplink);
+                    restored += 1;
+                }
+                // The slot belongs to a reading queued before the storage
+                (Some(uplink), _) => uplink.spilled = false,
+                (None, None) => {}
+            }
+        }
+        self.storage = Some(storage);
+        restored
+    }
+
+    /// Number of queued readings
+    pub fn len(&self) -> usize {
+        self.entries.iter().flatten().count()
+    }
+
+    /// Whether no reading is queued
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(Option::is_none)
+    }
+
+    /// Queue a reading
+    ///
+    /// Returns the reading dropped to make room, which is `uplink` itself
+    /// if every queued reading outranks it.
+    pub fn push(&mut self, mut uplink: QueuedUplink) -> Option<QueuedUplink> {
+        uplink.seq = self.next_seq;
+        uplink.spilled = false;
+        self.next_seq = self.next_seq.wrapping_add(1).max(1);
+
+        if let Some(free) = self.entries.iter().position(Option::is_none) {
+            self.entries[free] = Some(uplink);
+            return None;
+        }
+        let victim = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter_map(|(i, e)| e.as_ref().map(|e| (i, e)))
+            .filter(|(_, e)| e.options.priority <= uplink.options.priority)
+            .min_by_key(|(_, e)| (e.options.priority, e.seq))
+            .map(|(i, _)| i);
+        match victim {
+            Some(i) => {
+                // The newcomer is written to the slot when it is spilled
+                self.clear_slot(i);
+                self.entries[i].replace(uplink)
+            }
+            None => Some(uplink),
+        }
+    }
+
+    /// The reading to send next
+    pub fn peek(&self) -> Option<&QueuedUplink> {
+        self.next_index().and_then(|i| self.entries[i].as_ref())
+    }
+
+    /// Remove the reading [`peek`](Self::peek) returns, after it was sent
+    pub fn pop(&mut self) -> Option<QueuedUplink> {
+        let i = self.next_index()?;
+        let uplink = self.entries[i].take()?;
+        self.clear_slot(i);
+        Some(uplink)
+    }
+
+    /// Write the readings not yet in storage to their spill slots
+    ///
+    /// Does nothing without storage attached.
+    pub fn spill(&mut self) -> Result<(), NvError> {
+        let Some(storage) = self.storage.as_deref_mut() else {
+            return Ok(());
+        };
+        for (slot, entry) in self.entries.iter_mut().enumerate() {
+            let Some(uplink) = entry.as_mut().filter(|e| !e.spilled) else {
+                continue;
+            };
+            storage.write(slot * SPILL_SLOT_LEN, &uplink.encode_slot())?;
+            uplink.spilled = true;
+        }
+        Ok(())
+    }
+
+    /// Convert the local timestamps of queued readings to Unix time
+    ///
+    /// Readings spilled before are rewritten with the resolved time.
+    pub fn resolve_timestamps(&mut self, time: &NetworkTime) {
+        for uplink in self.entries.iter_mut().flatten() {
+            if let Timestamp::Local(_) = uplink.taken_at {
+                uplink.taken_at.resolve(time);
+                uplink.spilled = false;
+            }
+        }
+    }
+
+    /// Free spill slot `slot`, which may hold an outdated copy even when
+    /// its entry is not spilled
+    fn clear_slot(&mut self, slot: usize) {
+        let Some(storage) = self.storage.as_deref_mut() else {
+            return;
+        };
+        if let Err(e) = storage.write(slot * SPILL_SLOT_LEN, &[0u8; 4]) {
+            defmt::warn!("Failed to clear spilled uplink: {:?}", e);
+        }
+    }
+
+    /// Highest priority, then oldest
+    fn next_index(&self) -> Option<usize> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter_map(|(i, e)| e.as_ref().map(|e| (i, e)))
+            .max_by_key(|(_, e)| (e.options.priority, core::cmp::Reverse(e.seq)))
+            .map(|(i, _)| i)
+    }
+}
+
+impl Default for UplinkQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lora::persist::tests::Memory;
+
+    fn reading(tag: u8, priority: Priority) -> QueuedUplink {
+        let options = UplinkOptions { priority, ..UplinkOptions::default() };
+        QueuedUplink::new(10, &[tag], options, Instant::from_secs(tag as u64)).unwrap()
+    }
+
+    fn drain(queue: &mut UplinkQueue) -> Vec<u8> {
+        let mut tags = Vec::new();
+        while let Some(next) = queue.peek().map(|u| u.payload()[0]) {
+            assert_eq!(queue.pop().unwrap().payload()[0], next);
+            tags.push(next);
+        }
+        tags
+    }
+
+    #[test]
+    fn priority_then_age() {
+        let mut queue = UplinkQueue::new();
+        queue.push(reading(1, Priority::Periodic));
+        queue.push(reading(2, Priority::Telemetry));
+        queue.push(reading(3, Priority::Alarm));
+        queue.push(reading(4, Priority::Periodic));
+        queue.push(reading(5, Priority::Alarm));
+        assert_eq!(queue.len(), 5);
+        assert_eq!(drain(&mut queue), [3, 5, 1, 4, 2]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn push_never_evicts_higher_priority() {
+        let mut queue = UplinkQueue::new();
+        queue.push(reading(1, Priority::Alarm));
+        for tag in 2..=BACKLOG_LEN as u8 {
+            queue.push(reading(tag, Priority::Periodic));
+        }
+
+        // Everything queued outranks telemetry
+        let dropped = queue.push(reading(20, Priority::Telemetry)).unwrap();
+        assert_eq!(dropped.payload(), [20]);
+        // The oldest reading of the lowest priority makes room
+        let dropped = queue.push(reading(21, Priority::Periodic)).unwrap();
+        assert_eq!(dropped.payload(), [2]);
+        let dropped = queue.push(reading(22, Priority::Alarm)).unwrap();
+        assert_eq!(dropped.payload(), [3]);
+        assert_eq!(queue.len(), BACKLOG_LEN);
+        assert_eq!(drain(&mut queue), [1, 22, 4, 5, 6, 7, 8, 21]);
+
+        // Among alarms only, the oldest alarm goes
+        for tag in 30..30 + BACKLOG_LEN as u8 {
+            queue.push(reading(tag, Priority::Alarm));
+        }
+        assert_eq!(queue.push(reading(40, Priority::Periodic)).unwrap().payload(), [40]);
+        assert_eq!(queue.push(reading(41, Priority::Alarm)).unwrap().payload(), [30]);
+    }
+
+    /// A second queue restoring the spill area of `queue`, with slot
+    /// `corrupt` damaged
+    fn restore(queue: &mut UplinkQueue, corrupt: Option<usize>) -> (UplinkQueue, usize) {
+        let mut memory = Memory::blank(SPILL_LEN);
+        queue.storage.as_deref_mut().unwrap().read(0, &mut memory.0).unwrap();
+        if let Some(slot) = corrupt {
+            memory.0[slot * SPILL_SLOT_LEN + 12] ^= 0x01;
+        }
+        let mut restored = UplinkQueue::new();
+        let count = restored.attach_storage(Box::leak(Box::new(memory)));
+        (restored, count)
+    }
+
+    #[test]
+    fn spill_round_trip() {
+        let mut queue = UplinkQueue::new();
+        assert_eq!(queue.attach_storage(Box::leak(Box::new(Memory::blank(SPILL_LEN)))), 0);
+
+        let options = UplinkOptions { priority: Priority::Alarm, confirmed: true, timestamp: true };
+        let mut alarm = QueuedUplink::new(7, &[0xA1, 0xA2], options, Instant::from_secs(1)).unwrap();
+        alarm.taken_at = Timestamp::Unix(1_700_000_000);
+        queue.push(reading(1, Priority::Periodic));
+        queue.push(alarm);
+        queue.push(reading(3, Priority::Telemetry));
+        // Nothing is written before the readings are spilled
+        assert_eq!(restore(&mut queue, None).1, 0);
+        queue.spill().unwrap();
+
+        let (mut restored, count) = restore(&mut queue, None);
+        assert_eq!(count, 3);
+        let first = restored.pop().unwrap();
+        assert_eq!((first.port, first.payload()), (7, &[0xA1, 0xA2][..]));
+        assert_eq!(first.options, options);
+        assert_eq!(first.taken_at, Timestamp::Unix(1_700_000_000));
+        // Local timestamps do not survive a reboot
+        let second = restored.pop().unwrap();
+        assert_eq!(second.payload(), [1]);
+        assert_eq!(second.taken_at, Timestamp::Unknown);
+        // New readings queue behind the restored ones
+        restored.push(reading(4, Priority::Telemetry));
+        assert_eq!(drain(&mut restored), [3, 4]);
+
+        // A slot with a bad CRC is skipped
+        let (mut restored, count) = restore(&mut queue, Some(0));
+        assert_eq!(count, 2);
+        assert_eq!(drain(&mut restored), [0xA1, 3]);
+
+        // Sent readings free their slot
+        queue.pop();
+        let (mut restored, count) = restore(&mut queue, None);
+        assert_eq!(count, 2);
+        assert_eq!(drain(&mut restored), [1, 3]);
+    }
+
+    #[test]
+    fn resolve_local_timestamps() {
+        // DeviceTimeAns at local second 100: 2024-05-17 16:53:02 UTC
+        let time = NetworkTime { at: Instant::from_secs(100), gps_ms: 1_400_000_000_000 };
+        let unix_s = 1_400_000_000 + (time::GPS_EPOCH_UNIX_S - time::GPS_UTC_OFFSET_S) as u32;
+
+        let mut before = Timestamp::Local(Instant::from_millis(40_500));
+        before.resolve(&time);
+        assert_eq!(before, Timestamp::Unix(unix_s - 60));
+        let mut after = Timestamp::Local(Instant::from_secs(130));
+        after.resolve(&time);
+        assert_eq!(after, Timestamp::Unix(unix_s + 30));
+        // Resolved and unknown times stay as they are
+        after.resolve(&NetworkTime { at: Instant::from_secs(0), gps_ms: 0 });
+        assert_eq!(after, Timestamp::Unix(unix_s + 30));
+        let mut unknown = Timestamp::Unknown;
+        unknown.resolve(&time);
+        assert_eq!(unknown.unix_s(), None);
+
+        let mut queue = UplinkQueue::new();
+        let options = UplinkOptions { timestamp: true, ..UplinkOptions::default() };
+        queue.push(QueuedUplink::new(1, &[1], options, Instant::from_secs(70)).unwrap());
+        assert!(queue.peek().unwrap().needs_time());
+        queue.resolve_timestamps(&time);
+        assert!(!queue.peek().unwrap().needs_time());
+        assert_eq!(queue.peek().unwrap().taken_at, Timestamp::Unix(unix_s - 30));
+    }
+
+    #[test]
+    fn encode_appends_little_endian_time() {
+        let options = UplinkOptions { timestamp: true, ..UplinkOptions::default() };
+        let mut uplink = QueuedUplink::new(1, &[0xAA, 0xBB], options, Instant::from_secs(0)).unwrap();
+        let mut out = [0u8; MAX_PAYLOAD];
+        // 0 while the time is unknown
+        assert_eq!(uplink.encode(&mut out), 6);
+        assert_eq!(out[..6], [0xAA, 0xBB, 0, 0, 0, 0]);
+        uplink.taken_at = Timestamp::Unix(0x6647_8E52);
+        assert_eq!(uplink.encode(&mut out), 6);
+        assert_eq!(out[..6], [0xAA, 0xBB, 0x52, 0x8E, 0x47, 0x66]);
+
+        let plain = QueuedUplink::new(1, &[0xAA, 0xBB], UplinkOptions::default(), Instant::from_secs(0)).unwrap();
+        assert_eq!(plain.encode(&mut out), 2);
+
+        // The timestamp counts against the largest payload
+        assert!(QueuedUplink::new(1, &[0; MAX_PAYLOAD], options, Instant::from_secs(0)).is_none());
+        assert!(QueuedUplink::new(1, &[0; MAX_PAYLOAD - TIMESTAMP_LEN], options, Instant::from_secs(0)).is_some());
+    }
+}
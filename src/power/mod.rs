@@ -6,9 +6,10 @@
 //! - Low-power mode transitions
 //! - Power state management
 
+#[cfg(target_os = "none")]
 use embassy_stm32::adc::{Adc, AdcChannel};
 use embassy_time::{Duration, Timer};
-use defmt::{info, warn};
+use defmt::info;
 
 /// Battery voltage thresholds (in millivolts)
 pub const BATTERY_FULL: u16 = 4200;      // 4.2V - Fully charged Li-ion
@@ -73,10 +74,12 @@ impl BatteryStatus {
 }
 
 /// Power manager for monitoring battery and solar panel
+#[cfg(target_os = "none")]
 pub struct PowerManager<'a> {
     adc: &'a mut Adc<'static, embassy_stm32::peripherals::ADC1>,
 }
 
+#[cfg(target_os = "none")]
 impl<'a> PowerManager<'a> {
     /// Create a new power manager
     pub fn new(adc: &'a mut Adc<'static, embassy_stm32::peripherals::ADC1>) -> Self {